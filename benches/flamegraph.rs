@@ -1,16 +1,24 @@
 use criterion::{Criterion, criterion_group, criterion_main};
+use gpt_os::generate::{GenerateOptions, generate};
+use std::io::BufWriter;
 use std::process::Command;
 use std::time::Duration;
 use tempfile::NamedTempFile;
 
 fn bench_sample(c: &mut Criterion) {
-    c.bench_function("process_sample_export", |b| {
+    // A reproducible synthetic export, equivalent to
+    // `gpt-os generate --records 1000000 --types 80`
+    let input = NamedTempFile::with_suffix(".xml").expect("temp file");
+    generate(BufWriter::new(input.as_file()), &GenerateOptions::default())
+        .expect("generate export");
+
+    c.bench_function("process_synthetic_export", |b| {
         b.iter(|| {
             // Create a temporary output file for each iteration
             let output = NamedTempFile::with_suffix(".zip").expect("temp file");
             // Invoke the CLI binary to measure full execution
             let status = Command::new(env!("CARGO_BIN_EXE_gpt-os"))
-                .arg("AppleHealth2025-06-28.zip")
+                .arg(input.path())
                 .arg(output.path())
                 .status()
                 .expect("failed to execute process");
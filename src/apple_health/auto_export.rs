@@ -0,0 +1,227 @@
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::error::Result;
+use crate::intern::intern_key;
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// HealthKit types of the metrics Health Auto Export names differently.
+/// Metrics not listed keep their name as `type`.
+const METRIC_TYPES: [(&str, &str); 26] = [
+    (
+        "active_energy",
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+    ),
+    (
+        "apple_exercise_time",
+        "HKQuantityTypeIdentifierAppleExerciseTime",
+    ),
+    ("apple_stand_time", "HKQuantityTypeIdentifierAppleStandTime"),
+    (
+        "basal_energy_burned",
+        "HKQuantityTypeIdentifierBasalEnergyBurned",
+    ),
+    ("blood_glucose", "HKQuantityTypeIdentifierBloodGlucose"),
+    (
+        "blood_oxygen_saturation",
+        "HKQuantityTypeIdentifierOxygenSaturation",
+    ),
+    ("blood_pressure", "HKCorrelationTypeIdentifierBloodPressure"),
+    (
+        "body_fat_percentage",
+        "HKQuantityTypeIdentifierBodyFatPercentage",
+    ),
+    ("body_mass_index", "HKQuantityTypeIdentifierBodyMassIndex"),
+    (
+        "body_temperature",
+        "HKQuantityTypeIdentifierBodyTemperature",
+    ),
+    (
+        "dietary_energy",
+        "HKQuantityTypeIdentifierDietaryEnergyConsumed",
+    ),
+    ("dietary_water", "HKQuantityTypeIdentifierDietaryWater"),
+    ("flights_climbed", "HKQuantityTypeIdentifierFlightsClimbed"),
+    ("heart_rate", "HKQuantityTypeIdentifierHeartRate"),
+    (
+        "heart_rate_variability",
+        "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+    ),
+    ("height", "HKQuantityTypeIdentifierHeight"),
+    ("lean_body_mass", "HKQuantityTypeIdentifierLeanBodyMass"),
+    ("mindful_minutes", "HKCategoryTypeIdentifierMindfulSession"),
+    (
+        "respiratory_rate",
+        "HKQuantityTypeIdentifierRespiratoryRate",
+    ),
+    (
+        "resting_heart_rate",
+        "HKQuantityTypeIdentifierRestingHeartRate",
+    ),
+    ("sleep_analysis", "HKCategoryTypeIdentifierSleepAnalysis"),
+    ("step_count", "HKQuantityTypeIdentifierStepCount"),
+    ("vo2_max", "HKQuantityTypeIdentifierVO2Max"),
+    (
+        "walking_heart_rate_average",
+        "HKQuantityTypeIdentifierWalkingHeartRateAverage",
+    ),
+    (
+        "walking_running_distance",
+        "HKQuantityTypeIdentifierDistanceWalkingRunning",
+    ),
+    ("weight_body_mass", "HKQuantityTypeIdentifierBodyMass"),
+];
+
+/// Sample keys holding the start of a sample, in order of preference.
+const START_KEYS: [&str; 3] = ["start", "sleepStart", "date"];
+
+/// Sample keys holding the end of a sample, in order of preference.
+const END_KEYS: [&str; 3] = ["end", "sleepEnd", "date"];
+
+#[derive(Deserialize)]
+struct AutoExport {
+    data: Data,
+}
+
+#[derive(Deserialize)]
+struct Data {
+    #[serde(default)]
+    metrics: Vec<Metric>,
+    #[serde(default)]
+    workouts: Vec<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct Metric {
+    name: String,
+    #[serde(default)]
+    units: Option<String>,
+    #[serde(default)]
+    data: Vec<Map<String, Value>>,
+}
+
+/// Whether `path` looks like a JSON file of the Health Auto Export app
+/// rather than an Apple Health export.
+pub fn is_auto_export(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+/// Records of a JSON file written by the Health Auto Export app: each
+/// metric sample as a `Record` of the matching HealthKit type and each
+/// workout as a `Workout`, with attributes named as in Apple's export
+/// where there is an equivalent.
+///
+/// Both apps write dates as `2024-01-15 08:30:00 -0800`, so they are kept
+/// as they are. Nested arrays, such as a workout's heart rate samples or
+/// route, are left out.
+pub fn read_records<R: Read>(reader: R) -> Result<Vec<GenericRecord>> {
+    let export: AutoExport = serde_json::from_reader(BufReader::new(reader))?;
+    let mut records = Vec::new();
+    for metric in export.data.metrics {
+        let typ = METRIC_TYPES
+            .iter()
+            .find(|(name, _)| *name == metric.name)
+            .map_or(metric.name.as_str(), |(_, typ)| typ);
+        for sample in metric.data {
+            records.push(sample_record(typ, metric.units.as_deref(), sample));
+        }
+    }
+    for workout in export.data.workouts {
+        records.push(workout_record(workout));
+    }
+    Ok(records)
+}
+
+fn sample_record(typ: &str, unit: Option<&str>, mut sample: Map<String, Value>) -> GenericRecord {
+    let mut attributes = Attributes::new();
+    insert(&mut attributes, "type", typ);
+    if let Some(unit) = unit {
+        insert(&mut attributes, "unit", unit);
+    }
+    let start = START_KEYS.iter().find_map(|key| scalar(sample.get(*key)?));
+    let end = END_KEYS.iter().find_map(|key| scalar(sample.get(*key)?));
+    if let Some(start) = start {
+        insert(&mut attributes, "startDate", &start);
+    }
+    if let Some(end) = end {
+        insert(&mut attributes, "endDate", &end);
+    }
+    for key in ["date", "start", "end"] {
+        sample.remove(key);
+    }
+    // Aggregated metrics such as heart rate have no quantity but a mean
+    if let Some(value) = sample.remove("qty").or_else(|| sample.get("Avg").cloned()) {
+        insert_value(&mut attributes, "value", &value);
+    }
+    if let Some(source) = sample.remove("source") {
+        insert_value(&mut attributes, "sourceName", &source);
+    }
+    for (key, value) in &sample {
+        insert_value(&mut attributes, key, value);
+    }
+    GenericRecord {
+        element_name: "Record".to_string(),
+        attributes,
+    }
+}
+
+fn workout_record(mut workout: Map<String, Value>) -> GenericRecord {
+    let mut attributes = Attributes::new();
+    for (from, to) in [
+        ("name", "workoutActivityType"),
+        ("start", "startDate"),
+        ("end", "endDate"),
+    ] {
+        if let Some(value) = workout.remove(from) {
+            insert_value(&mut attributes, to, &value);
+        }
+    }
+    // Durations are in seconds, Apple's export gives minutes
+    if let Some(seconds) = workout.remove("duration").as_ref().and_then(Value::as_f64) {
+        insert(&mut attributes, "duration", &(seconds / 60.0).to_string());
+        insert(&mut attributes, "durationUnit", "min");
+    }
+    // An identifier of the app's own, which would take the `id` column
+    workout.remove("id");
+    for (key, value) in &workout {
+        match value {
+            // Quantities such as `{"qty": 5.2, "units": "km"}`
+            Value::Object(quantity) if quantity.contains_key("qty") => {
+                insert_value(&mut attributes, key, &quantity["qty"]);
+                if let Some(units) = quantity.get("units") {
+                    insert_value(&mut attributes, &format!("{}Unit", key), units);
+                }
+            }
+            Value::Object(_) | Value::Array(_) => debug!("Leaving '{}' out of a workout", key),
+            value => insert_value(&mut attributes, key, value),
+        }
+    }
+    GenericRecord {
+        element_name: "Workout".to_string(),
+        attributes,
+    }
+}
+
+/// `value` as an attribute value, unless it is null or nested.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+fn insert(attributes: &mut Attributes, key: &str, value: &str) {
+    attributes.insert(intern_key(key), attribute_value(key, value));
+}
+
+fn insert_value(attributes: &mut Attributes, key: &str, value: &Value) {
+    if let Some(value) = scalar(value) {
+        insert(attributes, key, &value);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::apple_health::export_info::lower_first;
+use crate::apple_health::types::GenericRecord;
+use crate::intern::intern_key;
+
+/// Element of the health records downloaded from providers.
+pub const CLINICAL_ELEMENT: &str = "ClinicalRecord";
+
+/// Element holding one metadata key and value of its parent element.
+pub const METADATA_ELEMENT: &str = "MetadataEntry";
+
+/// Group of the records of the [`MEDICATION_TYPES`], such as logged doses.
+pub const MEDICATIONS_GROUP: &str = "Medications";
+
+/// Prefixes of the `<Record>` types of medications: dose events, with or
+/// without a type identifier after them, and medication records.
+const MEDICATION_TYPES: [&str; 2] = [
+    "HKMedicationDoseEvent",
+    "HKClinicalTypeIdentifierMedicationRecord",
+];
+
+const CLINICAL_PREFIX: &str = "HKClinicalTypeIdentifier";
+
+const METADATA_PREFIX: &str = "HKMetadataKey";
+
+/// Group names of the known clinical record types.
+const CLINICAL_GROUPS: [(&str, &str); 9] = [
+    ("AllergyRecord", "ClinicalAllergies"),
+    ("ClinicalNoteRecord", "ClinicalNotes"),
+    ("ConditionRecord", "ClinicalConditions"),
+    ("CoverageRecord", "ClinicalCoverage"),
+    ("ImmunizationRecord", "ClinicalImmunizations"),
+    ("LabResultRecord", "ClinicalLabResults"),
+    ("MedicationRecord", "ClinicalMedications"),
+    ("ProcedureRecord", "ClinicalProcedures"),
+    ("VitalSignRecord", "ClinicalVitalSigns"),
+];
+
+/// Group of a `<Record>` of type `typ`: [`MEDICATIONS_GROUP`] for
+/// [`MEDICATION_TYPES`], otherwise the type itself.
+pub fn record_group(typ: &str) -> &str {
+    if MEDICATION_TYPES
+        .iter()
+        .any(|prefix| typ.starts_with(prefix))
+    {
+        MEDICATIONS_GROUP
+    } else {
+        typ
+    }
+}
+
+/// Group of a `<ClinicalRecord>` of type `typ`, such as `ClinicalLabResults`
+/// for `HKClinicalTypeIdentifierLabResultRecord`; unknown types keep their
+/// name after a `Clinical` prefix.
+pub fn clinical_group(typ: &str) -> String {
+    let kind = typ.strip_prefix(CLINICAL_PREFIX).unwrap_or(typ);
+    match CLINICAL_GROUPS.iter().find(|(k, _)| *k == kind) {
+        Some((_, group)) => group.to_string(),
+        None => format!("Clinical{}", kind.strip_prefix("Clinical").unwrap_or(kind)),
+    }
+}
+
+/// Whether `record` is a medication or clinical record, which takes in its
+/// `MetadataEntry` children as columns.
+fn takes_metadata(record: &GenericRecord) -> bool {
+    match record.element_name.as_str() {
+        CLINICAL_ELEMENT => true,
+        "Record" => record
+            .attributes
+            .get("type")
+            .is_some_and(|typ| record_group(typ) == MEDICATIONS_GROUP),
+        _ => false,
+    }
+}
+
+/// Fold `child` into `parent` as a column if it is a `MetadataEntry` of a
+/// medication or clinical record, such as `HKMetadataKeyDose` becoming
+/// `dose`. Other elements are handed back.
+pub fn nest_metadata(parent: &mut GenericRecord, child: GenericRecord) -> Option<GenericRecord> {
+    if child.element_name != METADATA_ELEMENT || !takes_metadata(parent) {
+        return Some(child);
+    }
+    let (Some(key), Some(value)) = (child.attributes.get("key"), child.attributes.get("value"))
+    else {
+        return Some(child);
+    };
+    let column = lower_first(key.strip_prefix(METADATA_PREFIX).unwrap_or(key));
+    // Never replace one of the record's own attributes
+    let column = if parent.attributes.contains_key(column.as_str()) {
+        key.to_string()
+    } else {
+        column
+    };
+    parent.attributes.insert(intern_key(&column), value.clone());
+    None
+}
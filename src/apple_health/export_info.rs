@@ -0,0 +1,77 @@
+use crate::apple_health::types::{Attributes, GenericRecord};
+use crate::intern::intern_key;
+use std::sync::Arc;
+
+/// Group (and CSV file) name for the consolidated export-level metadata.
+pub const EXPORT_INFO_GROUP: &str = "export_info";
+
+/// Element name of the synthesized export metadata record.
+pub const EXPORT_INFO_ELEMENT: &str = "ExportInfo";
+
+const CHARACTERISTIC_PREFIX: &str = "HKCharacteristicTypeIdentifier";
+
+/// Collects the `<ExportDate>` and `<Me>` elements into a single record.
+#[derive(Debug, Default)]
+pub struct ExportInfo {
+    attributes: Attributes,
+}
+
+impl ExportInfo {
+    /// Absorb `record` if it is export-level metadata, otherwise hand it back.
+    pub fn absorb(&mut self, record: GenericRecord) -> Option<GenericRecord> {
+        match record.element_name.as_str() {
+            "ExportDate" => {
+                if let Some(value) = record.attributes.get("value") {
+                    self.attributes
+                        .insert(intern_key("exportDate"), value.clone());
+                }
+                None
+            }
+            "Me" => {
+                for (key, value) in record.attributes {
+                    let (column, value) = characteristic_column(&key, value);
+                    self.attributes.insert(intern_key(&column), value);
+                }
+                None
+            }
+            _ => Some(record),
+        }
+    }
+
+    /// The consolidated record, or `None` if neither element was seen.
+    pub fn into_record(self) -> Option<GenericRecord> {
+        if self.attributes.is_empty() {
+            return None;
+        }
+        Some(GenericRecord {
+            element_name: EXPORT_INFO_ELEMENT.to_string(),
+            attributes: self.attributes,
+        })
+    }
+}
+
+/// Map a `<Me>` attribute to a friendly column name and strip enum prefixes
+/// such as `HKBiologicalSex` from its value.
+fn characteristic_column(key: &str, value: Arc<str>) -> (String, Arc<str>) {
+    let name = key.strip_prefix(CHARACTERISTIC_PREFIX).unwrap_or(key);
+    let (column, value_prefix) = match name {
+        "DateOfBirth" => ("dateOfBirth".to_string(), None),
+        "BiologicalSex" => ("biologicalSex".to_string(), Some("HKBiologicalSex")),
+        "BloodType" => ("bloodType".to_string(), Some("HKBloodType")),
+        "FitzpatrickSkinType" => ("skinType".to_string(), Some("HKFitzpatrickSkinType")),
+        other => (lower_first(other), None),
+    };
+    let value = match value_prefix.and_then(|prefix| value.strip_prefix(prefix)) {
+        Some(stripped) if !stripped.is_empty() => Arc::from(stripped),
+        _ => value,
+    };
+    (column, value)
+}
+
+pub(crate) fn lower_first(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -1,67 +1,386 @@
-use crate::xml_utils::{self, BUFFER_SIZE};
+use crate::xml_utils;
 use quick_xml::events::BytesStart;
 
+use crate::apple_health::auto_export::{self, is_auto_export};
+use crate::apple_health::export_info::ExportInfo;
+use crate::apple_health::prescan::count_record_types;
 use crate::apple_health::types::GenericRecord;
-use crate::core::Extractor;
+use crate::backpressure::Backpressure;
+use crate::core::{Extractor, Processable};
 use crate::error::{AppError, Result};
+use crate::fit;
+use crate::intern::intern_key;
+use crate::progress::{Progress, ProgressReader};
+use crate::remote::{self, Download, HashingReader, InputDigest};
+use crate::retry::RetryPolicy;
+use crate::runkeeper;
+use crate::sleep_cycle;
+use crate::suunto;
+use crate::wahoo;
+use crate::xml_utils::ParseFn;
+use ahash::AHashMap;
 use async_trait::async_trait;
 use crossbeam_channel as channel;
-use std::fs::File;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span, info_span};
 
-pub struct AppleHealthExtractor;
+/// Attribute naming the input a record was read from, added with
+/// [`AppleHealthExtractor::provenance`].
+pub const SOURCE_FILE_ATTRIBUTE: &str = "source_file";
+
+/// Attribute holding the byte offset of a record's element in the XML,
+/// added with [`AppleHealthExtractor::provenance`].
+pub const XML_OFFSET_ATTRIBUTE: &str = "xml_offset";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppleHealthExtractor {
+    mmap: bool,
+    chunked: bool,
+    provenance: bool,
+    backpressure: Backpressure,
+    retry: RetryPolicy,
+}
+
+impl AppleHealthExtractor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read plain `export.xml` inputs through a memory map instead of
+    /// buffered reads. The file must not change while it is being read.
+    /// ZIP inputs are always streamed.
+    pub fn mmap(mut self, enabled: bool) -> Self {
+        self.mmap = enabled;
+        self
+    }
+
+    /// Split plain `export.xml` inputs into chunks on `<Record` boundaries
+    /// and parse them on every thread at once. Implies a memory map, so the
+    /// file must not change while it is being read. ZIP inputs are always
+    /// streamed.
+    pub fn chunked(mut self, enabled: bool) -> Self {
+        self.chunked = enabled;
+        self
+    }
+
+    /// Add the input path and the byte offset of each element in the XML
+    /// (of `export.xml` inside a ZIP) to every record, as
+    /// [`SOURCE_FILE_ATTRIBUTE`] and [`XML_OFFSET_ATTRIBUTE`].
+    pub fn provenance(mut self, enabled: bool) -> Self {
+        self.provenance = enabled;
+        self
+    }
+
+    /// Bound the parsed records and raw XML bytes queued between reading,
+    /// parsing and grouping.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Retry opening and reading the input on transient errors, such as
+    /// those of network file systems. Reads through a memory map cannot be
+    /// retried, only opening the file.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
 
 #[async_trait]
 impl Extractor<GenericRecord> for AppleHealthExtractor {
-    async fn extract(&self, input_path: &Path) -> Result<mpsc::Receiver<Result<GenericRecord>>> {
-        let (tx, rx) = mpsc::channel(BUFFER_SIZE);
-        let (cb_tx, cb_rx) = channel::bounded(BUFFER_SIZE);
-        let path = Arc::new(input_path.to_path_buf());
-
-        let handle = if path.extension().and_then(|s| s.to_str()) == Some("zip") {
-            tokio::spawn(xml_utils::process_zip_stream_parallel(
-                path.clone(),
-                cb_tx.clone(),
-                Self::parse_generic,
-            ))
+    async fn extract(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<mpsc::Receiver<Result<GenericRecord>>> {
+        Ok(self.extract_hashed(input_path, progress).await?.0)
+    }
+
+    /// Hashes a download as it is parsed, for the manifest.
+    async fn extract_hashed(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<(mpsc::Receiver<Result<GenericRecord>>, Option<InputDigest>)> {
+        let mut digest = None;
+        let capacity = self.backpressure.channel_capacity();
+        let (tx, rx) = mpsc::channel(capacity);
+        let (cb_tx, cb_rx) = channel::bounded(capacity);
+        let budget = self.backpressure.byte_budget("parsers");
+        let (parse_fn, source_file): (ParseFn<GenericRecord>, _) = if self.provenance {
+            let source_file: Arc<str> = Arc::from(remote::redact(input_path));
+            (Self::parse_located, Some(source_file))
         } else {
-            let file = File::open(path.as_ref())?;
-            tokio::spawn(xml_utils::process_stream_parallel(
-                file,
+            (Self::parse_generic, None)
+        };
+
+        // Parsing outlives `extract`, running alongside the transform
+        let parse = info_span!("parse", input = %remote::redact(input_path));
+        let handle = if input_path.is_dir() {
+            let dir = input_path.to_path_buf();
+            spawn_records(move || fit::read_folder(&dir), cb_tx, parse.clone())
+        } else if remote::is_url(input_path) {
+            let url = input_path.to_string_lossy().into_owned();
+            let retry = self.retry;
+            let download = tokio::task::spawn_blocking(move || Download::open(&url, retry))
+                .await
+                .map_err(|e| AppError::Unknown(e.to_string()))??;
+            let total = download.size();
+            let (download, input_digest) = HashingReader::new(download);
+            digest = Some(input_digest);
+            let reader = ProgressReader::new(download, progress.clone(), total);
+            let name = remote::file_name(input_path);
+            if is_auto_export(name) {
+                spawn_records(
+                    move || auto_export::read_records(reader),
+                    cb_tx,
+                    parse.clone(),
+                )
+            } else if name.extension().and_then(|s| s.to_str()) == Some("zip") {
+                // A download cannot seek to the central directory
+                tokio::spawn(
+                    xml_utils::process_streamed_zip_parallel(reader, cb_tx, parse_fn, budget)
+                        .instrument(parse.clone()),
+                )
+            } else {
+                tokio::spawn(
+                    xml_utils::process_stream_parallel(reader, cb_tx, parse_fn, budget)
+                        .instrument(parse.clone()),
+                )
+            }
+        } else if suunto::is_suunto_workout(input_path) {
+            let file = self.retry.open(input_path)?;
+            let total = file.get_ref().metadata().ok().map(|m| m.len());
+            let reader = ProgressReader::new(file, progress.clone(), total);
+            let path = input_path.to_path_buf();
+            spawn_records(
+                move || suunto::read_workout(reader, &path),
+                cb_tx,
+                parse.clone(),
+            )
+        } else if is_auto_export(input_path) {
+            let file = self.retry.open(input_path)?;
+            let total = file.get_ref().metadata().ok().map(|m| m.len());
+            let reader = ProgressReader::new(file, progress.clone(), total);
+            spawn_records(
+                move || auto_export::read_records(reader),
+                cb_tx,
+                parse.clone(),
+            )
+        } else if runkeeper::is_runkeeper_export(input_path) {
+            let file = self.retry.open(input_path)?;
+            spawn_records(move || runkeeper::read_export(file), cb_tx, parse.clone())
+        } else if sleep_cycle::is_sleep_cycle_export(input_path) {
+            let file = self.retry.open(input_path)?;
+            let total = file.get_ref().metadata().ok().map(|m| m.len());
+            let reader = ProgressReader::new(file, progress.clone(), total);
+            spawn_records(
+                move || sleep_cycle::read_export(reader),
                 cb_tx,
-                Self::parse_generic,
-            ))
+                parse.clone(),
+            )
+        } else if wahoo::is_wahoo_export(input_path) {
+            let file = self.retry.open(input_path)?;
+            let path = input_path.to_path_buf();
+            spawn_records(
+                move || wahoo::read_export(file, &path),
+                cb_tx,
+                parse.clone(),
+            )
+        } else if input_path.extension().and_then(|s| s.to_str()) == Some("zip") {
+            let file = self.retry.open(input_path)?;
+            tokio::spawn(
+                xml_utils::process_zip_stream_parallel(
+                    file,
+                    cb_tx.clone(),
+                    parse_fn,
+                    budget,
+                    progress.clone(),
+                )
+                .instrument(parse.clone()),
+            )
+        } else if self.chunked {
+            let file = self.retry.open(input_path)?;
+            tokio::spawn(
+                xml_utils::process_mmap_chunked(
+                    file.into_inner(),
+                    cb_tx,
+                    parse_fn,
+                    budget,
+                    progress.clone(),
+                )
+                .instrument(parse.clone()),
+            )
+        } else if self.mmap {
+            let file = self.retry.open(input_path)?;
+            tokio::spawn(
+                xml_utils::process_mmap_parallel(
+                    file.into_inner(),
+                    cb_tx,
+                    parse_fn,
+                    budget,
+                    progress.clone(),
+                )
+                .instrument(parse.clone()),
+            )
+        } else {
+            let file = self.retry.open(input_path)?;
+            let total = file.get_ref().metadata().ok().map(|m| m.len());
+            tokio::spawn(
+                xml_utils::process_stream_parallel(
+                    ProgressReader::new(file, progress.clone(), total),
+                    cb_tx,
+                    parse_fn,
+                    budget,
+                )
+                .instrument(parse.clone()),
+            )
         };
 
-        let error_tx = tx.clone();
+        // Forward every parsed record before reporting how parsing ended, so
+        // a failure is always the last message the engine receives and
+        // cannot be lost to the channel closing
         tokio::spawn(async move {
-            match handle.await {
-                Ok(Ok(())) => {}
-                Ok(Err(e)) => {
-                    let _ = error_tx.send(Err(e)).await;
-                }
-                Err(e) => {
-                    let _ = error_tx.send(Err(AppError::Unknown(e.to_string()))).await;
-                }
+            let forward_tx = tx.clone();
+            let forwarded =
+                tokio::task::spawn_blocking(move || forward(cb_rx, forward_tx, source_file)).await;
+            let outcome = match handle.await {
+                Ok(result) => result,
+                Err(e) => Err(AppError::Unknown(e.to_string())),
+            };
+            let outcome = outcome.and(forwarded.map_err(|e| AppError::Unknown(e.to_string())));
+            if let Err(e) = outcome {
+                let _ = tx.send(Err(e)).await;
             }
         });
 
-        tokio::spawn(async move {
-            for record in cb_rx {
-                if tx.send(Ok(record)).await.is_err() {
-                    break;
-                }
+        Ok((rx, digest))
+    }
+
+    async fn count_groups(&self, input_path: &Path) -> Result<AHashMap<String, usize>> {
+        let path = input_path.to_path_buf();
+        let retry = self.retry;
+        tokio::task::spawn_blocking(move || {
+            let mut counts = AHashMap::new();
+            let records = if path.is_dir() {
+                fit::read_folder(&path)?
+            } else if remote::is_url(&path) && is_auto_export(remote::file_name(&path)) {
+                auto_export::read_records(Download::open(&path.to_string_lossy(), retry)?)?
+            } else if suunto::is_suunto_workout(&path) {
+                suunto::read_workout(retry.open(&path)?, &path)?
+            } else if is_auto_export(&path) {
+                auto_export::read_records(retry.open(&path)?)?
+            } else if runkeeper::is_runkeeper_export(&path) {
+                runkeeper::read_export(retry.open(&path)?)?
+            } else if sleep_cycle::is_sleep_cycle_export(&path) {
+                sleep_cycle::read_export(retry.open(&path)?)?
+            } else if wahoo::is_wahoo_export(&path) {
+                wahoo::read_export(retry.open(&path)?, &path)?
+            } else {
+                // A nested archive may hold several exports
+                xml_utils::with_export_reader(&path, |reader| {
+                    for (typ, count) in count_record_types(reader)? {
+                        *counts.entry(typ).or_insert(0) += count;
+                    }
+                    Ok(())
+                })?;
+                return Ok(counts);
+            };
+            for record in records {
+                *counts.entry(record.grouping_key()).or_insert(0) += 1;
             }
-        });
+            Ok(counts)
+        })
+        .await
+        .map_err(|e| AppError::Unknown(e.to_string()))?
+    }
+}
 
-        Ok(rx)
+/// Read the records of an input other than an Apple Health export with
+/// `read` on a blocking task, sending them to `sender`.
+fn spawn_records<F>(
+    read: F,
+    sender: channel::Sender<Result<GenericRecord>>,
+    parse: Span,
+) -> JoinHandle<Result<()>>
+where
+    F: FnOnce() -> Result<Vec<GenericRecord>> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let _span = parse.entered();
+        for record in read()? {
+            if sender.send(Ok(record)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Hand the parsed records over to the engine until the parsers are done
+/// or the engine stops listening. `ExportDate` and `Me` are folded into a
+/// single export_info record, sent last. Every record is tagged with
+/// `source_file` when given.
+fn forward(
+    parsed: channel::Receiver<Result<GenericRecord>>,
+    tx: mpsc::Sender<Result<GenericRecord>>,
+    source_file: Option<Arc<str>>,
+) {
+    let tag = |mut record: GenericRecord| {
+        if let Some(source_file) = &source_file {
+            let key = intern_key(SOURCE_FILE_ATTRIBUTE);
+            record.attributes.insert(key, Arc::clone(source_file));
+        }
+        record
+    };
+    let mut export_info = ExportInfo::default();
+    for result in parsed {
+        let result = match result {
+            Ok(record) => match export_info.absorb(record) {
+                Some(record) => Ok(tag(record)),
+                None => continue,
+            },
+            Err(e) => Err(e),
+        };
+        // Only a full channel opens a span, so profiles show how long the
+        // parsers waited on the transformer
+        let sent = match tx.try_send(result) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(result)) => {
+                let _wait = info_span!("wait", on = "transform").entered();
+                tx.blocking_send(result).map_err(drop)
+            }
+            Err(TrySendError::Closed(_)) => Err(()),
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+    if let Some(record) = export_info.into_record() {
+        let _ = tx.blocking_send(Ok(tag(record)));
     }
 }
 
 impl AppleHealthExtractor {
-    fn parse_generic(e: &BytesStart) -> Option<GenericRecord> {
-        GenericRecord::from_xml(e).ok()
+    /// [`parse_generic`](Self::parse_generic), keeping the element's offset
+    /// as [`XML_OFFSET_ATTRIBUTE`].
+    fn parse_located(e: &BytesStart, offset: u64) -> Result<GenericRecord> {
+        let mut record = Self::parse_generic(e, offset)?;
+        record.attributes.insert(
+            intern_key(XML_OFFSET_ATTRIBUTE),
+            Arc::from(offset.to_string()),
+        );
+        Ok(record)
+    }
+
+    fn parse_generic(e: &BytesStart, _offset: u64) -> Result<GenericRecord> {
+        GenericRecord::from_xml(e).map_err(|err| match err {
+            AppError::InvalidElement(e) => AppError::SkippedRecord(e),
+            err => err,
+        })
     }
 }
@@ -1,2 +1,8 @@
+pub mod auto_export;
+pub mod clinical;
+pub mod dates;
+pub mod export_info;
+#[cfg(feature = "native")]
 pub mod extractor;
+pub mod prescan;
 pub mod types;
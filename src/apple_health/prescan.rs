@@ -0,0 +1,67 @@
+use crate::apple_health::clinical::record_group;
+use crate::error::Result;
+use ahash::AHashMap;
+use memchr::memmem;
+use std::io::Read;
+
+/// What precedes every record type in an export: Apple always writes
+/// `type` as the first attribute of a `<Record>`.
+const RECORD_TYPE: &[u8] = b"<Record type=\"";
+
+const SCAN_BUFFER: usize = 1024 * 1024;
+
+/// Count `<Record>` elements per group without parsing the XML.
+///
+/// This is a single substring search over the raw bytes, much cheaper than
+/// the real extraction, and is used to pre-size the groups before it.
+/// Records whose `type` is not their first attribute are not counted, so
+/// the counts are a lower bound.
+pub fn count_record_types<R: Read>(mut reader: R) -> Result<AHashMap<String, usize>> {
+    let finder = memmem::Finder::new(RECORD_TYPE);
+    let mut counts: AHashMap<Vec<u8>, usize> = AHashMap::new();
+    let mut buf = vec![0; SCAN_BUFFER];
+    let mut filled = 0;
+
+    loop {
+        if filled == buf.len() {
+            buf.resize(buf.len() * 2, 0);
+        }
+        let read = reader.read(&mut buf[filled..])?;
+        filled += read;
+
+        let data = &buf[..filled];
+        let mut pos = 0;
+        // Start of a match whose value is cut off by the end of the buffer
+        let mut pending = None;
+        while let Some(i) = finder.find(&data[pos..]) {
+            let start = pos + i + RECORD_TYPE.len();
+            let Some(len) = memchr::memchr(b'"', &data[start..]) else {
+                pending = Some(pos + i);
+                break;
+            };
+            let typ = &data[start..start + len];
+            match counts.get_mut(typ) {
+                Some(count) => *count += 1,
+                None => {
+                    counts.insert(typ.to_vec(), 1);
+                }
+            }
+            pos = start + len + 1;
+        }
+        if read == 0 {
+            break;
+        }
+
+        // Carry over anything that may still complete a match
+        let keep = pending.unwrap_or_else(|| filled.saturating_sub(RECORD_TYPE.len() - 1).max(pos));
+        buf.copy_within(keep..filled, 0);
+        filled -= keep;
+    }
+
+    let mut groups = AHashMap::with_capacity(counts.len());
+    for (typ, count) in counts {
+        let group = record_group(&String::from_utf8_lossy(&typ)).to_string();
+        *groups.entry(group).or_insert(0) += count;
+    }
+    Ok(groups)
+}
@@ -1,34 +1,98 @@
+use crate::apple_health::clinical::{
+    CLINICAL_ELEMENT, clinical_group, nest_metadata, record_group,
+};
+use crate::apple_health::dates::parse_timestamp;
+use crate::apple_health::export_info::{EXPORT_INFO_ELEMENT, EXPORT_INFO_GROUP};
 use crate::core::Processable;
-use crate::error::{AppError, Result};
-use crate::sinks::csv_zip::CsvWritable;
+use crate::error::{AppError, ElementError, Result};
+use crate::intern::{intern_key, intern_value};
+use crate::sinks::csv_zip::{CaseVariants, CsvDialect, CsvWritable, NUMERIC_COLUMNS};
+use crate::xml_utils::Nest;
 use ahash::AHashMap;
+use quick_xml::escape::unescape;
 use quick_xml::events::BytesStart;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// Attributes of a [`GenericRecord`]. Names repeat across millions of
+/// records, so they are interned and share one allocation each; so are the
+/// values of [`INTERNED_VALUE_ATTRIBUTES`].
+pub type Attributes = AHashMap<Arc<str>, Arc<str>>;
+
+/// Attributes whose values take only a handful of distinct values across an
+/// export and are therefore interned.
+pub const INTERNED_VALUE_ATTRIBUTES: [&str; 4] = ["type", "sourceName", "unit", "device"];
+
+/// Build the stored value of attribute `key`, interning it if the attribute
+/// is one of [`INTERNED_VALUE_ATTRIBUTES`].
+pub fn attribute_value(key: &str, value: &str) -> Arc<str> {
+    if INTERNED_VALUE_ATTRIBUTES.contains(&key) {
+        intern_value(value)
+    } else {
+        Arc::from(value)
+    }
+}
 
 /// Generic representation for any Apple Health XML element.
-#[derive(Debug, Clone)]
+///
+/// Serializes as a flat map of the attributes plus an `element` key holding
+/// the element name, e.g. `{"element":"Record","type":"...","value":"72"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GenericRecord {
+    #[serde(rename = "element")]
     pub element_name: String,
-    pub attributes: AHashMap<String, String>,
+    #[serde(flatten, deserialize_with = "deserialize_attributes")]
+    pub attributes: Attributes,
 }
 
 impl GenericRecord {
     pub fn from_xml(element: &BytesStart) -> Result<Self> {
-        let element_name = String::from_utf8(element.name().as_ref().to_vec())
-            .map_err(|e| AppError::ParseError(format!("Invalid element name: {}", e)))?;
+        let element_name = String::from_utf8(element.name().as_ref().to_vec()).map_err(|e| {
+            AppError::InvalidElement(
+                ElementError::new(format!("invalid element name: {}", e))
+                    .element(String::from_utf8_lossy(e.as_bytes()))
+                    .snippet(element),
+            )
+        })?;
+        let invalid = |reason: String| {
+            ElementError::new(reason)
+                .element(element_name.as_str())
+                .snippet(element)
+        };
 
         let attributes_iter = element.attributes();
         let (lower, _) = attributes_iter.size_hint();
         let mut attributes = AHashMap::with_capacity(lower);
 
         for attr in attributes_iter {
-            let attr = attr
-                .map_err(|e| AppError::ParseError(format!("Failed to parse attribute: {}", e)))?;
+            let attr = attr.map_err(|e| {
+                AppError::InvalidElement(invalid(format!("failed to parse attribute: {}", e)))
+            })?;
 
-            let key = String::from_utf8(attr.key.as_ref().to_vec())
-                .map_err(|e| AppError::ParseError(format!("Invalid attribute key: {}", e)))?;
+            let key = std::str::from_utf8(attr.key.as_ref()).map_err(|e| {
+                AppError::InvalidElement(
+                    invalid(format!("invalid attribute key: {}", e))
+                        .attribute(String::from_utf8_lossy(attr.key.as_ref())),
+                )
+            })?;
+            let key = intern_key(key);
 
-            let value = String::from_utf8(attr.value.into_owned())
-                .map_err(|e| AppError::ParseError(format!("Invalid attribute value: {}", e)))?;
+            let value = std::str::from_utf8(&attr.value).map_err(|e| {
+                AppError::InvalidElement(
+                    invalid(format!("invalid attribute value: {}", e)).attribute(&*key),
+                )
+            })?;
+            // Device strings and metadata carry entities such as `&lt;` and `&#xA;`
+            let value = unescape(value).map_err(|e| {
+                AppError::InvalidElement(
+                    invalid(format!("invalid entity in attribute value: {}", e)).attribute(&*key),
+                )
+            })?;
+            let value = attribute_value(&key, &value);
 
             attributes.insert(key, value);
         }
@@ -40,35 +104,84 @@ impl GenericRecord {
     }
 }
 
+/// Read the flattened attributes back, interning them like
+/// [`GenericRecord::from_xml`] does.
+fn deserialize_attributes<'de, D>(deserializer: D) -> std::result::Result<Attributes, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct AttributesVisitor;
+
+    impl<'de> Visitor<'de> for AttributesVisitor {
+        type Value = Attributes;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map of attribute names to values")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> std::result::Result<Attributes, A::Error> {
+            let mut attributes = Attributes::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some((key, value)) = map.next_entry::<String, String>()? {
+                let value = attribute_value(&key, &value);
+                attributes.insert(intern_key(&key), value);
+            }
+            Ok(attributes)
+        }
+    }
+
+    deserializer.deserialize_map(AttributesVisitor)
+}
+
 impl CsvWritable for GenericRecord {
     fn header_keys(&self) -> impl Iterator<Item = &str> {
-        self.attributes.keys().map(String::as_str)
+        self.attributes.keys().map(|key| &**key)
     }
 
-    fn write<W: std::io::Write>(
+    fn cells(
         &self,
-        writer: &mut csv::Writer<W>,
         headers: &[&str],
-    ) -> csv::Result<()> {
-        let record: Vec<&str> = headers
+        variants: &CaseVariants<'_>,
+        dialect: &CsvDialect,
+    ) -> Vec<Cow<'_, str>> {
+        headers
             .iter()
-            .map(|h| self.attributes.get(*h).map(String::as_str).unwrap_or(""))
-            .collect();
-        writer.write_record(&record)
+            .map(|h| {
+                let value = self.attributes.get(*h).or_else(|| {
+                    let variants = variants.get(h)?;
+                    variants.iter().find_map(|v| self.attributes.get(*v))
+                });
+                match value {
+                    Some(value) if NUMERIC_COLUMNS.contains(h) => dialect.round(value),
+                    Some(value) => Cow::Borrowed(&**value),
+                    None => Cow::Borrowed(""),
+                }
+            })
+            .collect()
     }
 }
 
 impl Processable for GenericRecord {
     fn grouping_key(&self) -> String {
-        if self.element_name == "Record" {
-            if let Some(typ) = self.attributes.get("type") {
-                return typ.clone();
-            }
+        if self.element_name == "Record"
+            && let Some(typ) = self.attributes.get("type")
+        {
+            return record_group(typ).to_string();
+        }
+        if self.element_name == CLINICAL_ELEMENT
+            && let Some(typ) = self.attributes.get("type")
+        {
+            return clinical_group(typ);
+        }
+        if self.element_name == EXPORT_INFO_ELEMENT {
+            return EXPORT_INFO_GROUP.to_string();
         }
         self.element_name.clone()
     }
 
-    fn sort_key(&self) -> Option<&str> {
+    fn sort_key(&self) -> Option<i64> {
         let keys = [
             "startDate",
             "date",
@@ -78,11 +191,54 @@ impl Processable for GenericRecord {
             "dateIssued",
             "receivedDate",
         ];
-        for k in keys {
-            if let Some(v) = self.attributes.get(k) {
-                return Some(v.as_str());
-            }
+        keys.iter()
+            .filter_map(|k| self.attributes.get(*k))
+            .find_map(|v| parse_timestamp(v))
+    }
+
+    fn field(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|v| &**v)
+    }
+
+    /// The first 16 bytes of a SHA-256 over the element name and the
+    /// attributes in name order.
+    fn content_hash(&self) -> Option<u128> {
+        let mut attributes: Vec<(&Arc<str>, &Arc<str>)> = self.attributes.iter().collect();
+        attributes.sort_unstable();
+        let mut hasher = Sha256::new();
+        hasher.update(self.element_name.as_bytes());
+        // Separated by bytes no name or value contains, so neither can run
+        // into the next
+        for (key, value) in attributes {
+            hasher.update([0x1e]);
+            hasher.update(key.as_bytes());
+            hasher.update([0x1f]);
+            hasher.update(value.as_bytes());
         }
-        None
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hasher.finalize()[..16]);
+        Some(u128::from_be_bytes(bytes))
+    }
+
+    fn estimated_size(&self) -> usize {
+        // Each map slot holds a key and a value plus a control byte. Keys and
+        // interned values are shared with every other record; other values
+        // own their text behind the two reference counts.
+        let slot = 2 * std::mem::size_of::<Arc<str>>() + 1;
+        std::mem::size_of::<Self>()
+            + self.element_name.capacity()
+            + self.attributes.capacity() * slot
+            + self
+                .attributes
+                .iter()
+                .filter(|(k, _)| !INTERNED_VALUE_ATTRIBUTES.contains(&&***k))
+                .map(|(_, v)| 2 * std::mem::size_of::<usize>() + v.len())
+                .sum::<usize>()
+    }
+}
+
+impl Nest for GenericRecord {
+    fn nest(&mut self, child: Self) -> Option<Self> {
+        nest_metadata(self, child)
     }
 }
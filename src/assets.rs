@@ -0,0 +1,109 @@
+//! Copying the files an export keeps beside `export.xml`, such as workout
+//! routes and ECG recordings, without converting any records.
+
+use crate::ecg::ECG_DIR;
+use crate::error::Result;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Folder of an export with one GPX file per workout route.
+pub const ROUTES_DIR: &str = "workout-routes";
+
+/// Kinds of files that `extract-assets` copies out of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum AssetKind {
+    /// The GPX files of `workout-routes/`
+    Routes,
+    /// The voltage CSVs of `electrocardiograms/`
+    Ecg,
+}
+
+impl AssetKind {
+    pub const ALL: [AssetKind; 2] = [AssetKind::Routes, AssetKind::Ecg];
+
+    /// Folder holding these files, in the export and in the output.
+    pub fn dir(self) -> &'static str {
+        match self {
+            AssetKind::Routes => ROUTES_DIR,
+            AssetKind::Ecg => ECG_DIR,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            AssetKind::Routes => ".gpx",
+            AssetKind::Ecg => ".csv",
+        }
+    }
+
+    /// File name of `path` if it is an asset of this kind: a file with the
+    /// kind's extension directly in its folder.
+    fn file_name(self, path: &str) -> Option<&str> {
+        let (dir, name) = path.rsplit_once('/')?;
+        let folder = dir.rsplit('/').next()?;
+        (folder == self.dir() && name.ends_with(self.extension()) && !name.starts_with('.'))
+            .then_some(name)
+    }
+}
+
+/// Copy the files of `kinds` out of the export at `input` (a ZIP, or a
+/// plain `export.xml` with its folders beside it) into `output`, each under
+/// a folder named as in the export. Returns the number of files copied per
+/// kind.
+pub fn extract_assets(
+    input: &Path,
+    kinds: &[AssetKind],
+    output: &Path,
+) -> Result<BTreeMap<AssetKind, usize>> {
+    let mut copied: BTreeMap<AssetKind, usize> = kinds.iter().map(|&kind| (kind, 0)).collect();
+    let mut copy = |kind: AssetKind, name: &str, reader: &mut dyn Read| -> Result<()> {
+        let dir = output.join(kind.dir());
+        fs::create_dir_all(&dir)?;
+        io::copy(reader, &mut File::create(dir.join(name))?)?;
+        *copied.entry(kind).or_default() += 1;
+        Ok(())
+    };
+
+    if input.extension().and_then(|s| s.to_str()) == Some("zip") {
+        let mut archive = zip::ZipArchive::new(File::open(input)?)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if !entry.is_file() {
+                continue;
+            }
+            let path = entry.name().to_string();
+            if let Some((kind, name)) = kinds
+                .iter()
+                .find_map(|&kind| Some((kind, kind.file_name(&path)?)))
+            {
+                debug!("Copying {}", path);
+                copy(kind, name, &mut entry)?;
+            }
+        }
+    } else if let Some(parent) = input.parent() {
+        for &kind in kinds {
+            let dir = parent.join(kind.dir());
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                if path.is_file()
+                    && kind
+                        .file_name(&format!("{}/{}", kind.dir(), name))
+                        .is_some()
+                {
+                    copy(kind, name, &mut File::open(&path)?)?;
+                }
+            }
+        }
+    }
+    Ok(copied)
+}
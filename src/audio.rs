@@ -0,0 +1,135 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use std::collections::BTreeMap;
+
+/// Sound level, in dBA, above which a day's average exposure is flagged;
+/// the level the WHO considers safe for 40 hours a week.
+pub const EXPOSURE_THRESHOLD_DB: f64 = 80.0;
+
+/// Where a sound level was measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exposure {
+    Environmental,
+    Headphone,
+}
+
+impl Exposure {
+    const ALL: [Exposure; 2] = [Exposure::Environmental, Exposure::Headphone];
+
+    fn of(group: &str) -> Option<Self> {
+        match group {
+            "HKQuantityTypeIdentifierEnvironmentalAudioExposure" => Some(Exposure::Environmental),
+            "HKQuantityTypeIdentifierHeadphoneAudioExposure" => Some(Exposure::Headphone),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Exposure::Environmental => "environmental",
+            Exposure::Headphone => "headphone",
+        }
+    }
+}
+
+/// Sound energy and exposure time of one day.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct DayExposure {
+    /// Sum over the samples of their seconds times their relative intensity.
+    energy: f64,
+    seconds: f64,
+    max: f64,
+    samples: usize,
+}
+
+impl DayExposure {
+    /// The equivalent continuous sound level: the constant level carrying
+    /// the day's sound energy over its exposure time.
+    fn leq(&self) -> f64 {
+        10.0 * (self.energy / self.seconds).log10()
+    }
+}
+
+/// The audio exposure samples of one group per day, collected for
+/// [`audio_exposure_csv`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioDays {
+    exposure: Exposure,
+    /// Calendar dates as written in the export.
+    days: BTreeMap<String, DayExposure>,
+}
+
+impl AudioDays {
+    /// Collector for `group`, or `None` if it is not an audio exposure type.
+    pub fn new(group: &str) -> Option<Self> {
+        Some(Self {
+            exposure: Exposure::of(group)?,
+            days: BTreeMap::new(),
+        })
+    }
+
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        let level = record.field("value").and_then(|v| v.parse::<f64>().ok());
+        let (Some(level), Some(date)) =
+            (level.filter(|l| l.is_finite()), record.field("startDate"))
+        else {
+            return;
+        };
+        let Some(start) = parse_timestamp(date) else {
+            return;
+        };
+        // Instantaneous samples still count for a second
+        let end = record.field("endDate").and_then(parse_timestamp);
+        let seconds = end.map_or(0, |end| end - start).max(1) as f64;
+        let day = self.days.entry(date[..10].to_string()).or_default();
+        day.energy += seconds * 10f64.powf(level / 10.0);
+        day.seconds += seconds;
+        day.max = if day.samples == 0 {
+            level
+        } else {
+            day.max.max(level)
+        };
+        day.samples += 1;
+    }
+}
+
+/// One row per day with, for environmental and headphone audio, the
+/// equivalent continuous level (LEQ) in dBA, the loudest sample, the minutes
+/// measured and whether the LEQ exceeded [`EXPOSURE_THRESHOLD_DB`].
+pub fn audio_exposure_csv<'a>(groups: impl IntoIterator<Item = &'a AudioDays>) -> Result<Vec<u8>> {
+    let mut days: BTreeMap<&str, [Option<&DayExposure>; Exposure::ALL.len()]> = BTreeMap::new();
+    for group in groups {
+        // `ALL` lists the exposures in declaration order
+        let column = group.exposure as usize;
+        for (day, exposure) in &group.days {
+            days.entry(day).or_default()[column] = Some(exposure);
+        }
+    }
+
+    let round = |value: f64| ((value * 10.0).round() / 10.0).to_string();
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut header = vec!["date".to_string()];
+    for exposure in Exposure::ALL.map(Exposure::name) {
+        for column in ["leq_db", "max_db", "minutes", "over_threshold"] {
+            header.push(format!("{}_{}", exposure, column));
+        }
+    }
+    w.write_record(&header)?;
+    for (day, exposures) in days {
+        let mut row = vec![day.to_string()];
+        for exposure in exposures {
+            match exposure {
+                Some(exposure) => row.extend([
+                    round(exposure.leq()),
+                    round(exposure.max),
+                    round(exposure.seconds / 60.0),
+                    (exposure.leq() > EXPOSURE_THRESHOLD_DB).to_string(),
+                ]),
+                None => row.extend(std::iter::repeat_n(String::new(), 4)),
+            }
+        }
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
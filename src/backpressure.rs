@@ -0,0 +1,112 @@
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::info_span;
+
+/// How much work may queue up between pipeline stages before producers
+/// wait for consumers.
+///
+/// `records` bounds the channels carrying parsed records from the parsers to
+/// the transformer; `bytes` bounds the raw XML handed to the rayon pool
+/// and the compressed CSVs waiting to be written into the archive. The
+/// same policy is applied by the extractor and the sinks, so a run never
+/// holds more than a predictable amount of queued data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backpressure {
+    /// Parsed records in flight between parsing and grouping.
+    pub records: usize,
+    /// Bytes in flight in each stage that queues byte buffers.
+    pub bytes: usize,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Self {
+            records: 64 * 1024,
+            bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Backpressure {
+    /// Capacity for channels of records; never zero.
+    pub fn channel_capacity(&self) -> usize {
+        self.records.max(1)
+    }
+
+    /// A fresh budget of `bytes` for one stage, named `stage` in profiles.
+    pub fn byte_budget(&self, stage: &'static str) -> ByteBudget {
+        ByteBudget::new(self.bytes).named(stage)
+    }
+}
+
+/// Bytes queued in one stage, shared by its producers and consumer.
+///
+/// Producers [`acquire`](Self::acquire) before queueing a buffer and block
+/// while the budget is exhausted; the consumer [`release`](Self::release)s
+/// it once the buffer is processed. A single buffer larger than the whole
+/// budget is still let through when nothing else is queued.
+#[derive(Debug, Clone)]
+pub struct ByteBudget {
+    limit: usize,
+    stage: &'static str,
+    state: Arc<(Mutex<BudgetState>, Condvar)>,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    used: usize,
+    closed: bool,
+}
+
+impl ByteBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            stage: "bytes",
+            state: Arc::default(),
+        }
+    }
+
+    /// Name the stage consuming the budget, reported as the `on` field of
+    /// the `wait` spans producers open while blocked.
+    pub fn named(mut self, stage: &'static str) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    /// Wait until `bytes` fit in the budget, then take them.
+    pub fn acquire(&self, bytes: usize) {
+        let (lock, available) = &*self.state;
+        let mut state = lock.lock().expect("budget lock poisoned");
+        let is_full = |state: &BudgetState| {
+            !state.closed && state.used > 0 && state.used + bytes > self.limit
+        };
+        if is_full(&state) {
+            let _wait = info_span!("wait", on = self.stage).entered();
+            while is_full(&state) {
+                state = available.wait(state).expect("budget lock poisoned");
+            }
+        }
+        state.used += bytes;
+    }
+
+    /// Return `bytes` taken by [`acquire`](Self::acquire).
+    pub fn release(&self, bytes: usize) {
+        let (lock, available) = &*self.state;
+        let mut state = lock.lock().expect("budget lock poisoned");
+        state.used = state.used.saturating_sub(bytes);
+        available.notify_all();
+    }
+
+    /// Stop limiting, waking every waiting producer. Called when the
+    /// consumer stops early so producers can observe the closed channel.
+    pub fn close(&self) {
+        let (lock, available) = &*self.state;
+        lock.lock().expect("budget lock poisoned").closed = true;
+        available.notify_all();
+    }
+
+    /// Bytes currently taken.
+    pub fn used(&self) -> usize {
+        self.state.0.lock().expect("budget lock poisoned").used
+    }
+}
@@ -0,0 +1,151 @@
+use crate::apple_health::extractor::AppleHealthExtractor;
+use crate::apple_health::types::GenericRecord;
+use crate::backpressure::Backpressure;
+use crate::conversion::ConversionBuilder;
+use crate::core::{Extractor, transformer};
+use crate::error::{AppError, Result};
+use crate::progress::Progress;
+use ahash::AHashMap;
+use log::info;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Timings of one phase over every repetition of [`bench`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseTimings {
+    pub phase: &'static str,
+    /// Records the phase handled in each repetition.
+    pub records: usize,
+    /// Duration of each repetition, in the order they ran.
+    pub runs: Vec<Duration>,
+}
+
+impl PhaseTimings {
+    fn sorted(&self) -> Vec<Duration> {
+        let mut runs = self.runs.clone();
+        runs.sort_unstable();
+        runs
+    }
+
+    pub fn min(&self) -> Duration {
+        self.sorted().first().copied().unwrap_or_default()
+    }
+
+    pub fn median(&self) -> Duration {
+        let runs = self.sorted();
+        runs.get(runs.len() / 2).copied().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.sorted().last().copied().unwrap_or_default()
+    }
+
+    /// Throughput of the median repetition.
+    pub fn records_per_second(&self) -> f64 {
+        self.records as f64 / self.median().as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Time `runs` repetitions of extraction alone, of grouping records already
+/// in memory, and of a whole conversion of `input_path` to a temporary
+/// archive.
+///
+/// The transform phase is fed from records extracted once beforehand, so
+/// the export's records must fit in memory.
+pub async fn bench(input_path: &Path, runs: usize) -> Result<Vec<PhaseTimings>> {
+    let extractor = AppleHealthExtractor::new();
+    let mut extract = PhaseTimings {
+        phase: "extract",
+        records: 0,
+        runs: Vec::with_capacity(runs),
+    };
+    for run in 1..=runs {
+        info!("Timing extraction, run {}/{}", run, runs);
+        let start = Instant::now();
+        let mut receiver = extractor.extract(input_path, &Progress::default()).await?;
+        let mut records = 0;
+        while let Some(result) = receiver.recv().await {
+            records += usize::from(result.is_ok());
+        }
+        extract.runs.push(start.elapsed());
+        extract.records = records;
+    }
+
+    let mut receiver = extractor.extract(input_path, &Progress::default()).await?;
+    let mut records = Vec::new();
+    while let Some(result) = receiver.recv().await {
+        records.extend(result.ok());
+    }
+    let mut transform = PhaseTimings {
+        phase: "transform",
+        records: records.len(),
+        runs: Vec::with_capacity(runs),
+    };
+    for run in 1..=runs {
+        info!("Timing transformation, run {}/{}", run, runs);
+        transform.runs.push(time_transform(records.clone()).await?);
+    }
+    drop(records);
+
+    let output = tempfile::Builder::new().suffix(".zip").tempfile()?;
+    let mut full = PhaseTimings {
+        phase: "full",
+        records: 0,
+        runs: Vec::with_capacity(runs),
+    };
+    for run in 1..=runs {
+        info!("Timing the full pipeline, run {}/{}", run, runs);
+        let start = Instant::now();
+        let report = ConversionBuilder::new(input_path)
+            .output(output.path())
+            .run()
+            .await?;
+        full.runs.push(start.elapsed());
+        full.records = report.total_records();
+    }
+
+    Ok(vec![extract, transform, full])
+}
+
+/// Time grouping `records` by type, as the transform phase does.
+async fn time_transform(records: Vec<GenericRecord>) -> Result<Duration> {
+    let (tx, rx) = mpsc::channel(Backpressure::default().channel_capacity());
+    let feed = tokio::spawn(async move {
+        for record in records {
+            if tx.send(Ok(record)).await.is_err() {
+                break;
+            }
+        }
+    });
+    let start = Instant::now();
+    let mut groups: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
+    let progress = Progress::default();
+    transformer::transform(rx, None, None, false, false, &progress, &mut groups).await?;
+    let elapsed = start.elapsed();
+    feed.await.map_err(|e| AppError::Unknown(e.to_string()))?;
+    Ok(elapsed)
+}
+
+/// `timings` as an aligned table with the fastest, median and slowest
+/// repetition of each phase in seconds and the median throughput.
+pub fn table(timings: &[PhaseTimings]) -> String {
+    let mut table = format!(
+        "{:<10} {:>12} {:>10} {:>10} {:>10} {:>14}\n",
+        "phase", "records", "min (s)", "median (s)", "max (s)", "records/s"
+    );
+    for phase in timings {
+        let _ = writeln!(
+            table,
+            "{:<10} {:>12} {:>10.3} {:>10.3} {:>10.3} {:>14.0}",
+            phase.phase,
+            phase.records,
+            phase.min().as_secs_f64(),
+            phase.median().as_secs_f64(),
+            phase.max().as_secs_f64(),
+            phase.records_per_second()
+        );
+    }
+    table
+}
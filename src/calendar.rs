@@ -0,0 +1,157 @@
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::core::Processable;
+use crate::manifest::to_hex;
+use sha2::{Digest, Sha256};
+
+const ACTIVITY_PREFIXES: [&str; 2] = ["HKWorkoutActivityType", "HKWorkoutTypeIdentifier"];
+
+/// Longest content line, in bytes, before it is folded onto the next one.
+const MAX_LINE: usize = 75;
+
+/// One workout, with the details shown in its event.
+#[derive(Debug, Clone, PartialEq)]
+struct Event {
+    start: i64,
+    end: i64,
+    activity: String,
+    /// Lines of the event's description, such as `Duration: 30 min`.
+    details: Vec<String>,
+}
+
+/// The workouts of a group, collected for [`workouts_ics`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorkoutEvents {
+    events: Vec<Event>,
+}
+
+impl WorkoutEvents {
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        let date = |field: &str| record.field(field).and_then(parse_timestamp);
+        let (Some(start), Some(end)) = (date("startDate"), date("endDate")) else {
+            return;
+        };
+        let end = end.max(start);
+        let activity = record.field("workoutActivityType").unwrap_or_default();
+
+        // A quantity without its unit attribute is taken in `default_unit`,
+        // or left out without one
+        let quantity = |field: &str, default_unit: Option<&str>| {
+            let value = record.field(field)?.parse::<f64>().ok()?;
+            let unit = record.field(&format!("{}Unit", field)).or(default_unit)?;
+            Some(format!("{} {}", (value * 100.0).round() / 100.0, unit))
+        };
+        let duration = quantity("duration", None).unwrap_or_else(|| duration(end - start));
+        let mut details = vec![format!("Duration: {}", duration)];
+        details
+            .extend(quantity("totalEnergyBurned", Some("kcal")).map(|e| format!("Energy: {}", e)));
+        details.extend(quantity("totalDistance", Some("km")).map(|d| format!("Distance: {}", d)));
+        details.extend(record.field("sourceName").map(|s| format!("Source: {}", s)));
+
+        self.events.push(Event {
+            start,
+            end,
+            activity: activity_name(activity),
+            details,
+        });
+    }
+}
+
+/// `Traditional Strength Training` for
+/// `HKWorkoutActivityTypeTraditionalStrengthTraining`.
+fn activity_name(activity: &str) -> String {
+    let name = ACTIVITY_PREFIXES
+        .iter()
+        .find_map(|prefix| activity.strip_prefix(prefix))
+        .unwrap_or(activity);
+    let mut words = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_uppercase() && !words.is_empty() {
+            words.push(' ');
+        }
+        words.push(c);
+    }
+    if words.is_empty() {
+        "Workout".to_string()
+    } else {
+        words
+    }
+}
+
+fn duration(seconds: i64) -> String {
+    let minutes = (seconds + 30) / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, m) => format!("{} min", m),
+        (h, m) => format!("{} h {} min", h, m),
+    }
+}
+
+/// `20230101T090000Z`, the UTC form of an iCalendar date-time.
+fn ics_timestamp(secs: i64) -> String {
+    format_timestamp(secs).replace(['-', ':'], "")
+}
+
+/// Escape the characters with a meaning in iCalendar text values.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Append `line` with a CRLF ending, folding it into continuation lines of
+/// at most [`MAX_LINE`] bytes without splitting a character.
+fn push_line(ics: &mut String, line: &str) {
+    let mut rest = line;
+    let mut limit = MAX_LINE;
+    while rest.len() > limit {
+        let mut cut = limit;
+        while !rest.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        ics.push_str(&rest[..cut]);
+        ics.push_str("\r\n ");
+        rest = &rest[cut..];
+        // The leading space counts towards the continuation's length
+        limit = MAX_LINE - 1;
+    }
+    ics.push_str(rest);
+    ics.push_str("\r\n");
+}
+
+/// An iCalendar file with one event per workout, in order of their start:
+/// the activity as its title and the duration, energy, distance and source
+/// in its description. Event ids are derived from the workout, so importing
+/// a newer export again updates the events instead of duplicating them.
+pub fn workouts_ics(workouts: Option<&WorkoutEvents>) -> String {
+    let mut events: Vec<&Event> = workouts.map(|w| &w.events).into_iter().flatten().collect();
+    events.sort_by(|a, b| (a.start, a.end, &a.activity).cmp(&(b.start, b.end, &b.activity)));
+
+    let mut ics = String::new();
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        concat!("PRODID:-//gpt-os//", env!("CARGO_PKG_VERSION"), "//EN"),
+        "CALSCALE:GREGORIAN",
+    ] {
+        push_line(&mut ics, line);
+    }
+    for event in events {
+        let key = format!("{}/{}/{}", event.start, event.end, event.activity);
+        let uid = to_hex(&Sha256::digest(key.as_bytes())[..16]);
+        let description = escape(&event.details.join("\n"));
+        for line in [
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{}@gpt-os", uid),
+            format!("DTSTAMP:{}", ics_timestamp(event.start)),
+            format!("DTSTART:{}", ics_timestamp(event.start)),
+            format!("DTEND:{}", ics_timestamp(event.end)),
+            format!("SUMMARY:{}", escape(&event.activity)),
+            format!("DESCRIPTION:{}", description),
+            "END:VEVENT".to_string(),
+        ] {
+            push_line(&mut ics, &line);
+        }
+    }
+    push_line(&mut ics, "END:VCALENDAR");
+    ics
+}
@@ -0,0 +1,189 @@
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::correlations::CorrelationMatrix;
+use crate::error::{AppError, Result};
+use crate::report::GroupSummary;
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+
+/// Width and height of every chart, in pixels.
+const SIZE: (u32, u32) = (800, 300);
+
+/// Width and height of the correlation heatmap, in pixels.
+const HEATMAP_SIZE: (u32, u32) = (640, 480);
+
+/// Entry the correlation heatmap is written to.
+const HEATMAP_FILE_NAME: &str = "charts/correlations.svg";
+
+/// Types drawn as charts: group, entry name, title and unit.
+const CHARTS: [(&str, &str, &str, &str); 3] = [
+    (
+        "HKQuantityTypeIdentifierBodyMass",
+        "charts/weight.svg",
+        "Weight",
+        "kg",
+    ),
+    (
+        "HKQuantityTypeIdentifierRestingHeartRate",
+        "charts/resting_heart_rate.svg",
+        "Resting heart rate",
+        "bpm",
+    ),
+    (
+        "HKQuantityTypeIdentifierStepCount",
+        "charts/steps.svg",
+        "Steps",
+        "steps",
+    ),
+];
+
+/// A rendered chart and the archive entry it is written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chart {
+    pub file_name: &'static str,
+    pub svg: String,
+}
+
+/// Line charts of the daily weight, resting heart rate and steps found in
+/// `groups`, skipping types without data.
+pub fn daily_charts(groups: &[GroupSummary]) -> Result<Vec<Chart>> {
+    let mut charts = Vec::new();
+    for (group, file_name, title, unit) in CHARTS {
+        let Some(summary) = groups.iter().find(|g| g.name == group) else {
+            continue;
+        };
+        let points: Vec<(i64, f64)> = summary
+            .daily_values()
+            .into_iter()
+            .filter_map(|(day, value)| Some((parse_timestamp(day)? / 86_400, value)))
+            .collect();
+        if !points.is_empty() {
+            let svg =
+                line_chart(title, unit, &points).map_err(|e| AppError::Chart(e.to_string()))?;
+            charts.push(Chart { file_name, svg });
+        }
+    }
+    Ok(charts)
+}
+
+/// A heatmap of `matrix`, shading positive correlations red and negative
+/// ones blue, or `None` with fewer than two metrics to compare.
+pub fn correlation_heatmap(matrix: &CorrelationMatrix) -> Result<Option<Chart>> {
+    if matrix.metrics.len() < 2 {
+        return Ok(None);
+    }
+    let svg = heatmap(matrix).map_err(|e| AppError::Chart(e.to_string()))?;
+    Ok(Some(Chart {
+        file_name: HEATMAP_FILE_NAME,
+        svg,
+    }))
+}
+
+fn heatmap(matrix: &CorrelationMatrix) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let n = matrix.metrics.len() as i32;
+    // The first metric's row at the top
+    let row = |i: usize| n - 1 - i as i32;
+    let name = |value: &SegmentValue<i32>, flip: bool| match value {
+        SegmentValue::CenterOf(i) => {
+            let i = if flip { n - 1 - i } else { *i };
+            matrix
+                .metrics
+                .get(i as usize)
+                .copied()
+                .unwrap_or_default()
+                .to_string()
+        }
+        _ => String::new(),
+    };
+
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, HEATMAP_SIZE).into_drawing_area();
+        root.fill(&WHITE)?;
+        let mut chart = ChartBuilder::on(&root)
+            .caption("Correlations between daily metrics", ("sans-serif", 20))
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(130)
+            .build_cartesian_2d((0..n).into_segmented(), (0..n).into_segmented())?;
+        chart
+            .configure_mesh()
+            .disable_mesh()
+            .x_labels(n as usize)
+            .y_labels(n as usize)
+            .x_label_formatter(&|value| name(value, false))
+            .y_label_formatter(&|value| name(value, true))
+            .draw()?;
+        let cells = matrix.values.iter().enumerate().flat_map(|(i, values)| {
+            values
+                .iter()
+                .enumerate()
+                .map(move |(j, value)| (i, j, *value))
+        });
+        let label = TextStyle::from(("sans-serif", 14)).pos(Pos::new(HPos::Center, VPos::Center));
+        for (i, j, value) in cells {
+            let (x, y) = (j as i32, row(i));
+            let color = match value {
+                Some(r) if r >= 0.0 => {
+                    let fade = (255.0 * (1.0 - r)) as u8;
+                    RGBColor(255, fade, fade)
+                }
+                Some(r) => {
+                    let fade = (255.0 * (1.0 + r)) as u8;
+                    RGBColor(fade, fade, 255)
+                }
+                None => RGBColor(230, 230, 230),
+            };
+            chart.draw_series(std::iter::once(Rectangle::new(
+                [
+                    (SegmentValue::Exact(x), SegmentValue::Exact(y)),
+                    (SegmentValue::Exact(x + 1), SegmentValue::Exact(y + 1)),
+                ],
+                color.filled(),
+            )))?;
+            if let Some(r) = value {
+                chart.draw_series(std::iter::once(Text::new(
+                    format!("{:.2}", r),
+                    (SegmentValue::CenterOf(x), SegmentValue::CenterOf(y)),
+                    label.clone(),
+                )))?;
+            }
+        }
+        root.present()?;
+    }
+    Ok(svg)
+}
+
+/// Draw `points`, days since the Unix epoch and their values, as an SVG
+/// line chart.
+fn line_chart(
+    title: &str,
+    unit: &str,
+    points: &[(i64, f64)],
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let first = points.iter().map(|p| p.0).min().unwrap_or_default();
+    let last = points.iter().map(|p| p.0).max().unwrap_or_default();
+    let low = points.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+    let high = points.iter().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+    let margin = ((high - low) * 0.1).max(1.0);
+
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, SIZE).into_drawing_area();
+        root.fill(&WHITE)?;
+        let mut chart = ChartBuilder::on(&root)
+            .caption(title, ("sans-serif", 20))
+            .margin(10)
+            .x_label_area_size(30)
+            .y_label_area_size(60)
+            .build_cartesian_2d(first..last.max(first + 1), low - margin..high + margin)?;
+        chart
+            .configure_mesh()
+            .x_labels(6)
+            .x_label_formatter(&|day| format_timestamp(day * 86_400)[..10].to_string())
+            .y_desc(unit)
+            .draw()?;
+        chart.draw_series(LineSeries::new(points.iter().copied(), &BLUE))?;
+        root.present()?;
+    }
+    Ok(svg)
+}
@@ -1,21 +1,436 @@
-use clap::Parser;
+use crate::assets::AssetKind;
+use crate::logging::LogFormat;
+use crate::pivot::PivotInterval;
+use crate::report::ReportFormat;
+use crate::rollup::RollupPeriod;
+use crate::sinks::csv_zip::{CompressionMethod, DecimalSeparator};
+use crate::units::UnitMode;
+use crate::zones::HeartRateZones;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+/// Exit codes listed by `--help`, one per [`ExitCode`](crate::error::ExitCode).
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0    Success
+  1    Other failure
+  2    Invalid arguments or configuration
+  3    Input not found
+  4    Input could not be parsed
+  5    Reading or writing a file failed
+  6    Validation found mismatches
+  130  Cancelled";
 
 /// Configuration for the Apple Health transformer application
 #[derive(Debug, Parser)]
 #[command(name = "gpt-os")]
 #[command(about = "Convert Apple Health export data to structured CSV files")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(after_help = EXIT_CODES_HELP)]
 pub struct Config {
-    /// Path to the Apple Health export (export.zip or export.xml)
-    pub input_file: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the Apple Health export (export.zip or export.xml), or an
+    /// https:// or s3:// URL to download it from
+    #[arg(required = true)]
+    pub input_file: Option<String>,
 
     /// Path for the output ZIP archive containing CSV files
-    pub output_zip: String,
+    #[arg(required = true)]
+    pub output_zip: Option<String>,
 
     /// Enable verbose logging
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub verbose: bool,
 
+    /// Write a Chrome trace of the extract, transform and load phases, each
+    /// group's serialization and the archive writing to this file, for
+    /// Perfetto or `chrome://tracing`
+    #[arg(long, value_name = "FILE")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Write per-phase and per-group timings, channel wait times and peak
+    /// memory of the run as JSON to this file, to compare across releases
+    #[arg(long, value_name = "FILE")]
+    pub profile: Option<PathBuf>,
+
     /// Disable printing of end-of-run metrics
     #[arg(long)]
     pub no_metrics: bool,
+
+    /// Suppress the startup banner and end-of-run summary on the console
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Format of log lines written to stderr
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+
+    /// Also append log lines to this file, down to debug level whatever the
+    /// console verbosity, so unattended runs keep their logs
+    #[arg(long, value_name = "FILE", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Number of threads of the parsing and compression pool and of the async
+    /// workers (defaults to the number of logical CPUs); the blocking tasks
+    /// that drive them are not counted
+    #[arg(short = 'j', long, global = true)]
+    pub threads: Option<NonZeroUsize>,
+
+    /// Keep roughly at most this many bytes of records in memory, spilling
+    /// to temporary files beyond it (accepts K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_memory: Option<usize>,
+
+    /// Checkpoint the records spilled under --max-memory into this
+    /// directory; running again with it resumes an interrupted conversion
+    #[arg(long, value_name = "DIR", requires = "max_memory")]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// Parsed records allowed to queue between parsing and grouping
+    #[arg(long, value_name = "N")]
+    pub in_flight_records: Option<usize>,
+
+    /// Bytes of raw XML and compressed CSV allowed to queue in each stage
+    /// (accepts K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub in_flight_bytes: Option<usize>,
+
+    /// Retry opening, reading and writing files this many times on
+    /// transient errors such as EIO, as seen on network file systems
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub io_retries: u32,
+
+    /// Milliseconds to wait before the first retry of --io-retries,
+    /// doubling for each further one
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub io_retry_backoff: u64,
+
+    /// Memory-map a plain export.xml instead of using buffered reads
+    #[arg(long)]
+    pub mmap: bool,
+
+    /// Parse a plain export.xml in chunks on all threads (implies --mmap)
+    #[arg(long)]
+    pub chunked: bool,
+
+    /// Count records per type in a quick first pass to pre-size the groups
+    #[arg(long)]
+    pub prescan: bool,
+
+    /// On a mid-file XML error (e.g. a truncated export), keep the records
+    /// read so far instead of aborting
+    #[arg(long)]
+    pub recover: bool,
+
+    /// Only convert the records of the last days (`90d`) or weeks (`12w`),
+    /// adding the dates covered to the archive's file name
+    #[arg(long, value_name = "PERIOD", value_parser = parse_window)]
+    pub last: Option<u32>,
+
+    /// Drop records identical in every attribute to an earlier one; pass it
+    /// to `validate` as well when checking the archive
+    #[arg(long, global = true)]
+    pub dedup_exact: bool,
+
+    /// Add `source_file` and `xml_offset` columns naming the input and the
+    /// byte offset in its XML every record was read from
+    #[arg(long)]
+    pub provenance: bool,
+
+    /// Output format, by name: `zip`, `iceberg` (a table directory of
+    /// Parquet files) or one a library registers
+    #[arg(long, default_value = "zip")]
+    pub format: String,
+
+    /// URI the Iceberg table will be uploaded to, such as
+    /// `s3://bucket/health`, for the paths in its metadata; defaults to the
+    /// output directory
+    #[arg(long, value_name = "URI")]
+    pub table_location: Option<String>,
+
+    /// Decimal separator of numeric cells; `comma` also separates cells
+    /// with `;`
+    #[arg(long, value_enum, default_value_t = DecimalSeparator::Point)]
+    pub decimal_separator: DecimalSeparator,
+
+    /// Start each CSV with a `sep=` line so Excel splits cells correctly in
+    /// any locale
+    #[arg(long)]
+    pub excel_hint: bool,
+
+    /// Order the columns of every CSV alphabetically instead of starting
+    /// with startDate, endDate, creationDate, type, value and unit
+    #[arg(long)]
+    pub alphabetical_columns: bool,
+
+    /// Start every row with an `id` column holding a hash of the record's
+    /// type, dates, value and source, stable across exports
+    #[arg(long)]
+    pub record_ids: bool,
+
+    /// Follow startDate and endDate with startDate_ms and endDate_ms, the
+    /// same instants in milliseconds since the Unix epoch
+    #[arg(long)]
+    pub epoch_columns: bool,
+
+    /// Round the decimal numbers of measurements such as `value` to N
+    /// decimal places, halves away from zero, dropping trailing zeros
+    #[arg(long, value_name = "N")]
+    pub round: Option<usize>,
+
+    /// Write units as exported, with equivalent spellings normalized, or
+    /// with the values of each type converted to its most common unit
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = UnitMode::AsExported)]
+    pub units: UnitMode,
+
+    /// Leave out the columns that are empty in every row of their CSV
+    #[arg(long)]
+    pub drop_empty_columns: bool,
+
+    /// Write each type as a `Type.ndjson` entry with one JSON object per
+    /// record instead of a CSV, for consumers streaming huge types
+    #[arg(long)]
+    pub ndjson: bool,
+
+    /// Write each type as a `Type.pb` entry of length-delimited protobuf
+    /// messages instead of a CSV, described by a generated `records.proto`
+    #[arg(long, conflicts_with = "ndjson")]
+    pub protobuf: bool,
+
+    /// Write each type as a `Type.cbor` entry, a CBOR sequence of one map
+    /// per record, instead of a CSV
+    #[arg(long, conflicts_with_all = ["ndjson", "protobuf"])]
+    pub cbor: bool,
+
+    /// Also write summaries of record counts, date coverage, sources and
+    /// values per type into the archive (repeat or separate with commas)
+    #[arg(long, value_enum, value_name = "FORMAT", value_delimiter = ',')]
+    pub report: Vec<ReportFormat>,
+
+    /// Also draw charts of daily weight, resting heart rate and steps into
+    /// the archive and any HTML report (same as `--report charts`)
+    #[arg(long)]
+    pub charts: bool,
+
+    /// Also write a compact archive beside the output with one CSV per type
+    /// of weekly or monthly totals, such as `export_weekly.zip` (repeat or
+    /// separate with commas)
+    #[arg(long, value_enum, value_name = "PERIOD", value_delimiter = ',')]
+    pub rollup: Vec<RollupPeriod>,
+
+    /// Keep only the `--rollup` archives, removing the per-record archive
+    /// once they are written
+    #[arg(long, requires = "rollup")]
+    pub rollup_only: bool,
+
+    /// Bound the heart rate zones of `--report zones` by a maximum heart
+    /// rate such as `190` or the lower bounds of zones 1-5 such as
+    /// `95,114,133,152,171`, instead of 50-90% of 220 minus the age
+    #[arg(long, value_name = "BPM")]
+    pub heart_rate_zones: Option<HeartRateZones>,
+
+    /// TOML file of friendly entry names per type, such as
+    /// `HKQuantityTypeIdentifierStepCount = "steps"`; pass it to `validate`
+    /// as well when checking the archive
+    #[arg(long, value_name = "FILE", global = true)]
+    pub name_map: Option<PathBuf>,
+
+    /// POST a JSON summary of the run (status, counts, durations, output
+    /// path and hash) to this URL when the conversion finishes or fails
+    #[arg(long, value_name = "URL")]
+    pub notify_url: Option<String>,
+
+    /// Serve Prometheus metrics (records parsed, groups written, errors) on
+    /// `http://<ADDR>/metrics` while the conversion runs
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Compression method for the CSV entries in the output archive
+    #[arg(long, value_enum, default_value_t = CompressionMethod::Deflate, global = true)]
+    pub compression_method: CompressionMethod,
+
+    /// Compression level for the chosen method (deflate/bzip2: 1-9, zstd: 1-22)
+    #[arg(long, global = true)]
+    pub compression_level: Option<i64>,
+}
+
+/// Auxiliary commands; without one, the export is converted.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Re-count records per type in an export and a produced archive and
+    /// report any mismatch
+    Validate {
+        /// Path to the Apple Health export (export.zip or export.xml)
+        input_file: String,
+
+        /// Path to an archive previously produced from that export
+        output_zip: String,
+    },
+
+    /// Combine previously generated archives, dropping exact-duplicate rows
+    Merge {
+        /// Archives to combine
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+
+        /// Path for the merged archive
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Print the daily values of one metric with their rolling mean,
+    /// slope and change points as CSV
+    Trends {
+        /// Path to the Apple Health export (export.zip or export.xml)
+        input_file: String,
+
+        /// Record type to analyse, e.g. BodyMass or
+        /// HKQuantityTypeIdentifierRestingHeartRate
+        #[arg(long)]
+        metric: String,
+
+        /// Length of the rolling window, in days (`90d`) or weeks (`12w`)
+        #[arg(long, default_value = "90d", value_parser = parse_window)]
+        window: u32,
+
+        /// Write the CSV to this path instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write several metrics side by side, one row per day or hour and one
+    /// column per metric
+    Pivot {
+        /// Path to the Apple Health export (export.zip or export.xml)
+        input_file: String,
+
+        /// Metrics to write as columns, e.g. steps,resting_hr,weight,sleep_minutes
+        /// or record types such as BodyMass
+        #[arg(long, required = true, value_delimiter = ',')]
+        metrics: Vec<String>,
+
+        /// Length of each row
+        #[arg(long, value_enum, default_value_t = PivotInterval::Day)]
+        interval: PivotInterval,
+
+        /// Write the CSV to this path instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print chosen fields of the records matching a condition, one line per
+    /// record, as the export is read
+    Select {
+        /// Path to the Apple Health export (export.zip or export.xml)
+        input_file: String,
+
+        /// Fields to print, jq-style: `.type, .startDate, .value`, or `.`
+        /// for every attribute
+        projection: String,
+
+        /// Only print records satisfying this condition, such as
+        /// `.type == "HKQuantityTypeIdentifierBodyMass" and .value > 80`
+        #[arg(long = "where", value_name = "CONDITION")]
+        condition: Option<String>,
+
+        /// Print the values separated by tabs instead of as JSON
+        #[arg(short, long)]
+        raw_output: bool,
+    },
+
+    /// Copy the workout routes and ECG recordings out of an export into a
+    /// folder, without converting it
+    ExtractAssets {
+        /// Path to the Apple Health export (export.zip or export.xml)
+        input_file: String,
+
+        /// Kinds of files to copy, comma-separated; all by default
+        #[arg(long, value_enum, value_delimiter = ',')]
+        only: Vec<AssetKind>,
+
+        /// Folder to copy the files into
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Time extraction, transformation and the full pipeline over a few
+    /// runs and print a comparison table
+    Bench {
+        /// Path to the Apple Health export (export.zip or export.xml)
+        input_file: String,
+
+        /// Repetitions of each phase
+        #[arg(long, default_value = "3")]
+        runs: NonZeroUsize,
+    },
+
+    /// Write a synthetic export.xml with realistic types, sources and
+    /// dates, for benchmarking without a real export
+    Generate {
+        /// Number of records to generate
+        #[arg(long, default_value_t = 1_000_000)]
+        records: usize,
+
+        /// Number of distinct record types
+        #[arg(long, default_value_t = 80)]
+        types: usize,
+
+        /// Seed for the generator; equal seeds produce identical files
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Path for the generated export.xml
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+/// Parse a window length in days such as `90d`, `12w` or `30`.
+pub fn parse_window(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_lowercase() {
+                'd' => 1,
+                'w' => 7,
+                _ => return Err(format!("unknown window unit '{}'", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    match digits.trim().parse::<u32>() {
+        Ok(0) => Err("the window must be at least one day".to_string()),
+        Ok(n) => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("window '{}' is too long", s)),
+        Err(e) => Err(format!("invalid window '{}': {}", s, e)),
+    }
+}
+
+/// Parse a byte size such as `4096`, `64K`, `512M` or `2G` (binary units).
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(format!("unknown size suffix '{}'", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    digits
+        .trim()
+        .parse::<usize>()
+        .map_err(|e| format!("invalid size '{}': {}", s, e))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
 }
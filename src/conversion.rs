@@ -0,0 +1,320 @@
+use crate::apple_health::extractor::AppleHealthExtractor;
+use crate::backpressure::Backpressure;
+use crate::core::{Engine, RunReport};
+use crate::error::Result;
+use crate::progress::{Progress, ProgressEvent};
+use crate::report::{ReportFormat, Reports};
+use crate::retry::RetryPolicy;
+use crate::sinks::csv_zip::{Compression, CsvDialect};
+use crate::sinks::file_names::NameMap;
+use crate::sinks::registry::{SinkOptions, SinkRegistry, ZIP_FORMAT};
+use crate::zones::HeartRateZones;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Output format produced by a conversion.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Format {
+    /// A ZIP archive with one CSV per record type.
+    #[default]
+    Zip,
+    /// A format registered by name in the conversion's [`SinkRegistry`].
+    Named(String),
+}
+
+impl Format {
+    /// The name the format is registered under.
+    pub fn name(&self) -> &str {
+        match self {
+            Format::Zip => ZIP_FORMAT,
+            Format::Named(name) => name,
+        }
+    }
+}
+
+impl From<&str> for Format {
+    fn from(name: &str) -> Self {
+        if name == ZIP_FORMAT {
+            Format::Zip
+        } else {
+            Format::Named(name.to_string())
+        }
+    }
+}
+
+/// High-level entry point for converting an Apple Health export.
+///
+/// ```no_run
+/// # async fn example() -> gpt_os::Result<()> {
+/// use gpt_os::{ConversionBuilder, Format};
+///
+/// ConversionBuilder::new("export.zip")
+///     .output("health.zip")
+///     .format(Format::Zip)
+///     .types(["HKQuantityTypeIdentifierBodyMass", "Workout"])
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConversionBuilder {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    format: Format,
+    types: Option<Vec<String>>,
+    compression: Compression,
+    dialect: CsvDialect,
+    reports: Reports,
+    heart_rate_zones: HeartRateZones,
+    names: NameMap,
+    progress: Progress,
+    registry: SinkRegistry,
+    max_memory: Option<usize>,
+    mmap: bool,
+    chunked: bool,
+    prescan: bool,
+    recover: bool,
+    dedup_exact: bool,
+    since: Option<i64>,
+    checkpoint: Option<PathBuf>,
+    provenance: bool,
+    backpressure: Backpressure,
+    retry: RetryPolicy,
+    table_location: Option<String>,
+}
+
+impl ConversionBuilder {
+    /// Start a conversion of `input` (an `export.zip` or `export.xml`).
+    pub fn new(input: impl Into<PathBuf>) -> Self {
+        Self {
+            input: input.into(),
+            output: None,
+            format: Format::default(),
+            types: None,
+            compression: Compression::default(),
+            dialect: CsvDialect::default(),
+            reports: Reports::default(),
+            heart_rate_zones: HeartRateZones::default(),
+            names: NameMap::new(),
+            progress: Progress::default(),
+            registry: SinkRegistry::default(),
+            max_memory: None,
+            mmap: false,
+            chunked: false,
+            prescan: false,
+            recover: false,
+            dedup_exact: false,
+            since: None,
+            checkpoint: None,
+            provenance: false,
+            backpressure: Backpressure::default(),
+            retry: RetryPolicy::default(),
+            table_location: None,
+        }
+    }
+
+    /// Where to write the result. Defaults to the input path with a
+    /// `.csv.zip` extension.
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Only convert records of the given types (grouping keys such as
+    /// `HKQuantityTypeIdentifierStepCount` or `Workout`).
+    pub fn types<I, K>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write the CSVs with a different decimal separator or an Excel
+    /// `sep=` hint.
+    pub fn dialect(mut self, dialect: CsvDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Add a summary of the converted records, such as an HTML page with
+    /// the counts, date coverage and sources of every type. Call once per
+    /// format to write several.
+    pub fn report(mut self, format: ReportFormat) -> Self {
+        self.reports = self.reports.with(format);
+        self
+    }
+
+    /// Bound the heart rate zones of [`ReportFormat::Zones`] by a maximum
+    /// heart rate or explicit bpm instead of the age in the export.
+    pub fn heart_rate_zones(mut self, zones: HeartRateZones) -> Self {
+        self.heart_rate_zones = zones;
+        self
+    }
+
+    /// Name the output of each type in `names`, such as `steps` for
+    /// `HKQuantityTypeIdentifierStepCount`, instead of after the type.
+    pub fn names(mut self, names: NameMap) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Keep roughly at most `bytes` of records in memory, spilling sorted
+    /// runs of the largest groups to temporary files beyond that.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Checkpoint the records spilled under [`max_memory`](Self::max_memory)
+    /// into `dir`, so a conversion interrupted part-way resumes from there
+    /// when run again with the same directory. Has no effect without
+    /// [`max_memory`](Self::max_memory).
+    pub fn checkpoint(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(dir.into());
+        self
+    }
+
+    /// Read a plain `export.xml` through a memory map, which is faster on
+    /// fast disks. The file must not change during the conversion.
+    pub fn mmap(mut self, enabled: bool) -> Self {
+        self.mmap = enabled;
+        self
+    }
+
+    /// Parse a plain `export.xml` in chunks split on `<Record` boundaries,
+    /// using every thread instead of a single reader. Implies
+    /// [`mmap`](Self::mmap).
+    pub fn chunked(mut self, enabled: bool) -> Self {
+        self.chunked = enabled;
+        self
+    }
+
+    /// Count the records of each type in a quick first pass so every group
+    /// is allocated once. Has no effect with [`max_memory`](Self::max_memory).
+    pub fn prescan(mut self, enabled: bool) -> Self {
+        self.prescan = enabled;
+        self
+    }
+
+    /// Convert whatever precedes an XML error, such as the end of a truncated
+    /// export, instead of failing; see [`RunReport::truncated`].
+    pub fn recover(mut self, enabled: bool) -> Self {
+        self.recover = enabled;
+        self
+    }
+
+    /// Drop records identical in every attribute to an earlier one, as left
+    /// behind by re-imported backups and app migrations.
+    pub fn dedup_exact(mut self, enabled: bool) -> Self {
+        self.dedup_exact = enabled;
+        self
+    }
+
+    /// Only convert records dated at or after `since`, in seconds since the
+    /// Unix epoch.
+    pub fn since(mut self, since: i64) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Record the input and the byte offset in its XML of every record in
+    /// `source_file` and `xml_offset` columns, so rows can be traced back to
+    /// their export after a merge.
+    pub fn provenance(mut self, enabled: bool) -> Self {
+        self.provenance = enabled;
+        self
+    }
+
+    /// Bound how many records and bytes may queue between stages.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Retry opening, reading and writing files on transient errors, such as
+    /// the `EIO` of a network file system, instead of failing the run.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Where a table format's files will live once the output is uploaded,
+    /// such as `s3://bucket/health`; paths in its metadata point there.
+    pub fn table_location(mut self, location: impl Into<String>) -> Self {
+        self.table_location = Some(location.into());
+        self
+    }
+
+    /// Look up the output format in `registry` instead of the built-in one.
+    pub fn registry(mut self, registry: SinkRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Receive [`ProgressEvent`]s (bytes read, records parsed, groups
+    /// written) while the conversion runs.
+    pub fn progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ProgressEvent) + Send + Sync + 'static,
+    {
+        self.progress = Progress::new(callback);
+        self
+    }
+
+    /// Run the conversion to completion.
+    pub async fn run(self) -> Result<RunReport> {
+        let output = self
+            .output
+            .unwrap_or_else(|| self.input.with_extension("csv.zip"));
+
+        let options = SinkOptions {
+            compression: self.compression,
+            dialect: self.dialect,
+            reports: self.reports,
+            heart_rate_zones: self.heart_rate_zones,
+            names: Arc::new(self.names),
+            backpressure: self.backpressure,
+            retry: self.retry,
+            table_location: self.table_location,
+        };
+        let sink = self.registry.create(self.format.name(), &options)?;
+        let extractor = AppleHealthExtractor::new()
+            .mmap(self.mmap)
+            .chunked(self.chunked)
+            .provenance(self.provenance)
+            .backpressure(self.backpressure)
+            .retry(self.retry);
+        let mut engine = Engine::new(extractor, sink)
+            .with_progress_handle(self.progress)
+            .with_prescan(self.prescan)
+            .with_recover(self.recover)
+            .with_dedup_exact(self.dedup_exact)
+            .with_retry(self.retry);
+        if let Some(types) = self.types {
+            engine = engine.with_types(types);
+        }
+        if let Some(since) = self.since {
+            engine = engine.with_since(since);
+        }
+        if let Some(dir) = self.checkpoint {
+            engine = engine.with_checkpoint(dir);
+        }
+        match self.max_memory {
+            Some(max_memory) => engine.run_bounded(&self.input, &output, max_memory).await,
+            None => engine.run(&self.input, &output).await,
+        }
+    }
+}
@@ -1,29 +1,242 @@
-use crate::error::Result;
+use crate::error::{Result, SkipSummary, Truncation};
+use crate::manifest::Manifest;
+use crate::progress::Progress;
 use ahash::AHashMap;
-use async_trait::async_trait;
-use log::{debug, info};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::path::Path;
-use std::time::Instant;
-use tokio::sync::mpsc;
+use std::time::Duration;
+#[cfg(feature = "native")]
+use {
+    crate::apple_health::dates::format_timestamp,
+    crate::backpressure::Backpressure,
+    crate::error::AppError,
+    crate::fit,
+    crate::manifest::InputFile,
+    crate::progress::ProgressEvent,
+    crate::remote::{self, InputDigest},
+    crate::retry::RetryPolicy,
+    crate::spill::{self, SpilledGroups, SpillingGroups},
+    ahash::AHashSet,
+    async_trait::async_trait,
+    log::{debug, info},
+    serde::{Serialize, de::DeserializeOwned},
+    std::io::{Seek, Write},
+    std::path::{Path, PathBuf},
+    std::time::Instant,
+    tokio::sync::mpsc,
+    tokio::task,
+    tokio_stream::wrappers::ReceiverStream,
+    tracing::{Instrument, info_span},
+};
 
 /// Represents a single, processable data record.
 pub trait Processable: Send + Sync + Debug + 'static {
     /// A key for grouping records, e.g., by data type.
     fn grouping_key(&self) -> String;
 
-    /// Optional key used for ordering records within groups.
-    fn sort_key(&self) -> Option<&str> {
+    /// Optional key used for ordering records within groups, typically a
+    /// Unix timestamp. Records without one sort first.
+    fn sort_key(&self) -> Option<i64> {
         None
     }
+
+    /// Look up a named field of the record, if the record type exposes one.
+    fn field(&self, _name: &str) -> Option<&str> {
+        None
+    }
+
+    /// Hash of everything the record holds, equal only for exact duplicates,
+    /// or `None` if the record type cannot be compared.
+    fn content_hash(&self) -> Option<u128> {
+        None
+    }
+
+    /// Approximate heap and inline size of the record in bytes, used to
+    /// enforce memory budgets.
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+}
+
+/// Receives records from the transform phase, keyed by grouping key.
+pub trait RecordCollector<T> {
+    fn push(&mut self, key: String, record: T) -> Result<()>;
+
+    /// Fill in the record counts and export date of `manifest`.
+    fn describe(&self, manifest: &mut Manifest);
 }
 
+impl<T: Processable> RecordCollector<T> for AHashMap<String, Vec<T>> {
+    fn push(&mut self, key: String, record: T) -> Result<()> {
+        self.entry(key).or_default().push(record);
+        Ok(())
+    }
+
+    fn describe(&self, manifest: &mut Manifest) {
+        manifest.describe_records(self);
+    }
+}
+
+#[cfg(feature = "native")]
+/// A `futures`-compatible [`Stream`](tokio_stream::Stream) of extracted records.
+pub type RecordStream<T> = ReceiverStream<Result<T>>;
+
+#[cfg(feature = "native")]
 /// Extracts records from a data source into a channel.
+///
+/// Extractors should report consumed input through
+/// [`ProgressEvent::BytesRead`] on the given [`Progress`] handle.
 #[async_trait]
 pub trait Extractor<T: Processable> {
-    async fn extract(&self, input_path: &Path) -> Result<mpsc::Receiver<Result<T>>>;
+    async fn extract(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<mpsc::Receiver<Result<T>>>;
+
+    /// Like [`Extractor::extract`], exposing the records as a [`RecordStream`]
+    /// so stream combinators can be applied to them directly.
+    async fn extract_stream(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<RecordStream<T>>
+    where
+        Self: Sync,
+    {
+        Ok(ReceiverStream::new(
+            self.extract(input_path, progress).await?,
+        ))
+    }
+
+    /// Like [`Extractor::extract`], also returning the digest of the input
+    /// for the manifest when the extractor hashes it as it reads it. The
+    /// engine hashes local inputs itself, so extractors reading URLs should
+    /// hash the download here rather than have it fetched twice. Defaults
+    /// to no digest.
+    async fn extract_hashed(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<(mpsc::Receiver<Result<T>>, Option<InputDigest>)>
+    where
+        Self: Sync,
+    {
+        Ok((self.extract(input_path, progress).await?, None))
+    }
+
+    /// Cheap estimate of the number of records per grouping key, used by
+    /// [`Engine::with_prescan`] to pre-size the groups. Defaults to no
+    /// estimate.
+    async fn count_groups(&self, _input_path: &Path) -> Result<AHashMap<String, usize>> {
+        Ok(AHashMap::new())
+    }
 }
 
+#[cfg(feature = "native")]
+/// Runs several extractors over the same input and interleaves their
+/// records into one channel, in whatever order they arrive.
+///
+/// Lets additional data sources of an export, such as clinical records next
+/// to `export.xml`, feed a single [`Engine`]. Errors of any extractor are
+/// passed on like records; the channel closes once every extractor is done.
+pub struct ChainedExtractor<T: Processable> {
+    extractors: Vec<Box<dyn Extractor<T> + Send + Sync>>,
+    backpressure: Backpressure,
+}
+
+#[cfg(feature = "native")]
+impl<T: Processable> Default for ChainedExtractor<T> {
+    fn default() -> Self {
+        Self {
+            extractors: Vec::new(),
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl<T: Processable> ChainedExtractor<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also extract records with `extractor`.
+    pub fn with<E>(mut self, extractor: E) -> Self
+    where
+        E: Extractor<T> + Send + Sync + 'static,
+    {
+        self.extractors.push(Box::new(extractor));
+        self
+    }
+
+    /// Bound the records queued in the combined channel.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+}
+
+#[cfg(feature = "native")]
+#[async_trait]
+impl<T: Processable> Extractor<T> for ChainedExtractor<T> {
+    async fn extract(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<mpsc::Receiver<Result<T>>> {
+        Ok(self.extract_hashed(input_path, progress).await?.0)
+    }
+
+    /// The records of every extractor, with the first digest of the input
+    /// any of them returns.
+    async fn extract_hashed(
+        &self,
+        input_path: &Path,
+        progress: &Progress,
+    ) -> Result<(mpsc::Receiver<Result<T>>, Option<InputDigest>)> {
+        let (tx, rx) = mpsc::channel(self.backpressure.channel_capacity());
+        let mut digest = None;
+        for extractor in &self.extractors {
+            let (mut receiver, hashed) = extractor.extract_hashed(input_path, progress).await?;
+            digest = digest.or(hashed);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while let Some(record) = receiver.recv().await {
+                    if tx.send(record).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        Ok((rx, digest))
+    }
+
+    /// The estimates of every extractor, added up per grouping key.
+    async fn count_groups(&self, input_path: &Path) -> Result<AHashMap<String, usize>> {
+        let mut counts = AHashMap::new();
+        for extractor in &self.extractors {
+            for (key, count) in extractor.count_groups(input_path).await? {
+                *counts.entry(key).or_insert(0) += count;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+/// Per-run information handed to a [`Sink`] alongside the records.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadContext<'a> {
+    /// Provenance to embed alongside the data.
+    pub manifest: &'a Manifest,
+    /// Receives a [`ProgressEvent::GroupWritten`] for each written group.
+    pub progress: &'a Progress,
+    /// Malformed elements skipped during extraction, for sinks that list
+    /// them alongside the data.
+    pub skipped: &'a SkipSummary,
+}
+
+#[cfg(feature = "native")]
 /// Loads grouped records into a data sink.
 #[async_trait]
 pub trait Sink<T: Processable> {
@@ -31,9 +244,99 @@ pub trait Sink<T: Processable> {
         &self,
         grouped_records: AHashMap<String, Vec<T>>,
         output_path: &Path,
+        context: LoadContext<'_>,
     ) -> Result<()>;
+
+    /// Load groups collected by [`Engine::run_bounded`], parts of which live
+    /// in sorted runs on disk.
+    ///
+    /// The default reads every group back into memory and calls
+    /// [`Sink::load`]; sinks that can stream should override it.
+    async fn load_spilled(
+        &self,
+        groups: SpilledGroups<T>,
+        output_path: &Path,
+        context: LoadContext<'_>,
+    ) -> Result<()>
+    where
+        T: DeserializeOwned,
+    {
+        let grouped_records = task::spawn_blocking(move || groups.into_map())
+            .await
+            .map_err(|e| AppError::Unknown(e.to_string()))??;
+        self.load(grouped_records, output_path, context).await
+    }
+}
+
+#[cfg(feature = "native")]
+#[async_trait]
+impl<T: Processable> Sink<T> for Box<dyn Sink<T> + Send + Sync> {
+    async fn load(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        output_path: &Path,
+        context: LoadContext<'_>,
+    ) -> Result<()> {
+        (**self).load(grouped_records, output_path, context).await
+    }
+
+    async fn load_spilled(
+        &self,
+        groups: SpilledGroups<T>,
+        output_path: &Path,
+        context: LoadContext<'_>,
+    ) -> Result<()>
+    where
+        T: DeserializeOwned,
+    {
+        (**self).load_spilled(groups, output_path, context).await
+    }
 }
 
+#[cfg(feature = "native")]
+/// A [`Sink`] that can also write into an arbitrary seekable writer, such as
+/// an in-memory buffer, instead of a file at a path.
+#[async_trait]
+pub trait SinkTo<T: Processable>: Sink<T> {
+    /// Write the grouped records into `writer`, returning it once complete.
+    async fn load_to<W>(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        writer: W,
+        context: LoadContext<'_>,
+    ) -> Result<W>
+    where
+        W: Write + Seek + Send + 'static;
+}
+
+/// Summary of a completed [`Engine::run`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunReport {
+    pub extract_duration: Duration,
+    pub transform_duration: Duration,
+    pub load_duration: Duration,
+    pub total_duration: Duration,
+    /// Records written per group.
+    pub record_counts: BTreeMap<String, usize>,
+    /// Malformed elements that were skipped during extraction.
+    pub skipped: SkipSummary,
+    /// Where the input became unreadable, for runs that recovered from it.
+    pub truncated: Option<Truncation>,
+    /// Size of the output file in bytes.
+    pub output_bytes: u64,
+}
+
+impl RunReport {
+    pub fn total_records(&self) -> usize {
+        self.record_counts.values().sum()
+    }
+
+    pub fn skipped_records(&self) -> usize {
+        self.skipped.total()
+    }
+}
+
+#[cfg(feature = "native")]
 pub struct Engine<T, E, S>
 where
     T: Processable,
@@ -42,9 +345,18 @@ where
 {
     extractor: E,
     sink: S,
+    types: Option<AHashSet<String>>,
+    prescan: bool,
+    recover: bool,
+    dedup_exact: bool,
+    since: Option<i64>,
+    checkpoint: Option<PathBuf>,
+    retry: RetryPolicy,
+    progress: Progress,
     _marker: std::marker::PhantomData<T>,
 }
 
+#[cfg(feature = "native")]
 impl<T, E, S> Engine<T, E, S>
 where
     T: Processable,
@@ -55,20 +367,160 @@ where
         Self {
             extractor,
             sink,
+            types: None,
+            prescan: false,
+            recover: false,
+            dedup_exact: false,
+            since: None,
+            checkpoint: None,
+            retry: RetryPolicy::default(),
+            progress: Progress::default(),
             _marker: std::marker::PhantomData,
         }
     }
 
-    pub async fn run(&self, input_path: &Path, output_path: &Path) -> Result<()> {
+    /// Register a callback receiving [`ProgressEvent`]s while the engine runs.
+    pub fn with_progress<F>(self, callback: F) -> Self
+    where
+        F: Fn(&ProgressEvent) + Send + Sync + 'static,
+    {
+        self.with_progress_handle(Progress::new(callback))
+    }
+
+    /// Like [`Engine::with_progress`], sharing an existing [`Progress`] handle.
+    pub fn with_progress_handle(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Only keep records whose grouping key is one of `types`.
+    pub fn with_types<I, K>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Count the records per group with [`Extractor::count_groups`] before
+    /// extracting, so [`Engine::run`] can allocate each group once instead
+    /// of growing it. Costs an extra pass over the input.
+    pub fn with_prescan(mut self, enabled: bool) -> Self {
+        self.prescan = enabled;
+        self
+    }
+
+    /// On an XML error, such as a truncated input, stop extracting and write
+    /// the records read so far instead of failing. The error is reported in
+    /// [`RunReport::truncated`] and the manifest.
+    pub fn with_recover(mut self, enabled: bool) -> Self {
+        self.recover = enabled;
+        self
+    }
+
+    /// Drop records identical in every attribute to one seen before, by
+    /// [`Processable::content_hash`]. Costs 16 bytes per record kept.
+    pub fn with_dedup_exact(mut self, enabled: bool) -> Self {
+        self.dedup_exact = enabled;
+        self
+    }
+
+    /// Only keep records dated at or after `since`, in seconds since the
+    /// Unix epoch, by their [`Processable::sort_key`]. Undated records, such
+    /// as the export info, are kept.
+    pub fn with_since(mut self, since: i64) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Spill into `dir` during [`Engine::run_bounded`] and checkpoint every
+    /// spill there, so an interrupted run started again with the same
+    /// directory picks up the records already spilled. The directory is
+    /// emptied once the output is written.
+    pub fn with_checkpoint(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(dir.into());
+        self
+    }
+
+    /// Retry reading the input for the manifest's hash on transient errors;
+    /// the extractor and sink take their own [`RetryPolicy`].
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub async fn run(&self, input_path: &Path, output_path: &Path) -> Result<RunReport> {
+        let mut grouped_records = if self.prescan {
+            self.presized_groups(input_path).await?
+        } else {
+            AHashMap::new()
+        };
+        let phases = self
+            .extract_and_transform(input_path, output_path, &mut grouped_records)
+            .await?;
+
+        let load_start = Instant::now();
+        info!(phase = "load"; "Starting load phase...");
+        self.sink
+            .load(grouped_records, output_path, phases.context(&self.progress))
+            .instrument(info_span!("load"))
+            .await?;
+        phases.finish(load_start, output_path)
+    }
+
+    async fn presized_groups(&self, input_path: &Path) -> Result<AHashMap<String, Vec<T>>> {
+        let scan_start = Instant::now();
+        let counts = self.extractor.count_groups(input_path).await?;
+        let groups: AHashMap<String, Vec<T>> = counts
+            .into_iter()
+            .filter(|(key, _)| self.types.as_ref().is_none_or(|types| types.contains(key)))
+            .map(|(key, count)| (key, Vec::with_capacity(count)))
+            .collect();
+        debug!(
+            "Pre-sized {} groups for {} records in {:.3}s",
+            groups.len(),
+            groups.values().map(Vec::capacity).sum::<usize>(),
+            scan_start.elapsed().as_secs_f64()
+        );
+        Ok(groups)
+    }
+
+    async fn extract_and_transform<C>(
+        &self,
+        input_path: &Path,
+        output_path: &Path,
+        collector: &mut C,
+    ) -> Result<Phases>
+    where
+        C: RecordCollector<T> + Send,
+    {
         let start_time = Instant::now();
-        info!("Starting ETL pipeline");
-        info!("Input: {}", input_path.display());
+        info!(phase = "start"; "Starting ETL pipeline");
+        info!("Input: {}", remote::redact(input_path));
         info!("Output: {}", output_path.display());
 
         // Extract phase
         let extract_start = Instant::now();
-        info!("Starting extraction phase...");
-        let receiver = self.extractor.extract(input_path).await?;
+        info!(phase = "extract"; "Starting extraction phase...");
+        let (receiver, digest) = self
+            .extractor
+            .extract_hashed(input_path, &self.progress)
+            .instrument(info_span!("extract"))
+            .await?;
+        // Hash a local input concurrently with the transform for the
+        // manifest; the extractor hashes a download as it reads it
+        let hash_handle = digest.is_none().then(|| {
+            let hash_path = input_path.to_path_buf();
+            let retry = self.retry;
+            task::spawn_blocking(move || {
+                if hash_path.is_dir() {
+                    InputFile::hashed_from(&hash_path, fit::folder_reader(&hash_path)?)
+                } else {
+                    InputFile::hashed_from(&hash_path, retry.open(&hash_path)?)
+                }
+            })
+        });
         let extract_duration = extract_start.elapsed();
         debug!(
             "Extraction phase setup completed in {:.3}s",
@@ -77,72 +529,283 @@ where
 
         // Transform phase
         let transform_start = Instant::now();
-        info!("Starting transformation phase...");
-        let grouped_records = transformer::transform(receiver).await?;
+        info!(phase = "transform"; "Starting transformation phase...");
+        let (skipped, truncated) = transformer::transform(
+            receiver,
+            self.types.as_ref(),
+            self.since,
+            self.recover,
+            self.dedup_exact,
+            &self.progress,
+            collector,
+        )
+        .instrument(info_span!("transform"))
+        .await?;
         let transform_duration = transform_start.elapsed();
 
-        let total_records: usize = grouped_records.values().map(Vec::len).sum();
-        let record_types = grouped_records.len();
+        let input = match (digest, hash_handle) {
+            (Some(digest), _) => InputFile {
+                path: remote::redact(input_path),
+                sha256: digest.wait().await?,
+            },
+            (None, Some(hash_handle)) => hash_handle
+                .await
+                .map_err(|e| AppError::Unknown(e.to_string()))??,
+            (None, None) => unreachable!("inputs without a digest are hashed here"),
+        };
+        let mut manifest = Manifest::new(vec![input]);
+        collector.describe(&mut manifest);
+        manifest.truncated = truncated;
+        if let Some(types) = &self.types {
+            let mut types: Vec<&str> = types.iter().map(String::as_str).collect();
+            types.sort_unstable();
+            manifest.filters.push(format!("types={}", types.join(",")));
+        }
+        if let Some(since) = self.since {
+            manifest
+                .filters
+                .push(format!("since={}", format_timestamp(since)));
+        }
+        if self.dedup_exact {
+            manifest.filters.push("dedup-exact".to_string());
+        }
+        manifest
+            .timings
+            .insert("extract".to_string(), extract_duration.as_secs_f64());
+        manifest
+            .timings
+            .insert("transform".to_string(), transform_duration.as_secs_f64());
+
+        let total_records: usize = manifest.record_counts.values().sum();
+        let record_types = manifest.record_counts.len();
         info!(
+            phase = "transform",
+            duration_s = transform_duration.as_secs_f64(),
+            records = total_records,
+            types = record_types;
             "Transformation completed in {:.3}s: {} records grouped into {} types",
             transform_duration.as_secs_f64(),
             total_records,
             record_types
         );
 
-        // Load phase
+        Ok(Phases {
+            start_time,
+            extract_duration,
+            transform_duration,
+            manifest,
+            skipped,
+        })
+    }
+}
+
+#[cfg(feature = "native")]
+impl<T, E, S> Engine<T, E, S>
+where
+    T: Processable + Serialize + DeserializeOwned,
+    E: Extractor<T> + Sync,
+    S: Sink<T> + Sync,
+{
+    /// Like [`Engine::run`], but keeps roughly at most `max_memory` bytes of
+    /// records in memory, spilling sorted runs to temporary files beyond that.
+    pub async fn run_bounded(
+        &self,
+        input_path: &Path,
+        output_path: &Path,
+        max_memory: usize,
+    ) -> Result<RunReport> {
+        let mut groups = match &self.checkpoint {
+            Some(dir) => SpillingGroups::checkpointed(max_memory, dir, input_path)?,
+            None => SpillingGroups::new(max_memory)?,
+        };
+        let phases = self
+            .extract_and_transform(input_path, output_path, &mut groups)
+            .await?;
+        info!(runs = groups.runs(); "Spilled {} sorted runs to disk", groups.runs());
+
         let load_start = Instant::now();
-        info!("Starting load phase...");
-        self.sink.load(grouped_records, output_path).await?;
+        info!(phase = "load"; "Starting load phase...");
+        self.sink
+            .load_spilled(
+                groups.finish()?,
+                output_path,
+                phases.context(&self.progress),
+            )
+            .instrument(info_span!("load", spilled = true))
+            .await?;
+        if let Some(dir) = &self.checkpoint {
+            spill::remove_checkpoint(dir)?;
+        }
+        phases.finish(load_start, output_path)
+    }
+}
+
+/// State carried from the transform phase into the load phase of a run.
+#[cfg(feature = "native")]
+struct Phases {
+    start_time: Instant,
+    extract_duration: Duration,
+    transform_duration: Duration,
+    manifest: Manifest,
+    skipped: SkipSummary,
+}
+
+#[cfg(feature = "native")]
+impl Phases {
+    fn context<'a>(&'a self, progress: &'a Progress) -> LoadContext<'a> {
+        LoadContext {
+            manifest: &self.manifest,
+            progress,
+            skipped: &self.skipped,
+        }
+    }
+
+    fn finish(self, load_start: Instant, output_path: &Path) -> Result<RunReport> {
         let load_duration = load_start.elapsed();
         info!(
+            phase = "load",
+            duration_s = load_duration.as_secs_f64();
             "Load phase completed in {:.3}s",
             load_duration.as_secs_f64()
         );
 
-        let total_duration = start_time.elapsed();
+        let total_records: usize = self.manifest.record_counts.values().sum();
+        let total_duration = self.start_time.elapsed();
         info!(
+            phase = "done",
+            duration_s = total_duration.as_secs_f64(),
+            records = total_records;
             "ETL pipeline completed successfully in {:.3}s",
             total_duration.as_secs_f64()
         );
         info!(
+            extract_s = self.extract_duration.as_secs_f64(),
+            transform_s = self.transform_duration.as_secs_f64(),
+            load_s = load_duration.as_secs_f64();
             "Performance breakdown - Extract: {:.3}s, Transform: {:.3}s, Load: {:.3}s",
-            extract_duration.as_secs_f64(),
-            transform_duration.as_secs_f64(),
+            self.extract_duration.as_secs_f64(),
+            self.transform_duration.as_secs_f64(),
             load_duration.as_secs_f64()
         );
 
         if total_records > 0 {
             let throughput = total_records as f64 / total_duration.as_secs_f64();
-            info!("Throughput: {:.0} records/second", throughput);
+            info!(records_per_s = throughput; "Throughput: {:.0} records/second", throughput);
         }
 
-        Ok(())
+        Ok(RunReport {
+            extract_duration: self.extract_duration,
+            transform_duration: self.transform_duration,
+            load_duration,
+            total_duration,
+            record_counts: self.manifest.record_counts,
+            skipped: self.skipped,
+            truncated: self.manifest.truncated,
+            output_bytes: std::fs::metadata(output_path)?.len(),
+        })
     }
 }
 
-mod transformer {
-    use super::Processable;
-    use crate::error::Result;
-    use ahash::AHashMap;
-    use log::{debug, info};
+#[cfg(feature = "native")]
+pub(crate) mod transformer {
+    use super::{Processable, RecordCollector};
+    use crate::error::{AppError, Result, SkipSummary, Truncation};
+    use crate::progress::{Progress, ProgressEvent, RECORDS_REPORT_INTERVAL};
+    use ahash::AHashSet;
+    use log::{debug, info, warn};
     use std::time::Instant;
     use tokio::sync::mpsc::Receiver;
+    use tokio::sync::mpsc::error::TryRecvError;
+    use tracing::{Instrument, info_span};
 
-    pub async fn transform<T: Processable>(
+    /// Group records by key into `collector`, returning a summary of skipped
+    /// (malformed) records. With `recover`, an XML error ends the input
+    /// instead and is returned as a [`Truncation`]; any other error aborts
+    /// the transform. Records dated before `since` are dropped, and with
+    /// `dedup_exact` so are those with the content hash of an earlier one.
+    pub async fn transform<T, C>(
         mut receiver: Receiver<Result<T>>,
-    ) -> Result<AHashMap<String, Vec<T>>> {
+        types: Option<&AHashSet<String>>,
+        since: Option<i64>,
+        recover: bool,
+        dedup_exact: bool,
+        progress: &Progress,
+        collector: &mut C,
+    ) -> Result<(SkipSummary, Option<Truncation>)>
+    where
+        T: Processable,
+        C: RecordCollector<T>,
+    {
         let start_time = Instant::now();
-        let mut grouped_records: AHashMap<String, Vec<T>> = AHashMap::new();
         let mut total_processed = 0usize;
+        let mut skipped = SkipSummary::default();
+        let mut truncated = None;
+        let mut last_sort_key = None;
+        let mut seen: AHashSet<u128> = AHashSet::new();
+        let mut duplicates = 0usize;
 
-        while let Some(result) = receiver.recv().await {
-            let record = result?;
-            grouped_records
-                .entry(record.grouping_key())
-                .or_default()
-                .push(record);
+        loop {
+            let result = match receiver.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => {
+                    let wait = info_span!("wait", on = "parsed_records");
+                    match receiver.recv().instrument(wait).await {
+                        Some(result) => result,
+                        None => break,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+            let record = match result {
+                Ok(record) => record,
+                Err(AppError::SkippedRecord(error)) => {
+                    debug!("Skipped record {}", error);
+                    skipped.record(error);
+                    continue;
+                }
+                // Records parsed before the error may still be in flight, so
+                // keep draining until the extractor closes the channel
+                Err(AppError::Xml { offset, message }) if recover => {
+                    truncated.get_or_insert(Truncation {
+                        offset,
+                        message,
+                        last_sort_key: None,
+                    });
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if recover {
+                last_sort_key = last_sort_key.max(record.sort_key());
+            }
+            let key = record.grouping_key();
+            if types.is_some_and(|types| !types.contains(&key)) {
+                continue;
+            }
+            if let (Some(since), Some(date)) = (since, record.sort_key())
+                && date < since
+            {
+                continue;
+            }
+            if dedup_exact
+                && let Some(hash) = record.content_hash()
+                && !seen.insert(hash)
+            {
+                duplicates += 1;
+                continue;
+            }
+            collector.push(key, record)?;
             total_processed += 1;
+            if total_processed.is_multiple_of(RECORDS_REPORT_INTERVAL) {
+                progress.emit(ProgressEvent::RecordsParsed {
+                    records: total_processed,
+                });
+            }
+        }
+        if !total_processed.is_multiple_of(RECORDS_REPORT_INTERVAL) {
+            progress.emit(ProgressEvent::RecordsParsed {
+                records: total_processed,
+            });
         }
 
         let duration = start_time.elapsed();
@@ -160,6 +823,21 @@ mod transformer {
             );
         }
 
-        Ok(grouped_records)
+        if dedup_exact {
+            info!("Dropped {} exact duplicate records", duplicates);
+        }
+        skipped.log();
+        if let Some(truncation) = &mut truncated {
+            truncation.last_sort_key = last_sort_key;
+            warn!(
+                offset = truncation.offset;
+                "Input unreadable past byte {} ({}), kept the {} records before it",
+                truncation.offset,
+                truncation.message,
+                total_processed
+            );
+        }
+
+        Ok((skipped, truncated))
     }
 }
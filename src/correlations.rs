@@ -0,0 +1,105 @@
+use crate::error::{AppError, Result};
+use crate::report::GroupSummary;
+use std::collections::BTreeMap;
+
+/// Daily metrics correlated with each other: group and name, as in
+/// `daily_summary.csv`.
+const METRICS: [(&str, &str); 6] = [
+    ("HKQuantityTypeIdentifierStepCount", "steps"),
+    (
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+        "active_energy_kcal",
+    ),
+    (
+        "HKQuantityTypeIdentifierAppleExerciseTime",
+        "exercise_minutes",
+    ),
+    (
+        "HKQuantityTypeIdentifierRestingHeartRate",
+        "resting_heart_rate",
+    ),
+    ("HKCategoryTypeIdentifierSleepAnalysis", "sleep_minutes"),
+    ("HKQuantityTypeIdentifierBodyMass", "body_mass"),
+];
+
+/// Fewest days with values of both metrics for them to be correlated.
+const MIN_SHARED_DAYS: usize = 3;
+
+/// Pearson correlations between the daily metrics of an export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorrelationMatrix {
+    /// Names of the metrics with data, in the order of `daily_summary.csv`
+    /// followed by `body_mass`.
+    pub metrics: Vec<&'static str>,
+    /// Correlation of each pair of metrics over the days both have values,
+    /// or `None` when they share fewer than three days or either is
+    /// constant over them.
+    pub values: Vec<Vec<Option<f64>>>,
+}
+
+impl CorrelationMatrix {
+    /// Correlate the daily steps, active energy, exercise minutes, resting
+    /// heart rate, minutes asleep and weight found in `groups`, combined per
+    /// day as in `daily_summary.csv`. Sleep counts towards the day it ends,
+    /// so a night is paired with the resting heart rate and steps of the
+    /// next day.
+    pub fn new(groups: &[GroupSummary]) -> Self {
+        let mut metrics = Vec::new();
+        let mut days: Vec<BTreeMap<&str, f64>> = Vec::new();
+        for (group, name) in METRICS {
+            let Some(summary) = groups.iter().find(|g| g.name == group) else {
+                continue;
+            };
+            let values: BTreeMap<&str, f64> = summary.daily_values().into_iter().collect();
+            if !values.is_empty() {
+                metrics.push(name);
+                days.push(values);
+            }
+        }
+        let values = days
+            .iter()
+            .map(|a| days.iter().map(|b| pearson(a, b)).collect())
+            .collect();
+        Self { metrics, values }
+    }
+}
+
+fn pearson(a: &BTreeMap<&str, f64>, b: &BTreeMap<&str, f64>) -> Option<f64> {
+    let pairs: Vec<(f64, f64)> = a
+        .iter()
+        .filter_map(|(day, &x)| Some((x, *b.get(day)?)))
+        .collect();
+    if pairs.len() < MIN_SHARED_DAYS {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let (covariance, spread_x, spread_y) =
+        pairs.iter().fold((0.0, 0.0, 0.0), |(c, sx, sy), &(x, y)| {
+            let (dx, dy) = (x - mean_x, y - mean_y);
+            (c + dx * dy, sx + dx * dx, sy + dy * dy)
+        });
+    (spread_x > 0.0 && spread_y > 0.0).then(|| covariance / (spread_x * spread_y).sqrt())
+}
+
+/// `matrix` as CSV: a `metric` column naming each row, then one column per
+/// metric with the correlations rounded to three decimals, left empty where
+/// there are too few days to tell.
+pub fn correlations_csv(matrix: &CorrelationMatrix) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut header = vec!["metric"];
+    header.extend(&matrix.metrics);
+    w.write_record(&header)?;
+    for (metric, values) in matrix.metrics.iter().zip(&matrix.values) {
+        let mut row = vec![metric.to_string()];
+        // Adding zero turns a rounded `-0` into `0`
+        row.extend(values.iter().map(|value| {
+            value
+                .map(|r| ((r * 1000.0).round() / 1000.0 + 0.0).to_string())
+                .unwrap_or_default()
+        }));
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
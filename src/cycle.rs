@@ -0,0 +1,195 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+const CATEGORY_PREFIX: &str = "HKCategoryTypeIdentifier";
+
+const CATEGORY_VALUE_PREFIX: &str = "HKCategoryValue";
+
+/// Symptoms logged in cycle tracking, listed together in the `symptoms`
+/// column.
+const SYMPTOMS: [&str; 22] = [
+    "AbdominalCramps",
+    "Acne",
+    "AppetiteChanges",
+    "BladderIncontinence",
+    "Bloating",
+    "BreastPain",
+    "ChestTightnessOrPain",
+    "Constipation",
+    "Diarrhea",
+    "Dizziness",
+    "DrySkin",
+    "Fatigue",
+    "HairLoss",
+    "Headache",
+    "HotFlashes",
+    "LowerBackPain",
+    "MoodChanges",
+    "Nausea",
+    "NightSweats",
+    "PelvicPain",
+    "SleepChanges",
+    "VaginalDryness",
+];
+
+/// A column of `CycleTracking.csv` and the type it is filled from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CycleColumn {
+    MenstrualFlow,
+    IntermenstrualBleeding,
+    OvulationTest,
+    CervicalMucus,
+    BasalBodyTemperature,
+    SexualActivity,
+    PregnancyTest,
+    ProgesteroneTest,
+    /// Any of the [`SYMPTOMS`].
+    Symptoms,
+}
+
+impl CycleColumn {
+    const ALL: [CycleColumn; 9] = [
+        CycleColumn::MenstrualFlow,
+        CycleColumn::IntermenstrualBleeding,
+        CycleColumn::OvulationTest,
+        CycleColumn::CervicalMucus,
+        CycleColumn::BasalBodyTemperature,
+        CycleColumn::SexualActivity,
+        CycleColumn::PregnancyTest,
+        CycleColumn::ProgesteroneTest,
+        CycleColumn::Symptoms,
+    ];
+
+    fn of(group: &str) -> Option<Self> {
+        match group {
+            "HKCategoryTypeIdentifierMenstrualFlow" => Some(CycleColumn::MenstrualFlow),
+            "HKCategoryTypeIdentifierIntermenstrualBleeding" => {
+                Some(CycleColumn::IntermenstrualBleeding)
+            }
+            "HKCategoryTypeIdentifierOvulationTestResult" => Some(CycleColumn::OvulationTest),
+            "HKCategoryTypeIdentifierCervicalMucusQuality" => Some(CycleColumn::CervicalMucus),
+            "HKQuantityTypeIdentifierBasalBodyTemperature" => {
+                Some(CycleColumn::BasalBodyTemperature)
+            }
+            "HKCategoryTypeIdentifierSexualActivity" => Some(CycleColumn::SexualActivity),
+            "HKCategoryTypeIdentifierPregnancyTestResult" => Some(CycleColumn::PregnancyTest),
+            "HKCategoryTypeIdentifierProgesteroneTestResult" => Some(CycleColumn::ProgesteroneTest),
+            _ => group
+                .strip_prefix(CATEGORY_PREFIX)
+                .filter(|name| SYMPTOMS.contains(name))
+                .map(|_| CycleColumn::Symptoms),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CycleColumn::MenstrualFlow => "menstrual_flow",
+            CycleColumn::IntermenstrualBleeding => "intermenstrual_bleeding",
+            CycleColumn::OvulationTest => "ovulation_test",
+            CycleColumn::CervicalMucus => "cervical_mucus",
+            CycleColumn::BasalBodyTemperature => "basal_body_temperature",
+            CycleColumn::SexualActivity => "sexual_activity",
+            CycleColumn::PregnancyTest => "pregnancy_test",
+            CycleColumn::ProgesteroneTest => "progesterone_test",
+            CycleColumn::Symptoms => "symptoms",
+        }
+    }
+
+    /// What a record of `group` contributes to its day, such as `Medium`
+    /// for a `HKCategoryValueMenstrualFlowMedium` flow or `Headache (Mild)`
+    /// for a mild headache. Symptoms marked as not present are left out.
+    fn entry<T: Processable>(self, group: &str, record: &T) -> Option<String> {
+        let value = record.field("value")?;
+        if self == CycleColumn::BasalBodyTemperature {
+            return Some(match record.field("unit") {
+                Some(unit) => format!("{} {}", value, unit),
+                None => value.to_string(),
+            });
+        }
+        let value = value.strip_prefix(CATEGORY_VALUE_PREFIX).unwrap_or(value);
+        let type_name = group.strip_prefix(CATEGORY_PREFIX).unwrap_or(group);
+        let label = [type_name, "Severity", "Presence"]
+            .iter()
+            .find_map(|prefix| value.strip_prefix(prefix))
+            .filter(|label| !label.is_empty())
+            .unwrap_or(value);
+        match (self, label) {
+            (_, "NotPresent") => None,
+            (CycleColumn::Symptoms, "Unspecified" | "Present" | "NotApplicable") => {
+                Some(type_name.to_string())
+            }
+            (CycleColumn::Symptoms, severity) => Some(format!("{} ({})", type_name, severity)),
+            (_, "NotApplicable") => Some("true".to_string()),
+            (_, label) => Some(label.to_string()),
+        }
+    }
+}
+
+/// The cycle-tracking entries of one group per day, collected for
+/// [`cycle_tracking_csv`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleDays {
+    group: String,
+    column: CycleColumn,
+    /// Calendar dates as written in the export, to their distinct entries.
+    days: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl CycleDays {
+    /// Collector for `group`, or `None` if it is not a cycle-tracking type.
+    pub fn new(group: &str) -> Option<Self> {
+        Some(Self {
+            group: group.to_string(),
+            column: CycleColumn::of(group)?,
+            days: BTreeMap::new(),
+        })
+    }
+
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        let Some(date) = record.field("startDate") else {
+            return;
+        };
+        if parse_timestamp(date).is_none() {
+            return;
+        }
+        if let Some(entry) = self.column.entry(&self.group, record) {
+            self.days
+                .entry(date[..10].to_string())
+                .or_default()
+                .insert(entry);
+        }
+    }
+}
+
+/// One row per day with cycle-tracking data: menstrual flow, intermenstrual
+/// bleeding, ovulation, pregnancy and progesterone tests, cervical mucus,
+/// basal body temperature, sexual activity and the symptoms logged. Several
+/// entries on one day are separated by `; `, and days are the calendar
+/// dates as written in the export.
+pub fn cycle_tracking_csv<'a>(groups: impl IntoIterator<Item = &'a CycleDays>) -> Result<Vec<u8>> {
+    let mut days: BTreeMap<&str, [BTreeSet<&str>; CycleColumn::ALL.len()]> = BTreeMap::new();
+    for group in groups {
+        // `ALL` lists the columns in declaration order
+        let column = group.column as usize;
+        for (day, entries) in &group.days {
+            days.entry(day).or_default()[column].extend(entries.iter().map(String::as_str));
+        }
+    }
+
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut header = vec!["date"];
+    header.extend(CycleColumn::ALL.map(CycleColumn::name));
+    w.write_record(&header)?;
+    for (day, columns) in days {
+        let mut row = vec![day.to_string()];
+        row.extend(
+            columns
+                .iter()
+                .map(|entries| entries.iter().copied().collect::<Vec<_>>().join("; ")),
+        );
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
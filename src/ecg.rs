@@ -0,0 +1,137 @@
+use crate::error::{AppError, Result};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+/// Folder of an export, and of the archive, with one CSV of voltages per
+/// ECG recording.
+pub const ECG_DIR: &str = "electrocardiograms";
+
+/// Header lines of a recording copied into `ECGRecordings.csv`, with their
+/// column there. The name and date of birth are left out.
+const HEADER_COLUMNS: [(&str, &str); 6] = [
+    ("Recorded Date", "date"),
+    ("Classification", "classification"),
+    ("Symptoms", "symptoms"),
+    ("Average Heart Rate", "average_heart_rate"),
+    ("Software Version", "software_version"),
+    ("Device", "device"),
+];
+
+/// One ECG recording as exported by the Health app.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    /// Path of the recording in the archive, under [`ECG_DIR`].
+    pub file_name: String,
+    pub data: Vec<u8>,
+}
+
+impl Recording {
+    /// The `key,value` lines before the voltages, such as `Classification`,
+    /// and the number of voltage samples.
+    fn header(&self) -> (BTreeMap<String, String>, usize) {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(&self.data[..]);
+        let (mut header, mut samples) = (BTreeMap::new(), 0);
+        for record in reader.records().flatten() {
+            match (record.get(0), record.get(1)) {
+                (Some(value), None) if value.trim().parse::<f64>().is_ok() => samples += 1,
+                (Some(key), Some(value)) => {
+                    header.insert(key.trim().to_string(), value.trim().to_string());
+                }
+                _ => {}
+            }
+        }
+        (header, samples)
+    }
+}
+
+/// Every ECG recording of the export at `input`: the CSVs in the
+/// `electrocardiograms` folder of a ZIP, or in the one next to a plain
+/// `export.xml`. Recordings are ordered by file name.
+pub fn read_recordings(input: &Path) -> Result<Vec<Recording>> {
+    let mut recordings = Vec::new();
+    #[cfg(feature = "native")]
+    if crate::remote::is_url(input) {
+        warn!("ECG recordings are not read from downloaded exports");
+        return Ok(recordings);
+    }
+    if input.extension().and_then(|s| s.to_str()) == Some("zip") {
+        let mut archive = match zip::ZipArchive::new(File::open(input)?) {
+            Ok(archive) => archive,
+            Err(e) => {
+                warn!(
+                    "Cannot list the ECG recordings of {}: {}",
+                    input.display(),
+                    e
+                );
+                return Ok(recordings);
+            }
+        };
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let Some(name) = recording_name(entry.name()) else {
+                continue;
+            };
+            let file_name = format!("{}/{}", ECG_DIR, name);
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut data)?;
+            recordings.push(Recording { file_name, data });
+        }
+    } else if let Some(dir) = input.parent().map(|parent| parent.join(ECG_DIR))
+        && dir.is_dir()
+    {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if name.ends_with(".csv") {
+                recordings.push(Recording {
+                    file_name: format!("{}/{}", ECG_DIR, name),
+                    data: fs::read(&path)?,
+                });
+            }
+        }
+    }
+    recordings.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(recordings)
+}
+
+/// File name of a ZIP entry that is an ECG recording.
+fn recording_name(entry_name: &str) -> Option<&str> {
+    let (dir, name) = entry_name.rsplit_once('/')?;
+    let folder = dir.rsplit('/').next()?;
+    (folder == ECG_DIR && name.ends_with(".csv")).then_some(name)
+}
+
+/// One row per recording: its date, classification, symptoms, average
+/// heart rate (when the recording has one), software version, device,
+/// sample rate in Hz, number of voltage samples and the path of its CSV in
+/// the archive.
+pub fn ecg_recordings_csv(recordings: &[Recording]) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut columns: Vec<&str> = HEADER_COLUMNS.iter().map(|(_, column)| *column).collect();
+    columns.extend(["sample_rate_hz", "samples", "file"]);
+    w.write_record(&columns)?;
+    for recording in recordings {
+        let (header, samples) = recording.header();
+        let mut row: Vec<&str> = HEADER_COLUMNS
+            .iter()
+            .map(|(key, _)| header.get(*key).map_or("", String::as_str))
+            .collect();
+        // `512 hertz`
+        let sample_rate = header
+            .get("Sample Rate")
+            .and_then(|rate| rate.split_whitespace().next())
+            .unwrap_or_default();
+        let samples = samples.to_string();
+        row.extend([sample_rate, &samples, &recording.file_name]);
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
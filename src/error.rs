@@ -1,9 +1,25 @@
+use log::warn;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
-    #[error("Parse error: {0}")]
-    ParseError(String),
+    /// The XML itself is malformed; parsing cannot continue past `offset`.
+    #[error("XML error at byte {offset}: {message}")]
+    Xml { offset: u64, message: String },
+
+    /// A well-formed element whose contents could not be decoded.
+    #[error("Invalid element {0}")]
+    InvalidElement(ElementError),
+
+    /// A single malformed element that was skipped; not fatal to the run.
+    #[error("Skipped record {0}")]
+    SkippedRecord(ElementError),
+
+    #[error("Could not find {0} in the zip archive")]
+    MissingEntry(String),
 
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
@@ -11,9 +27,31 @@ pub enum AppError {
     #[error("CSV error: {0}")]
     CsvError(#[from] csv::Error),
 
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
     #[error("ZIP error: {0}")]
     ZipArchiveError(#[from] zip::result::ZipError),
 
+    /// A written archive does not hold the records that were written to it.
+    #[error("Output archive does not match the records written: {0}")]
+    OutputMismatch(String),
+
+    #[error("Chart error: {0}")]
+    Chart(String),
+
+    /// A completion webhook could not be reached or rejected the request.
+    #[error("Notification error: {0}")]
+    Notification(String),
+
+    /// An input URL could not be downloaded.
+    #[error("Download error: {0}")]
+    Download(String),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[cfg(feature = "native")]
     #[error("Thread pool build error: {0}")]
     ThreadPoolError(#[from] rayon::ThreadPoolBuildError),
 
@@ -21,6 +59,35 @@ pub enum AppError {
     Unknown(String),
 }
 
+impl AppError {
+    /// The class of failure this error is reported as when it ends the
+    /// command line tool.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            AppError::MissingEntry(_) => ExitCode::InputNotFound,
+            AppError::Xml { .. }
+            | AppError::InvalidElement(_)
+            | AppError::SkippedRecord(_)
+            | AppError::CsvError(_)
+            | AppError::JsonError(_)
+            | AppError::ZipArchiveError(_) => ExitCode::ParseFailure,
+            AppError::IoError(_) | AppError::Download(_) => ExitCode::Io,
+            AppError::OutputMismatch(_) => ExitCode::ValidationMismatch,
+            AppError::InvalidConfig(_) => ExitCode::Usage,
+            _ => ExitCode::Failure,
+        }
+    }
+
+    /// Attach the byte offset of the offending element, if the error
+    /// concerns one and does not carry an offset yet.
+    pub fn at_offset(mut self, offset: u64) -> Self {
+        if let AppError::InvalidElement(e) | AppError::SkippedRecord(e) = &mut self {
+            e.offset.get_or_insert(offset);
+        }
+        self
+    }
+}
+
 impl From<Box<dyn std::error::Error>> for AppError {
     fn from(err: Box<dyn std::error::Error>) -> Self {
         AppError::Unknown(err.to_string())
@@ -28,3 +95,173 @@ impl From<Box<dyn std::error::Error>> for AppError {
 }
 
 pub type Result<T> = std::result::Result<T, AppError>;
+
+/// Exit status of the command line tool for each class of failure, so shell
+/// pipelines can branch on why a run failed. Listed by `--help`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// A failure of no more specific class.
+    Failure = 1,
+    /// Invalid arguments or configuration, the code clap uses for usage
+    /// errors.
+    Usage = 2,
+    /// An input file, or `export.xml` inside an export archive, is missing.
+    InputNotFound = 3,
+    /// An input could not be parsed.
+    ParseFailure = 4,
+    /// Reading or writing a file failed.
+    Io = 5,
+    /// `validate` found the archive does not match the export.
+    ValidationMismatch = 6,
+    /// Interrupted by Ctrl-C, reported as `128 + SIGINT` like shells do.
+    Cancelled = 130,
+}
+
+impl ExitCode {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Where and why a single element could not be decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ElementError {
+    /// Byte offset of the element in the uncompressed XML, when known.
+    pub offset: Option<u64>,
+    pub element: Option<String>,
+    pub attribute: Option<String>,
+    pub reason: String,
+    /// Start of the raw element, at most [`SNIPPET_LEN`] bytes of it.
+    pub snippet: Option<String>,
+}
+
+/// Longest raw element excerpt kept in an [`ElementError`].
+pub const SNIPPET_LEN: usize = 256;
+
+impl ElementError {
+    pub fn new(reason: impl fmt::Display) -> Self {
+        Self {
+            reason: reason.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn element(mut self, element: impl Into<String>) -> Self {
+        self.element = Some(element.into());
+        self
+    }
+
+    pub fn attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.attribute = Some(attribute.into());
+        self
+    }
+
+    /// Keep the start of the raw tag, `<` included, for diagnosis.
+    pub fn snippet(mut self, tag: &[u8]) -> Self {
+        let tag = &tag[..tag.len().min(SNIPPET_LEN - 1)];
+        self.snippet = Some(format!("<{}", String::from_utf8_lossy(tag)));
+        self
+    }
+
+    /// Key used to group similar errors in a [`SkipSummary`], such as
+    /// `Record/@value`.
+    pub fn category(&self) -> String {
+        let element = self.element.as_deref().unwrap_or("?");
+        match &self.attribute {
+            Some(attribute) => format!("{}/@{}", element, attribute),
+            None => element.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ElementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.element {
+            Some(element) => write!(f, "<{}>", element)?,
+            None => write!(f, "element")?,
+        }
+        if let Some(attribute) = &self.attribute {
+            write!(f, " attribute '{}'", attribute)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+/// Skipped records of one [`ElementError::category`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkipCategory {
+    pub count: usize,
+    /// The first error seen in this category.
+    pub first: ElementError,
+}
+
+/// Where a damaged input stopped being readable, recorded instead of
+/// failing when a run recovers from XML errors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Truncation {
+    /// Byte offset of the error in the uncompressed XML.
+    pub offset: u64,
+    pub message: String,
+    /// Latest sort key among the records read before the error; a Unix
+    /// timestamp for Apple Health records, approximating where data ends.
+    pub last_sort_key: Option<i64>,
+}
+
+/// Tally of skipped records by category, reported at the end of a run,
+/// along with every individual error in the order they were seen.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SkipSummary {
+    categories: BTreeMap<String, SkipCategory>,
+    errors: Vec<ElementError>,
+}
+
+impl SkipSummary {
+    pub fn record(&mut self, error: ElementError) {
+        self.categories
+            .entry(error.category())
+            .and_modify(|c| c.count += 1)
+            .or_insert_with(|| SkipCategory {
+                count: 1,
+                first: error.clone(),
+            });
+        self.errors.push(error);
+    }
+
+    pub fn total(&self) -> usize {
+        self.categories.values().map(|c| c.count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.categories.is_empty()
+    }
+
+    /// Every skipped element, in the order they were recorded.
+    pub fn errors(&self) -> &[ElementError] {
+        &self.errors
+    }
+
+    pub fn categories(&self) -> impl Iterator<Item = (&str, &SkipCategory)> {
+        self.categories.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// Log one warning per category, with the first occurrence as an example.
+    pub fn log(&self) {
+        if self.is_empty() {
+            return;
+        }
+        warn!(skipped = self.total(); "Skipped {} malformed records", self.total());
+        for (category, skipped) in self.categories() {
+            warn!(
+                category = category,
+                count = skipped.count;
+                "  {} x {} (first: {})",
+                skipped.count,
+                category,
+                skipped.first
+            );
+        }
+    }
+}
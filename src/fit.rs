@@ -0,0 +1,388 @@
+use crate::apple_health::dates::format_timestamp;
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::error::{AppError, ElementError, Result};
+use crate::intern::intern_key;
+use log::{debug, warn};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Group of the sessions, one per activity.
+pub const SESSION_ELEMENT: &str = "FitSession";
+
+/// Group of the laps of each session.
+pub const LAP_ELEMENT: &str = "FitLap";
+
+/// Group of the samples recorded during each activity.
+pub const RECORD_ELEMENT: &str = "FitRecord";
+
+/// Attribute naming the FIT file a record was decoded from, which ties the
+/// laps and samples to their session.
+pub const FIT_FILE_ATTRIBUTE: &str = "fitFile";
+
+/// Seconds from the Unix epoch to the FIT epoch, 1989-12-31 00:00:00 UTC.
+const FIT_EPOCH: i64 = 631_065_600;
+
+const SESSION: u16 = 18;
+const LAP: u16 = 19;
+const RECORD: u16 = 20;
+
+/// Field holding the timestamp of every message.
+const TIMESTAMP_FIELD: u8 = 253;
+
+/// Field holding the start of sessions and laps.
+const START_TIME_FIELD: u8 = 2;
+
+/// How a field is turned into an attribute.
+#[derive(Clone, Copy)]
+enum Unit {
+    /// Divided by a scale after subtracting an offset.
+    Scaled(f64, f64),
+    /// Semicircles converted to degrees.
+    Degrees,
+    /// A `sport` enum value.
+    Sport,
+}
+
+use Unit::{Degrees, Scaled, Sport};
+
+const PLAIN: Unit = Scaled(1.0, 0.0);
+
+/// Attributes of the decoded fields of `record` messages.
+const RECORD_FIELDS: [(u8, &str, Unit); 11] = [
+    (0, "latitude", Degrees),
+    (1, "longitude", Degrees),
+    (2, "altitude", Scaled(5.0, 500.0)),
+    (3, "heartRate", PLAIN),
+    (4, "cadence", PLAIN),
+    (5, "distance", Scaled(100.0, 0.0)),
+    (6, "speed", Scaled(1000.0, 0.0)),
+    (7, "power", PLAIN),
+    (13, "temperature", PLAIN),
+    (73, "speed", Scaled(1000.0, 0.0)),
+    (78, "altitude", Scaled(5.0, 500.0)),
+];
+
+/// Attributes of the decoded fields of `lap` messages.
+const LAP_FIELDS: [(u8, &str, Unit); 15] = [
+    (7, "totalElapsedTime", Scaled(1000.0, 0.0)),
+    (8, "totalTimerTime", Scaled(1000.0, 0.0)),
+    (9, "totalDistance", Scaled(100.0, 0.0)),
+    (11, "totalCalories", PLAIN),
+    (13, "avgSpeed", Scaled(1000.0, 0.0)),
+    (14, "maxSpeed", Scaled(1000.0, 0.0)),
+    (15, "avgHeartRate", PLAIN),
+    (16, "maxHeartRate", PLAIN),
+    (17, "avgCadence", PLAIN),
+    (18, "maxCadence", PLAIN),
+    (19, "avgPower", PLAIN),
+    (20, "maxPower", PLAIN),
+    (21, "totalAscent", PLAIN),
+    (22, "totalDescent", PLAIN),
+    (25, "sport", Sport),
+];
+
+/// Attributes of the decoded fields of `session` messages.
+const SESSION_FIELDS: [(u8, &str, Unit); 16] = [
+    (5, "sport", Sport),
+    (7, "totalElapsedTime", Scaled(1000.0, 0.0)),
+    (8, "totalTimerTime", Scaled(1000.0, 0.0)),
+    (9, "totalDistance", Scaled(100.0, 0.0)),
+    (11, "totalCalories", PLAIN),
+    (14, "avgSpeed", Scaled(1000.0, 0.0)),
+    (15, "maxSpeed", Scaled(1000.0, 0.0)),
+    (16, "avgHeartRate", PLAIN),
+    (17, "maxHeartRate", PLAIN),
+    (18, "avgCadence", PLAIN),
+    (19, "maxCadence", PLAIN),
+    (20, "avgPower", PLAIN),
+    (21, "maxPower", PLAIN),
+    (22, "totalAscent", PLAIN),
+    (23, "totalDescent", PLAIN),
+    (26, "numLaps", PLAIN),
+];
+
+/// Names of the `sport` enum values, by value.
+const SPORTS: [&str; 20] = [
+    "generic",
+    "running",
+    "cycling",
+    "transition",
+    "fitness_equipment",
+    "swimming",
+    "basketball",
+    "soccer",
+    "tennis",
+    "american_football",
+    "training",
+    "walking",
+    "cross_country_skiing",
+    "alpine_skiing",
+    "snowboarding",
+    "rowing",
+    "mountaineering",
+    "hiking",
+    "multisport",
+    "paddling",
+];
+
+/// The `.fit` files directly in `dir`, sorted by name.
+pub fn fit_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_fit = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("fit"));
+        if is_fit && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The contents of every FIT file in `dir` one after the other, to hash
+/// the folder as a whole.
+pub fn folder_reader(dir: &Path) -> Result<Box<dyn Read + Send>> {
+    let mut reader: Box<dyn Read + Send> = Box::new(std::io::empty());
+    for path in fit_files(dir)? {
+        reader = Box::new(reader.chain(File::open(path)?));
+    }
+    Ok(reader)
+}
+
+/// Sessions, laps and samples of every FIT file in `dir`, such as a folder
+/// exported by HealthFit. Files that are not valid FIT files are skipped
+/// with a warning.
+pub fn read_folder(dir: &Path) -> Result<Vec<GenericRecord>> {
+    let mut records = Vec::new();
+    for path in fit_files(dir)? {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        let decoded = fs::read(&path)
+            .map_err(AppError::from)
+            .and_then(|bytes| decode(&bytes, name.as_deref().unwrap_or_default()));
+        match decoded {
+            Ok(decoded) => {
+                debug!("Decoded {} records of {}", decoded.len(), path.display());
+                records.extend(decoded);
+            }
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    Ok(records)
+}
+
+/// A message definition, as announced for one local message type.
+struct Definition {
+    global: u16,
+    big_endian: bool,
+    /// Number, size and base type of each field.
+    fields: Vec<(u8, u8, u8)>,
+    /// Bytes of developer fields, which are skipped.
+    developer_bytes: usize,
+}
+
+/// The session, lap and record messages of the FIT file `bytes`, named
+/// `file_name`.
+pub fn decode(bytes: &[u8], file_name: &str) -> Result<Vec<GenericRecord>> {
+    let header_size = *bytes.first().ok_or_else(|| invalid("empty file"))? as usize;
+    if header_size < 12 || bytes.len() < header_size || &bytes[8..12] != b".FIT" {
+        return Err(invalid("missing FIT header"));
+    }
+    let data_size = u32::from_le_bytes(bytes[4..8].try_into().expect("4 bytes")) as usize;
+    let end = (header_size + data_size).min(bytes.len());
+    let mut cursor = Cursor {
+        bytes: &bytes[..end],
+        pos: header_size,
+    };
+
+    let mut definitions: [Option<Definition>; 16] = Default::default();
+    let mut last_timestamp = 0u32;
+    let mut records = Vec::new();
+    while cursor.pos < end {
+        let header = cursor.take(1)?[0];
+        let (local, compressed_offset) = if header & 0x80 != 0 {
+            // Compressed timestamp header: a data message whose time is
+            // given as an offset from the last timestamp
+            ((header >> 5) & 0x03, Some(u32::from(header & 0x1f)))
+        } else if header & 0x40 != 0 {
+            definitions[usize::from(header & 0x0f)] =
+                Some(read_definition(&mut cursor, header & 0x20 != 0)?);
+            continue;
+        } else {
+            (header & 0x0f, None)
+        };
+        let Some(definition) = &definitions[usize::from(local)] else {
+            return Err(invalid("data message without a definition"));
+        };
+
+        let mut values: Vec<(u8, f64)> = Vec::new();
+        for &(number, size, base_type) in &definition.fields {
+            let field = cursor.take(usize::from(size))?;
+            if let Some(value) = read_value(field, base_type, definition.big_endian) {
+                values.push((number, value));
+            }
+        }
+        cursor.take(definition.developer_bytes)?;
+
+        let timestamp = match (compressed_offset, field(&values, TIMESTAMP_FIELD)) {
+            (_, Some(timestamp)) => Some(timestamp as u32),
+            (Some(offset), None) => {
+                let rollover = offset.wrapping_sub(last_timestamp & 0x1f) & 0x1f;
+                Some(last_timestamp.wrapping_add(rollover))
+            }
+            (None, None) => None,
+        };
+        if let Some(timestamp) = timestamp {
+            last_timestamp = timestamp;
+        }
+
+        let (element, fields): (&str, &[(u8, &str, Unit)]) = match definition.global {
+            SESSION => (SESSION_ELEMENT, &SESSION_FIELDS),
+            LAP => (LAP_ELEMENT, &LAP_FIELDS),
+            RECORD => (RECORD_ELEMENT, &RECORD_FIELDS),
+            _ => continue,
+        };
+        let mut attributes = Attributes::new();
+        insert(&mut attributes, FIT_FILE_ATTRIBUTE, file_name);
+        let date = |seconds: f64| format_timestamp(FIT_EPOCH + seconds as i64);
+        let start = field(&values, START_TIME_FIELD).filter(|_| definition.global != RECORD);
+        match (start, timestamp) {
+            (Some(start), Some(end)) => {
+                insert(&mut attributes, "startDate", &date(start));
+                insert(&mut attributes, "endDate", &date(f64::from(end)));
+            }
+            (None, Some(at)) => {
+                insert(&mut attributes, "startDate", &date(f64::from(at)));
+                insert(&mut attributes, "endDate", &date(f64::from(at)));
+            }
+            (Some(start), None) => insert(&mut attributes, "startDate", &date(start)),
+            (None, None) => {}
+        }
+        for &(number, name, unit) in fields {
+            let Some(value) = field(&values, number) else {
+                continue;
+            };
+            let value = match unit {
+                Scaled(scale, offset) => format_number(value / scale - offset),
+                Degrees => format_number(value * 180.0 / 2f64.powi(31)),
+                Sport => SPORTS
+                    .get(value as usize)
+                    .map_or_else(|| format_number(value), |sport| sport.to_string()),
+            };
+            insert(&mut attributes, name, &value);
+        }
+        records.push(GenericRecord {
+            element_name: element.to_string(),
+            attributes,
+        });
+    }
+    Ok(records)
+}
+
+fn read_definition(cursor: &mut Cursor<'_>, developer: bool) -> Result<Definition> {
+    let header = cursor.take(5)?;
+    let big_endian = header[1] == 1;
+    let global = if big_endian {
+        u16::from_be_bytes([header[2], header[3]])
+    } else {
+        u16::from_le_bytes([header[2], header[3]])
+    };
+    let fields = cursor
+        .take(usize::from(header[4]) * 3)?
+        .chunks_exact(3)
+        .map(|field| (field[0], field[1], field[2]))
+        .collect();
+    let mut developer_bytes = 0;
+    if developer {
+        let count = cursor.take(1)?[0];
+        for field in cursor.take(usize::from(count) * 3)?.chunks_exact(3) {
+            developer_bytes += usize::from(field[1]);
+        }
+    }
+    Ok(Definition {
+        global,
+        big_endian,
+        fields,
+        developer_bytes,
+    })
+}
+
+/// The numeric value of `bytes`, a field of `base_type`, or `None` for the
+/// type's invalid value, arrays, strings and unknown types.
+fn read_value(bytes: &[u8], base_type: u8, big_endian: bool) -> Option<f64> {
+    macro_rules! int {
+        ($t:ty, $invalid:expr) => {{
+            let bytes = bytes.try_into().ok()?;
+            let value = if big_endian {
+                <$t>::from_be_bytes(bytes)
+            } else {
+                <$t>::from_le_bytes(bytes)
+            };
+            (value != $invalid).then_some(value as f64)
+        }};
+    }
+    match base_type & 0x1f {
+        0 | 2 => int!(u8, u8::MAX),
+        1 => int!(i8, i8::MAX),
+        3 => int!(i16, i16::MAX),
+        4 => int!(u16, u16::MAX),
+        5 => int!(i32, i32::MAX),
+        6 => int!(u32, u32::MAX),
+        10 => int!(u8, 0),
+        11 => int!(u16, 0),
+        12 => int!(u32, 0),
+        14 => int!(i64, i64::MAX),
+        15 => int!(u64, u64::MAX),
+        16 => int!(u64, 0),
+        8 => {
+            let value = int!(u32, u32::MAX)? as u32;
+            Some(f64::from(f32::from_bits(value)))
+        }
+        9 => {
+            let value = int!(u64, u64::MAX)? as u64;
+            Some(f64::from_bits(value))
+        }
+        _ => None,
+    }
+}
+
+fn field(values: &[(u8, f64)], number: u8) -> Option<f64> {
+    values.iter().find(|(n, _)| *n == number).map(|(_, v)| *v)
+}
+
+/// `value` without trailing zeros, rounded to what the FIT scales resolve.
+fn format_number(value: f64) -> String {
+    let formatted = format!("{:.7}", value);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+fn insert(attributes: &mut Attributes, key: &str, value: &str) {
+    attributes.insert(intern_key(key), attribute_value(key, value));
+}
+
+fn invalid(reason: &str) -> AppError {
+    AppError::InvalidElement(ElementError::new(format!("in FIT file: {}", reason)))
+}
+
+/// Reads the data records of a FIT file.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("truncated record"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
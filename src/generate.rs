@@ -0,0 +1,304 @@
+use crate::error::{AppError, Result};
+use std::io::Write;
+
+/// Shape of a synthetic export produced by [`generate`].
+#[derive(Debug, Clone, Copy)]
+pub struct GenerateOptions {
+    /// Number of `<Record>`, `<Workout>` and `<ActivitySummary>` elements.
+    pub records: usize,
+    /// Number of distinct record types.
+    pub types: usize,
+    /// Seed for the generator; the same options always produce the same file.
+    pub seed: u64,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        Self {
+            records: 1_000_000,
+            types: 80,
+            seed: 0,
+        }
+    }
+}
+
+/// Exports span the ten years before this date (2025-06-28T00:00:00Z).
+const EXPORT_EPOCH: i64 = 1_751_068_800;
+const SPAN_SECONDS: i64 = 10 * 365 * 24 * 60 * 60;
+
+/// One in this many elements is a `<Workout>`, and one in
+/// `SUMMARY_EVERY` an `<ActivitySummary>`.
+const WORKOUT_EVERY: u64 = 200;
+const SUMMARY_EVERY: u64 = 100;
+
+const QUANTITY_PREFIX: &str = "HKQuantityTypeIdentifier";
+
+/// Common quantity types with their unit, value range and decimals, in
+/// roughly the order of how often they appear in real exports.
+const QUANTITY_TYPES: &[(&str, &str, f64, f64, usize)] = &[
+    ("StepCount", "count", 10.0, 2000.0, 0),
+    ("ActiveEnergyBurned", "kcal", 0.1, 50.0, 3),
+    ("BasalEnergyBurned", "kcal", 0.5, 80.0, 3),
+    ("HeartRate", "count/min", 45.0, 180.0, 0),
+    ("DistanceWalkingRunning", "km", 0.01, 2.0, 4),
+    ("AppleStandTime", "min", 1.0, 10.0, 0),
+    ("AppleExerciseTime", "min", 1.0, 30.0, 0),
+    ("EnvironmentalAudioExposure", "dBASPL", 30.0, 90.0, 1),
+    ("WalkingSpeed", "km/hr", 2.0, 6.0, 2),
+    ("WalkingStepLength", "cm", 50.0, 90.0, 0),
+    ("WalkingDoubleSupportPercentage", "%", 0.2, 0.4, 3),
+    ("FlightsClimbed", "count", 1.0, 10.0, 0),
+    ("HeadphoneAudioExposure", "dBASPL", 40.0, 90.0, 1),
+    ("RespiratoryRate", "count/min", 12.0, 20.0, 1),
+    ("HeartRateVariabilitySDNN", "ms", 10.0, 120.0, 1),
+    ("OxygenSaturation", "%", 0.9, 1.0, 2),
+    ("WalkingHeartRateAverage", "count/min", 70.0, 120.0, 0),
+    ("RestingHeartRate", "count/min", 45.0, 80.0, 0),
+    ("DietaryWater", "mL", 100.0, 750.0, 0),
+    ("BloodPressureSystolic", "mmHg", 100.0, 140.0, 0),
+    ("BloodPressureDiastolic", "mmHg", 60.0, 90.0, 0),
+    ("BodyMass", "kg", 50.0, 110.0, 1),
+    ("Height", "cm", 150.0, 200.0, 0),
+];
+
+/// Sources with their version, device (already XML-escaped) and weight.
+const SOURCES: &[(&str, &str, Option<&str>, f64)] = &[
+    (
+        "Apple Watch",
+        "11.5",
+        Some(
+            "&lt;&lt;HKDevice: 0x3005f8c30&gt;, name:Apple Watch, manufacturer:Apple Inc., model:Watch, hardware:Watch7,2, software:11.5&gt;",
+        ),
+        0.6,
+    ),
+    (
+        "iPhone",
+        "18.5",
+        Some(
+            "&lt;&lt;HKDevice: 0x3005f8d20&gt;, name:iPhone, manufacturer:Apple Inc., model:iPhone, hardware:iPhone16,1, software:18.5&gt;",
+        ),
+        0.3,
+    ),
+    ("Health", "18.5", None, 0.05),
+    ("Withings", "6.2.1", None, 0.05),
+];
+
+const WORKOUT_TYPES: &[&str] = &[
+    "HKWorkoutActivityTypeWalking",
+    "HKWorkoutActivityTypeRunning",
+    "HKWorkoutActivityTypeCycling",
+    "HKWorkoutActivityTypeSwimming",
+    "HKWorkoutActivityTypeYoga",
+    "HKWorkoutActivityTypeTraditionalStrengthTraining",
+];
+
+/// Write a synthetic but realistic `export.xml` to `writer`.
+///
+/// Record types follow a Zipf-like popularity, sources are weighted towards
+/// the watch and phone, and dates spread over ten years in local time with
+/// daylight-saving offsets, like a real export.
+pub fn generate<W: Write>(mut writer: W, options: &GenerateOptions) -> Result<()> {
+    if options.types == 0 {
+        return Err(AppError::InvalidConfig(
+            "at least one record type is required".to_string(),
+        ));
+    }
+    let mut rng = SplitMix64(options.seed);
+    let types: Vec<RecordType> = (0..options.types).map(RecordType::nth).collect();
+    let type_weights = cumulative((1..=options.types).map(|rank| 1.0 / rank as f64));
+    let source_weights = cumulative(SOURCES.iter().map(|source| source.3));
+
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<HealthData locale="en_US">"#)?;
+    writeln!(
+        writer,
+        r#" <ExportDate value="{}"/>"#,
+        local_time(EXPORT_EPOCH)
+    )?;
+    writeln!(
+        writer,
+        r#" <Me HKCharacteristicTypeIdentifierDateOfBirth="1990-05-04" HKCharacteristicTypeIdentifierBiologicalSex="HKBiologicalSexFemale" HKCharacteristicTypeIdentifierBloodType="HKBloodTypeNotSet" HKCharacteristicTypeIdentifierFitzpatrickSkinType="HKFitzpatrickSkinTypeNotSet" HKCharacteristicTypeIdentifierCardioFitnessMedicationsUse="None"/>"#
+    )?;
+
+    for _ in 0..options.records {
+        let start = EXPORT_EPOCH - SPAN_SECONDS + rng.below(SPAN_SECONDS as u64) as i64;
+        let roll = rng.below(WORKOUT_EVERY * SUMMARY_EVERY);
+        if roll.is_multiple_of(WORKOUT_EVERY) {
+            write_workout(&mut writer, &mut rng, start)?;
+        } else if roll % SUMMARY_EVERY == 1 {
+            write_summary(&mut writer, &mut rng, start)?;
+        } else {
+            let record_type = &types[pick(&type_weights, &mut rng)];
+            let (source, version, device, _) = SOURCES[pick(&source_weights, &mut rng)];
+            let end = start + rng.below(600) as i64;
+            let created = end + rng.below(3600) as i64;
+            write!(
+                writer,
+                r#" <Record type="{}" sourceName="{}" sourceVersion="{}""#,
+                record_type.name, source, version
+            )?;
+            if let Some(device) = device {
+                write!(writer, r#" device="{}""#, device)?;
+            }
+            writeln!(
+                writer,
+                r#" unit="{}" creationDate="{}" startDate="{}" endDate="{}" value="{:.*}"/>"#,
+                record_type.unit,
+                local_time(created),
+                local_time(start),
+                local_time(end),
+                record_type.decimals,
+                rng.between(record_type.min, record_type.max)
+            )?;
+        }
+    }
+
+    writeln!(writer, "</HealthData>")?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_workout<W: Write>(writer: &mut W, rng: &mut SplitMix64, start: i64) -> Result<()> {
+    let activity = WORKOUT_TYPES[rng.below(WORKOUT_TYPES.len() as u64) as usize];
+    let (source, version, device, _) = SOURCES[0];
+    let minutes = rng.between(10.0, 90.0);
+    let end = start + (minutes * 60.0) as i64;
+    writeln!(
+        writer,
+        r#" <Workout workoutActivityType="{}" duration="{:.4}" durationUnit="min" totalDistance="{:.4}" totalDistanceUnit="km" totalEnergyBurned="{:.3}" totalEnergyBurnedUnit="kcal" sourceName="{}" sourceVersion="{}" device="{}" creationDate="{}" startDate="{}" endDate="{}"/>"#,
+        activity,
+        minutes,
+        minutes * rng.between(0.05, 0.2),
+        minutes * rng.between(4.0, 12.0),
+        source,
+        version,
+        device.unwrap_or_default(),
+        local_time(end),
+        local_time(start),
+        local_time(end)
+    )?;
+    Ok(())
+}
+
+fn write_summary<W: Write>(writer: &mut W, rng: &mut SplitMix64, start: i64) -> Result<()> {
+    let day = &local_time(start)[..10];
+    writeln!(
+        writer,
+        r#" <ActivitySummary dateComponents="{}" activeEnergyBurned="{:.3}" activeEnergyBurnedGoal="500" activeEnergyBurnedUnit="kcal" appleMoveTime="0" appleMoveTimeGoal="0" appleExerciseTime="{}" appleExerciseTimeGoal="30" appleStandHours="{}" appleStandHoursGoal="12"/>"#,
+        day,
+        rng.between(100.0, 900.0),
+        rng.below(90),
+        rng.below(17)
+    )?;
+    Ok(())
+}
+
+struct RecordType {
+    name: String,
+    unit: &'static str,
+    min: f64,
+    max: f64,
+    decimals: usize,
+}
+
+impl RecordType {
+    /// The `n`th most common type; past the known ones, synthetic types
+    /// with generic values are made up.
+    fn nth(n: usize) -> Self {
+        match QUANTITY_TYPES.get(n) {
+            Some(&(name, unit, min, max, decimals)) => Self {
+                name: format!("{}{}", QUANTITY_PREFIX, name),
+                unit,
+                min,
+                max,
+                decimals,
+            },
+            None => Self {
+                name: format!("{}Synthetic{}", QUANTITY_PREFIX, n),
+                unit: "count",
+                min: 0.0,
+                max: 100.0,
+                decimals: 1,
+            },
+        }
+    }
+}
+
+fn cumulative(weights: impl Iterator<Item = f64>) -> Vec<f64> {
+    weights
+        .scan(0.0, |total, weight| {
+            *total += weight;
+            Some(*total)
+        })
+        .collect()
+}
+
+/// Index drawn according to cumulative `weights`.
+fn pick(weights: &[f64], rng: &mut SplitMix64) -> usize {
+    let target = rng.unit() * weights.last().copied().unwrap_or_default();
+    weights
+        .partition_point(|&w| w <= target)
+        .min(weights.len() - 1)
+}
+
+/// Format a Unix timestamp as Apple Health does, in US Pacific time:
+/// `2023-01-01 08:00:00 -0800`. Daylight saving is approximated as April
+/// through October.
+fn local_time(timestamp: i64) -> String {
+    let (_, month, _) = civil_from_days(timestamp.div_euclid(86_400));
+    let offset_hours = if (4..=10).contains(&month) { -7 } else { -8 };
+    let local = timestamp + offset_hours * 3600;
+    let (year, month, day) = civil_from_days(local.div_euclid(86_400));
+    let seconds = local.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {:+03}00",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        offset_hours
+    )
+}
+
+/// Convert days since 1970-01-01 into a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Small, fast and reproducible generator; statistical quality is
+/// irrelevant here, but output must not change between releases.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn between(&mut self, min: f64, max: f64) -> f64 {
+        min + self.unit() * (max - min)
+    }
+}
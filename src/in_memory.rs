@@ -0,0 +1,85 @@
+use crate::apple_health::export_info::ExportInfo;
+use crate::apple_health::types::GenericRecord;
+use crate::core::{LoadContext, Processable};
+use crate::error::{AppError, Result, SkipSummary};
+use crate::manifest::{InputFile, Manifest, to_hex};
+use crate::progress::Progress;
+use crate::sinks::csv_zip::{Compression, CsvZipSink};
+use crate::xml_utils::{self, Nesting};
+use ahash::AHashMap;
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read};
+
+/// Path recorded in the manifest for an export passed as bytes.
+pub const IN_MEMORY_INPUT: &str = "<memory>";
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Convert the contents of an `export.zip` or `export.xml` into the bytes of
+/// a CSV archive, parsing and compressing on the calling thread.
+///
+/// Unlike the `Engine` pipeline this needs no async runtime, thread pool or
+/// file system, so it also runs in a browser.
+pub fn convert_bytes(export: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    let (grouped, skipped) = group_records(export)?;
+
+    let mut manifest = Manifest::new(vec![InputFile {
+        path: IN_MEMORY_INPUT.to_string(),
+        sha256: to_hex(&Sha256::digest(export)),
+    }]);
+    manifest.describe_records(&grouped);
+    let context = LoadContext {
+        manifest: &manifest,
+        progress: &Progress::default(),
+        skipped: &skipped,
+    };
+
+    let archive =
+        CsvZipSink::new(compression).write_sequential(grouped, Cursor::new(Vec::new()), context)?;
+    Ok(archive.into_inner())
+}
+
+fn group_records(export: &[u8]) -> Result<(AHashMap<String, Vec<GenericRecord>>, SkipSummary)> {
+    let mut grouped: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
+    let mut export_info = ExportInfo::default();
+    let mut nesting = Nesting::default();
+    let mut skipped = SkipSummary::default();
+
+    let mut visit = |reader: &mut dyn Read| {
+        xml_utils::for_each_element(reader, |element| match GenericRecord::from_xml(element) {
+            Ok(record) => {
+                if let Some(record) = nesting.push(record)
+                    && let Some(record) = export_info.absorb(record)
+                {
+                    grouped
+                        .entry(record.grouping_key())
+                        .or_default()
+                        .push(record);
+                }
+            }
+            Err(AppError::InvalidElement(error)) => {
+                debug!("Skipped record {}", error);
+                skipped.record(error);
+            }
+            Err(e) => debug!("{}", e),
+        })
+    };
+    if export.starts_with(ZIP_MAGIC) {
+        xml_utils::with_zipped_export_reader(Cursor::new(export), visit)?;
+    } else {
+        visit(&mut &export[..])?;
+    }
+
+    let last = nesting
+        .finish()
+        .and_then(|record| export_info.absorb(record));
+    for record in last.into_iter().chain(export_info.into_record()) {
+        grouped
+            .entry(record.grouping_key())
+            .or_default()
+            .push(record);
+    }
+    skipped.log();
+    Ok((grouped, skipped))
+}
@@ -0,0 +1,48 @@
+use ahash::AHashSet;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+/// A set of shared strings handing out one `Arc<str>` per distinct value.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: AHashSet<Arc<str>>,
+}
+
+impl Interner {
+    /// Return the shared handle for `s`, allocating it on first use.
+    pub fn intern(&mut self, s: &str) -> Arc<str> {
+        if let Some(existing) = self.strings.get(s) {
+            return existing.clone();
+        }
+        let interned: Arc<str> = Arc::from(s);
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    /// Number of distinct strings held.
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+thread_local! {
+    // Per thread, so parser threads never contend on a lock. Both pools hold a
+    // small closed set of strings, so each thread ends up with a copy of it.
+    static KEYS: RefCell<Interner> = RefCell::new(Interner::default());
+    static VALUES: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+/// Intern an attribute name in the current thread's key table.
+pub fn intern_key(key: &str) -> Arc<str> {
+    KEYS.with(|keys| keys.borrow_mut().intern(key))
+}
+
+/// Intern a value of a highly repetitive attribute, such as a record type or
+/// source name, in the current thread's value pool.
+pub fn intern_value(value: &str) -> Arc<str> {
+    VALUES.with(|values| values.borrow_mut().intern(value))
+}
@@ -1,6 +1,77 @@
+//! Convert personal data exports (currently Apple Health) into structured
+//! archives.
+//!
+//! Most callers only need [`ConversionBuilder`]; the [`core`] traits are
+//! available for wiring custom extractors and sinks.
+//!
+//! Without the default `native` feature only [`in_memory::convert_bytes`] and
+//! the modules it depends on are built, which keeps the crate compatible with
+//! `wasm32-unknown-unknown`.
+
 pub mod apple_health;
+pub mod assets;
+pub mod audio;
+#[cfg(feature = "native")]
+pub mod backpressure;
+#[cfg(feature = "native")]
+pub mod bench;
+pub mod calendar;
+pub mod charts;
+#[cfg(feature = "native")]
 pub mod config;
+#[cfg(feature = "native")]
+pub mod conversion;
 pub mod core;
+pub mod correlations;
+pub mod cycle;
+pub mod ecg;
 pub mod error;
+pub mod fit;
+pub mod generate;
+pub mod in_memory;
+pub mod intern;
+#[cfg(feature = "native")]
+pub mod logging;
+pub mod manifest;
+#[cfg(feature = "native")]
+pub mod merge;
+#[cfg(feature = "native")]
+pub mod metrics;
+pub mod mindfulness;
+#[cfg(feature = "native")]
+pub mod notify;
+pub mod pivot;
+#[cfg(feature = "native")]
+pub mod profile;
+pub mod progress;
+#[cfg(feature = "native")]
+pub mod remote;
+pub mod report;
+#[cfg(feature = "native")]
+pub mod retry;
+#[cfg(feature = "native")]
+pub mod rollup;
+pub mod runkeeper;
+pub mod select;
 pub mod sinks;
+pub mod sleep_cycle;
+#[cfg(feature = "native")]
+pub mod spill;
+pub mod splits;
+pub mod streaks;
+pub mod suunto;
+pub mod trends;
+pub mod units;
+pub mod validate;
+pub mod wahoo;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 pub mod xml_utils;
+pub mod zones;
+
+#[cfg(feature = "native")]
+pub use conversion::{ConversionBuilder, Format};
+pub use error::{AppError, Result};
+pub use progress::ProgressEvent;
+#[cfg(feature = "native")]
+pub use sinks::registry::SinkRegistry;
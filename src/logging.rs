@@ -0,0 +1,146 @@
+use crate::profile::ProfileLayer;
+use clap::ValueEnum;
+use env_logger::{Builder, Logger, Target, WriteStyle};
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Output format for log lines written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, including structured fields
+    Json,
+}
+
+/// Initialize the global logger with the given verbosity and format. With
+/// `log_file`, every line down to debug level is also appended to that
+/// file, whatever the console shows.
+pub fn init(verbose: bool, format: LogFormat, log_file: Option<&Path>) -> std::io::Result<()> {
+    let mut console = Builder::from_default_env();
+    format_lines(&mut console, format);
+    console.filter_level(if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    });
+    let console = console.build();
+
+    let file = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut builder = Builder::new();
+            format_lines(&mut builder, format);
+            builder
+                .filter_level(LevelFilter::Debug)
+                .target(Target::Pipe(Box::new(file)))
+                .write_style(WriteStyle::Never);
+            Some(builder.build())
+        }
+        None => None,
+    };
+    let max_level = file
+        .iter()
+        .map(Logger::filter)
+        .fold(console.filter(), Ord::max);
+    log::set_boxed_logger(Box::new(Tee { console, file }))
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Make `builder` write lines in `format`.
+fn format_lines(builder: &mut Builder, format: LogFormat) {
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert("ts".into(), buf.timestamp().to_string().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("msg".into(), record.args().to_string().into());
+
+            let mut fields = JsonFields(&mut line);
+            let _ = record.key_values().visit(&mut fields);
+
+            writeln!(buf, "{}", JsonValue::Object(line))
+        });
+    }
+}
+
+/// Sends each line to the console and to the log file, each applying its
+/// own level.
+struct Tee {
+    console: Logger,
+    file: Option<Logger>,
+}
+
+impl Log for Tee {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata) || self.file.as_ref().is_some_and(|f| f.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+        if let Some(file) = &self.file {
+            file.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file {
+            file.flush();
+        }
+    }
+}
+
+/// Record the pipeline's `tracing` spans into a Chrome trace at
+/// `trace_file`, which Perfetto and `chrome://tracing` open, and into
+/// `profile`. The trace is complete once the returned guard is dropped.
+pub fn trace(
+    trace_file: Option<&Path>,
+    profile: Option<ProfileLayer>,
+) -> std::io::Result<Option<FlushGuard>> {
+    let (chrome, guard) = match trace_file {
+        Some(path) => {
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .writer(File::create(path)?)
+                .include_args(true)
+                .build();
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let subscriber = tracing_subscriber::registry().with(chrome).with(profile);
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    Ok(guard)
+}
+
+/// Copies structured log fields into a JSON object, keeping numbers numeric.
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        let json = if let Some(v) = value.to_u64() {
+            v.into()
+        } else if let Some(v) = value.to_i64() {
+            v.into()
+        } else if let Some(v) = value.to_f64() {
+            v.into()
+        } else if let Some(v) = value.to_bool() {
+            v.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_string(), json);
+        Ok(())
+    }
+}
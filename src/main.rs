@@ -1,47 +1,304 @@
-mod apple_health;
-mod config;
-mod core;
-mod error;
-mod sinks;
-mod xml_utils;
-
 use clap::Parser;
-use log::{LevelFilter, error, info};
-use std::path::Path;
+use gpt_os::apple_health::dates;
+use gpt_os::assets::{self, AssetKind};
+use gpt_os::backpressure::Backpressure;
+use gpt_os::bench;
+use gpt_os::config::{Command, Config};
+use gpt_os::core::RunReport;
+use gpt_os::error::{AppError, ExitCode};
+use gpt_os::generate::{self, GenerateOptions};
+use gpt_os::metrics::{METRICS_PATH, Metrics};
+use gpt_os::notify::Notification;
+use gpt_os::pivot::{self, PivotInterval};
+use gpt_os::profile::{Profile, ProfileLayer};
+use gpt_os::remote;
+use gpt_os::report::ReportFormat;
+use gpt_os::retry::RetryPolicy;
+use gpt_os::rollup;
+use gpt_os::select::{self, Selection};
+use gpt_os::sinks::csv_zip::{Compression, CsvDialect, CsvZipSink};
+use gpt_os::sinks::file_names::{NameMap, load_name_map};
+use gpt_os::sinks::registry::ZIP_FORMAT;
+use gpt_os::{ConversionBuilder, Format, logging, merge, trends, validate, xml_utils};
+use log::{error, info};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[tokio::main]
-async fn main() {
-    let start_time = std::time::Instant::now();
-    let config = config::Config::parse();
+fn main() {
+    let start_time = Instant::now();
+    let config = Config::parse();
 
-    // Initialize logging
-    env_logger::Builder::from_default_env()
-        .filter_level(if config.verbose {
-            LevelFilter::Debug
-        } else {
-            LevelFilter::Info
-        })
-        .init();
+    if let Err(e) = logging::init(
+        config.verbose,
+        config.log_format,
+        config.log_file.as_deref(),
+    ) {
+        eprintln!("❌ Cannot write the log file: {}", e);
+        exit(ExitCode::Io);
+    }
+    let profile = config.profile.as_ref().map(|_| ProfileLayer::default());
+    let _trace = if config.trace_file.is_some() || profile.is_some() {
+        match logging::trace(config.trace_file.as_deref(), profile.clone()) {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("❌ Cannot record the trace: {}", e);
+                exit(ExitCode::Io);
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = config.threads {
+        info!(threads = threads.get(); "Limiting processing to {} threads", threads);
+        runtime.worker_threads(threads.get());
+        if let Err(e) = xml_utils::configure_threads(threads.get()) {
+            error!("❌ Failed to configure the thread pool: {}", e);
+            exit(e.exit_code());
+        }
+    }
 
-    info!("🚀 Starting Apple Health Transformer");
-    info!("📁 Input: {}", config.input_file);
-    info!("📦 Output: {}", config.output_zip);
+    let runtime = match runtime.build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("❌ Failed to start async runtime: {}", e);
+            exit(ExitCode::Failure);
+        }
+    };
 
-    let extractor = apple_health::extractor::AppleHealthExtractor;
-    let sink = sinks::csv_zip::CsvZipSink;
+    match &config.command {
+        Some(Command::Validate {
+            input_file,
+            output_zip,
+        }) => run_validate(
+            Path::new(input_file),
+            Path::new(output_zip),
+            &name_map(&config),
+            config.dedup_exact,
+        ),
+        Some(Command::Merge { inputs, output }) => {
+            runtime.block_on(run_merge(&config, inputs, output))
+        }
+        Some(Command::ExtractAssets {
+            input_file,
+            only,
+            output,
+        }) => run_extract_assets(Path::new(input_file), only, output),
+        Some(Command::Bench { input_file, runs }) => {
+            runtime.block_on(run_bench(Path::new(input_file), runs.get()))
+        }
+        Some(Command::Generate {
+            records,
+            types,
+            seed,
+            output,
+        }) => run_generate(
+            &GenerateOptions {
+                records: *records,
+                types: *types,
+                seed: *seed,
+            },
+            output,
+        ),
+        Some(Command::Trends {
+            input_file,
+            metric,
+            window,
+            output,
+        }) => run_trends(Path::new(input_file), metric, *window, output.as_deref()),
+        Some(Command::Pivot {
+            input_file,
+            metrics,
+            interval,
+            output,
+        }) => run_pivot(Path::new(input_file), metrics, *interval, output.as_deref()),
+        Some(Command::Select {
+            input_file,
+            projection,
+            condition,
+            raw_output,
+        }) => run_select(
+            Path::new(input_file),
+            projection,
+            condition.as_deref(),
+            *raw_output,
+        ),
+        None => runtime.block_on(run(config, profile, start_time)),
+    }
+}
 
-    let engine = core::Engine::new(extractor, sink);
+async fn run(config: Config, profile: Option<ProfileLayer>, start_time: Instant) {
+    // Both positionals are required by clap unless a subcommand is given
+    let (Some(input_file), Some(output_zip)) = (&config.input_file, &config.output_zip) else {
+        unreachable!("clap enforces the input and output arguments");
+    };
+    // Rollups read the per-record archive back, which only `zip` writes
+    if !config.rollup.is_empty() && config.format != ZIP_FORMAT {
+        let e = AppError::InvalidConfig(format!(
+            "--rollup needs --format {}, not '{}'",
+            ZIP_FORMAT, config.format
+        ));
+        error!("❌ {}", e);
+        exit(e.exit_code());
+    }
+    let since = config.last.map(|days| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        (now - i64::from(days) * 86_400, now)
+    });
+    let output_zip = &match since {
+        Some((since, now)) => dated_output(output_zip, since, now),
+        None => output_zip.clone(),
+    };
 
-    let input_path = Path::new(&config.input_file);
-    let output_path = Path::new(&config.output_zip);
+    if !config.quiet {
+        info!("🚀 Starting Apple Health Transformer");
+        info!("📁 Input: {}", remote::redact(Path::new(input_file)));
+        info!("📦 Output: {}", output_zip);
+    }
 
-    if let Err(e) = engine.run(input_path, output_path).await {
-        error!("❌ Application error: {}", e);
-        process::exit(1);
+    let mut conversion = ConversionBuilder::new(input_file)
+        .output(output_zip)
+        .format(Format::from(config.format.as_str()))
+        .compression(compression(&config))
+        .dialect(CsvDialect {
+            decimal_separator: config.decimal_separator,
+            excel_hint: config.excel_hint,
+            alphabetical_columns: config.alphabetical_columns,
+            record_ids: config.record_ids,
+            epoch_columns: config.epoch_columns,
+            decimal_places: config.round,
+            units: config.units,
+            drop_empty_columns: config.drop_empty_columns,
+            ndjson: config.ndjson,
+            protobuf: config.protobuf,
+            cbor: config.cbor,
+        })
+        .backpressure(backpressure(&config))
+        .retry(RetryPolicy {
+            retries: config.io_retries,
+            backoff: Duration::from_millis(config.io_retry_backoff),
+        })
+        .mmap(config.mmap)
+        .chunked(config.chunked)
+        .prescan(config.prescan)
+        .recover(config.recover)
+        .dedup_exact(config.dedup_exact)
+        .provenance(config.provenance);
+    if let Some((since, _)) = since {
+        conversion = conversion.since(since);
+    }
+    if let Some(max_memory) = config.max_memory {
+        conversion = conversion.max_memory(max_memory);
+    }
+    if let Some(dir) = &config.checkpoint_dir {
+        conversion = conversion.checkpoint(dir);
+    }
+    for &report in &config.report {
+        conversion = conversion.report(report);
+    }
+    if config.charts {
+        conversion = conversion.report(ReportFormat::Charts);
+    }
+    if let Some(zones) = config.heart_rate_zones {
+        conversion = conversion.heart_rate_zones(zones);
+    }
+    if let Some(location) = &config.table_location {
+        conversion = conversion.table_location(location);
+    }
+    if config.name_map.is_some() {
+        conversion = conversion.names(name_map(&config));
+    }
+    let metrics = config.metrics_addr.map(|addr| {
+        let metrics = Arc::new(Metrics::default());
+        match Arc::clone(&metrics).serve(addr) {
+            Ok(local) => info!("📈 Serving metrics on http://{}{}", local, METRICS_PATH),
+            Err(e) => {
+                error!("❌ Could not serve metrics on {}: {}", addr, e);
+                exit(ExitCode::Failure);
+            }
+        }
+        metrics
+    });
+    if let Some(metrics) = &metrics {
+        let metrics = Arc::clone(metrics);
+        conversion = conversion.progress(move |event| metrics.observe(event));
+    }
+
+    let (input_path, output_path) = (Path::new(input_file), Path::new(output_zip));
+    let result = tokio::select! {
+        result = conversion.run() => result,
+        // A handler that cannot be installed never cancels the run
+        Ok(()) = tokio::signal::ctrl_c() => {
+            error!("❌ Cancelled");
+            exit(ExitCode::Cancelled);
+        }
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            error!("❌ Application error: {}", e);
+            if let Some(metrics) = &metrics {
+                metrics.fail();
+            }
+            if let Some(url) = &config.notify_url {
+                let failed =
+                    Notification::failed(input_path, output_path, &e, start_time.elapsed());
+                notify(url, Ok(failed)).await;
+            }
+            exit(exit_code(&e, &[input_path]));
+        }
+    };
+    if let Some(metrics) = &metrics {
+        metrics.finish(&report);
+    }
+    if let (Some(path), Some(layer)) = (&config.profile, &profile) {
+        match Profile::new(&report, layer).write(path) {
+            Ok(()) => info!("⏱️  Wrote the profile to {}", path.display()),
+            Err(e) => error!(
+                "❌ Could not write the profile to {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    for &period in &config.rollup {
+        let path = period.archive_path(output_path);
+        match rollup::rollup(output_path, period, config.decimal_separator, &path) {
+            Ok(groups) => info!("🗓️  Rolled {} types up into {}", groups, path.display()),
+            Err(e) => {
+                error!("❌ Could not write {}: {}", path.display(), e);
+                exit(e.exit_code());
+            }
+        }
+    }
+    if let Some(url) = &config.notify_url {
+        notify(
+            url,
+            Notification::succeeded(input_path, output_path, &report),
+        )
+        .await;
+    }
+    // The notification hashes the archive, so it goes after that
+    if config.rollup_only
+        && let Err(e) = std::fs::remove_file(output_path)
+    {
+        error!("❌ Could not remove {}: {}", output_path.display(), e);
+        exit(ExitCode::Failure);
     }
 
     let total_time = start_time.elapsed();
+    if config.quiet {
+        return;
+    }
+
     info!(
         "✅ Transformation completed successfully in {:.2}s!",
         total_time.as_secs_f64()
@@ -53,6 +310,314 @@ async fn main() {
             "📊 Total execution time: {:.2} seconds",
             total_time.as_secs_f64()
         );
-        println!("📁 Output saved to: {}", config.output_zip);
+        print_report(&report);
+        if config.rollup_only {
+            for &period in &config.rollup {
+                println!(
+                    "📁 Output saved to: {}",
+                    period.archive_path(output_path).display()
+                );
+            }
+        } else {
+            println!("📁 Output saved to: {}", output_zip);
+        }
+    }
+}
+
+/// Send `notification` to the webhook at `url`, only logging failures so
+/// they never change the outcome of the run.
+async fn notify(url: &str, notification: gpt_os::Result<Notification>) {
+    let url = url.to_string();
+    let sent = tokio::task::spawn_blocking(move || notification?.send(&url)).await;
+    match sent {
+        Ok(Ok(())) => info!("📨 Notified the webhook"),
+        Ok(Err(e)) => error!("❌ Could not send the notification: {}", e),
+        Err(e) => error!("❌ Could not send the notification: {}", e),
+    }
+}
+
+fn print_report(report: &RunReport) {
+    println!(
+        "⏱️  Extract: {:.2}s, Transform: {:.2}s, Load: {:.2}s",
+        report.extract_duration.as_secs_f64(),
+        report.transform_duration.as_secs_f64(),
+        report.load_duration.as_secs_f64()
+    );
+    println!(
+        "🧾 {} records in {} types ({} skipped), {} bytes written",
+        report.total_records(),
+        report.record_counts.len(),
+        report.skipped_records(),
+        report.output_bytes
+    );
+    for (name, count) in &report.record_counts {
+        println!("   {:<60} {:>12}", name, count);
+    }
+    for (category, skipped) in report.skipped.categories() {
+        println!(
+            "⚠️  {} skipped as {} (first: {})",
+            skipped.count, category, skipped.first
+        );
+    }
+    if let Some(truncated) = &report.truncated {
+        let last = truncated
+            .last_sort_key
+            .map_or_else(|| "unknown".to_string(), dates::format_timestamp);
+        println!(
+            "⚠️  Input unreadable past byte {} ({}); data ends around {}",
+            truncated.offset, truncated.message, last
+        );
+    }
+}
+
+fn backpressure(config: &Config) -> Backpressure {
+    let defaults = Backpressure::default();
+    Backpressure {
+        records: config.in_flight_records.unwrap_or(defaults.records),
+        bytes: config.in_flight_bytes.unwrap_or(defaults.bytes),
+    }
+}
+
+fn compression(config: &Config) -> Compression {
+    match Compression::new(config.compression_method, config.compression_level) {
+        Ok(compression) => compression,
+        Err(e) => {
+            error!("❌ {}", e);
+            exit(e.exit_code());
+        }
+    }
+}
+
+async fn run_merge(config: &Config, inputs: &[PathBuf], output: &Path) {
+    let sink = CsvZipSink::new(compression(config));
+    match merge::merge(inputs, output, &sink).await {
+        Ok(stats) => info!(
+            "✅ Merged {} archives into {}: {} records, {} duplicates dropped",
+            inputs.len(),
+            output.display(),
+            stats.records,
+            stats.duplicates
+        ),
+        Err(e) => {
+            error!("❌ Merge error: {}", e);
+            let inputs: Vec<&Path> = inputs.iter().map(PathBuf::as_path).collect();
+            exit(exit_code(&e, &inputs));
+        }
+    }
+}
+
+async fn run_bench(input_path: &Path, runs: usize) {
+    match bench::bench(input_path, runs).await {
+        Ok(timings) => print!("{}", bench::table(&timings)),
+        Err(e) => {
+            error!("❌ Benchmark error: {}", e);
+            exit(exit_code(&e, &[input_path]));
+        }
+    }
+}
+
+fn run_generate(options: &GenerateOptions, output: &Path) {
+    let result = File::create(output)
+        .map_err(Into::into)
+        .and_then(|file| generate::generate(BufWriter::new(file), options));
+    match result {
+        Ok(()) => info!(
+            "✅ Generated {} records of {} types into {}",
+            options.records,
+            options.types,
+            output.display()
+        ),
+        Err(e) => {
+            error!("❌ Generation error: {}", e);
+            exit(e.exit_code());
+        }
+    }
+}
+
+fn run_trends(input_path: &Path, metric: &str, window: u32, output: Option<&Path>) {
+    let points = match trends::trends(input_path, metric, window) {
+        Ok(points) => points,
+        Err(e) => {
+            error!("❌ Trend analysis error: {}", e);
+            exit(exit_code(&e, &[input_path]));
+        }
+    };
+    if points.is_empty() {
+        error!(
+            "❌ No {} values found in {}",
+            trends::metric_type(metric),
+            input_path.display()
+        );
+        exit(ExitCode::Failure);
+    }
+
+    let written = match output {
+        Some(path) => File::create(path)
+            .map_err(Into::into)
+            .and_then(|file| trends::write_csv(&points, BufWriter::new(file))),
+        None => trends::write_csv(&points, std::io::stdout().lock()),
+    };
+    if let Err(e) = written {
+        error!("❌ Could not write the trend: {}", e);
+        exit(e.exit_code());
+    }
+    let changes: Vec<&str> = points
+        .iter()
+        .filter(|p| p.change_point)
+        .map(|p| p.date.as_str())
+        .collect();
+    info!(
+        "✅ {} days of {}, {} change points{}{}",
+        points.len(),
+        trends::metric_type(metric),
+        changes.len(),
+        if changes.is_empty() { "" } else { ": " },
+        changes.join(", ")
+    );
+}
+
+fn run_pivot(
+    input_path: &Path,
+    metrics: &[String],
+    interval: PivotInterval,
+    output: Option<&Path>,
+) {
+    let written = match output {
+        Some(path) => File::create(path)
+            .map_err(Into::into)
+            .and_then(|file| pivot::pivot(input_path, metrics, interval, BufWriter::new(file))),
+        None => pivot::pivot(input_path, metrics, interval, std::io::stdout().lock()),
+    };
+    match written {
+        Ok(0) => {
+            error!(
+                "❌ No values of {} found in {}",
+                metrics.join(", "),
+                input_path.display()
+            );
+            exit(ExitCode::Failure);
+        }
+        Ok(rows) => info!("✅ {} rows of {} metrics", rows, metrics.len()),
+        Err(e) => {
+            error!("❌ Pivot error: {}", e);
+            exit(exit_code(&e, &[input_path]));
+        }
+    }
+}
+
+fn run_select(input_path: &Path, projection: &str, condition: Option<&str>, raw: bool) {
+    let selection = match Selection::parse(projection, condition) {
+        Ok(selection) => selection,
+        Err(e) => {
+            error!("❌ {}", e);
+            exit(e.exit_code());
+        }
+    };
+    let out = BufWriter::new(std::io::stdout().lock());
+    match select::select(input_path, &selection, raw, out) {
+        Ok(matched) => info!("✅ {} matching records", matched),
+        // The reader, such as `head`, has seen enough
+        Err(AppError::IoError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            error!("❌ Selection error: {}", e);
+            exit(exit_code(&e, &[input_path]));
+        }
+    }
+}
+
+/// End the process with `code`.
+fn exit(code: ExitCode) -> ! {
+    process::exit(code.code())
+}
+
+/// The exit code for `error`, or [`ExitCode::InputNotFound`] when one of
+/// `inputs` does not exist, whichever error reading it produced.
+fn exit_code(error: &AppError, inputs: &[&Path]) -> ExitCode {
+    if inputs
+        .iter()
+        .any(|input| !remote::is_url(input) && !input.exists())
+    {
+        ExitCode::InputNotFound
+    } else {
+        error.exit_code()
+    }
+}
+
+/// `output` with the dates from `since` to `now` added to its file name,
+/// such as `health_2023-10-03_2024-01-01.zip` for `health.zip`.
+fn dated_output(output: &str, since: i64, now: i64) -> String {
+    let date = |secs: i64| dates::format_timestamp(secs)[..10].to_string();
+    let path = Path::new(output);
+    let stem = path
+        .file_stem()
+        .map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+    let mut name = format!("{}_{}_{}", stem, date(since), date(now));
+    if let Some(extension) = path.extension() {
+        name = format!("{}.{}", name, extension.to_string_lossy());
+    }
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// The entry names of `--name-map`, exiting on an unreadable file.
+fn name_map(config: &Config) -> NameMap {
+    let Some(path) = &config.name_map else {
+        return NameMap::new();
+    };
+    match load_name_map(path) {
+        Ok(names) => names,
+        Err(e) => {
+            error!("❌ Cannot read name map {}: {}", path.display(), e);
+            exit(e.exit_code());
+        }
+    }
+}
+
+fn run_extract_assets(input_path: &Path, only: &[AssetKind], output: &Path) {
+    let kinds = if only.is_empty() {
+        &AssetKind::ALL[..]
+    } else {
+        only
+    };
+    let copied = match assets::extract_assets(input_path, kinds, output) {
+        Ok(copied) => copied,
+        Err(e) => {
+            error!("❌ Could not extract the assets: {}", e);
+            exit(exit_code(&e, &[input_path]));
+        }
+    };
+    let summary: Vec<String> = copied
+        .iter()
+        .map(|(kind, count)| format!("{} files into {}/", count, kind.dir()))
+        .collect();
+    info!(
+        "✅ Copied {} of {}",
+        summary.join(" and "),
+        output.display()
+    );
+}
+
+fn run_validate(input_path: &Path, output_path: &Path, names: &NameMap, dedup_exact: bool) {
+    let report = match validate::validate(input_path, output_path, names, dedup_exact) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("❌ Validation error: {}", e);
+            exit(exit_code(&e, &[input_path, output_path]));
+        }
+    };
+
+    println!("{:<60} {:>12} {:>12}", "type", "source", "output");
+    for t in &report.types {
+        let marker = if t.matches() { "" } else { "  ❌ mismatch" };
+        println!("{:<60} {:>12} {:>12}{}", t.name, t.source, t.output, marker);
+    }
+
+    if !report.is_ok() {
+        error!(
+            "❌ {} of {} types do not match",
+            report.mismatches().count(),
+            report.types.len()
+        );
+        exit(ExitCode::ValidationMismatch);
     }
+    info!("✅ All {} types match", report.types.len());
 }
@@ -0,0 +1,123 @@
+use crate::apple_health::export_info::EXPORT_INFO_GROUP;
+use crate::core::Processable;
+use crate::error::{Result, Truncation};
+use ahash::AHashMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Name of the manifest entry written into every output archive.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// A file the archive was produced from.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InputFile {
+    pub path: String,
+    pub sha256: String,
+}
+
+impl InputFile {
+    /// Describe `path`, hashing its contents.
+    pub fn hashed(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.display().to_string(),
+            sha256: sha256_file(path)?,
+        })
+    }
+
+    /// Describe `path`, hashing the contents read from `reader`.
+    pub fn hashed_from(path: &Path, reader: impl Read) -> Result<Self> {
+        Ok(Self {
+            path: path.display().to_string(),
+            sha256: sha256_reader(reader)?,
+        })
+    }
+}
+
+/// Provenance information describing how an output archive was produced.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub inputs: Vec<InputFile>,
+    pub export_date: Option<String>,
+    pub record_counts: BTreeMap<String, usize>,
+    pub filters: Vec<String>,
+    /// Phase durations in seconds, keyed by phase name.
+    pub timings: BTreeMap<String, f64>,
+    /// Set when the input was damaged and only the records before the error
+    /// were converted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<Truncation>,
+}
+
+impl Manifest {
+    pub fn new(inputs: Vec<InputFile>) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            inputs,
+            ..Default::default()
+        }
+    }
+
+    /// Fill in the per-type record counts and the (latest) export date.
+    pub fn describe_records<T: Processable>(&mut self, grouped: &AHashMap<String, Vec<T>>) {
+        self.record_counts = grouped
+            .iter()
+            .map(|(key, records)| (key.clone(), records.len()))
+            .collect();
+        self.export_date = grouped
+            .iter()
+            .flat_map(|(key, records)| records.iter().map(move |record| (key, record)))
+            .filter_map(|(key, record)| export_date(key, record))
+            .max()
+            .map(str::to_string);
+    }
+
+    /// Serialize the manifest as pretty-printed JSON.
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// The export date carried by `record` of group `key`, for the records of
+/// the export's info group.
+pub fn export_date<'a, T: Processable>(key: &str, record: &'a T) -> Option<&'a str> {
+    if key == EXPORT_INFO_GROUP {
+        record.field("exportDate")
+    } else {
+        None
+    }
+}
+
+/// Compute the hex-encoded SHA-256 digest of a file.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    sha256_reader(File::open(path)?)
+}
+
+/// Compute the hex-encoded SHA-256 digest of everything `reader` yields.
+pub fn sha256_reader(mut reader: impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; crate::xml_utils::BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Lowercase hex encoding of a digest.
+pub fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        })
+}
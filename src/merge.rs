@@ -0,0 +1,194 @@
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::core::{LoadContext, Sink};
+use crate::error::{AppError, Result, SkipSummary};
+use crate::intern::intern_key;
+use crate::manifest::{InputFile, Manifest};
+use crate::progress::Progress;
+use crate::sinks::cbor::MapReader;
+use crate::sinks::csv_zip::{
+    CBOR_EXTENSION, NDJSON_EXTENSION, PROTOBUF_EXTENSION, csv_reader, group_name,
+};
+use ahash::{AHashMap, AHashSet};
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::task;
+use zip::ZipArchive;
+
+/// Outcome of merging several archives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Rows written to the merged archive.
+    pub records: usize,
+    /// Exact-duplicate rows that were dropped.
+    pub duplicates: usize,
+}
+
+/// Union previously generated archives into `output_path`, dropping rows that
+/// are exact duplicates within a type. The sink re-sorts each group, so the
+/// usual per-type ordering is preserved.
+pub async fn merge<S>(inputs: &[PathBuf], output_path: &Path, sink: &S) -> Result<MergeStats>
+where
+    S: Sink<GenericRecord> + Sync,
+{
+    let start = Instant::now();
+    let paths = inputs.to_vec();
+    let (grouped, stats, sources) = task::spawn_blocking(move || read_archives(&paths))
+        .await
+        .map_err(|e| AppError::Unknown(e.to_string()))??;
+    info!(
+        "Read {} archives in {:.3}s: {} records, {} duplicates dropped",
+        inputs.len(),
+        start.elapsed().as_secs_f64(),
+        stats.records,
+        stats.duplicates
+    );
+
+    let mut manifest = Manifest::new(sources);
+    manifest.describe_records(&grouped);
+    manifest
+        .timings
+        .insert("read".to_string(), start.elapsed().as_secs_f64());
+
+    let context = LoadContext {
+        manifest: &manifest,
+        progress: &Progress::default(),
+        skipped: &SkipSummary::default(),
+    };
+    sink.load(grouped, output_path, context).await?;
+    Ok(stats)
+}
+
+type MergedArchives = (
+    AHashMap<String, Vec<GenericRecord>>,
+    MergeStats,
+    Vec<InputFile>,
+);
+
+fn read_archives(paths: &[PathBuf]) -> Result<MergedArchives> {
+    let mut grouped: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
+    let mut seen: AHashMap<String, AHashSet<String>> = AHashMap::new();
+    let mut stats = MergeStats::default();
+    let mut sources = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        sources.push(InputFile::hashed(path)?);
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            let Some(group) = group_name(entry.name()).map(str::to_string) else {
+                continue;
+            };
+            debug!("Merging '{}' from {}", entry.name(), path.display());
+
+            let records = grouped.entry(group.clone()).or_default();
+            let seen = seen.entry(group.clone()).or_default();
+            if entry.name().ends_with(PROTOBUF_EXTENSION) {
+                return Err(AppError::InvalidConfig(format!(
+                    "{} holds protobuf entries, which cannot be merged",
+                    path.display()
+                )));
+            }
+            let name = entry.name().to_string();
+            let rows = entry_rows(&name, entry)?;
+
+            for attributes in rows {
+                if !seen.insert(canonical_row(&attributes)) {
+                    stats.duplicates += 1;
+                    continue;
+                }
+                records.push(GenericRecord {
+                    element_name: group.clone(),
+                    attributes,
+                });
+                stats.records += 1;
+            }
+        }
+    }
+
+    Ok((grouped, stats, sources))
+}
+
+/// The rows of the group entry `name` as attributes, read as NDJSON, CBOR or
+/// CSV after its extension.
+pub(crate) fn entry_rows<R: Read>(name: &str, entry: R) -> Result<Vec<Attributes>> {
+    if name.ends_with(NDJSON_EXTENSION) {
+        ndjson_rows(entry)
+    } else if name.ends_with(CBOR_EXTENSION) {
+        cbor_rows(entry)
+    } else {
+        csv_rows(entry)
+    }
+}
+
+/// The rows of a CSV entry as attributes.
+fn csv_rows<R: Read>(entry: R) -> Result<Vec<Attributes>> {
+    let mut reader = csv_reader(entry)?;
+    let headers = reader.headers()?.clone();
+    let mut rows = Vec::new();
+    for row in reader.records() {
+        let row = row?;
+        // Missing attributes are written as empty cells, so ignoring
+        // them makes rows comparable across differing header sets.
+        let mut attributes = Attributes::with_capacity(row.len());
+        for (header, value) in headers.iter().zip(row.iter()) {
+            if !value.is_empty() {
+                attributes.insert(intern_key(header), attribute_value(header, value));
+            }
+        }
+        rows.push(attributes);
+    }
+    Ok(rows)
+}
+
+/// The rows of an NDJSON entry as attributes; empty cells are left out of
+/// its objects already.
+fn ndjson_rows<R: Read>(entry: R) -> Result<Vec<Attributes>> {
+    let mut rows = Vec::new();
+    for line in BufReader::new(entry).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let object: BTreeMap<String, String> = serde_json::from_str(&line)?;
+        let mut attributes = Attributes::with_capacity(object.len());
+        for (header, value) in &object {
+            attributes.insert(intern_key(header), attribute_value(header, value));
+        }
+        rows.push(attributes);
+    }
+    Ok(rows)
+}
+
+/// The rows of a CBOR entry as attributes; empty cells are left out of its
+/// maps already.
+fn cbor_rows<R: Read>(entry: R) -> Result<Vec<Attributes>> {
+    let mut reader = MapReader::new(entry);
+    let mut rows = Vec::new();
+    while let Some(map) = reader.next_map()? {
+        let mut attributes = Attributes::with_capacity(map.len());
+        for (header, value) in &map {
+            attributes.insert(intern_key(header), attribute_value(header, value));
+        }
+        rows.push(attributes);
+    }
+    Ok(rows)
+}
+
+/// Order-independent representation of a row used for exact-duplicate checks.
+fn canonical_row(attributes: &Attributes) -> String {
+    let mut pairs: Vec<(&Arc<str>, &Arc<str>)> = attributes.iter().collect();
+    pairs.sort_unstable();
+    let mut key = String::new();
+    for (k, v) in pairs {
+        key.push_str(k);
+        key.push('\u{1f}');
+        key.push_str(v);
+        key.push('\u{1e}');
+    }
+    key
+}
@@ -0,0 +1,168 @@
+use crate::core::RunReport;
+use crate::progress::ProgressEvent;
+use log::debug;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Path the metrics are served under; every other path is a 404.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// How long a connection may wait to send its request or take the answer.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters of a running conversion, fed by its [`ProgressEvent`]s and
+/// rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bytes_read: AtomicU64,
+    records_parsed: AtomicU64,
+    groups_written: AtomicU64,
+    records_written: AtomicU64,
+    errors: AtomicU64,
+    /// Phase durations in seconds, known once the run ends.
+    phases: Mutex<Vec<(&'static str, f64)>>,
+}
+
+impl Metrics {
+    pub fn observe(&self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::BytesRead { bytes, .. } => {
+                self.bytes_read.fetch_max(*bytes, Ordering::Relaxed);
+            }
+            ProgressEvent::RecordsParsed { records } => {
+                self.records_parsed
+                    .fetch_max(*records as u64, Ordering::Relaxed);
+            }
+            ProgressEvent::GroupWritten { records, .. } => {
+                self.groups_written.fetch_add(1, Ordering::Relaxed);
+                self.records_written
+                    .fetch_add(*records as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Record the skipped elements and phase durations of a finished run.
+    pub fn finish(&self, report: &RunReport) {
+        self.errors
+            .fetch_add(report.skipped_records() as u64, Ordering::Relaxed);
+        let mut phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        *phases = vec![
+            ("extract", report.extract_duration.as_secs_f64()),
+            ("transform", report.transform_duration.as_secs_f64()),
+            ("load", report.load_duration.as_secs_f64()),
+            ("total", report.total_duration.as_secs_f64()),
+        ];
+    }
+
+    /// Count a conversion that failed.
+    pub fn fail(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            ("bytes_read", "Input bytes read", &self.bytes_read),
+            (
+                "records_parsed",
+                "Records received by the transform phase",
+                &self.records_parsed,
+            ),
+            (
+                "groups_written",
+                "Groups written to the output",
+                &self.groups_written,
+            ),
+            (
+                "records_written",
+                "Records written to the output",
+                &self.records_written,
+            ),
+            (
+                "errors",
+                "Malformed elements skipped and conversions failed",
+                &self.errors,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP gpt_os_{}_total {}.", name, help);
+            let _ = writeln!(out, "# TYPE gpt_os_{}_total counter", name);
+            let _ = writeln!(
+                out,
+                "gpt_os_{}_total {}",
+                name,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        let phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        if !phases.is_empty() {
+            out.push_str("# HELP gpt_os_phase_duration_seconds Duration of each phase.\n");
+            out.push_str("# TYPE gpt_os_phase_duration_seconds gauge\n");
+            for (phase, seconds) in phases.iter() {
+                let _ = writeln!(
+                    out,
+                    "gpt_os_phase_duration_seconds{{phase=\"{}\"}} {}",
+                    phase, seconds
+                );
+            }
+        }
+        out
+    }
+
+    /// Serve [`render`](Self::render) under [`METRICS_PATH`] on `addr` from
+    /// a background thread, returning the address actually bound. Each
+    /// connection is answered on its own thread, so a client that never
+    /// finishes its request cannot hold up the others.
+    pub fn serve(self: Arc<Self>, addr: SocketAddr) -> crate::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let metrics = Arc::clone(&self);
+                let answered = stream.map(|stream| {
+                    thread::spawn(move || {
+                        if let Err(e) = metrics.answer(stream) {
+                            debug!("Metrics request failed: {}", e);
+                        }
+                    })
+                });
+                if let Err(e) = answered {
+                    debug!("Metrics connection failed: {}", e);
+                }
+            }
+        });
+        Ok(local)
+    }
+
+    fn answer(&self, stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // Skip the headers; requests to this endpoint carry no body
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+        let (status, body) = if request.starts_with("GET ") && path == METRICS_PATH {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", String::new())
+        };
+        write!(
+            &stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
@@ -0,0 +1,113 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+
+/// Group holding the mindful minutes logged by meditation and breathing
+/// apps.
+pub const MINDFUL_GROUP: &str = "HKCategoryTypeIdentifierMindfulSession";
+
+/// Longest pause, in seconds, between two samples from the same app that
+/// still continue one session.
+const SESSION_GAP: i64 = 60;
+
+/// One mindful sample, with its dates as written in the export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sample {
+    start: i64,
+    end: i64,
+    start_date: String,
+    end_date: String,
+    source: String,
+}
+
+/// The mindful session samples of a group, collected for
+/// [`mindful_sessions_csv`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MindfulSamples {
+    samples: Vec<Sample>,
+}
+
+impl MindfulSamples {
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        let date = |field: &str| {
+            let date = record.field(field)?;
+            Some((parse_timestamp(date)?, date.to_string()))
+        };
+        let (Some((start, start_date)), Some((end, end_date))) =
+            (date("startDate"), date("endDate"))
+        else {
+            return;
+        };
+        self.samples.push(Sample {
+            start,
+            end: end.max(start),
+            start_date,
+            end_date,
+            source: record.field("sourceName").unwrap_or_default().to_string(),
+        });
+    }
+}
+
+/// A run of back-to-back samples from one app.
+struct Session<'a> {
+    first: &'a Sample,
+    last: &'a Sample,
+    end: i64,
+    seconds: i64,
+    samples: usize,
+}
+
+/// One row per session, in order of their start: the start and end dates,
+/// the minutes spent, the app (`sourceName`) and the number of samples.
+/// Samples from the same app that overlap or start within a minute of the
+/// previous one ending are merged into one session; overlapping time is
+/// only counted once.
+pub fn mindful_sessions_csv(samples: Option<&MindfulSamples>) -> Result<Vec<u8>> {
+    let mut samples: Vec<&Sample> = samples.map(|s| &s.samples).into_iter().flatten().collect();
+    samples.sort_by(|a, b| (&a.source, a.start, a.end).cmp(&(&b.source, b.start, b.end)));
+
+    let mut sessions: Vec<Session> = Vec::new();
+    for sample in samples {
+        match sessions.last_mut() {
+            Some(session)
+                if session.first.source == sample.source
+                    && sample.start <= session.end + SESSION_GAP =>
+            {
+                session.seconds += (sample.end - sample.start.max(session.end)).max(0);
+                if sample.end > session.end {
+                    session.end = sample.end;
+                    session.last = sample;
+                }
+                session.samples += 1;
+            }
+            _ => sessions.push(Session {
+                first: sample,
+                last: sample,
+                end: sample.end,
+                seconds: sample.end - sample.start,
+                samples: 1,
+            }),
+        }
+    }
+    sessions.sort_by_key(|s| (s.first.start, s.end));
+
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record([
+        "startDate",
+        "endDate",
+        "duration_minutes",
+        "sourceName",
+        "samples",
+    ])?;
+    for session in sessions {
+        let minutes = (session.seconds as f64 / 60.0 * 100.0).round() / 100.0;
+        w.write_record([
+            session.first.start_date.as_str(),
+            &session.last.end_date,
+            &minutes.to_string(),
+            &session.first.source,
+            &session.samples.to_string(),
+        ])?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
@@ -0,0 +1,107 @@
+use crate::core::RunReport;
+use crate::error::{AppError, Result, Truncation};
+use crate::manifest::sha256_file;
+use crate::remote;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a webhook may take to accept a notification.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a conversion ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+}
+
+/// The JSON body POSTed to `--notify-url` when a conversion ends.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub status: RunStatus,
+    pub tool_version: String,
+    pub input: String,
+    pub output: String,
+    /// Hex SHA-256 of the output archive, once written.
+    pub output_sha256: Option<String>,
+    pub output_bytes: u64,
+    pub records: usize,
+    pub record_counts: BTreeMap<String, usize>,
+    pub skipped: usize,
+    pub truncated: Option<Truncation>,
+    /// Phase durations in seconds, keyed by phase name.
+    pub timings: BTreeMap<String, f64>,
+    /// Why the conversion failed.
+    pub error: Option<String>,
+}
+
+impl Notification {
+    /// Describe a finished conversion of `input` into `output`, hashing the
+    /// archive.
+    pub fn succeeded(input: &Path, output: &Path, report: &RunReport) -> Result<Self> {
+        let timings = [
+            ("extract", report.extract_duration),
+            ("transform", report.transform_duration),
+            ("load", report.load_duration),
+            ("total", report.total_duration),
+        ];
+        Ok(Self {
+            output_sha256: Some(sha256_file(output)?),
+            output_bytes: report.output_bytes,
+            records: report.total_records(),
+            record_counts: report.record_counts.clone(),
+            skipped: report.skipped_records(),
+            truncated: report.truncated.clone(),
+            timings: timings
+                .into_iter()
+                .map(|(phase, duration)| (phase.to_string(), duration.as_secs_f64()))
+                .collect(),
+            ..Self::new(RunStatus::Succeeded, input, output)
+        })
+    }
+
+    /// Describe a conversion that failed with `error` after `elapsed`.
+    pub fn failed(input: &Path, output: &Path, error: &AppError, elapsed: Duration) -> Self {
+        Self {
+            timings: BTreeMap::from([("total".to_string(), elapsed.as_secs_f64())]),
+            error: Some(error.to_string()),
+            ..Self::new(RunStatus::Failed, input, output)
+        }
+    }
+
+    fn new(status: RunStatus, input: &Path, output: &Path) -> Self {
+        Self {
+            status,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            input: remote::redact(input),
+            output: output.display().to_string(),
+            output_sha256: None,
+            output_bytes: 0,
+            records: 0,
+            record_counts: BTreeMap::new(),
+            skipped: 0,
+            truncated: None,
+            timings: BTreeMap::new(),
+            error: None,
+        }
+    }
+
+    /// POST the notification as JSON to `url`, failing on any status other
+    /// than 2xx.
+    pub fn send(&self, url: &str) -> Result<()> {
+        let body = serde_json::to_vec(self)?;
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            .build()
+            .into();
+        agent
+            .post(url)
+            .header("Content-Type", "application/json")
+            .send(&body[..])
+            .map_err(|e| AppError::Notification(format!("{}: {}", url, e)))?;
+        Ok(())
+    }
+}
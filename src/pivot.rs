@@ -0,0 +1,114 @@
+use crate::apple_health::types::GenericRecord;
+use crate::core::Processable;
+use crate::error::Result;
+use crate::report::GroupSummary;
+use crate::{trends, xml_utils};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Record types of the short metric names, after those of
+/// `daily_summary.csv`.
+const METRIC_ALIASES: [(&str, &str); 10] = [
+    ("steps", "HKQuantityTypeIdentifierStepCount"),
+    (
+        "active_energy",
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+    ),
+    (
+        "active_energy_kcal",
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+    ),
+    (
+        "exercise_minutes",
+        "HKQuantityTypeIdentifierAppleExerciseTime",
+    ),
+    ("resting_hr", "HKQuantityTypeIdentifierRestingHeartRate"),
+    (
+        "resting_heart_rate",
+        "HKQuantityTypeIdentifierRestingHeartRate",
+    ),
+    ("sleep", "HKCategoryTypeIdentifierSleepAnalysis"),
+    ("sleep_minutes", "HKCategoryTypeIdentifierSleepAnalysis"),
+    ("weight", "HKQuantityTypeIdentifierBodyMass"),
+    ("body_mass", "HKQuantityTypeIdentifierBodyMass"),
+];
+
+/// Length of the rows of a pivoted table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum PivotInterval {
+    /// One row per day, as written in the export
+    #[default]
+    Day,
+    /// One row per hour of the export's local time
+    Hour,
+}
+
+/// Record type of `metric`: one of the short names of `daily_summary.csv`
+/// such as `steps` or `resting_hr`, or a type as taken by
+/// [`trends::metric_type`].
+pub fn metric_type(metric: &str) -> String {
+    METRIC_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(metric))
+        .map_or_else(|| trends::metric_type(metric), |(_, typ)| typ.to_string())
+}
+
+/// Write the values of `metrics` in the export at `input_path` as CSV with
+/// one row per day or hour and one column per metric, headed by the metrics
+/// as given. Values are combined as in `daily_summary.csv`, other types are
+/// averaged, and cells without data are left empty. Returns the number of
+/// rows.
+pub fn pivot<W: Write>(
+    input_path: &Path,
+    metrics: &[String],
+    interval: PivotInterval,
+    writer: W,
+) -> Result<usize> {
+    let mut summaries: Vec<GroupSummary> = metrics
+        .iter()
+        .map(|metric| match interval {
+            PivotInterval::Day => GroupSummary::daily(metric_type(metric)),
+            PivotInterval::Hour => GroupSummary::hourly(metric_type(metric)),
+        })
+        .collect();
+    xml_utils::with_export_reader(input_path, |reader| {
+        xml_utils::for_each_element(reader, |element| {
+            let Ok(record) = GenericRecord::from_xml(element) else {
+                return;
+            };
+            let key = record.grouping_key();
+            for summary in summaries.iter_mut().filter(|s| s.name == key) {
+                summary.add(&record);
+            }
+        })
+    })?;
+
+    let mut rows: BTreeMap<&str, Vec<Option<f64>>> = BTreeMap::new();
+    for (column, summary) in summaries.iter().enumerate() {
+        for (bucket, value) in summary.daily_values() {
+            rows.entry(bucket)
+                .or_insert_with(|| vec![None; metrics.len()])[column] = Some(value);
+        }
+    }
+
+    let mut w = csv::Writer::from_writer(writer);
+    let mut header = vec![match interval {
+        PivotInterval::Day => "date",
+        PivotInterval::Hour => "hour",
+    }];
+    header.extend(metrics.iter().map(String::as_str));
+    w.write_record(&header)?;
+    for (bucket, values) in &rows {
+        let mut row = vec![bucket.to_string()];
+        row.extend(values.iter().map(|value| {
+            value
+                .map(|v| ((v * 100.0).round() / 100.0).to_string())
+                .unwrap_or_default()
+        }));
+        w.write_record(&row)?;
+    }
+    w.flush()?;
+    Ok(rows.len())
+}
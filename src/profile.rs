@@ -0,0 +1,202 @@
+use crate::core::RunReport;
+use crate::error::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Subscriber, span};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Span covering the serialization of one group to CSV.
+const SERIALIZE_SPAN: &str = "serialize";
+
+/// Span covering the compression of one group's CSV.
+const COMPRESS_SPAN: &str = "compress";
+
+/// Span covering a stage blocked on a full or empty queue, with the stage
+/// it waits on as its `on` field.
+pub const WAIT_SPAN: &str = "wait";
+
+/// Timings of one run, written as `profile.json` by `--profile` to track
+/// performance across releases.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Profile {
+    pub tool_version: String,
+    /// Seconds spent in the extract, transform and load phases and overall.
+    pub phases: BTreeMap<String, f64>,
+    /// Total seconds and count of every other span, such as `parse` or
+    /// `write_archive`.
+    pub spans: BTreeMap<String, SpanTimes>,
+    /// Records and seconds spent serializing and compressing each group.
+    pub groups: BTreeMap<String, GroupTimes>,
+    /// Seconds stages spent blocked, by the stage they waited on.
+    pub channel_waits: BTreeMap<String, SpanTimes>,
+    /// Peak resident memory of the process, where the platform reports it.
+    pub peak_memory_bytes: Option<u64>,
+    pub records: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SpanTimes {
+    pub count: usize,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct GroupTimes {
+    pub records: usize,
+    pub serialize_seconds: f64,
+    pub compress_seconds: f64,
+}
+
+impl Profile {
+    /// The timings collected by `layer` during the run that produced
+    /// `report`.
+    pub fn new(report: &RunReport, layer: &ProfileLayer) -> Self {
+        let collected = layer.collected.lock().expect("profile lock poisoned");
+        let phases = [
+            ("extract", report.extract_duration),
+            ("transform", report.transform_duration),
+            ("load", report.load_duration),
+            ("total", report.total_duration),
+        ]
+        .into_iter()
+        .map(|(phase, duration)| (phase.to_string(), duration.as_secs_f64()))
+        .collect();
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            phases,
+            spans: collected.spans.clone(),
+            groups: collected.groups.clone(),
+            channel_waits: collected.waits.clone(),
+            peak_memory_bytes: peak_memory_bytes(),
+            records: report.total_records(),
+        }
+    }
+
+    /// Write the profile as pretty-printed JSON to `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// A `tracing` layer adding up how long the pipeline's spans were open,
+/// for [`Profile::new`].
+#[derive(Debug, Clone, Default)]
+pub struct ProfileLayer {
+    collected: Arc<Mutex<Collected>>,
+}
+
+#[derive(Debug, Default)]
+struct Collected {
+    spans: BTreeMap<String, SpanTimes>,
+    groups: BTreeMap<String, GroupTimes>,
+    waits: BTreeMap<String, SpanTimes>,
+}
+
+/// When a span opened and the fields the profile is keyed by.
+struct Timing {
+    start: Instant,
+    fields: SpanFields,
+}
+
+#[derive(Default)]
+struct SpanFields {
+    group: Option<String>,
+    on: Option<String>,
+    records: Option<u64>,
+}
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "group" => self.group = Some(value.to_string()),
+            "on" => self.on = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "records" {
+            self.records = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        // Display fields such as `group = %name` arrive here
+        if field.name() == "group" {
+            self.group = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl<S> Layer<S> for ProfileLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = SpanFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing {
+                start: Instant::now(),
+                fields,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(timing) = span.extensions_mut().get_mut::<Timing>()
+        {
+            values.record(&mut timing.fields);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+        let seconds = timing.start.elapsed().as_secs_f64();
+        let add = |times: &mut SpanTimes| {
+            times.count += 1;
+            times.seconds += seconds;
+        };
+        let mut collected = self.collected.lock().expect("profile lock poisoned");
+        let fields = &timing.fields;
+        match (span.name(), &fields.group, &fields.on) {
+            (SERIALIZE_SPAN, Some(group), _) => {
+                let times = collected.groups.entry(group.clone()).or_default();
+                times.serialize_seconds += seconds;
+                if let Some(records) = fields.records {
+                    times.records = records as usize;
+                }
+            }
+            (COMPRESS_SPAN, Some(group), _) => {
+                let times = collected.groups.entry(group.clone()).or_default();
+                times.compress_seconds += seconds;
+            }
+            (WAIT_SPAN, _, Some(on)) => add(collected.waits.entry(on.clone()).or_default()),
+            (name, ..) => add(collected.spans.entry(name.to_string()).or_default()),
+        }
+    }
+}
+
+/// Peak resident set size of the process, from `VmHWM` on Linux.
+fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
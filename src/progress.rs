@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+/// Minimum number of bytes between two [`ProgressEvent::BytesRead`] events.
+const BYTES_REPORT_INTERVAL: u64 = 1024 * 1024;
+
+/// Number of records between two [`ProgressEvent::RecordsParsed`] events.
+pub const RECORDS_REPORT_INTERVAL: usize = 10_000;
+
+/// Notifications emitted while a pipeline runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Input bytes consumed so far, with the input size when known.
+    BytesRead { bytes: u64, total: Option<u64> },
+    /// Records received by the transform phase so far.
+    RecordsParsed { records: usize },
+    /// A group has been written to the output.
+    GroupWritten { name: String, records: usize },
+}
+
+type Callback = dyn Fn(&ProgressEvent) + Send + Sync;
+
+/// Cloneable handle forwarding [`ProgressEvent`]s to an optional callback.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<Callback>>);
+
+impl Progress {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&ProgressEvent) + Send + Sync + 'static,
+    {
+        Self(Some(Arc::new(callback)))
+    }
+
+    /// Whether a callback is registered; lets callers skip building events.
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.0 {
+            callback(&event);
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Progress").field(&self.is_enabled()).finish()
+    }
+}
+
+/// Turns a growing byte position into throttled
+/// [`ProgressEvent::BytesRead`] events.
+pub struct ByteProgress {
+    progress: Progress,
+    total: Option<u64>,
+    reported: u64,
+}
+
+impl ByteProgress {
+    pub fn new(progress: Progress, total: Option<u64>) -> Self {
+        Self {
+            progress,
+            total,
+            reported: 0,
+        }
+    }
+
+    /// Record that `bytes` have been consumed in total; `done` forces a
+    /// final report.
+    pub fn update(&mut self, bytes: u64, done: bool) {
+        if self.progress.is_enabled()
+            && (bytes - self.reported >= BYTES_REPORT_INTERVAL || (done && bytes != self.reported))
+        {
+            self.reported = bytes;
+            self.progress.emit(ProgressEvent::BytesRead {
+                bytes,
+                total: self.total,
+            });
+        }
+    }
+}
+
+/// Reader adapter reporting consumed bytes as [`ProgressEvent::BytesRead`].
+pub struct ProgressReader<R> {
+    inner: R,
+    bytes: u64,
+    counter: ByteProgress,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Progress, total: Option<u64>) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            counter: ByteProgress::new(progress, total),
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        self.counter.update(self.bytes, n == 0);
+        Ok(n)
+    }
+}
@@ -0,0 +1,435 @@
+//! Reading exports straight from `https://` and `s3://` URLs.
+
+use crate::apple_health::dates::format_timestamp;
+use crate::error::{AppError, Result};
+use crate::manifest::to_hex;
+use crate::retry::RetryPolicy;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io::{self, Read};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+/// URL schemes accepted in place of an input path.
+const SCHEMES: [&str; 3] = ["https://", "http://", "s3://"];
+
+/// How long connecting and waiting for the response headers may take.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a download that keeps failing without progress is resumed.
+const RESUMES: u32 = 5;
+
+/// Wait before resuming a download, doubled after each failed attempt.
+const RESUME_BACKOFF: Duration = Duration::from_secs(1);
+
+/// S3 region used when none is configured.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Whether `input` is a URL to download rather than a local path.
+pub fn is_url(input: &Path) -> bool {
+    input
+        .to_str()
+        .is_some_and(|input| SCHEMES.iter().any(|scheme| input.starts_with(scheme)))
+}
+
+/// `input` for display, without the query string of a URL, which holds the
+/// signature of presigned URLs.
+pub fn redact(input: &Path) -> String {
+    let display = input.display().to_string();
+    if !is_url(input) {
+        return display;
+    }
+    match display.split_once('?') {
+        Some((url, _)) => url.to_string(),
+        None => display,
+    }
+}
+
+/// Name of the file a URL points to, such as `export.zip`, to tell the
+/// kind of input apart as for local paths.
+pub fn file_name(input: &Path) -> &Path {
+    let url = input.to_str().unwrap_or_default();
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    Path::new(url.rsplit('/').next().unwrap_or(url))
+}
+
+/// A download read as it arrives, resumed with a `Range` request where the
+/// connection dropped.
+pub struct Download {
+    agent: ureq::Agent,
+    request: Request,
+    body: Option<ureq::BodyReader<'static>>,
+    /// Bytes received so far.
+    offset: u64,
+    size: Option<u64>,
+    etag: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl Download {
+    /// Start downloading `url`, retrying the request as `retry` allows.
+    pub fn open(url: &str, retry: RetryPolicy) -> Result<Self> {
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_connect(Some(TIMEOUT))
+            .timeout_recv_response(Some(TIMEOUT))
+            .build()
+            .into();
+        let mut download = Self {
+            agent,
+            request: Request::new(url)?,
+            body: None,
+            offset: 0,
+            size: None,
+            etag: None,
+            retry,
+        };
+        let response = retry
+            .run(
+                &format!("Downloading {}", download.request.redacted()),
+                || download.get().map_err(ureq::Error::into_io),
+            )
+            .map_err(|e| AppError::Download(format!("{}: {}", download.request.redacted(), e)))?;
+        download.size = response.body().content_length();
+        download.etag = response
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        download.body = Some(response.into_body().into_reader());
+        Ok(download)
+    }
+
+    /// Size of the download, when the server sent it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn get(&self) -> std::result::Result<ureq::http::Response<ureq::Body>, ureq::Error> {
+        let mut request = self
+            .agent
+            .get(&self.request.url)
+            // Offsets must count the bytes of the file, not of an encoding
+            .header("Accept-Encoding", "identity");
+        for (name, value) in self.request.headers() {
+            request = request.header(name, value);
+        }
+        if self.offset > 0 {
+            request = request.header("Range", format!("bytes={}-", self.offset));
+            if let Some(etag) = &self.etag {
+                // The whole file instead of the rest if it changed meanwhile
+                request = request.header("If-Range", etag);
+            }
+        }
+        request.call()
+    }
+
+    /// Request the rest of the file from `offset` on.
+    fn resume(&mut self) -> io::Result<()> {
+        let response = self.get().map_err(ureq::Error::into_io)?;
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "{} cannot be resumed: the server answered {} to a range request",
+                self.request.redacted(),
+                response.status()
+            )));
+        }
+        self.body = Some(response.into_body().into_reader());
+        Ok(())
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            let result = match &mut self.body {
+                Some(body) => body.read(buf),
+                None => match self.resume() {
+                    Ok(()) => continue,
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(n) => {
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if attempt < RESUMES.max(self.retry.retries) => {
+                    attempt += 1;
+                    let wait = RESUME_BACKOFF.saturating_mul(1 << (attempt - 1).min(16));
+                    warn!(
+                        "⚠️  Download of {} interrupted at byte {} ({}), resuming {}/{} in {:.1}s",
+                        self.request.redacted(),
+                        self.offset,
+                        e,
+                        attempt,
+                        RESUMES.max(self.retry.retries),
+                        wait.as_secs_f64()
+                    );
+                    self.body = None;
+                    thread::sleep(wait);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reader hashing the bytes read through it, so that a download is hashed
+/// for the manifest as it is extracted instead of being fetched twice.
+///
+/// Dropping it reads the rest of the input, which a parser may stop short
+/// of, and hands the digest over to its [`InputDigest`] unless that was
+/// dropped first.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    /// The first read error, after which the digest cannot be completed.
+    failed: Option<io::Error>,
+    digest: Option<oneshot::Sender<Result<String>>>,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> (Self, InputDigest) {
+        let (tx, rx) = oneshot::channel();
+        let reader = Self {
+            inner,
+            hasher: Sha256::new(),
+            failed: None,
+            digest: Some(tx),
+        };
+        (reader, InputDigest(rx))
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; crate::xml_utils::BUFFER_SIZE];
+        while self.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.hasher.update(&buf[..n]);
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(e),
+            Err(e) => {
+                self.failed
+                    .get_or_insert_with(|| io::Error::new(e.kind(), e.to_string()));
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<R: Read> Drop for HashingReader<R> {
+    fn drop(&mut self) {
+        let Some(digest) = self.digest.take().filter(|digest| !digest.is_closed()) else {
+            return;
+        };
+        let result = match self.failed.take() {
+            Some(e) => Err(e),
+            None => self.drain(),
+        };
+        let hasher = std::mem::take(&mut self.hasher);
+        let _ = digest.send(
+            result
+                .map(|()| to_hex(&hasher.finalize()))
+                .map_err(AppError::from),
+        );
+    }
+}
+
+/// The SHA-256 digest of everything read through a [`HashingReader`].
+pub struct InputDigest(oneshot::Receiver<Result<String>>);
+
+impl InputDigest {
+    /// Wait for the reader to be dropped and return the hex-encoded digest.
+    pub async fn wait(self) -> Result<String> {
+        self.0
+            .await
+            .map_err(|_| AppError::Unknown("the input was not hashed".to_string()))?
+    }
+}
+
+/// The HTTP request fetching a URL given as input, signed for S3 when it
+/// is an `s3://` URL and credentials are configured.
+struct Request {
+    url: String,
+    /// Parts of the request signed for S3.
+    s3: Option<S3Request>,
+}
+
+struct S3Request {
+    host: String,
+    /// Percent-encoded path of the object.
+    path: String,
+    region: String,
+    credentials: Credentials,
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Request {
+    fn new(url: &str) -> Result<Self> {
+        let Some(location) = url.strip_prefix("s3://") else {
+            return Ok(Self {
+                url: url.to_string(),
+                s3: None,
+            });
+        };
+        let (bucket, key) = location
+            .split_once('/')
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .ok_or_else(|| AppError::InvalidConfig(format!("{} names no object", url)))?;
+        let region = env::var("AWS_REGION")
+            .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| DEFAULT_REGION.to_string());
+        let key = encode_path(key);
+        // Path-style requests to a custom endpoint, such as MinIO
+        let (base, path) =
+            match env::var("AWS_ENDPOINT_URL_S3").or_else(|_| env::var("AWS_ENDPOINT_URL")) {
+                Ok(endpoint) => (
+                    endpoint.trim_end_matches('/').to_string(),
+                    format!("/{}/{}", bucket, key),
+                ),
+                Err(_) => (
+                    format!("https://{}.s3.{}.amazonaws.com", bucket, region),
+                    format!("/{}", key),
+                ),
+            };
+        let url = format!("{}{}", base, path);
+        let s3 = Credentials::from_env().map(|credentials| S3Request {
+            host: base
+                .split_once("://")
+                .map_or(base.as_str(), |(_, host)| host)
+                .to_string(),
+            path,
+            region,
+            credentials,
+        });
+        if s3.is_none() {
+            debug!("No AWS credentials set, requesting {} unsigned", url);
+        }
+        Ok(Self { url, s3 })
+    }
+
+    fn redacted(&self) -> String {
+        redact(Path::new(&self.url))
+    }
+
+    /// Headers to add to the request, signing it with AWS Signature
+    /// Version 4 for S3.
+    fn headers(&self) -> Vec<(&'static str, String)> {
+        let Some(s3) = &self.s3 else {
+            return Vec::new();
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        // 2024-01-15T08:30:00Z as 20240115T083000Z
+        let timestamp: String = format_timestamp(now)
+            .chars()
+            .filter(|c| !matches!(c, '-' | ':'))
+            .collect();
+        let date = &timestamp[..8];
+        let mut headers = vec![
+            ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &s3.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers: Vec<&str> = ["host"]
+            .into_iter()
+            .chain(headers.iter().map(|(name, _)| *name))
+            .collect();
+        let canonical_headers: String = [("host", s3.host.as_str())]
+            .into_iter()
+            .chain(headers.iter().map(|(name, value)| (*name, value.as_str())))
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = signed_headers.join(";");
+        let canonical_request = format!(
+            "GET\n{}\n\n{}\n{}\nUNSIGNED-PAYLOAD",
+            s3.path, canonical_headers, signed_headers
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, s3.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            to_hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = format!("AWS4{}", s3.credentials.secret_access_key);
+        let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key, s3.region.as_bytes());
+        let key = hmac_sha256(&key, b"s3");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = to_hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+        headers.push((
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                s3.credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        headers
+    }
+}
+
+impl Credentials {
+    /// Credentials from the standard AWS environment variables.
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// `key` with every byte but unreserved characters and `/` percent-encoded,
+/// as S3 signatures expect.
+fn encode_path(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
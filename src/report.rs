@@ -0,0 +1,872 @@
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::audio::AudioDays;
+use crate::calendar::WorkoutEvents;
+use crate::charts::Chart;
+use crate::core::Processable;
+use crate::cycle::CycleDays;
+use crate::error::{AppError, Result, SkipSummary, Truncation};
+use crate::manifest::Manifest;
+use crate::mindfulness::{MINDFUL_GROUP, MindfulSamples};
+use crate::splits::SplitSource;
+use crate::streaks::{ACTIVITY_GROUP, RingDays};
+use crate::zones::{WORKOUT_GROUP, ZoneSource};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Name of the HTML summary entry written into the archive.
+pub const REPORT_FILE_NAME: &str = "report.html";
+
+/// Name of the per-type statistics entry written into the archive.
+pub const STATISTICS_FILE_NAME: &str = "statistics.csv";
+
+/// Name of the per-source breakdown of each type written into the archive.
+pub const SOURCES_FILE_NAME: &str = "sources.csv";
+
+/// Name of the per-day rollup entry written into the archive.
+pub const DAILY_SUMMARY_FILE_NAME: &str = "daily_summary.csv";
+
+/// Name of the correlations between the daily metrics.
+pub const CORRELATIONS_FILE_NAME: &str = "correlations.csv";
+
+/// Name of the activity ring attainment entry written into the archive.
+pub const STREAKS_FILE_NAME: &str = "streaks.csv";
+
+/// Name of the daily audio exposure levels.
+pub const AUDIO_EXPOSURE_FILE_NAME: &str = "AudioExposureDaily.csv";
+
+/// Name of the per-day cycle-tracking summary.
+pub const CYCLE_TRACKING_FILE_NAME: &str = "CycleTracking.csv";
+
+/// Name of the index of the ECG recordings copied into the archive.
+pub const ECG_RECORDINGS_FILE_NAME: &str = "ECGRecordings.csv";
+
+/// Name of the kilometer and mile splits per workout.
+pub const WORKOUT_SPLITS_FILE_NAME: &str = "WorkoutSplits.csv";
+
+/// Name of the calendar of workouts.
+pub const WORKOUTS_CALENDAR_FILE_NAME: &str = "workouts.ics";
+
+/// Name of the time spent in each heart rate zone per workout.
+pub const WORKOUT_HR_ZONES_FILE_NAME: &str = "WorkoutHeartRateZones.csv";
+
+/// Name of the consolidated mindful sessions.
+pub const MINDFUL_SESSIONS_FILE_NAME: &str = "MindfulSessions.csv";
+
+/// Name of the daily metrics shaped for Home Assistant's statistics import.
+pub const HOME_ASSISTANT_FILE_NAME: &str = "home_assistant.csv";
+
+/// Source of the external statistics in `home_assistant.csv`, the part of
+/// each `statistic_id` before the colon.
+const HOME_ASSISTANT_SOURCE: &str = "apple_health";
+
+/// Record field naming the app or device a record came from.
+const SOURCE_FIELD: &str = "sourceName";
+
+/// Record field holding the measured quantity.
+const VALUE_FIELD: &str = "value";
+
+/// Sources listed per type; the rest are counted together.
+const SOURCES_PER_TYPE: usize = 3;
+
+/// Sleep analysis values that count as time asleep rather than in bed or
+/// awake.
+const ASLEEP_VALUES: [&str; 5] = [
+    "HKCategoryValueSleepAnalysisAsleep",
+    "HKCategoryValueSleepAnalysisAsleepUnspecified",
+    "HKCategoryValueSleepAnalysisAsleepCore",
+    "HKCategoryValueSleepAnalysisAsleepDeep",
+    "HKCategoryValueSleepAnalysisAsleepREM",
+];
+
+/// Summary documents that can be written alongside the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum ReportFormat {
+    /// A self-contained `report.html`
+    Html,
+    /// A `statistics.csv` with one row of counts, dates and values per type
+    Statistics,
+    /// A `sources.csv` with the records and date range of every source of
+    /// each type
+    Sources,
+    /// A `daily_summary.csv` with steps, energy, exercise, resting heart
+    /// rate and sleep per day
+    Daily,
+    /// SVG line charts of daily weight, resting heart rate and steps
+    Charts,
+    /// A `correlations.csv` with the correlation of each pair of daily
+    /// metrics, drawn as a heatmap in `charts/correlations.svg`
+    Correlations,
+    /// A Frictionless Table Schema `<type>.schema.json` next to each CSV
+    Schema,
+    /// A `streaks.csv` with activity ring goal attainment and streaks
+    Streaks,
+    /// A long-format `home_assistant.csv` of the daily metrics, ready for
+    /// Home Assistant's statistics import
+    HomeAssistant,
+    /// A `MindfulSessions.csv` merging back-to-back mindful samples from
+    /// the same app into sessions
+    Mindfulness,
+    /// A `CycleTracking.csv` combining flow, tests and symptoms per day
+    Cycle,
+    /// An `AudioExposureDaily.csv` with the daily environmental and
+    /// headphone sound levels
+    Audio,
+    /// A `WorkoutHeartRateZones.csv` with the minutes each workout spent in
+    /// heart rate zones 1 to 5
+    Zones,
+    /// A `workouts.ics` with one calendar event per workout
+    Calendar,
+    /// A `WorkoutSplits.csv` with the kilometer and mile splits of every
+    /// workout with distance samples
+    Splits,
+    /// The export's ECG recordings under `electrocardiograms/`, indexed in
+    /// an `ECGRecordings.csv`
+    Ecg,
+}
+
+/// The set of [`ReportFormat`]s to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Reports {
+    html: bool,
+    statistics: bool,
+    sources: bool,
+    daily: bool,
+    charts: bool,
+    correlations: bool,
+    schema: bool,
+    streaks: bool,
+    home_assistant: bool,
+    mindfulness: bool,
+    cycle: bool,
+    audio: bool,
+    zones: bool,
+    calendar: bool,
+    splits: bool,
+    ecg: bool,
+}
+
+impl Reports {
+    pub fn with(mut self, format: ReportFormat) -> Self {
+        match format {
+            ReportFormat::Html => self.html = true,
+            ReportFormat::Statistics => self.statistics = true,
+            ReportFormat::Sources => self.sources = true,
+            ReportFormat::Daily => self.daily = true,
+            ReportFormat::Charts => self.charts = true,
+            ReportFormat::Correlations => self.correlations = true,
+            ReportFormat::Schema => self.schema = true,
+            ReportFormat::Streaks => self.streaks = true,
+            ReportFormat::HomeAssistant => self.home_assistant = true,
+            ReportFormat::Mindfulness => self.mindfulness = true,
+            ReportFormat::Cycle => self.cycle = true,
+            ReportFormat::Audio => self.audio = true,
+            ReportFormat::Zones => self.zones = true,
+            ReportFormat::Calendar => self.calendar = true,
+            ReportFormat::Splits => self.splits = true,
+            ReportFormat::Ecg => self.ecg = true,
+        }
+        self
+    }
+
+    pub fn contains(&self, format: ReportFormat) -> bool {
+        match format {
+            ReportFormat::Html => self.html,
+            ReportFormat::Statistics => self.statistics,
+            ReportFormat::Sources => self.sources,
+            ReportFormat::Daily => self.daily,
+            ReportFormat::Charts => self.charts,
+            ReportFormat::Correlations => self.correlations,
+            ReportFormat::Schema => self.schema,
+            ReportFormat::Streaks => self.streaks,
+            ReportFormat::HomeAssistant => self.home_assistant,
+            ReportFormat::Mindfulness => self.mindfulness,
+            ReportFormat::Cycle => self.cycle,
+            ReportFormat::Audio => self.audio,
+            ReportFormat::Zones => self.zones,
+            ReportFormat::Calendar => self.calendar,
+            ReportFormat::Splits => self.splits,
+            ReportFormat::Ecg => self.ecg,
+        }
+    }
+
+    /// Whether any format is built from [`GroupSummary`]s.
+    pub fn needs_summaries(&self) -> bool {
+        self.html
+            || self.statistics
+            || self.sources
+            || self.daily
+            || self.charts
+            || self.correlations
+            || self.streaks
+            || self.home_assistant
+            || self.mindfulness
+            || self.cycle
+            || self.audio
+            || self.zones
+            || self.calendar
+            || self.splits
+    }
+}
+
+/// Smallest, largest and summed numeric values of a group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueStats {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: usize,
+}
+
+impl ValueStats {
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Record count, date coverage, sources and values of one group.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupSummary {
+    pub name: String,
+    pub records: usize,
+    /// Smallest and largest sort key, Unix timestamps for Apple Health.
+    pub first: Option<i64>,
+    pub last: Option<i64>,
+    /// Records per source name.
+    pub sources: BTreeMap<String, usize>,
+    /// Smallest and largest sort key per source name.
+    pub source_dates: BTreeMap<String, (i64, i64)>,
+    /// Statistics of the numeric `value` fields, if any.
+    pub values: Option<ValueStats>,
+    /// Per-day totals for groups that feed `daily_summary.csv`.
+    daily: Option<DailyTotals>,
+    /// Closed activity rings per day, for the activity summary group.
+    rings: Option<RingDays>,
+    /// Every sample, for the mindful session group.
+    mindful: Option<MindfulSamples>,
+    /// Entries per day, for the cycle-tracking groups.
+    cycle: Option<CycleDays>,
+    /// Sound levels per day, for the audio exposure groups.
+    audio: Option<AudioDays>,
+    /// Every workout, for the workout group.
+    workouts: Option<WorkoutEvents>,
+    /// Workouts, heart rate samples or the date of birth, when joining
+    /// them into heart rate zones.
+    zones: Option<ZoneSource>,
+    /// Workouts or distance samples, when joining them into splits.
+    splits: Option<SplitSource>,
+}
+
+impl GroupSummary {
+    pub fn new(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            daily: DailyMetric::of(&name).map(DailyTotals::new),
+            rings: (name == ACTIVITY_GROUP).then(RingDays::default),
+            mindful: (name == MINDFUL_GROUP).then(MindfulSamples::default),
+            cycle: CycleDays::new(&name),
+            audio: AudioDays::new(&name),
+            workouts: (name == WORKOUT_GROUP).then(WorkoutEvents::default),
+            name,
+            ..Self::default()
+        }
+    }
+
+    /// A summary that also collects what `reports` need beyond the
+    /// defaults of [`new`](Self::new), such as every heart rate sample for
+    /// [`ReportFormat::Zones`].
+    pub fn for_reports(name: impl Into<String>, reports: Reports) -> Self {
+        let mut summary = Self::new(name);
+        if reports.contains(ReportFormat::Zones) {
+            summary.zones = ZoneSource::new(&summary.name);
+        }
+        if reports.contains(ReportFormat::Splits) {
+            summary.splits = SplitSource::new(&summary.name);
+        }
+        summary
+    }
+
+    /// A summary that tracks [`daily_values`](Self::daily_values) for any
+    /// type, averaging the values of types without a rule of their own.
+    pub fn daily(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            daily: Some(DailyTotals::new(
+                DailyMetric::of(&name).unwrap_or(DailyMetric::Mean),
+            )),
+            name,
+            ..Self::default()
+        }
+    }
+
+    /// Like [`daily`](Self::daily), but tracking the values per hour, as
+    /// `2024-01-15 08:00`, with time asleep split across the hours it spans.
+    pub fn hourly(name: impl Into<String>) -> Self {
+        let mut summary = Self::daily(name);
+        if let Some(daily) = &mut summary.daily {
+            daily.hourly = true;
+        }
+        summary
+    }
+
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        self.records += 1;
+        if let Some(key) = record.sort_key() {
+            self.first = Some(self.first.map_or(key, |first| first.min(key)));
+            self.last = Some(self.last.map_or(key, |last| last.max(key)));
+        }
+        if let Some(source) = record.field(SOURCE_FIELD) {
+            match self.sources.get_mut(source) {
+                Some(count) => *count += 1,
+                None => {
+                    self.sources.insert(source.to_string(), 1);
+                }
+            }
+            if let Some(key) = record.sort_key() {
+                match self.source_dates.get_mut(source) {
+                    Some((first, last)) => {
+                        *first = (*first).min(key);
+                        *last = (*last).max(key);
+                    }
+                    None => {
+                        self.source_dates.insert(source.to_string(), (key, key));
+                    }
+                }
+            }
+        }
+        let value = record
+            .field(VALUE_FIELD)
+            .and_then(|v| v.parse::<f64>().ok());
+        if let Some(value) = value.filter(|v| v.is_finite()) {
+            let stats = self.values.get_or_insert(ValueStats {
+                min: value,
+                max: value,
+                sum: 0.0,
+                count: 0,
+            });
+            stats.min = stats.min.min(value);
+            stats.max = stats.max.max(value);
+            stats.sum += value;
+            stats.count += 1;
+        }
+        if let Some(daily) = &mut self.daily {
+            daily.add(record);
+        }
+        if let Some(rings) = &mut self.rings {
+            rings.add(record);
+        }
+        if let Some(mindful) = &mut self.mindful {
+            mindful.add(record);
+        }
+        if let Some(cycle) = &mut self.cycle {
+            cycle.add(record);
+        }
+        if let Some(audio) = &mut self.audio {
+            audio.add(record);
+        }
+        if let Some(workouts) = &mut self.workouts {
+            workouts.add(record);
+        }
+        if let Some(zones) = &mut self.zones {
+            zones.add(record);
+        }
+        if let Some(splits) = &mut self.splits {
+            splits.add(record);
+        }
+    }
+
+    /// The activity rings closed each day, for the activity summary group.
+    pub fn rings(&self) -> Option<&RingDays> {
+        self.rings.as_ref()
+    }
+
+    /// The samples of the mindful session group.
+    pub fn mindful_samples(&self) -> Option<&MindfulSamples> {
+        self.mindful.as_ref()
+    }
+
+    /// The entries per day of a cycle-tracking group.
+    pub fn cycle_days(&self) -> Option<&CycleDays> {
+        self.cycle.as_ref()
+    }
+
+    /// The sound levels per day of an audio exposure group.
+    pub fn audio_days(&self) -> Option<&AudioDays> {
+        self.audio.as_ref()
+    }
+
+    /// The workouts of the workout group.
+    pub fn workout_events(&self) -> Option<&WorkoutEvents> {
+        self.workouts.as_ref()
+    }
+
+    /// What the group contributes to the heart rate zones of the workouts.
+    pub fn zone_source(&self) -> Option<&ZoneSource> {
+        self.zones.as_ref()
+    }
+
+    /// What the group contributes to the splits of the workouts.
+    pub fn split_source(&self) -> Option<&SplitSource> {
+        self.splits.as_ref()
+    }
+
+    /// The group's value on each day it has data, for groups tracked per
+    /// day such as steps or weight, or on each hour for an
+    /// [`hourly`](Self::hourly) summary.
+    pub fn daily_values(&self) -> Vec<(&str, f64)> {
+        self.daily.as_ref().map_or_else(Vec::new, |daily| {
+            daily
+                .days
+                .iter()
+                .map(|(day, sources)| (day.as_str(), daily.value(sources)))
+                .collect()
+        })
+    }
+}
+
+/// A quantity tracked per day and the type it is computed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DailyMetric {
+    Steps,
+    ActiveEnergy,
+    Exercise,
+    RestingHeartRate,
+    Sleep,
+    BodyMass,
+    /// The average value of any other type.
+    Mean,
+}
+
+impl DailyMetric {
+    /// The columns of `daily_summary.csv`.
+    const COLUMNS: [DailyMetric; 5] = [
+        DailyMetric::Steps,
+        DailyMetric::ActiveEnergy,
+        DailyMetric::Exercise,
+        DailyMetric::RestingHeartRate,
+        DailyMetric::Sleep,
+    ];
+
+    fn of(group: &str) -> Option<Self> {
+        match group {
+            "HKQuantityTypeIdentifierStepCount" => Some(DailyMetric::Steps),
+            "HKQuantityTypeIdentifierActiveEnergyBurned" => Some(DailyMetric::ActiveEnergy),
+            "HKQuantityTypeIdentifierAppleExerciseTime" => Some(DailyMetric::Exercise),
+            "HKQuantityTypeIdentifierRestingHeartRate" => Some(DailyMetric::RestingHeartRate),
+            "HKCategoryTypeIdentifierSleepAnalysis" => Some(DailyMetric::Sleep),
+            "HKQuantityTypeIdentifierBodyMass" => Some(DailyMetric::BodyMass),
+            _ => None,
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            DailyMetric::Steps => "steps",
+            DailyMetric::ActiveEnergy => "active_energy_kcal",
+            DailyMetric::Exercise => "exercise_minutes",
+            DailyMetric::RestingHeartRate => "resting_heart_rate",
+            DailyMetric::Sleep => "sleep_minutes",
+            DailyMetric::BodyMass => "body_mass",
+            DailyMetric::Mean => "mean",
+        }
+    }
+
+    /// Unit of the daily value, left empty where the export chooses it, as
+    /// for weight.
+    fn unit(self) -> &'static str {
+        match self {
+            DailyMetric::Steps => "steps",
+            DailyMetric::ActiveEnergy => "kcal",
+            DailyMetric::Exercise | DailyMetric::Sleep => "min",
+            DailyMetric::RestingHeartRate => "bpm",
+            DailyMetric::BodyMass | DailyMetric::Mean => "",
+        }
+    }
+
+    /// The day a record counts towards, as written in the export, and its
+    /// contribution. Sleep counts towards the day it ends on.
+    fn sample<T: Processable>(self, record: &T) -> Option<(String, f64)> {
+        let day = |field: &str| {
+            let date = record.field(field)?;
+            parse_timestamp(date)?;
+            Some(date[..10].to_string())
+        };
+        match self {
+            DailyMetric::Sleep => {
+                if !ASLEEP_VALUES.contains(&record.field(VALUE_FIELD)?) {
+                    return None;
+                }
+                let start = parse_timestamp(record.field("startDate")?)?;
+                let end = parse_timestamp(record.field("endDate")?)?;
+                Some((day("endDate")?, (end - start).max(0) as f64 / 60.0))
+            }
+            _ => {
+                let value = record.field(VALUE_FIELD)?.parse::<f64>().ok();
+                let value = value.filter(|v| v.is_finite())?;
+                Some((day("startDate")?, value))
+            }
+        }
+    }
+
+    /// The hours a record counts towards, in the export's local time, and
+    /// its contribution to each. Sleep is split across the hours it spans.
+    fn hourly_samples<T: Processable>(self, record: &T) -> Vec<(String, f64)> {
+        // Dates read without their offset give the local clock time
+        let local = |field: &str| {
+            let date = record.field(field)?;
+            parse_timestamp(date)?;
+            parse_timestamp(date.get(..19)?)
+        };
+        let hour = |seconds: i64| {
+            let date = format_timestamp(seconds - seconds.rem_euclid(3600));
+            format!("{} {}", &date[..10], &date[11..16])
+        };
+        match self {
+            DailyMetric::Sleep => {
+                let asleep = record
+                    .field(VALUE_FIELD)
+                    .is_some_and(|value| ASLEEP_VALUES.contains(&value));
+                let (Some(mut at), Some(end)) = (local("startDate"), local("endDate")) else {
+                    return Vec::new();
+                };
+                let mut samples = Vec::new();
+                while asleep && at < end {
+                    let next = (at - at.rem_euclid(3600) + 3600).min(end);
+                    samples.push((hour(at), (next - at) as f64 / 60.0));
+                    at = next;
+                }
+                samples
+            }
+            _ => {
+                let value = record
+                    .field(VALUE_FIELD)
+                    .and_then(|v| v.parse::<f64>().ok());
+                match (value.filter(|v| v.is_finite()), local("startDate")) {
+                    (Some(value), Some(start)) => vec![(hour(start), value)],
+                    _ => Vec::new(),
+                }
+            }
+        }
+    }
+}
+
+/// Sums and sample counts of one metric per day and source.
+#[derive(Debug, Clone, PartialEq)]
+struct DailyTotals {
+    metric: DailyMetric,
+    /// Whether the totals are kept per hour rather than per day.
+    hourly: bool,
+    days: BTreeMap<String, BTreeMap<String, (f64, usize)>>,
+}
+
+impl DailyTotals {
+    fn new(metric: DailyMetric) -> Self {
+        Self {
+            metric,
+            hourly: false,
+            days: BTreeMap::new(),
+        }
+    }
+
+    fn add<T: Processable>(&mut self, record: &T) {
+        let samples = if self.hourly {
+            self.metric.hourly_samples(record)
+        } else {
+            self.metric.sample(record).into_iter().collect()
+        };
+        let source = record.field(SOURCE_FIELD).unwrap_or_default();
+        for (day, value) in samples {
+            let total = self
+                .days
+                .entry(day)
+                .or_default()
+                .entry(source.to_string())
+                .or_default();
+            total.0 += value;
+            total.1 += 1;
+        }
+    }
+
+    /// The day's value: the mean over every sample for the resting heart
+    /// rate, weight and other types, otherwise the total of the source that
+    /// recorded the most, so a phone and a watch counting the same steps are
+    /// not added together.
+    fn value(&self, sources: &BTreeMap<String, (f64, usize)>) -> f64 {
+        match self.metric {
+            DailyMetric::RestingHeartRate | DailyMetric::BodyMass | DailyMetric::Mean => {
+                let (sum, count) = sources
+                    .values()
+                    .fold((0.0, 0), |(sum, count), (s, c)| (sum + s, count + c));
+                sum / count as f64
+            }
+            _ => sources.values().map(|(sum, _)| *sum).fold(0.0, f64::max),
+        }
+    }
+}
+
+/// One row per day with the daily steps, active energy, exercise minutes,
+/// resting heart rate and minutes asleep found in `groups`, left empty where
+/// the export has no data.
+pub fn daily_summary_csv(groups: &[GroupSummary]) -> Result<Vec<u8>> {
+    let mut days: BTreeMap<&str, [Option<f64>; DailyMetric::COLUMNS.len()]> = BTreeMap::new();
+    for daily in groups.iter().filter_map(|g| g.daily.as_ref()) {
+        let Some(column) = DailyMetric::COLUMNS.iter().position(|m| *m == daily.metric) else {
+            continue;
+        };
+        for (day, sources) in &daily.days {
+            days.entry(day).or_default()[column] = Some(daily.value(sources));
+        }
+    }
+
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut header = vec!["date"];
+    header.extend(DailyMetric::COLUMNS.map(DailyMetric::column));
+    w.write_record(&header)?;
+    for (day, values) in days {
+        let mut row = vec![day.to_string()];
+        row.extend(values.map(|value| {
+            value
+                .map(|v| ((v * 100.0).round() / 100.0).to_string())
+                .unwrap_or_default()
+        }));
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
+
+/// The metrics of `daily_summary.csv` in the long format read by Home
+/// Assistant's statistics import: one row per metric and day with its
+/// `statistic_id` (such as `apple_health:steps`), unit, the day's start as
+/// `dd.mm.yyyy 00:00` and the day's value as its `min`, `max` and `mean`.
+pub fn home_assistant_csv(groups: &[GroupSummary]) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record(["statistic_id", "unit", "start", "min", "max", "mean"])?;
+    for metric in DailyMetric::COLUMNS {
+        let Some(daily) = groups
+            .iter()
+            .filter_map(|g| g.daily.as_ref())
+            .find(|daily| daily.metric == metric)
+        else {
+            continue;
+        };
+        let statistic_id = format!("{}:{}", HOME_ASSISTANT_SOURCE, metric.column());
+        for (day, sources) in &daily.days {
+            let start = format!("{}.{}.{} 00:00", &day[8..10], &day[5..7], &day[..4]);
+            let value = ((daily.value(sources) * 100.0).round() / 100.0).to_string();
+            w.write_record([
+                statistic_id.as_str(),
+                metric.unit(),
+                &start,
+                &value,
+                &value,
+                &value,
+            ])?;
+        }
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
+
+/// One row per group with its record count, first and last date, number of
+/// distinct sources and the minimum, mean and maximum of its numeric values.
+pub fn statistics_csv(groups: &[GroupSummary]) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record([
+        "type", "records", "first", "last", "sources", "min", "mean", "max",
+    ])?;
+    for group in groups {
+        let timestamp = |t: Option<i64>| t.map(format_timestamp).unwrap_or_default();
+        let value = |f: fn(&ValueStats) -> f64| {
+            group
+                .values
+                .as_ref()
+                .map(|stats| f(stats).to_string())
+                .unwrap_or_default()
+        };
+        w.write_record([
+            group.name.clone(),
+            group.records.to_string(),
+            timestamp(group.first),
+            timestamp(group.last),
+            group.sources.len().to_string(),
+            value(|s| s.min),
+            value(ValueStats::mean),
+            value(|s| s.max),
+        ])?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
+
+/// One row per source of each group with its record count and first and
+/// last date, the sources of a group ordered from the most records down.
+pub fn sources_csv(groups: &[GroupSummary]) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record(["type", "source", "records", "first", "last"])?;
+    for group in groups {
+        let mut sources: Vec<(&String, &usize)> = group.sources.iter().collect();
+        sources.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (source, count) in sources {
+            let dates = group.source_dates.get(source);
+            let timestamp = |t: Option<i64>| t.map(format_timestamp).unwrap_or_default();
+            w.write_record([
+                group.name.clone(),
+                source.clone(),
+                count.to_string(),
+                timestamp(dates.map(|d| d.0)),
+                timestamp(dates.map(|d| d.1)),
+            ])?;
+        }
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
+
+/// An HTML overview of an archive: totals, date coverage and sources per
+/// type, and anything that went wrong while reading the export.
+#[derive(Debug, Clone, Default)]
+pub struct HtmlReport {
+    tool_version: String,
+    export_date: Option<String>,
+    inputs: Vec<String>,
+    skipped: usize,
+    truncated: Option<Truncation>,
+}
+
+impl HtmlReport {
+    pub fn new(manifest: &Manifest, skipped: &SkipSummary) -> Self {
+        Self {
+            tool_version: manifest.tool_version.clone(),
+            export_date: manifest.export_date.clone(),
+            inputs: manifest.inputs.iter().map(|i| i.path.clone()).collect(),
+            skipped: skipped.total(),
+            truncated: manifest.truncated.clone(),
+        }
+    }
+
+    /// Render the report for `groups` as a single HTML page with inline
+    /// styles, listing the groups in the given order and ending with any
+    /// `charts`.
+    pub fn render(&self, groups: &[GroupSummary], charts: &[Chart]) -> String {
+        let total: usize = groups.iter().map(|g| g.records).sum();
+        let first = groups.iter().filter_map(|g| g.first).min();
+        let last = groups.iter().filter_map(|g| g.last).max();
+        let mut sources: BTreeMap<&str, usize> = BTreeMap::new();
+        for group in groups {
+            for (source, count) in &group.sources {
+                *sources.entry(source).or_insert(0) += count;
+            }
+        }
+
+        let mut html = String::new();
+        html.push_str(concat!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n",
+            "<title>Export summary</title>\n<style>\n",
+            "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\n",
+            "table{border-collapse:collapse;margin-bottom:2em}\n",
+            "th,td{border:1px solid #ccc;padding:.3em .6em;text-align:left}\n",
+            "td.n{text-align:right;font-variant-numeric:tabular-nums}\n",
+            "th{background:#f3f3f3}\n.warn{color:#a40}\n",
+            "</style>\n</head>\n<body>\n<h1>Export summary</h1>\n<table>\n",
+        ));
+        let mut row = |label: &str, value: String| {
+            let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", label, value);
+        };
+        row("Records", total.to_string());
+        row("Types", groups.len().to_string());
+        row("Date coverage", coverage(first, last));
+        row("Sources", sources.len().to_string());
+        row(
+            "Export date",
+            escape(self.export_date.as_deref().unwrap_or("unknown")),
+        );
+        row("Input", escape(&self.inputs.join(", ")));
+        row("Tool version", escape(&self.tool_version));
+        html.push_str("</table>\n");
+
+        if self.skipped > 0 {
+            let _ = writeln!(
+                html,
+                "<p class=\"warn\">{} malformed elements were skipped; see errors.csv.</p>",
+                self.skipped
+            );
+        }
+        if let Some(truncated) = &self.truncated {
+            let _ = writeln!(
+                html,
+                "<p class=\"warn\">The export is unreadable past byte {} ({}); data ends around {}.</p>",
+                truncated.offset,
+                escape(&truncated.message),
+                truncated
+                    .last_sort_key
+                    .map_or_else(|| "an unknown date".to_string(), format_timestamp)
+            );
+        }
+
+        html.push_str(concat!(
+            "<h2>Types</h2>\n<table>\n",
+            "<tr><th>Type</th><th>Records</th><th>First</th><th>Last</th><th>Sources</th></tr>\n",
+        ));
+        for group in groups {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"n\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&group.name),
+                group.records,
+                date(group.first),
+                date(group.last),
+                top_sources(&group.sources)
+            );
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Sources</h2>\n<table>\n<tr><th>Source</th><th>Records</th></tr>\n");
+        let mut sources: Vec<(&str, usize)> = sources.into_iter().collect();
+        sources.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (source, count) in sources {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td class=\"n\">{}</td></tr>",
+                escape(source),
+                count
+            );
+        }
+        html.push_str("</table>\n");
+
+        if !charts.is_empty() {
+            html.push_str("<h2>Charts</h2>\n");
+            for chart in charts {
+                let _ = writeln!(html, "<figure>{}</figure>", chart.svg);
+            }
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+fn date(timestamp: Option<i64>) -> String {
+    timestamp.map_or_else(String::new, |t| format_timestamp(t)[..10].to_string())
+}
+
+fn coverage(first: Option<i64>, last: Option<i64>) -> String {
+    match (first, last) {
+        (Some(first), Some(last)) => format!("{} to {}", date(Some(first)), date(Some(last))),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// The most frequent sources of a group, e.g. `Apple Watch (120), iPhone (3)`.
+fn top_sources(sources: &BTreeMap<String, usize>) -> String {
+    let mut sorted: Vec<(&String, &usize)> = sources.iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    let mut listed: Vec<String> = sorted
+        .iter()
+        .take(SOURCES_PER_TYPE)
+        .map(|(source, count)| format!("{} ({})", escape(source), count))
+        .collect();
+    if sorted.len() > SOURCES_PER_TYPE {
+        listed.push(format!("{} more", sorted.len() - SOURCES_PER_TYPE));
+    }
+    listed.join(", ")
+}
+
+fn escape(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
@@ -0,0 +1,131 @@
+use log::warn;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// `EIO`, which network and FUSE file systems return for transient failures
+/// as well as for failing disks.
+#[cfg(unix)]
+const EIO: i32 = 5;
+
+/// How often file operations failing with a transient error are retried,
+/// as happens on NFS, SMB or FUSE-mounted cloud drives.
+///
+/// The first retry waits `backoff`, and each further one twice as long as
+/// the one before. The default retries nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub retries: u32,
+    /// Wait before the first retry.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Run `op`, retrying it while it fails with a transient error and
+    /// retries are left. `what` names the operation in the log.
+    pub fn run<T>(&self, what: &str, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    let wait = self.backoff.saturating_mul(1 << (attempt - 1).min(16));
+                    warn!(
+                        "⚠️  {} failed ({}), retry {}/{} in {:.1}s",
+                        what,
+                        e,
+                        attempt,
+                        self.retries,
+                        wait.as_secs_f64()
+                    );
+                    thread::sleep(wait);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Open `path` for reading.
+    pub fn open(&self, path: &Path) -> io::Result<Retrying<File>> {
+        let file = self.run(&format!("Opening {}", path.display()), || File::open(path))?;
+        Ok(Retrying::new(file, *self))
+    }
+
+    /// Create or truncate `path` for writing.
+    pub fn create(&self, path: &Path) -> io::Result<Retrying<File>> {
+        let file = self.run(&format!("Creating {}", path.display()), || {
+            File::create(path)
+        })?;
+        Ok(Retrying::new(file, *self))
+    }
+}
+
+/// Whether `error` may go away when the operation is tried again.
+pub fn is_transient(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(EIO) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ResourceBusy
+            | ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+/// A reader or writer whose every read, write, flush and seek is retried
+/// by a [`RetryPolicy`].
+///
+/// A read or write that fails transfers nothing, so trying it again at the
+/// same position continues exactly where it stopped.
+#[derive(Debug)]
+pub struct Retrying<T> {
+    inner: T,
+    policy: RetryPolicy,
+}
+
+impl<T> Retrying<T> {
+    pub fn new(inner: T, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Retrying<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run("Reading", || inner.read(buf))
+    }
+}
+
+impl<W: Write> Write for Retrying<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run("Writing", || inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.run("Flushing", || inner.flush())
+    }
+}
+
+impl<S: Seek> Seek for Retrying<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let inner = &mut self.inner;
+        self.policy.run("Seeking", || inner.seek(pos))
+    }
+}
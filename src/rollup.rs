@@ -0,0 +1,164 @@
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::apple_health::types::GenericRecord;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use crate::merge::entry_rows;
+use crate::report::{GroupSummary, ValueStats};
+use crate::sinks::csv_zip::{DecimalSeparator, NUMERIC_COLUMNS, PROTOBUF_EXTENSION, group_name};
+use log::{debug, warn};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use zip::{ZipArchive, ZipWriter, write::FileOptions};
+
+/// Length of the rows of a rollup archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum RollupPeriod {
+    /// One row per week, from Monday to Sunday
+    Weekly,
+    /// One row per calendar month
+    Monthly,
+}
+
+impl RollupPeriod {
+    fn name(self) -> &'static str {
+        match self {
+            RollupPeriod::Weekly => "weekly",
+            RollupPeriod::Monthly => "monthly",
+        }
+    }
+
+    /// Path of the rollup archive written beside `archive`, such as
+    /// `health_weekly.zip` for `health.zip`.
+    pub fn archive_path(self, archive: &Path) -> PathBuf {
+        let stem = archive.file_stem().unwrap_or_default().to_string_lossy();
+        archive.with_file_name(format!("{}_{}.zip", stem, self.name()))
+    }
+
+    /// The period `date` falls in, by the day it is written with: the
+    /// Monday starting its week as `2024-01-15`, or its month as `2024-01`.
+    fn period(self, date: &str) -> Option<String> {
+        parse_timestamp(date)?;
+        let day = date.get(..10)?;
+        match self {
+            RollupPeriod::Weekly => {
+                let days = parse_timestamp(day)? / 86_400;
+                // 1970-01-01 was a Thursday
+                let monday = days - (days + 3).rem_euclid(7);
+                Some(format_timestamp(monday * 86_400)[..10].to_string())
+            }
+            RollupPeriod::Monthly => Some(day[..7].to_string()),
+        }
+    }
+}
+
+/// Records starting in a period and statistics of its daily values.
+type PeriodTotals = (usize, Option<ValueStats>);
+
+/// Roll every group of the archive at `archive_path` up into a CSV of the
+/// same name in a new archive at `output_path`, with one row per week or
+/// month: the records starting in it, then the days with a value and the
+/// total, mean, smallest and largest of those daily values, combined per
+/// day as in `daily_summary.csv`. Groups without dated records are left
+/// out. Numbers are read with the `separator` the archive was written with.
+/// Returns the number of groups written.
+pub fn rollup(
+    archive_path: &Path,
+    period: RollupPeriod,
+    separator: DecimalSeparator,
+    output_path: &Path,
+) -> Result<usize> {
+    let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(output_path)?));
+    let mut written = 0;
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        let Some(group) = group_name(&name).map(str::to_string) else {
+            continue;
+        };
+        if name.ends_with(PROTOBUF_EXTENSION) {
+            warn!(
+                "Leaving {} out of the rollup: protobuf entries cannot be read back",
+                name
+            );
+            continue;
+        }
+
+        let mut summary = GroupSummary::daily(&group);
+        let mut periods: BTreeMap<String, PeriodTotals> = BTreeMap::new();
+        for mut attributes in entry_rows(&name, entry)? {
+            for column in NUMERIC_COLUMNS {
+                if let Some(value) = attributes.get_mut(column)
+                    && let Cow::Owned(read) = separator.read(value)
+                {
+                    *value = read.into();
+                }
+            }
+            let record = GenericRecord {
+                element_name: group.clone(),
+                attributes,
+            };
+            if let Some(start) = record.field("startDate").and_then(|d| period.period(d)) {
+                periods.entry(start).or_default().0 += 1;
+            }
+            summary.add(&record);
+        }
+        for (day, value) in summary.daily_values() {
+            let Some(start) = period.period(day) else {
+                continue;
+            };
+            let stats = periods
+                .entry(start)
+                .or_default()
+                .1
+                .get_or_insert(ValueStats {
+                    min: value,
+                    max: value,
+                    sum: 0.0,
+                    count: 0,
+                });
+            stats.min = stats.min.min(value);
+            stats.max = stats.max.max(value);
+            stats.sum += value;
+            stats.count += 1;
+        }
+        if periods.is_empty() {
+            debug!("No dated records of '{}' to roll up", group);
+            continue;
+        }
+
+        zip.start_file(format!("{}.csv", group), FileOptions::<()>::default())?;
+        zip.write_all(&periods_csv(period, &periods)?)?;
+        written += 1;
+    }
+    zip.finish()?.flush()?;
+    Ok(written)
+}
+
+fn periods_csv(period: RollupPeriod, periods: &BTreeMap<String, PeriodTotals>) -> Result<Vec<u8>> {
+    let round = |value: f64| ((value * 100.0).round() / 100.0).to_string();
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let column = match period {
+        RollupPeriod::Weekly => "week",
+        RollupPeriod::Monthly => "month",
+    };
+    w.write_record([column, "records", "days", "total", "mean", "min", "max"])?;
+    for (start, (records, stats)) in periods {
+        let mut row = vec![start.clone(), records.to_string()];
+        match stats {
+            Some(stats) => row.extend([
+                stats.count.to_string(),
+                round(stats.sum),
+                round(stats.mean()),
+                round(stats.min),
+                round(stats.max),
+            ]),
+            None => row.extend(["0", "", "", "", ""].map(String::from)),
+        }
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
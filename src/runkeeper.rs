@@ -0,0 +1,256 @@
+//! RunKeeper's data export: a ZIP with the activities in
+//! `cardioActivities.csv` and the route of each in a GPX file.
+
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::error::{AppError, ElementError, Result};
+use crate::intern::intern_key;
+use log::{debug, warn};
+use quick_xml::events::Event;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+/// Entry of the export listing every activity.
+pub const ACTIVITIES_FILE: &str = "cardioActivities.csv";
+
+/// Group of the points of the activities' routes.
+pub const ROUTE_POINT_ELEMENT: &str = "RunKeeperRoutePoint";
+
+/// Attribute naming the GPX file of an activity's route, which ties the
+/// route points to their workout.
+pub const GPX_FILE_ATTRIBUTE: &str = "gpxFile";
+
+/// Value of `sourceName` for every activity.
+const SOURCE_NAME: &str = "RunKeeper";
+
+/// Attributes of the columns of `cardioActivities.csv`, by column name
+/// without the unit in parentheses. The unit goes into an attribute of the
+/// same name suffixed with `Unit`.
+const COLUMNS: [(&str, &str); 11] = [
+    ("Activity Id", "activityId"),
+    ("Route Name", "routeName"),
+    ("Distance", "totalDistance"),
+    ("Average Pace", "averagePace"),
+    ("Average Speed", "averageSpeed"),
+    ("Calories Burned", "totalEnergyBurned"),
+    ("Climb", "elevationAscended"),
+    ("Average Heart Rate", "averageHeartRate"),
+    ("Friend's Tagged", "friendsTagged"),
+    ("Notes", "notes"),
+    ("GPX File", GPX_FILE_ATTRIBUTE),
+];
+
+/// Activity types whose HealthKit name is not the type without spaces.
+const ACTIVITY_TYPES: [(&str, &str); 6] = [
+    ("Mountain Biking", "Cycling"),
+    ("Spinning", "Cycling"),
+    ("Skating", "SkatingSports"),
+    ("Strength Training", "TraditionalStrengthTraining"),
+    ("Circuit Training", "FunctionalStrengthTraining"),
+    ("Wheelchair", "WheelchairRunPace"),
+];
+
+/// Whether `path` is a RunKeeper export: a ZIP archive with a
+/// `cardioActivities.csv`.
+pub fn is_runkeeper_export(path: &Path) -> bool {
+    if path.extension().and_then(|s| s.to_str()) != Some("zip") {
+        return false;
+    }
+    File::open(path)
+        .ok()
+        .and_then(|file| zip::ZipArchive::new(file).ok())
+        .is_some_and(|archive| archive.file_names().any(is_activities_file))
+}
+
+fn is_activities_file(name: &str) -> bool {
+    name.rsplit('/').next() == Some(ACTIVITIES_FILE)
+}
+
+/// Every activity of a RunKeeper export as a `Workout` with attributes
+/// named as in Apple's export, and every point of their routes as a
+/// [`ROUTE_POINT_ELEMENT`].
+///
+/// RunKeeper writes the activities' dates in local time without an
+/// offset, so they are kept that way; the GPX times are in UTC. GPX files
+/// that cannot be parsed are skipped with a warning.
+pub fn read_export<R: Read + Seek>(zip: R) -> Result<Vec<GenericRecord>> {
+    let mut archive = zip::ZipArchive::new(zip)?;
+    let mut records = Vec::new();
+    let activities = archive
+        .file_names()
+        .find(|name| is_activities_file(name))
+        .map(str::to_string)
+        .ok_or_else(|| AppError::MissingEntry(ACTIVITIES_FILE.to_string()))?;
+    {
+        let mut reader = csv::Reader::from_reader(archive.by_name(&activities)?);
+        let headers = reader.headers()?.clone();
+        for row in reader.records() {
+            records.push(workout_record(&headers, &row?));
+        }
+    }
+
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if !entry.name().ends_with(".gpx") {
+            continue;
+        }
+        let file_name = entry
+            .name()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        match route_points(entry, &file_name) {
+            Ok(points) => {
+                debug!("Read {} route points of {}", points.len(), file_name);
+                records.extend(points);
+            }
+            Err(e) => warn!("Skipping {}: {}", file_name, e),
+        }
+    }
+    Ok(records)
+}
+
+fn workout_record(headers: &csv::StringRecord, row: &csv::StringRecord) -> GenericRecord {
+    let mut attributes = Attributes::new();
+    insert(&mut attributes, "sourceName", SOURCE_NAME);
+    let mut start = None;
+    let mut minutes = None;
+    for (header, value) in headers.iter().zip(row) {
+        if value.is_empty() {
+            continue;
+        }
+        // `Distance (km)`
+        let (column, unit) = match header.split_once(" (") {
+            Some((column, unit)) => (column, unit.strip_suffix(')')),
+            None => (header, None),
+        };
+        match column {
+            "Date" => start = Some(value),
+            "Duration" => minutes = duration_minutes(value),
+            "Type" => insert(
+                &mut attributes,
+                "workoutActivityType",
+                &activity_type(value),
+            ),
+            _ => {
+                let Some((_, key)) = COLUMNS.iter().find(|(name, _)| *name == column) else {
+                    debug!("Leaving '{}' out of a RunKeeper activity", header);
+                    continue;
+                };
+                insert(&mut attributes, key, value);
+                if let Some(unit) = unit {
+                    insert(&mut attributes, &format!("{}Unit", key), unit);
+                }
+            }
+        }
+    }
+    if let Some(start) = start {
+        insert(&mut attributes, "startDate", start);
+        // The end in the same local time as the start
+        if let (Some(seconds), Some(minutes)) = (parse_timestamp(start), minutes) {
+            let end = format_timestamp(seconds + (minutes * 60.0).round() as i64);
+            insert(&mut attributes, "endDate", &end[..19].replace('T', " "));
+        }
+    }
+    if let Some(minutes) = minutes {
+        insert(&mut attributes, "duration", &minutes.to_string());
+        insert(&mut attributes, "durationUnit", "min");
+    }
+    GenericRecord {
+        element_name: "Workout".to_string(),
+        attributes,
+    }
+}
+
+/// HealthKit activity type of a RunKeeper activity type, such as
+/// `HKWorkoutActivityTypeCrossCountrySkiing` for `Cross-Country Skiing`.
+fn activity_type(typ: &str) -> String {
+    let name = ACTIVITY_TYPES
+        .iter()
+        .find(|(from, _)| *from == typ)
+        .map_or_else(
+            || typ.chars().filter(|c| c.is_ascii_alphanumeric()).collect(),
+            |(_, to)| to.to_string(),
+        );
+    format!("HKWorkoutActivityType{}", name)
+}
+
+/// Minutes of a duration written as `h:mm:ss` or `mm:ss`.
+fn duration_minutes(duration: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in duration.trim().split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds / 60.0)
+}
+
+/// The track points of a GPX file, with their time, position and
+/// elevation.
+fn route_points<R: Read>(gpx: R, file_name: &str) -> Result<Vec<GenericRecord>> {
+    let mut reader = quick_xml::Reader::from_reader(BufReader::new(gpx));
+    reader.config_mut().trim_text(true);
+    let mut buf = Vec::new();
+    let mut points = Vec::new();
+    let mut point: Option<Attributes> = None;
+    // Child of the current point whose text is being read
+    let mut child: Option<&str> = None;
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"trkpt" => {
+                let mut attributes = Attributes::new();
+                for attribute in e.attributes().flatten() {
+                    let key = match attribute.key.as_ref() {
+                        b"lat" => "latitude",
+                        b"lon" => "longitude",
+                        _ => continue,
+                    };
+                    let value = attribute.unescape_value().map_err(|e| invalid(&e))?;
+                    insert(&mut attributes, key, &value);
+                }
+                insert(&mut attributes, GPX_FILE_ATTRIBUTE, file_name);
+                point = Some(attributes);
+            }
+            Ok(Event::Start(e)) if point.is_some() => {
+                child = match e.local_name().as_ref() {
+                    b"time" => Some("startDate"),
+                    b"ele" => Some("elevation"),
+                    _ => None,
+                };
+            }
+            Ok(Event::Text(text)) => {
+                if let (Some(attributes), Some(key)) = (&mut point, child) {
+                    let value = text.decode().map_err(|e| invalid(&e))?;
+                    insert(attributes, key, &value);
+                    // Points last no time
+                    if key == "startDate" {
+                        insert(attributes, "endDate", &value);
+                    }
+                }
+            }
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"trkpt" => {
+                if let Some(attributes) = point.take() {
+                    points.push(GenericRecord {
+                        element_name: ROUTE_POINT_ELEMENT.to_string(),
+                        attributes,
+                    });
+                }
+            }
+            Ok(Event::End(_)) => child = None,
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(invalid(&e)),
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(points)
+}
+
+fn insert(attributes: &mut Attributes, key: &str, value: &str) {
+    attributes.insert(intern_key(key), attribute_value(key, value));
+}
+
+fn invalid(error: &impl std::fmt::Display) -> AppError {
+    AppError::InvalidElement(ElementError::new(format!("in GPX file: {}", error)))
+}
@@ -0,0 +1,423 @@
+//! jq-style projections of the records of an export, streamed as they are
+//! read for quick questions that need no archive.
+
+use crate::apple_health::types::GenericRecord;
+use crate::error::{AppError, Result};
+use crate::xml_utils;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::path::Path;
+
+/// What to print of each matching record: attributes such as `.type`, or
+/// `.` for all of them.
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Whole,
+    Attribute(String),
+}
+
+/// A `--where` condition, compared as numbers when both sides are numbers
+/// and as text otherwise.
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Field(Field),
+    Text(String),
+    Number(f64),
+    Bool(bool),
+    Null,
+    Compare(Box<Condition>, Comparison, Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// The fields to print of the records matching a condition, as given to
+/// `gpt-os select`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    fields: Vec<Field>,
+    condition: Option<Condition>,
+}
+
+impl Selection {
+    /// Parse a projection such as `.type, .startDate, .value` and a
+    /// condition such as `.type == "HKQuantityTypeIdentifierBodyMass" and
+    /// .value > 80`. Attribute names that are not plain identifiers are
+    /// quoted, as in `."HKMetadataKeyTimeZone"`.
+    pub fn parse(projection: &str, condition: Option<&str>) -> Result<Self> {
+        let mut parser = Parser::new(projection)?;
+        let mut fields = vec![parser.field()?];
+        while parser.eat(&Token::Comma) {
+            fields.push(parser.field()?);
+        }
+        parser.end()?;
+        let condition = condition
+            .map(|condition| {
+                let mut parser = Parser::new(condition)?;
+                let condition = parser.or()?;
+                parser.end()?;
+                Ok::<_, AppError>(condition)
+            })
+            .transpose()?;
+        Ok(Self { fields, condition })
+    }
+
+    /// Whether `record` satisfies the condition, if any.
+    pub fn matches(&self, record: &GenericRecord) -> bool {
+        self.condition
+            .as_ref()
+            .is_none_or(|condition| truthy(&evaluate(condition, record)))
+    }
+
+    /// The fields of `record`: one JSON value for a single field, an array
+    /// of them otherwise, or with `raw` the values separated by tabs.
+    pub fn project(&self, record: &GenericRecord, raw: bool) -> String {
+        let values: Vec<Value> = self
+            .fields
+            .iter()
+            .map(|field| match field {
+                Field::Whole => {
+                    let mut attributes: Vec<_> = record.attributes.iter().collect();
+                    attributes.sort_unstable();
+                    Value::Object(
+                        attributes
+                            .into_iter()
+                            .map(|(key, value)| (key.to_string(), Value::from(&**value)))
+                            .collect::<Map<_, _>>(),
+                    )
+                }
+                Field::Attribute(name) => record
+                    .attributes
+                    .get(name.as_str())
+                    .map_or(Value::Null, |value| Value::from(&**value)),
+            })
+            .collect();
+        if raw {
+            return values
+                .iter()
+                .map(|value| match value {
+                    Value::String(s) => s.clone(),
+                    Value::Null => String::new(),
+                    value => value.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("\t");
+        }
+        match <[Value; 1]>::try_from(values) {
+            Ok([value]) => value.to_string(),
+            Err(values) => Value::Array(values).to_string(),
+        }
+    }
+}
+
+/// Write the projection of every record of the export at `input_path`
+/// matching `selection` to `out`, one line each, as they are read. Returns
+/// the number of records written.
+pub fn select<W: Write>(
+    input_path: &Path,
+    selection: &Selection,
+    raw: bool,
+    mut out: W,
+) -> Result<usize> {
+    let mut matched = 0;
+    xml_utils::with_export_reader(input_path, |reader| {
+        xml_utils::try_for_each_element(reader, |element| {
+            let Ok(record) = GenericRecord::from_xml(element) else {
+                return Ok(());
+            };
+            if selection.matches(&record) {
+                writeln!(out, "{}", selection.project(&record, raw))?;
+                matched += 1;
+            }
+            Ok(())
+        })
+    })?;
+    out.flush()?;
+    Ok(matched)
+}
+
+/// A value a condition evaluates to.
+enum Operand<'a> {
+    Text(&'a str),
+    Number(f64),
+    Bool(bool),
+    Null,
+}
+
+fn evaluate<'a>(condition: &'a Condition, record: &'a GenericRecord) -> Operand<'a> {
+    match condition {
+        Condition::Field(Field::Attribute(name)) => record
+            .attributes
+            .get(name.as_str())
+            .map_or(Operand::Null, |value| Operand::Text(value)),
+        Condition::Field(Field::Whole) => Operand::Bool(true),
+        Condition::Text(text) => Operand::Text(text),
+        Condition::Number(number) => Operand::Number(*number),
+        Condition::Bool(b) => Operand::Bool(*b),
+        Condition::Null => Operand::Null,
+        Condition::And(left, right) => {
+            Operand::Bool(truthy(&evaluate(left, record)) && truthy(&evaluate(right, record)))
+        }
+        Condition::Or(left, right) => {
+            Operand::Bool(truthy(&evaluate(left, record)) || truthy(&evaluate(right, record)))
+        }
+        Condition::Compare(left, comparison, right) => Operand::Bool(compare(
+            &evaluate(left, record),
+            *comparison,
+            &evaluate(right, record),
+        )),
+    }
+}
+
+fn truthy(operand: &Operand) -> bool {
+    !matches!(operand, Operand::Null | Operand::Bool(false))
+}
+
+fn compare(left: &Operand, comparison: Comparison, right: &Operand) -> bool {
+    let number = |operand: &Operand| match operand {
+        Operand::Number(n) => Some(*n),
+        Operand::Text(text) => text.trim().parse::<f64>().ok(),
+        _ => None,
+    };
+    let ordering = match (left, right) {
+        (Operand::Null, Operand::Null) => Some(std::cmp::Ordering::Equal),
+        (Operand::Null, _) | (_, Operand::Null) => None,
+        (Operand::Bool(a), Operand::Bool(b)) => Some(a.cmp(b)),
+        (Operand::Bool(_), _) | (_, Operand::Bool(_)) => None,
+        _ => match (number(left), number(right)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => match (left, right) {
+                (Operand::Text(a), Operand::Text(b)) => Some(a.cmp(b)),
+                _ => None,
+            },
+        },
+    };
+    match comparison {
+        Comparison::Equal => ordering.is_some_and(|o| o.is_eq()),
+        Comparison::NotEqual => !ordering.is_some_and(|o| o.is_eq()),
+        Comparison::Less => ordering.is_some_and(|o| o.is_lt()),
+        Comparison::LessOrEqual => ordering.is_some_and(|o| o.is_le()),
+        Comparison::Greater => ordering.is_some_and(|o| o.is_gt()),
+        Comparison::GreaterOrEqual => ordering.is_some_and(|o| o.is_ge()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(Field),
+    Text(String),
+    Number(f64),
+    Word(String),
+    Comparison(Comparison),
+    Comma,
+    Open,
+    Close,
+}
+
+/// Recursive descent over the tokens of a projection or condition.
+struct Parser {
+    source: String,
+    /// Each token with the byte offset it starts at.
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self> {
+        Ok(Self {
+            source: source.to_string(),
+            tokens: tokenize(source)?,
+            pos: 0,
+        })
+    }
+
+    fn error(&self, expected: &str) -> AppError {
+        let found = match self.tokens.get(self.pos) {
+            Some((offset, _)) => format!("'{}'", &self.source[*offset..]),
+            None => "the end".to_string(),
+        };
+        AppError::InvalidConfig(format!(
+            "expected {} in '{}' but found {}",
+            expected, self.source, found
+        ))
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn end(&self) -> Result<()> {
+        if self.pos == self.tokens.len() {
+            Ok(())
+        } else {
+            Err(self.error("the end"))
+        }
+    }
+
+    fn field(&mut self) -> Result<Field> {
+        match self.peek().cloned() {
+            Some(Token::Field(field)) => {
+                self.pos += 1;
+                Ok(field)
+            }
+            _ => Err(self.error("a field such as .type")),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition> {
+        let mut condition = self.and()?;
+        while self.eat(&Token::Word("or".to_string())) {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition> {
+        let mut condition = self.comparison()?;
+        while self.eat(&Token::Word("and".to_string())) {
+            condition = Condition::And(Box::new(condition), Box::new(self.comparison()?));
+        }
+        Ok(condition)
+    }
+
+    fn comparison(&mut self) -> Result<Condition> {
+        let left = self.operand()?;
+        match self.peek() {
+            Some(&Token::Comparison(comparison)) => {
+                self.pos += 1;
+                let right = self.operand()?;
+                Ok(Condition::Compare(
+                    Box::new(left),
+                    comparison,
+                    Box::new(right),
+                ))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Condition> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error("a field or value"));
+        };
+        self.pos += 1;
+        let condition = match token {
+            Token::Field(field) => Condition::Field(field),
+            Token::Text(text) => Condition::Text(text),
+            Token::Number(number) => Condition::Number(number),
+            Token::Word(word) if word == "true" => Condition::Bool(true),
+            Token::Word(word) if word == "false" => Condition::Bool(false),
+            Token::Word(word) if word == "null" => Condition::Null,
+            Token::Open => {
+                let condition = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err(self.error("')'"));
+                }
+                condition
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("a field or value"));
+            }
+        };
+        Ok(condition)
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let invalid = |reason: &str| AppError::InvalidConfig(format!("{} in '{}'", reason, source));
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            ',' => Token::Comma,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' => Token::Text(string(&mut chars).ok_or_else(|| invalid("unclosed string"))?),
+            '.' => match chars.peek() {
+                Some(&(_, '"')) => {
+                    chars.next();
+                    let name = string(&mut chars).ok_or_else(|| invalid("unclosed string"))?;
+                    Token::Field(Field::Attribute(name))
+                }
+                Some(&(_, c)) if is_name(c) => {
+                    let mut name = String::new();
+                    while let Some(&(_, c)) = chars.peek().filter(|(_, c)| is_name(*c)) {
+                        name.push(c);
+                        chars.next();
+                    }
+                    Token::Field(Field::Attribute(name))
+                }
+                _ => Token::Field(Field::Whole),
+            },
+            '=' | '!' | '<' | '>' => {
+                let equals = chars.next_if(|&(_, c)| c == '=').is_some();
+                Token::Comparison(match (c, equals) {
+                    ('=', true) => Comparison::Equal,
+                    ('!', true) => Comparison::NotEqual,
+                    ('<', false) => Comparison::Less,
+                    ('<', true) => Comparison::LessOrEqual,
+                    ('>', false) => Comparison::Greater,
+                    ('>', true) => Comparison::GreaterOrEqual,
+                    _ => return Err(invalid(&format!("unknown operator '{}'", c))),
+                })
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E'))
+                {
+                    end = i + c.len_utf8();
+                }
+                let number = &source[start..end];
+                Token::Number(
+                    number
+                        .parse()
+                        .map_err(|_| invalid(&format!("invalid number '{}'", number)))?,
+                )
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut end = start + 1;
+                while let Some((i, c)) = chars.next_if(|&(_, c)| is_name(c)) {
+                    end = i + c.len_utf8();
+                }
+                Token::Word(source[start..end].to_string())
+            }
+            c => return Err(invalid(&format!("unexpected '{}'", c))),
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+/// The rest of a string literal whose opening quote has been read, with
+/// `\"` and `\\` escapes, or `None` if it is not closed.
+fn string(chars: &mut impl Iterator<Item = (usize, char)>) -> Option<String> {
+    let mut text = String::new();
+    loop {
+        match chars.next()?.1 {
+            '"' => return Some(text),
+            '\\' => text.push(chars.next()?.1),
+            c => text.push(c),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::error::{AppError, Result};
+use std::borrow::Cow;
+use std::io::{self, BufReader, Read, Write};
+
+/// CBOR major types used by the entries.
+const TEXT: u8 = 3;
+const MAP: u8 = 5;
+
+/// Append the head of a data item of `major` type with argument `value`,
+/// in its shortest form.
+fn write_head(buf: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => buf.push(major | value as u8),
+        24..=0xff => buf.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            buf.push(major | 25);
+            buf.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(major | 26);
+            buf.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(major | 27);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+/// `text` encoded as a CBOR text string.
+pub fn text(text: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(text.len() + 9);
+    write_head(&mut buf, TEXT, text.len() as u64);
+    buf.extend_from_slice(text.as_bytes());
+    buf
+}
+
+/// Write the non-empty `cells` as one map from the already encoded `keys`
+/// to text strings, reusing `map` as a buffer.
+pub fn write_map<W: Write>(
+    writer: &mut W,
+    keys: &[Vec<u8>],
+    cells: &[Cow<'_, str>],
+    map: &mut Vec<u8>,
+) -> io::Result<()> {
+    map.clear();
+    let pairs = cells.iter().filter(|cell| !cell.is_empty()).count();
+    write_head(map, MAP, pairs as u64);
+    for (key, cell) in keys.iter().zip(cells) {
+        if cell.is_empty() {
+            continue;
+        }
+        map.extend_from_slice(key);
+        write_head(map, TEXT, cell.len() as u64);
+        map.extend_from_slice(cell.as_bytes());
+    }
+    writer.write_all(map)
+}
+
+/// Reads the maps of text strings written by [`write_map`] back, one at a
+/// time.
+pub struct MapReader<R> {
+    inner: BufReader<R>,
+}
+
+impl<R: Read> MapReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+        }
+    }
+
+    /// The next map's pairs, or `None` at the end of the sequence.
+    pub fn next_map(&mut self) -> Result<Option<Vec<(String, String)>>> {
+        let Some(pairs) = self.head(MAP)? else {
+            return Ok(None);
+        };
+        let mut map = Vec::with_capacity(pairs.min(1024) as usize);
+        for _ in 0..pairs {
+            let key = self.text()?;
+            let value = self.text()?;
+            map.push((key, value));
+        }
+        Ok(Some(map))
+    }
+
+    /// Count the remaining maps without keeping them.
+    pub fn count(mut self) -> Result<usize> {
+        let mut count = 0;
+        while self.next_map()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn text(&mut self) -> Result<String> {
+        let len = self.head(TEXT)?.ok_or_else(truncated)?;
+        let mut bytes = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(truncated());
+        }
+        String::from_utf8(bytes).map_err(|e| AppError::Unknown(format!("invalid CBOR text: {}", e)))
+    }
+
+    /// The argument of the next head, which must be of `major` type, or
+    /// `None` at the end of the input.
+    fn head(&mut self, major: u8) -> Result<Option<u64>> {
+        let mut initial = [0];
+        if self.inner.read(&mut initial)? == 0 {
+            return Ok(None);
+        }
+        if initial[0] >> 5 != major {
+            return Err(AppError::Unknown(format!(
+                "unexpected CBOR item of major type {}",
+                initial[0] >> 5
+            )));
+        }
+        let width = match initial[0] & 0x1f {
+            value @ 0..=23 => return Ok(Some(u64::from(value))),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(AppError::Unknown("unsupported CBOR item".to_string())),
+        };
+        let mut bytes = [0; 8];
+        self.inner
+            .read_exact(&mut bytes[8 - width..])
+            .map_err(|_| truncated())?;
+        Ok(Some(u64::from_be_bytes(bytes)))
+    }
+}
+
+fn truncated() -> AppError {
+    AppError::Unknown("truncated CBOR item".to_string())
+}
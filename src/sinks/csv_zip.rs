@@ -1,32 +1,1046 @@
-use crate::core::{Processable, Sink};
-use crate::error::{AppError, Result};
+use crate::apple_health::dates::parse_timestamp;
+use crate::audio::audio_exposure_csv;
+use crate::calendar::workouts_ics;
+use crate::charts::{correlation_heatmap, daily_charts};
+use crate::core::{LoadContext, Processable};
+use crate::correlations::{CorrelationMatrix, correlations_csv};
+use crate::cycle::cycle_tracking_csv;
+use crate::ecg::{ecg_recordings_csv, read_recordings};
+use crate::error::{AppError, Result, SkipSummary};
+use crate::manifest::{MANIFEST_FILE_NAME, to_hex};
+use crate::mindfulness::mindful_sessions_csv;
+use crate::progress::{Progress, ProgressEvent};
+use crate::report::{
+    AUDIO_EXPOSURE_FILE_NAME, CORRELATIONS_FILE_NAME, CYCLE_TRACKING_FILE_NAME,
+    DAILY_SUMMARY_FILE_NAME, ECG_RECORDINGS_FILE_NAME, GroupSummary, HOME_ASSISTANT_FILE_NAME,
+    HtmlReport, MINDFUL_SESSIONS_FILE_NAME, REPORT_FILE_NAME, ReportFormat, Reports,
+    SOURCES_FILE_NAME, STATISTICS_FILE_NAME, STREAKS_FILE_NAME, WORKOUT_HR_ZONES_FILE_NAME,
+    WORKOUT_SPLITS_FILE_NAME, WORKOUTS_CALENDAR_FILE_NAME, daily_summary_csv, home_assistant_csv,
+    sources_csv, statistics_csv,
+};
+use crate::sinks::cbor::{self, write_map};
+use crate::sinks::file_names::{NameMap, entry_file_names};
+use crate::sinks::protobuf::{PROTO_FILE_NAME, ProtoSchema, write_message};
+use crate::sinks::table_schema::{TableSchema, schema_file_name};
+use crate::splits::workout_splits_csv;
+use crate::streaks::streaks_csv;
+use crate::units::{UnitMode, UnitPlan};
+use crate::zones::{HeartRateZones, heart_rate_zones_csv};
 use ahash::{AHashMap, AHashSet};
-use crossbeam_channel::{Receiver, bounded};
-use log::{debug, info, warn};
-use rayon::prelude::*;
-use std::fs::File;
-use std::io::{Cursor, Write};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::hash_map::Entry;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
 use std::mem::MaybeUninit;
-use std::path::Path;
-use std::thread;
-use std::time::Instant;
-use tokio::task;
-use zip::ZipArchive;
-use zip::{CompressionMethod, ZipWriter, write::FileOptions};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info_span;
+use zip::{ZipWriter, write::FileOptions};
+#[cfg(feature = "native")]
+use {
+    crate::backpressure::{Backpressure, ByteBudget},
+    crate::core::{Sink, SinkTo},
+    crate::retry::RetryPolicy,
+    crate::spill::SpilledGroups,
+    crate::validate,
+    clap::ValueEnum,
+    crossbeam_channel::{Receiver, TrySendError, bounded},
+    log::info,
+    rayon::prelude::*,
+    serde::de::DeserializeOwned,
+    std::collections::BTreeSet,
+    std::io::Cursor,
+    std::path::Path,
+    std::thread,
+    std::time::Instant,
+    tokio::task,
+    zip::ZipArchive,
+};
 
 const STORE_THRESHOLD: usize = 8 * 1024;
 
+/// Entries at least this large need Zip64 size fields.
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+
+/// Name of the `sha256sum -c` compatible checksum entry in the archive.
+pub const CHECKSUMS_FILE_NAME: &str = "SHA256SUMS";
+
+/// Extension of the group entries written with [`CsvDialect::ndjson`].
+pub const NDJSON_EXTENSION: &str = ".ndjson";
+
+/// Extension of the group entries written with [`CsvDialect::protobuf`].
+pub const PROTOBUF_EXTENSION: &str = ".pb";
+
+/// Extension of the group entries written with [`CsvDialect::cbor`].
+pub const CBOR_EXTENSION: &str = ".cbor";
+
+/// Name of the entry listing every malformed element skipped during
+/// extraction; only written when there are any.
+pub const ERRORS_FILE_NAME: &str = "errors.csv";
+
+/// CSV entries written next to the groups, whose names no group may take.
+pub const RESERVED_CSV_NAMES: [&str; 13] = [
+    ERRORS_FILE_NAME,
+    STATISTICS_FILE_NAME,
+    SOURCES_FILE_NAME,
+    DAILY_SUMMARY_FILE_NAME,
+    CORRELATIONS_FILE_NAME,
+    STREAKS_FILE_NAME,
+    HOME_ASSISTANT_FILE_NAME,
+    MINDFUL_SESSIONS_FILE_NAME,
+    CYCLE_TRACKING_FILE_NAME,
+    AUDIO_EXPOSURE_FILE_NAME,
+    WORKOUT_HR_ZONES_FILE_NAME,
+    WORKOUT_SPLITS_FILE_NAME,
+    ECG_RECORDINGS_FILE_NAME,
+];
+
+/// Group name of a CSV entry in an archive written by [`CsvZipSink`], or
+/// `None` for entries that hold no group such as [`ERRORS_FILE_NAME`] or
+/// the ECG recordings in their folder.
+///
+/// Entry names are made safe for Windows by
+/// [`entry_file_names`](crate::sinks::file_names::entry_file_names), so
+/// unusual group names come back in their sanitized form.
+pub fn group_name(entry_name: &str) -> Option<&str> {
+    // Group file names never contain a `/`
+    if RESERVED_CSV_NAMES.contains(&entry_name) || entry_name.contains('/') {
+        return None;
+    }
+    entry_name
+        .strip_suffix(".csv")
+        .or_else(|| entry_name.strip_suffix(NDJSON_EXTENSION))
+        .or_else(|| entry_name.strip_suffix(PROTOBUF_EXTENSION))
+        .or_else(|| entry_name.strip_suffix(CBOR_EXTENSION))
+}
+
+/// Entries appended after the groups: the manifest, the error list when
+/// elements were skipped and any requested reports, which are filled in with
+/// each group as it is written.
+struct Trailer {
+    entries: Vec<(&'static str, Vec<u8>)>,
+    reports: Reports,
+    zones: HeartRateZones,
+    html: Option<HtmlReport>,
+    summaries: Vec<GroupSummary>,
+    /// Exports whose ECG recordings are copied into the archive.
+    ecg_inputs: Vec<PathBuf>,
+    /// Messages of the protobuf entries written.
+    proto: ProtoSchema,
+}
+
+impl Trailer {
+    fn new(context: &LoadContext<'_>, reports: Reports, zones: HeartRateZones) -> Result<Self> {
+        let mut entries = vec![(MANIFEST_FILE_NAME, context.manifest.to_json()?)];
+        if !context.skipped.is_empty() {
+            entries.push((ERRORS_FILE_NAME, errors_csv(context.skipped)?));
+        }
+        let html = reports
+            .contains(ReportFormat::Html)
+            .then(|| HtmlReport::new(context.manifest, context.skipped));
+        let ecg_inputs = if reports.contains(ReportFormat::Ecg) {
+            let inputs = context.manifest.inputs.iter();
+            inputs.map(|input| PathBuf::from(&input.path)).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            entries,
+            reports,
+            zones,
+            html,
+            summaries: Vec::new(),
+            ecg_inputs,
+            proto: ProtoSchema::default(),
+        })
+    }
+
+    fn add_group(&mut self, summary: Option<GroupSummary>) {
+        self.summaries.extend(summary);
+    }
+
+    /// Describe protobuf entry `file_name` in the [`PROTO_FILE_NAME`] entry.
+    fn add_message(&mut self, file_name: &str, columns: Vec<String>) {
+        self.proto.add(file_name, columns);
+    }
+
+    /// Every trailing entry, reports and copied ECG recordings included.
+    fn into_entries(mut self) -> Result<Vec<(Cow<'static, str>, Vec<u8>)>> {
+        self.summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut charts = if self.reports.contains(ReportFormat::Charts) {
+            daily_charts(&self.summaries)?
+        } else {
+            Vec::new()
+        };
+        let correlations = self
+            .reports
+            .contains(ReportFormat::Correlations)
+            .then(|| CorrelationMatrix::new(&self.summaries));
+        if let Some(matrix) = &correlations {
+            charts.extend(correlation_heatmap(matrix)?);
+        }
+        if let Some(html) = &self.html {
+            let page = html.render(&self.summaries, &charts);
+            self.entries.push((REPORT_FILE_NAME, page.into_bytes()));
+        }
+        if self.reports.contains(ReportFormat::Statistics) {
+            let statistics = statistics_csv(&self.summaries)?;
+            self.entries.push((STATISTICS_FILE_NAME, statistics));
+        }
+        if self.reports.contains(ReportFormat::Sources) {
+            let sources = sources_csv(&self.summaries)?;
+            self.entries.push((SOURCES_FILE_NAME, sources));
+        }
+        if self.reports.contains(ReportFormat::Daily) {
+            let daily = daily_summary_csv(&self.summaries)?;
+            self.entries.push((DAILY_SUMMARY_FILE_NAME, daily));
+        }
+        if let Some(matrix) = &correlations {
+            let csv = correlations_csv(matrix)?;
+            self.entries.push((CORRELATIONS_FILE_NAME, csv));
+        }
+        if self.reports.contains(ReportFormat::Streaks) {
+            let rings = self.summaries.iter().find_map(GroupSummary::rings);
+            self.entries.push((STREAKS_FILE_NAME, streaks_csv(rings)?));
+        }
+        if self.reports.contains(ReportFormat::HomeAssistant) {
+            let statistics = home_assistant_csv(&self.summaries)?;
+            self.entries.push((HOME_ASSISTANT_FILE_NAME, statistics));
+        }
+        if self.reports.contains(ReportFormat::Mindfulness) {
+            let samples = self
+                .summaries
+                .iter()
+                .find_map(GroupSummary::mindful_samples);
+            let sessions = mindful_sessions_csv(samples)?;
+            self.entries.push((MINDFUL_SESSIONS_FILE_NAME, sessions));
+        }
+        if self.reports.contains(ReportFormat::Cycle) {
+            let days = self.summaries.iter().filter_map(GroupSummary::cycle_days);
+            let cycle = cycle_tracking_csv(days)?;
+            self.entries.push((CYCLE_TRACKING_FILE_NAME, cycle));
+        }
+        if self.reports.contains(ReportFormat::Audio) {
+            let days = self.summaries.iter().filter_map(GroupSummary::audio_days);
+            let audio = audio_exposure_csv(days)?;
+            self.entries.push((AUDIO_EXPOSURE_FILE_NAME, audio));
+        }
+        if self.reports.contains(ReportFormat::Zones) {
+            let sources = self.summaries.iter().filter_map(GroupSummary::zone_source);
+            let zones = heart_rate_zones_csv(sources, self.zones)?;
+            self.entries.push((WORKOUT_HR_ZONES_FILE_NAME, zones));
+        }
+        if self.reports.contains(ReportFormat::Splits) {
+            let sources = self.summaries.iter().filter_map(GroupSummary::split_source);
+            let splits = workout_splits_csv(sources)?;
+            self.entries.push((WORKOUT_SPLITS_FILE_NAME, splits));
+        }
+        if self.reports.contains(ReportFormat::Calendar) {
+            let workouts = self.summaries.iter().find_map(GroupSummary::workout_events);
+            let calendar = workouts_ics(workouts);
+            self.entries
+                .push((WORKOUTS_CALENDAR_FILE_NAME, calendar.into_bytes()));
+        }
+        for chart in charts {
+            self.entries.push((chart.file_name, chart.svg.into_bytes()));
+        }
+        if !self.proto.is_empty() {
+            let proto = self.proto.to_proto();
+            self.entries.push((PROTO_FILE_NAME, proto.into_bytes()));
+        }
+        let mut entries: Vec<_> = self
+            .entries
+            .into_iter()
+            .map(|(file_name, data)| (Cow::Borrowed(file_name), data))
+            .collect();
+        if self.reports.contains(ReportFormat::Ecg) {
+            let mut recordings = Vec::new();
+            for input in &self.ecg_inputs {
+                recordings.extend(read_recordings(input)?);
+            }
+            entries.push((
+                Cow::Borrowed(ECG_RECORDINGS_FILE_NAME),
+                ecg_recordings_csv(&recordings)?,
+            ));
+            for recording in recordings {
+                entries.push((Cow::Owned(recording.file_name), recording.data));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// One row per skipped element with its location, reason and raw start.
+fn errors_csv(skipped: &SkipSummary) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record(["element", "attribute", "offset", "reason", "snippet"])?;
+    for error in skipped.errors() {
+        w.write_record([
+            error.element.as_deref().unwrap_or_default(),
+            error.attribute.as_deref().unwrap_or_default(),
+            &error.offset.map(|o| o.to_string()).unwrap_or_default(),
+            &error.reason,
+            error.snippet.as_deref().unwrap_or_default(),
+        ])?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
+
+/// A serialized CSV produced by a worker, awaiting its place in the archive.
+struct CsvEntry {
+    group: String,
+    records: usize,
+    file_name: String,
+    data: EntryData,
+    /// Hex SHA-256 of the CSV contents.
+    sha256: String,
+    /// Coverage of the group, when a report is written.
+    summary: Option<GroupSummary>,
+    /// The entry's Table Schema, when schemas are written.
+    schema: Option<Vec<u8>>,
+    /// Columns of the entry's messages, when written as protobuf.
+    columns: Option<Vec<String>>,
+}
+
+/// Contents of a [`CsvEntry`].
+enum EntryData {
+    /// The CSV itself, compressed by the archive writer.
+    Csv(Vec<u8>),
+    /// A single-entry archive holding the CSV already compressed by a
+    /// worker, copied into the output without recompressing.
+    #[cfg(feature = "native")]
+    Compressed(Vec<u8>),
+}
+
+impl EntryData {
+    fn len(&self) -> usize {
+        match self {
+            EntryData::Csv(bytes) => bytes.len(),
+            #[cfg(feature = "native")]
+            EntryData::Compressed(bytes) => bytes.len(),
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl CsvEntry {
+    /// Compress the CSV on the calling thread, so the archive writer only
+    /// has to copy the result.
+    fn compress(mut self, compression: Compression) -> Result<Self> {
+        if let EntryData::Csv(csv) = &self.data {
+            let _span = info_span!("compress", group = %self.group, bytes = csv.len()).entered();
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            zip.start_file(self.file_name.as_str(), compression.file_options(csv.len()))?;
+            zip.write_all(csv)?;
+            self.data = EntryData::Compressed(zip.finish()?.into_inner());
+        }
+        Ok(self)
+    }
+}
+
+/// Compression method applied to the CSV entries of the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "native", derive(ValueEnum))]
+pub enum CompressionMethod {
+    /// No compression; fastest to write and read
+    Stored,
+    /// Deflate; readable by every unzip tool
+    #[default]
+    Deflate,
+    /// Bzip2; slower than deflate but smaller
+    Bzip2,
+    /// Zstandard; fast with a good ratio, needs a recent unzip tool
+    #[cfg(feature = "native")]
+    Zstd,
+}
+
+impl CompressionMethod {
+    /// Valid compression levels for this method, or `None` if it takes no level.
+    pub fn level_range(self) -> Option<RangeInclusive<i64>> {
+        match self {
+            CompressionMethod::Stored => None,
+            CompressionMethod::Deflate => Some(1..=9),
+            CompressionMethod::Bzip2 => Some(1..=9),
+            #[cfg(feature = "native")]
+            CompressionMethod::Zstd => Some(1..=22),
+        }
+    }
+
+    fn default_level(self) -> Option<i64> {
+        match self {
+            // Favor throughput; deflate dominates the cost of writing large groups.
+            CompressionMethod::Deflate => Some(1),
+            _ => None,
+        }
+    }
+
+    fn zip_method(self) -> zip::CompressionMethod {
+        match self {
+            CompressionMethod::Stored => zip::CompressionMethod::Stored,
+            CompressionMethod::Deflate => zip::CompressionMethod::Deflated,
+            CompressionMethod::Bzip2 => zip::CompressionMethod::Bzip2,
+            #[cfg(feature = "native")]
+            CompressionMethod::Zstd => zip::CompressionMethod::Zstd,
+        }
+    }
+}
+
+/// Compression settings used by [`CsvZipSink`] for each CSV entry.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compression {
+    method: CompressionMethod,
+    level: Option<i64>,
+}
+
+impl Compression {
+    /// Validate `level` against `method`; `None` selects the method's default.
+    pub fn new(method: CompressionMethod, level: Option<i64>) -> Result<Self> {
+        if let Some(level) = level {
+            match method.level_range() {
+                None => {
+                    return Err(AppError::InvalidConfig(format!(
+                        "compression method '{:?}' does not accept a level",
+                        method
+                    )));
+                }
+                Some(range) if !range.contains(&level) => {
+                    return Err(AppError::InvalidConfig(format!(
+                        "compression level {} is outside {}..={} for '{:?}'",
+                        level,
+                        range.start(),
+                        range.end(),
+                        method
+                    )));
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(Self { method, level })
+    }
+
+    /// Options for an entry of `len` uncompressed bytes. Zip64 is enabled for
+    /// entries that may not fit the 32-bit size fields.
+    fn file_options(&self, len: usize) -> FileOptions<'static, ()> {
+        let opts = FileOptions::<()>::default()
+            .unix_permissions(0o644)
+            .large_file(len as u64 >= ZIP64_THRESHOLD);
+        if self.method == CompressionMethod::Stored || len < STORE_THRESHOLD {
+            return opts.compression_method(zip::CompressionMethod::Stored);
+        }
+        opts.compression_method(self.method.zip_method())
+            .compression_level(self.level.or(self.method.default_level()))
+    }
+
+    /// Options for an entry streamed without knowing its size up front.
+    /// Zip64 is always enabled: it costs 20 bytes, while an entry that
+    /// crosses 4 GiB without it fails only once written that far.
+    pub fn streamed_file_options(&self) -> FileOptions<'static, ()> {
+        self.file_options(usize::MAX)
+    }
+}
+
+/// Decimal separator written in numeric CSV cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "native", derive(ValueEnum))]
+pub enum DecimalSeparator {
+    /// `72.5`, with cells separated by `,`
+    #[default]
+    Point,
+    /// `72,5`, with cells separated by `;` as spreadsheets expect in most
+    /// European locales
+    Comma,
+}
+
+impl DecimalSeparator {
+    /// `cell` of a numeric column as written with this separator, with a
+    /// `.` again.
+    pub fn read<'a>(self, cell: &'a str) -> Cow<'a, str> {
+        match self {
+            DecimalSeparator::Comma if is_decimal(&cell.replacen(',', ".", 1)) => {
+                Cow::Owned(cell.replacen(',', ".", 1))
+            }
+            _ => Cow::Borrowed(cell),
+        }
+    }
+}
+
+/// Columns written first, in this order, before the others alphabetically.
+const LEADING_COLUMNS: [&str; 6] = [
+    "startDate",
+    "endDate",
+    "creationDate",
+    "type",
+    "value",
+    "unit",
+];
+
+/// Columns holding measurements. Their decimal numbers are rounded to
+/// [`CsvDialect::decimal_places`] and, in CSV entries, written with the
+/// dialect's [`DecimalSeparator`]; other columns, such as `sourceVersion` or
+/// folded metadata, are written as exported.
+pub const NUMERIC_COLUMNS: [&str; 43] = [
+    "activeEnergyBurned",
+    "activeEnergyBurnedGoal",
+    "altitude",
+    "appleExerciseTime",
+    "appleExerciseTimeGoal",
+    "appleMoveTime",
+    "appleMoveTimeGoal",
+    "appleStandHours",
+    "appleStandHoursGoal",
+    "averageCadence",
+    "averageHeartRate",
+    "averageSpeed",
+    "avgCadence",
+    "avgHeartRate",
+    "avgPower",
+    "avgSpeed",
+    "cadence",
+    "distance",
+    "duration",
+    "elevationAscended",
+    "elevationDescended",
+    "heartRate",
+    "latitude",
+    "longitude",
+    "maxCadence",
+    "maxHeartRate",
+    "maxPower",
+    "maxSpeed",
+    "originalValue",
+    "power",
+    "speed",
+    "temperature",
+    "totalAscent",
+    "totalCalories",
+    "totalDescent",
+    "totalDistance",
+    "totalElapsedTime",
+    "totalEnergyBurned",
+    "totalFlightsClimbed",
+    "totalSwimmingStrokeCount",
+    "totalTimerTime",
+    "value",
+    "verticalSpeed",
+];
+
+/// Column of the record hashes added by [`CsvDialect::record_ids`].
+pub const ID_COLUMN: &str = "id";
+
+/// Fields hashed into a record's id.
+const ID_FIELDS: [&str; 4] = ["startDate", "endDate", "value", "sourceName"];
+
+/// Date columns followed by their milliseconds since the epoch with
+/// [`CsvDialect::epoch_columns`].
+const EPOCH_COLUMNS: [(&str, &str); 2] = [("startDate", "startDate_ms"), ("endDate", "endDate_ms")];
+
+/// Milliseconds column written after `header`, if it is one of
+/// [`EPOCH_COLUMNS`].
+fn epoch_column(header: &str) -> Option<&'static str> {
+    EPOCH_COLUMNS
+        .iter()
+        .find(|(date, _)| date.eq_ignore_ascii_case(header))
+        .map(|(_, column)| *column)
+}
+
+/// How the CSV entries are laid out for the programs reading them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CsvDialect {
+    pub decimal_separator: DecimalSeparator,
+    /// Start every CSV with a `sep=` line, which makes Excel split the
+    /// cells correctly whatever the system locale. Other CSV readers see it
+    /// as an extra line.
+    pub excel_hint: bool,
+    /// Order every column alphabetically instead of putting the dates,
+    /// type, value and unit first.
+    pub alphabetical_columns: bool,
+    /// Start every row with a hash of the record's type, dates, value and
+    /// source in an [`ID_COLUMN`], the same for the same record in any
+    /// export, so rows can be upserted and deduplicated by it.
+    pub record_ids: bool,
+    /// Follow `startDate` and `endDate` with `startDate_ms` and
+    /// `endDate_ms`, the same instants in milliseconds since the Unix epoch.
+    pub epoch_columns: bool,
+    /// Round the decimal numbers of the [`NUMERIC_COLUMNS`] to this many
+    /// decimal places, halves away from zero, dropping trailing zeros,
+    /// instead of writing them as exported.
+    pub decimal_places: Option<usize>,
+    /// Whether units are written as exported, with their spellings
+    /// normalized or with the values of each type converted to one unit.
+    pub units: UnitMode,
+    /// Leave out the columns that are empty in every row of their CSV.
+    pub drop_empty_columns: bool,
+    /// Write each group as `Type.ndjson` instead, one JSON object per
+    /// record mapping the columns to their non-empty cells, so consumers
+    /// can stream huge types record by record.
+    pub ndjson: bool,
+    /// Write each group as `Type.pb` instead, length-delimited protobuf
+    /// messages of the type describing it in [`PROTO_FILE_NAME`].
+    pub protobuf: bool,
+    /// Write each group as `Type.cbor` instead, a CBOR sequence of one map
+    /// per record from the columns to their non-empty cells, for consumers
+    /// that decode CBOR but cannot afford parsing JSON.
+    pub cbor: bool,
+}
+
+impl CsvDialect {
+    /// Order alphabetically sorted `headers` as they are written:
+    /// [`LEADING_COLUMNS`] first, in any case, unless columns are kept
+    /// alphabetical.
+    fn order_columns(&self, headers: &mut [&str]) {
+        if !self.alphabetical_columns {
+            // A stable sort keeps the other columns alphabetical
+            headers.sort_by_key(|header| {
+                LEADING_COLUMNS
+                    .iter()
+                    .position(|column| column.eq_ignore_ascii_case(header))
+                    .unwrap_or(LEADING_COLUMNS.len())
+            });
+        }
+    }
+
+    /// Whether `key` of `record` makes a column: always, unless empty
+    /// columns are dropped and its value is empty.
+    fn keeps_column<T: Processable>(&self, record: &T, key: &str) -> bool {
+        !self.drop_empty_columns || record.field(key).is_some_and(|value| !value.is_empty())
+    }
+
+    /// Header row for the attribute columns `headers`, with the
+    /// [`ID_COLUMN`] first, the milliseconds columns after their dates and
+    /// the columns `units` adds last when those are added.
+    fn header_row<'a>(&self, headers: &[&'a str], units: &UnitPlan) -> Vec<&'a str> {
+        let extra = units.extra_columns();
+        let mut row = Vec::with_capacity(headers.len() + 1 + EPOCH_COLUMNS.len() + extra.len());
+        row.extend(self.record_ids.then_some(ID_COLUMN));
+        for &header in headers {
+            row.push(header);
+            if self.epoch_columns {
+                row.extend(epoch_column(header));
+            }
+        }
+        row.extend(extra);
+        row
+    }
+
+    /// Byte separating the cells of a row.
+    pub fn delimiter(&self) -> u8 {
+        match self.decimal_separator {
+            DecimalSeparator::Point => b',',
+            DecimalSeparator::Comma => b';',
+        }
+    }
+
+    /// `value` as written in a numeric CSV cell: decimal numbers are
+    /// [rounded](Self::round) and get the configured separator, anything
+    /// else is written unchanged.
+    pub fn number<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let number = self.round(value);
+        if self.decimal_separator == DecimalSeparator::Comma && is_decimal(&number) {
+            Cow::Owned(number.replacen('.', ",", 1))
+        } else {
+            number
+        }
+    }
+
+    /// `value` of a numeric column in any layout: decimal numbers are
+    /// rounded to the configured places, anything else is unchanged.
+    pub fn round<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        match self.decimal_places {
+            Some(places) if is_decimal(value) => Cow::Owned(round_decimal(value, places)),
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// Name of a group's entry, given the `.csv` name
+    /// [`entry_file_names`] chose for it.
+    fn entry_name(&self, csv_name: String) -> String {
+        match csv_name.strip_suffix(".csv") {
+            Some(stem) if self.ndjson => format!("{}{}", stem, NDJSON_EXTENSION),
+            Some(stem) if self.protobuf => format!("{}{}", stem, PROTOBUF_EXTENSION),
+            Some(stem) if self.cbor => format!("{}{}", stem, CBOR_EXTENSION),
+            _ => csv_name,
+        }
+    }
+
+    fn writer<W: Write>(&self, mut inner: W) -> Result<EntryWriter<W>> {
+        if self.ndjson {
+            return Ok(EntryWriter::Ndjson {
+                inner: BufWriter::with_capacity(128 * 1024, inner),
+                headers: Vec::new(),
+            });
+        }
+        if self.protobuf {
+            return Ok(EntryWriter::Protobuf {
+                inner: BufWriter::with_capacity(128 * 1024, inner),
+                message: Vec::new(),
+            });
+        }
+        if self.cbor {
+            return Ok(EntryWriter::Cbor {
+                inner: BufWriter::with_capacity(128 * 1024, inner),
+                keys: Vec::new(),
+                map: Vec::new(),
+            });
+        }
+        if self.excel_hint {
+            writeln!(inner, "sep={}", self.delimiter() as char)?;
+        }
+        Ok(EntryWriter::Csv {
+            writer: Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(true)
+                    .delimiter(self.delimiter())
+                    .buffer_capacity(128 * 1024)
+                    .from_writer(inner),
+            ),
+            comma: self.decimal_separator == DecimalSeparator::Comma,
+            numeric: Vec::new(),
+        })
+    }
+}
+
+/// Writes the rows of a group's entry, as CSV, as one JSON object per line
+/// for [`CsvDialect::ndjson`], as protobuf messages for
+/// [`CsvDialect::protobuf`] or as CBOR maps for [`CsvDialect::cbor`].
+enum EntryWriter<W: Write> {
+    Csv {
+        writer: Box<csv::Writer<W>>,
+        /// Whether decimal numbers are written with a comma.
+        comma: bool,
+        /// Positions of the [`NUMERIC_COLUMNS`] in the rows, when their
+        /// decimal numbers get a comma.
+        numeric: Vec<usize>,
+    },
+    Ndjson {
+        inner: BufWriter<W>,
+        /// Column names, JSON-encoded once for every row.
+        headers: Vec<String>,
+    },
+    Protobuf {
+        inner: BufWriter<W>,
+        /// Buffer for the message being encoded.
+        message: Vec<u8>,
+    },
+    Cbor {
+        inner: BufWriter<W>,
+        /// Column names, CBOR-encoded once for every row.
+        keys: Vec<Vec<u8>>,
+        /// Buffer for the map being encoded.
+        map: Vec<u8>,
+    },
+}
+
+impl<W: Write> EntryWriter<W> {
+    fn write_header(&mut self, header_row: &[&str]) -> Result<()> {
+        match self {
+            EntryWriter::Csv {
+                writer,
+                comma,
+                numeric,
+            } => {
+                if *comma {
+                    *numeric = header_row
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, header)| NUMERIC_COLUMNS.contains(header))
+                        .map(|(at, _)| at)
+                        .collect();
+                }
+                writer.write_record(header_row)?
+            }
+            EntryWriter::Ndjson { headers, .. } => {
+                *headers = header_row
+                    .iter()
+                    .map(serde_json::to_string)
+                    .collect::<serde_json::Result<_>>()?;
+            }
+            // Fields are numbered by column, as in the generated schema
+            EntryWriter::Protobuf { .. } => {}
+            EntryWriter::Cbor { keys, .. } => {
+                *keys = header_row.iter().map(|header| cbor::text(header)).collect();
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the decimal numbers of the numeric columns among `cells` a
+    /// comma when the dialect asks for one; only CSV entries are localized,
+    /// the other layouts are read by programs.
+    fn localize(&self, cells: &mut [Cow<'_, str>]) {
+        if let EntryWriter::Csv { numeric, .. } = self {
+            for &at in numeric {
+                if let Some(cell) = cells.get_mut(at)
+                    && is_decimal(cell)
+                {
+                    *cell = Cow::Owned(cell.replacen('.', ",", 1));
+                }
+            }
+        }
+    }
+
+    fn write_row(&mut self, cells: &[Cow<'_, str>]) -> Result<()> {
+        match self {
+            EntryWriter::Csv { writer, .. } => {
+                writer.write_record(cells.iter().map(|c| c.as_bytes()))?
+            }
+            EntryWriter::Ndjson { inner, headers } => {
+                let mut separator = b"{";
+                for (header, cell) in headers.iter().zip(cells) {
+                    if cell.is_empty() {
+                        continue;
+                    }
+                    inner.write_all(separator)?;
+                    inner.write_all(header.as_bytes())?;
+                    inner.write_all(b":")?;
+                    serde_json::to_writer(&mut *inner, cell)?;
+                    separator = b",";
+                }
+                if separator == b"{" {
+                    inner.write_all(b"{")?;
+                }
+                inner.write_all(b"}\n")?;
+            }
+            EntryWriter::Protobuf { inner, message } => write_message(inner, cells, message)?,
+            EntryWriter::Cbor { inner, keys, map } => write_map(inner, keys, cells, map)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            EntryWriter::Csv { writer, .. } => writer.flush()?,
+            EntryWriter::Ndjson { inner, .. }
+            | EntryWriter::Protobuf { inner, .. }
+            | EntryWriter::Cbor { inner, .. } => inner.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// `value`, a decimal number, rounded to `places` decimal places without
+/// trailing zeros: `74.83` for `74.83462809917356` and 2 places. Halves are
+/// rounded away from zero, so `70.5` becomes `71` with no places.
+pub(crate) fn round_decimal(value: &str, places: usize) -> String {
+    let Ok(number) = value.parse::<f64>() else {
+        return value.to_string();
+    };
+    let scale = 10f64.powi(i32::try_from(places).unwrap_or(i32::MAX));
+    let scaled = (number * scale).round() / scale;
+    // Past the precision of an f64 there is nothing left to round
+    let number = if scaled.is_finite() { scaled } else { number };
+    let rounded = format!("{:.*}", places, number);
+    let rounded = if rounded.contains('.') {
+        rounded.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &rounded
+    };
+    if rounded == "-0" {
+        "0".to_string()
+    } else {
+        rounded.to_string()
+    }
+}
+
+/// Whether `value` is a number with a fractional part, such as `-0.25` or
+/// `1.5e3`; integers have no separator to localize.
+pub(crate) fn is_decimal(value: &str) -> bool {
+    let (mantissa, exponent) = match value.find(['e', 'E']) {
+        Some(i) => (&value[..i], Some(&value[i + 1..])),
+        None => (value, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let mantissa = mantissa.strip_prefix('-').unwrap_or(mantissa);
+    let exponent_ok = exponent.is_none_or(|e| digits(e.strip_prefix(['-', '+']).unwrap_or(e)));
+    match mantissa.split_once('.') {
+        Some((int, frac)) => digits(int) && digits(frac) && exponent_ok,
+        None => false,
+    }
+}
+
+/// A CSV reader for an entry written by [`CsvZipSink`] in any
+/// [`CsvDialect`], skipping the `sep=` line if there is one.
+pub fn csv_reader<R: Read>(entry: R) -> Result<csv::Reader<BufReader<R>>> {
+    let mut entry = BufReader::new(entry);
+    let mut delimiter = None;
+    if entry.fill_buf()?.starts_with(b"sep=") {
+        let mut hint = String::new();
+        entry.read_line(&mut hint)?;
+        delimiter = hint.trim_end().as_bytes().get(4).copied();
+    }
+    // Header names never contain a delimiter, so the header line tells
+    // which one was used when there is no hint
+    let delimiter = match delimiter {
+        Some(delimiter) => delimiter,
+        None => {
+            let header = entry.fill_buf()?;
+            let header = &header[..memchr::memchr(b'\n', header).unwrap_or(header.len())];
+            if memchr::memchr(b';', header).is_some() {
+                b';'
+            } else {
+                b','
+            }
+        }
+    };
+    Ok(csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(entry))
+}
+
+/// The other spellings of the headers that attributes differing only in
+/// case were merged into, as found by [`merge_case_variants`].
+pub type CaseVariants<'a> = AHashMap<&'a str, Vec<&'a str>>;
+
 /// Trait for writing records to a CSV writer using dynamic headers.
 pub trait CsvWritable {
     /// Return the attribute keys used for CSV headers.
     fn header_keys(&self) -> impl Iterator<Item = &str>;
 
-    /// Write the record using the provided header ordering.
-    fn write<W: Write>(&self, writer: &mut csv::Writer<W>, headers: &[&str]) -> csv::Result<()>;
+    /// The record's cells in the provided header ordering, with numeric
+    /// cells rounded by [`CsvDialect::round`]. A header missing from the
+    /// record takes the value of the first of its `variants` it has.
+    fn cells(
+        &self,
+        headers: &[&str],
+        variants: &CaseVariants<'_>,
+        dialect: &CsvDialect,
+    ) -> Vec<Cow<'_, str>>;
+}
+
+/// Deterministic id of `record`: the first 16 bytes of a SHA-256 over its
+/// type and [`ID_FIELDS`], as hex.
+pub fn record_id<T: Processable>(record: &T) -> String {
+    let typ = match record.field("type") {
+        Some(typ) => Cow::Borrowed(typ),
+        None => Cow::Owned(record.grouping_key()),
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(typ.as_bytes());
+    for field in ID_FIELDS {
+        // Separated by a byte no attribute contains, so fields cannot run
+        // into each other
+        hasher.update([0x1f]);
+        hasher.update(record.field(field).unwrap_or_default().as_bytes());
+    }
+    to_hex(&hasher.finalize()[..16])
 }
 
-pub struct CsvZipSink;
+/// The cells of `record` under `headers`, laid out like
+/// [`CsvDialect::header_row`]: preceded by its [`record_id`], with the
+/// milliseconds after each date and with its units as `units` plans when
+/// the dialect adds those.
+fn row_cells<'a, T>(
+    record: &'a T,
+    headers: &[&str],
+    variants: &CaseVariants<'_>,
+    dialect: &CsvDialect,
+    units: &UnitPlan,
+) -> Vec<Cow<'a, str>>
+where
+    T: Processable + CsvWritable,
+{
+    let mut cells = record.cells(headers, variants, dialect);
+    units.apply(record, &mut cells, dialect);
+    if !dialect.record_ids && !dialect.epoch_columns {
+        return cells;
+    }
+    let mut row = Vec::with_capacity(cells.len() + 1 + EPOCH_COLUMNS.len());
+    row.extend(dialect.record_ids.then(|| Cow::Owned(record_id(record))));
+    let mut cells = cells.into_iter();
+    for (header, cell) in headers.iter().zip(&mut cells) {
+        let millis = (dialect.epoch_columns && epoch_column(header).is_some()).then(|| {
+            let millis = parse_timestamp(&cell).map(|secs| secs * 1000);
+            Cow::Owned(millis.map(|ms| ms.to_string()).unwrap_or_default())
+        });
+        row.push(cell);
+        row.extend(millis);
+    }
+    // The cells `units` appended
+    row.extend(cells);
+    row
+}
+
+/// Write one row of `cells`, adding them to `schema` if one is inferred.
+fn write_row<W: Write>(
+    writer: &mut EntryWriter<W>,
+    mut cells: Vec<Cow<'_, str>>,
+    schema: &mut Option<TableSchema>,
+) -> Result<()> {
+    writer.localize(&mut cells);
+    if let Some(schema) = schema {
+        schema.add(&cells);
+    }
+    writer.write_row(&cells)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CsvZipSink {
+    compression: Compression,
+    dialect: CsvDialect,
+    reports: Reports,
+    heart_rate_zones: HeartRateZones,
+    names: Arc<NameMap>,
+    #[cfg(feature = "native")]
+    backpressure: Backpressure,
+    #[cfg(feature = "native")]
+    retry: RetryPolicy,
+}
+
+impl CsvZipSink {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            ..Self::default()
+        }
+    }
+
+    /// Write the CSVs in `dialect` instead of the default comma-separated
+    /// one with `.` decimals.
+    pub fn dialect(mut self, dialect: CsvDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Also write summaries of the archive in each of `reports`' formats.
+    pub fn reports(mut self, reports: Reports) -> Self {
+        self.reports = reports;
+        self
+    }
+
+    /// Bound the zones of [`ReportFormat::Zones`] with `zones` instead of
+    /// the age-based defaults.
+    pub fn heart_rate_zones(mut self, zones: HeartRateZones) -> Self {
+        self.heart_rate_zones = zones;
+        self
+    }
+
+    /// Name the entries of the groups in `names` as given there instead of
+    /// after the group.
+    pub fn names(mut self, names: Arc<NameMap>) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Bound the serialized CSV bytes waiting to be compressed into the
+    /// archive.
+    #[cfg(feature = "native")]
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Retry creating and writing the output archive on transient errors,
+    /// such as those of network file systems.
+    #[cfg(feature = "native")]
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
 
+#[cfg(feature = "native")]
 #[async_trait::async_trait]
 impl<T> Sink<T> for CsvZipSink
 where
@@ -36,56 +1050,244 @@ where
         &self,
         grouped_records: AHashMap<String, Vec<T>>,
         output_path: &Path,
+        context: LoadContext<'_>,
     ) -> Result<()> {
-        let out = output_path.to_owned();
-        task::spawn_blocking(move || Self::load_sync(grouped_records, &out))
+        let record_counts = grouped_records
+            .iter()
+            .filter(|(_, records)| !records.is_empty())
+            .map(|(name, records)| (name.clone(), records.len()))
+            .collect();
+        let file = self.retry.create(output_path)?;
+        self.load_to(grouped_records, file, context).await?;
+        reconcile(output_path, record_counts, Arc::clone(&self.names)).await
+    }
+
+    async fn load_spilled(
+        &self,
+        groups: SpilledGroups<T>,
+        output_path: &Path,
+        context: LoadContext<'_>,
+    ) -> Result<()>
+    where
+        T: DeserializeOwned,
+    {
+        let record_counts = groups
+            .groups()
+            .iter()
+            .map(|group| (group.name.clone(), group.len()))
+            .collect();
+        let file = self.retry.create(output_path)?;
+        let (compression, dialect) = (self.compression, self.dialect);
+        let trailer = Trailer::new(&context, self.reports, self.heart_rate_zones)?;
+        let progress = context.progress.clone();
+        let names = Arc::clone(&self.names);
+        task::spawn_blocking(move || {
+            write_spilled(
+                &groups,
+                &names,
+                file,
+                compression,
+                dialect,
+                trailer,
+                &progress,
+            )
+        })
+        .await
+        .map_err(|e| AppError::Unknown(e.to_string()))??;
+        reconcile(output_path, record_counts, Arc::clone(&self.names)).await
+    }
+}
+
+/// Re-read the archive written to `output_path` and fail unless every CSV
+/// holds the rows of its group.
+#[cfg(feature = "native")]
+async fn reconcile(
+    output_path: &Path,
+    record_counts: BTreeMap<String, usize>,
+    names: Arc<NameMap>,
+) -> Result<()> {
+    let start = Instant::now();
+    let path = output_path.to_path_buf();
+    task::spawn_blocking(move || validate::reconcile(&path, record_counts, &names))
+        .await
+        .map_err(|e| AppError::Unknown(e.to_string()))??;
+    debug!(
+        "Reconciled row counts in {:.3}s",
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "native")]
+#[async_trait::async_trait]
+impl<T> SinkTo<T> for CsvZipSink
+where
+    T: Processable + CsvWritable + Send + Sync + 'static,
+{
+    async fn load_to<W>(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        writer: W,
+        context: LoadContext<'_>,
+    ) -> Result<W>
+    where
+        W: Write + Seek + Send + 'static,
+    {
+        self.load_zip(grouped_records, ZipWriter::new(writer), context)
             .await
-            .unwrap()
     }
 }
 
+#[cfg(feature = "native")]
 impl CsvZipSink {
-    fn load_sync<T>(grouped_records: AHashMap<String, Vec<T>>, output_path: &Path) -> Result<()>
+    /// Like [`SinkTo::load_to`] for writers that cannot seek, such as sockets
+    /// or upload streams. Entry sizes are recorded in data descriptors.
+    pub async fn load_to_stream<T, W>(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        writer: W,
+        context: LoadContext<'_>,
+    ) -> Result<W>
+    where
+        T: Processable + CsvWritable + Send + Sync + 'static,
+        W: Write + Send + 'static,
+    {
+        let zip = ZipWriter::new_stream(writer);
+        let stream = self.load_zip(grouped_records, zip, context).await?;
+        Ok(stream.into_inner())
+    }
+
+    async fn load_zip<T, W>(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        zip: ZipWriter<W>,
+        context: LoadContext<'_>,
+    ) -> Result<W>
     where
         T: Processable + CsvWritable + Send + Sync + 'static,
+        W: Write + Seek + Send + 'static,
+    {
+        let (compression, dialect) = (self.compression, self.dialect);
+        let budget = self.backpressure.byte_budget("archive_writer");
+        let trailer = Trailer::new(&context, self.reports, self.heart_rate_zones)?;
+        let progress = context.progress.clone();
+        let names = Arc::clone(&self.names);
+        task::spawn_blocking(move || {
+            Self::load_sync(
+                filter_entries(grouped_records, &names),
+                zip,
+                compression,
+                dialect,
+                budget,
+                trailer,
+                progress,
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    fn load_sync<T, W>(
+        entries: Vec<(String, String, Vec<T>)>,
+        zip: ZipWriter<W>,
+        compression: Compression,
+        dialect: CsvDialect,
+        budget: ByteBudget,
+        trailer: Trailer,
+        progress: Progress,
+    ) -> Result<W>
+    where
+        T: Processable + CsvWritable + Send + Sync + 'static,
+        W: Write + Seek + Send + 'static,
     {
         let start = Instant::now();
 
-        let entries = filter_entries(grouped_records);
+        let reports = trailer.reports;
         let total_files = entries.len();
-        let total_recs: usize = entries.iter().map(|(_, v)| v.len()).sum();
+        let total_recs: usize = entries.iter().map(|(_, _, v)| v.len()).sum();
         info!(
             "Exporting {} CSVs, {} total records",
             total_files, total_recs
         );
 
-        // 2. Parallel CSV serialization into byte buffers and streaming merge into the final ZIP
-        //    Benchmarks with `tests/fixtures/sample_export.xml` showed a small win from
-        //    buffering four mini-zips at a time (~0.28s vs. 0.33s for capacity 1).
-        //    If memory usage allows in the future, we could stream CSV data directly into the
-        //    final archive and remove this channel entirely.
+        // 2. Parallel CSV serialization and compression, each entry copied into
+        //    the final ZIP as soon as it is finished. The bounded channel keeps at
+        //    most a few finished entries waiting for the writer, and the byte
+        //    budget keeps a few huge ones from piling up.
         let queue_capacity = (rayon::current_num_threads().saturating_mul(2)).max(4);
-        let (tx, rx) = bounded::<(String, Cursor<Vec<u8>>)>(queue_capacity);
+        let (tx, rx) = bounded::<CsvEntry>(queue_capacity);
 
-        let merge_handle = spawn_merger(output_path, rx, start);
+        let writer_handle = spawn_writer(
+            zip,
+            rx,
+            budget.clone(),
+            start,
+            compression,
+            trailer,
+            progress,
+        );
 
-        // 3. Produce mini-zips in parallel and stream into the merge channel
+        // 3. Serialize and compress groups in parallel and stream them into the
+        //    writer channel
         entries
             .into_par_iter()
-            .try_for_each(|(name, mut recs)| -> Result<()> {
-                let cursor = create_mini_zip(&name, &mut recs)?;
-                tx.send((name, cursor))
+            .try_for_each(|(name, file_name, mut recs)| -> Result<()> {
+                let entry = create_csv_entry(name, file_name, &mut recs, &dialect, reports)?;
+                // Free the records before holding a compressed copy as well
+                drop(recs);
+                let entry = entry.compress(compression)?;
+                budget.acquire(entry.data.len());
+                let entry = match tx.try_send(entry) {
+                    Err(TrySendError::Full(entry)) => entry,
+                    sent => return sent.map_err(|e| AppError::Unknown(e.to_string())),
+                };
+                let _wait = info_span!("wait", on = "archive_writer").entered();
+                tx.send(entry)
                     .map_err(|e| AppError::Unknown(e.to_string()))?;
                 Ok(())
             })?;
 
-        // drop sender and wait for merging to complete
+        // drop sender and wait for writing to complete
         drop(tx);
-        merge_handle.join().expect("merge thread panicked")
+        writer_handle.join().expect("writer thread panicked")
+    }
+}
+
+impl CsvZipSink {
+    /// Sequential counterpart of `SinkTo::load_to` that needs neither an
+    /// async runtime nor worker threads.
+    pub fn write_sequential<T, W>(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        writer: W,
+        context: LoadContext<'_>,
+    ) -> Result<W>
+    where
+        T: Processable + CsvWritable,
+        W: Write + Seek,
+    {
+        let compression = self.compression;
+        let trailer = Trailer::new(&context, self.reports, self.heart_rate_zones)?;
+        let entries = filter_entries(grouped_records, &self.names)
+            .into_iter()
+            .map(|(name, file_name, mut recs)| {
+                create_csv_entry(name, file_name, &mut recs, &self.dialect, self.reports)
+            });
+        write_archive(
+            ZipWriter::new(writer),
+            entries,
+            compression,
+            trailer,
+            context.progress,
+        )
     }
 }
 
-fn filter_entries<T>(grouped_records: AHashMap<String, Vec<T>>) -> Vec<(String, Vec<T>)>
+/// Non-empty groups sorted by name, each with its entry file name.
+fn filter_entries<T>(
+    grouped_records: AHashMap<String, Vec<T>>,
+    names: &NameMap,
+) -> Vec<(String, String, Vec<T>)>
 where
     T: Processable + CsvWritable,
 {
@@ -101,35 +1303,277 @@ where
         })
         .collect();
     entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let file_names = entry_file_names(entries.iter().map(|(name, _)| name.as_str()), names);
     entries
+        .into_iter()
+        .zip(file_names)
+        .map(|((name, recs), file_name)| (name, file_name, recs))
+        .collect()
 }
 
-fn spawn_merger(
-    output_path: &Path,
-    rx: Receiver<(String, Cursor<Vec<u8>>)>,
+#[cfg(feature = "native")]
+fn spawn_writer<W>(
+    zip: ZipWriter<W>,
+    rx: Receiver<CsvEntry>,
+    budget: ByteBudget,
     start: Instant,
-) -> thread::JoinHandle<Result<()>> {
-    let output_path = output_path.to_owned();
-    thread::spawn(move || -> Result<()> {
-        let mut out = File::create(&output_path)?;
-        let mut zip = ZipWriter::new(&mut out);
-        for (name, mut mini) in rx {
-            let src = ZipArchive::new(&mut mini)?;
-            zip.merge_archive(src)?;
-            debug!("Merged '{}.csv' from mini-zip", name);
-        }
-        zip.finish()?;
+    compression: Compression,
+    trailer: Trailer,
+    progress: Progress,
+) -> thread::JoinHandle<Result<W>>
+where
+    W: Write + Seek + Send + 'static,
+{
+    thread::spawn(move || -> Result<W> {
+        let entries = rx.into_iter().map(|entry| {
+            budget.release(entry.data.len());
+            Ok(entry)
+        });
+        let written = write_archive(zip, entries, compression, trailer, &progress);
+        // Producers waiting for room must see the closed channel instead
+        budget.close();
+        let writer = written?;
         log::info!("Done in {:.2}s", start.elapsed().as_secs_f64());
-        Ok(())
+        Ok(writer)
     })
 }
 
-fn create_mini_zip<T>(name: &str, recs: &mut [T]) -> Result<Cursor<Vec<u8>>>
+/// Add `entries` to `zip`, compressing those that are not compressed yet,
+/// then append the trailer and checksum entries.
+fn write_archive<W, I>(
+    mut zip: ZipWriter<W>,
+    entries: I,
+    compression: Compression,
+    mut trailer: Trailer,
+    progress: &Progress,
+) -> Result<W>
+where
+    W: Write + Seek,
+    I: IntoIterator<Item = Result<CsvEntry>>,
+{
+    let _span = info_span!("write_archive").entered();
+    let mut checksums = BTreeMap::new();
+    for entry in entries {
+        let entry = entry?;
+        match &entry.data {
+            EntryData::Csv(csv) => {
+                zip.start_file(
+                    entry.file_name.as_str(),
+                    compression.file_options(csv.len()),
+                )?;
+                zip.write_all(csv)?;
+            }
+            #[cfg(feature = "native")]
+            EntryData::Compressed(archive) => {
+                let mut archive = ZipArchive::new(Cursor::new(archive.as_slice()))?;
+                zip.raw_copy_file(archive.by_index_raw(0)?)?;
+            }
+        }
+        debug!("Wrote '{}' ({} bytes)", entry.file_name, entry.data.len());
+        if let Some(schema) = entry.schema {
+            write_schema(
+                &mut zip,
+                &entry.file_name,
+                &schema,
+                compression,
+                &mut checksums,
+            )?;
+        }
+        if let Some(columns) = entry.columns {
+            trailer.add_message(&entry.file_name, columns);
+        }
+        checksums.insert(entry.file_name, entry.sha256);
+        trailer.add_group(entry.summary);
+        progress.emit(ProgressEvent::GroupWritten {
+            name: entry.group,
+            records: entry.records,
+        });
+    }
+
+    finish_archive(zip, checksums, compression, trailer)
+}
+
+/// Write the Table Schema of CSV entry `file_name` next to it.
+fn write_schema<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    file_name: &str,
+    schema: &[u8],
+    compression: Compression,
+    checksums: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    let schema_name = schema_file_name(file_name);
+    zip.start_file(schema_name.as_str(), compression.file_options(schema.len()))?;
+    zip.write_all(schema)?;
+    checksums.insert(schema_name, to_hex(&Sha256::digest(schema)));
+    Ok(())
+}
+
+/// Append the trailer entries and the checksums of every entry, then close
+/// the archive.
+fn finish_archive<W: Write + Seek>(
+    mut zip: ZipWriter<W>,
+    mut checksums: BTreeMap<String, String>,
+    compression: Compression,
+    trailer: Trailer,
+) -> Result<W> {
+    let _span = info_span!("write_trailer").entered();
+    for (file_name, data) in trailer.into_entries()? {
+        zip.start_file(&*file_name, compression.file_options(data.len()))?;
+        zip.write_all(&data)?;
+        checksums.insert(file_name.to_string(), to_hex(&Sha256::digest(&data)));
+    }
+
+    let sums: String = checksums
+        .iter()
+        .map(|(file_name, sha256)| format!("{}  {}\n", sha256, file_name))
+        .collect();
+    zip.start_file(CHECKSUMS_FILE_NAME, compression.file_options(sums.len()))?;
+    zip.write_all(sums.as_bytes())?;
+    Ok(zip.finish()?)
+}
+
+/// Stream each spilled group into `writer` one at a time, so only one
+/// record per sorted run is held in memory.
+#[cfg(feature = "native")]
+fn write_spilled<T, W>(
+    groups: &SpilledGroups<T>,
+    names: &NameMap,
+    writer: W,
+    compression: Compression,
+    dialect: CsvDialect,
+    mut trailer: Trailer,
+    progress: &Progress,
+) -> Result<W>
+where
+    T: Processable + CsvWritable + DeserializeOwned,
+    W: Write + Seek,
+{
+    let mut zip = ZipWriter::new(writer);
+    let mut checksums = BTreeMap::new();
+    let file_names = entry_file_names(groups.groups().iter().map(|g| g.name.as_str()), names);
+    for (group, file_name) in groups.groups().iter().zip(file_names) {
+        let file_name = dialect.entry_name(file_name);
+        let _span =
+            info_span!("serialize", group = %group.name, records = group.len(), spilled = true)
+                .entered();
+        // Headers must be known before the first row, so take one pass for
+        // them, summarizing the group on the way if needed
+        let mut header_set: BTreeSet<String> = BTreeSet::new();
+        let reports = trailer.reports;
+        let mut summary = reports
+            .needs_summaries()
+            .then(|| GroupSummary::for_reports(&group.name, reports));
+        let mut units = UnitPlan::new(dialect.units);
+        group.for_each(|record| {
+            if let Some(summary) = &mut summary {
+                summary.add(record);
+            }
+            units.add(record);
+            for key in record.header_keys() {
+                if !header_set.contains(key) && dialect.keeps_column(record, key) {
+                    header_set.insert(key.to_string());
+                }
+            }
+            Ok(())
+        })?;
+        let (mut headers, variants) =
+            merge_case_variants(&group.name, header_set.iter().map(String::as_str));
+        dialect.order_columns(&mut headers);
+        units.finish(&headers);
+
+        // The CSV size is only known once written
+        zip.start_file(file_name.as_str(), compression.streamed_file_options())?;
+        let mut hashing = HashingWriter::new(&mut zip);
+        let header_row = dialect.header_row(&headers, &units);
+        let mut schema = reports
+            .contains(ReportFormat::Schema)
+            .then(|| TableSchema::new(&header_row, dialect));
+        {
+            let mut w = dialect.writer(&mut hashing)?;
+            w.write_header(&header_row)?;
+            group.for_each(|record| {
+                write_row(
+                    &mut w,
+                    row_cells(record, &headers, &variants, &dialect, &units),
+                    &mut schema,
+                )
+            })?;
+            w.flush()?;
+        }
+        debug!("Streamed '{}' from {} records", file_name, group.len());
+        let digest = hashing.hex_digest();
+        if let Some(schema) = schema {
+            write_schema(
+                &mut zip,
+                &file_name,
+                &schema.to_json()?,
+                compression,
+                &mut checksums,
+            )?;
+        }
+        if dialect.protobuf {
+            let columns = header_row.iter().map(|c| c.to_string()).collect();
+            trailer.add_message(&file_name, columns);
+        }
+        checksums.insert(file_name, digest);
+        trailer.add_group(summary);
+        progress.emit(ProgressEvent::GroupWritten {
+            name: group.name.clone(),
+            records: group.len(),
+        });
+    }
+    finish_archive(zip, checksums, compression, trailer)
+}
+
+/// Forwards writes while hashing everything written.
+#[cfg(feature = "native")]
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn hex_digest(self) -> String {
+        to_hex(&self.hasher.finalize())
+    }
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn create_csv_entry<T>(
+    name: String,
+    file_name: String,
+    recs: &mut [T],
+    dialect: &CsvDialect,
+    reports: Reports,
+) -> Result<CsvEntry>
 where
     T: Processable + CsvWritable,
 {
+    let _span = info_span!("serialize", group = %name, records = recs.len()).entered();
+    let file_name = dialect.entry_name(file_name);
     let mut has_sort_keys = false;
-    let sort_keys: Vec<Option<&str>> = recs
+    // Parse every key once rather than on each comparison
+    let sort_keys: Vec<Option<i64>> = recs
         .iter()
         .map(|r| {
             let key = r.sort_key();
@@ -141,58 +1585,89 @@ where
         .collect();
     if has_sort_keys {
         let mut indices: Vec<usize> = (0..recs.len()).collect();
-        indices.sort_unstable_by_key(|&idx| sort_keys[idx]);
+        indices.sort_by_key(|&idx| sort_keys[idx]);
         drop(sort_keys);
         reorder_by_indices(recs, &indices);
     }
 
     // Determine dynamic headers once per file
     let mut header_set: AHashSet<&str> = AHashSet::new();
+    let mut units = UnitPlan::new(dialect.units);
     for r in &*recs {
-        header_set.extend(r.header_keys());
+        header_set.extend(r.header_keys().filter(|key| dialect.keeps_column(r, key)));
+        units.add(r);
     }
     let mut headers: Vec<&str> = header_set.into_iter().collect();
     headers.sort_unstable();
+    let (mut headers, variants) = merge_case_variants(&name, headers);
+    dialect.order_columns(&mut headers);
+    units.finish(&headers);
 
-    let mut csv_buf = Vec::with_capacity(recs.len().saturating_mul(headers.len().max(1) * 8));
+    let header_row = dialect.header_row(&headers, &units);
+    let mut schema = reports
+        .contains(ReportFormat::Schema)
+        .then(|| TableSchema::new(&header_row, *dialect));
+    let mut csv_buf = Vec::with_capacity(recs.len().saturating_mul(header_row.len().max(1) * 8));
     {
-        let mut w = csv::WriterBuilder::new()
-            .has_headers(true)
-            .buffer_capacity(128 * 1024)
-            .from_writer(&mut csv_buf);
-        w.write_record(&headers)?;
+        let mut w = dialect.writer(&mut csv_buf)?;
+        w.write_header(&header_row)?;
         for r in &*recs {
-            r.write(&mut w, &headers)?;
+            let cells = row_cells(r, &headers, &variants, dialect, &units);
+            write_row(&mut w, cells, &mut schema)?;
         }
         w.flush()?;
     }
     debug!("CSV for '{}' is {} bytes", name, csv_buf.len());
+    let sha256 = to_hex(&Sha256::digest(&csv_buf));
+    let summary = reports.needs_summaries().then(|| {
+        let mut summary = GroupSummary::for_reports(name.as_str(), reports);
+        recs.iter().for_each(|r| summary.add(r));
+        summary
+    });
 
-    let mut cursor = Cursor::new(Vec::with_capacity(csv_buf.len() / 3 + 256));
-    {
-        let mut mini = ZipWriter::new(&mut cursor);
-        let (method, level) = if csv_buf.len() < STORE_THRESHOLD {
-            (CompressionMethod::Stored, None)
-        } else {
-            (CompressionMethod::Deflated, Some(1))
-        };
-        let mut opts = FileOptions::<()>::default()
-            .compression_method(method)
-            .unix_permissions(0o644);
-        if let Some(level) = level {
-            opts = opts.compression_level(Some(level));
+    Ok(CsvEntry {
+        group: name,
+        records: recs.len(),
+        file_name,
+        data: EntryData::Csv(csv_buf),
+        sha256,
+        summary,
+        schema: schema.map(|schema| schema.to_json()).transpose()?,
+        columns: dialect
+            .protobuf
+            .then(|| header_row.iter().map(|c| c.to_string()).collect()),
+    })
+}
+
+/// Drop headers that differ from an earlier one only in case, so such
+/// attributes share a column, and return the dropped spellings of each
+/// header kept for [`CsvWritable::cells`] to look up. `headers` must be
+/// sorted, and the first spelling in that order is kept.
+fn merge_case_variants<'a, I>(group: &str, headers: I) -> (Vec<&'a str>, CaseVariants<'a>)
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut seen: AHashMap<String, &str> = AHashMap::new();
+    let mut merged = Vec::new();
+    let mut variants = CaseVariants::new();
+    for header in headers {
+        match seen.entry(header.to_ascii_lowercase()) {
+            Entry::Occupied(kept) => {
+                warn!(
+                    "Merging column '{}' into '{}' in '{}'",
+                    header,
+                    kept.get(),
+                    group
+                );
+                variants.entry(*kept.get()).or_default().push(header);
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(header);
+                merged.push(header);
+            }
         }
-        mini.start_file(format!("{}.csv", name), opts)?;
-        mini.write_all(&csv_buf)?;
-        mini.finish()?;
     }
-    debug!(
-        "Compressed CSV for '{}' is {} bytes",
-        name,
-        cursor.get_ref().len()
-    );
-    cursor.set_position(0);
-    Ok(cursor)
+    (merged, variants)
 }
 
 fn reorder_by_indices<T>(items: &mut [T], order: &[usize]) {
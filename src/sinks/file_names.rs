@@ -0,0 +1,120 @@
+#[cfg(feature = "native")]
+use crate::error::{AppError, Result};
+use crate::manifest::to_hex;
+use crate::sinks::csv_zip::RESERVED_CSV_NAMES;
+use ahash::AHashMap;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+#[cfg(feature = "native")]
+use std::path::Path;
+
+/// Longest entry name written, extension included, leaving room under
+/// Windows' 260-character path limit for the directory it is extracted to.
+pub const MAX_ENTRY_NAME_LEN: usize = 120;
+
+const EXTENSION: &str = ".csv";
+
+/// Hex digits of the group's SHA-256 appended to disambiguate collisions.
+const HASH_SUFFIX_LEN: usize = 8;
+
+/// Device names Windows reserves regardless of extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Output names chosen by the user for some groups, such as `steps` for
+/// `HKQuantityTypeIdentifierStepCount`, without the `.csv` extension.
+pub type NameMap = BTreeMap<String, String>;
+
+/// Read a [`NameMap`] from a TOML file of `group = "name"` lines.
+#[cfg(feature = "native")]
+pub fn load_name_map(path: &Path) -> Result<NameMap> {
+    let text = std::fs::read_to_string(path)?;
+    toml::from_str(&text)
+        .map_err(|e| AppError::InvalidConfig(format!("invalid name map {}: {}", path.display(), e)))
+}
+
+/// CSV entry names for `groups`, in the same order, that extract cleanly on
+/// Windows: reserved characters are replaced, reserved device names and
+/// trailing dots or spaces are avoided and names are kept short. Groups in
+/// `names` are named as given there.
+///
+/// Groups whose names end up equal, ignoring case as Windows does, are told
+/// apart by a hash of the group name; the alphabetically first one keeps
+/// the plain name, so the result does not depend on the order of `groups`.
+pub fn entry_file_names<'a, I>(groups: I, names: &NameMap) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let groups: Vec<(&str, String)> = groups
+        .into_iter()
+        .map(|group| {
+            let name = names.get(group).map_or(group, String::as_str);
+            (group, sanitize(name, MAX_ENTRY_NAME_LEN - EXTENSION.len()))
+        })
+        .collect();
+
+    let mut first_by_stem: AHashMap<String, &str> = AHashMap::new();
+    for (group, stem) in &groups {
+        first_by_stem
+            .entry(stem.to_lowercase())
+            .and_modify(|first| *first = (*first).min(group))
+            .or_insert(group);
+    }
+
+    groups
+        .iter()
+        .map(|(group, stem)| {
+            let key = stem.to_lowercase();
+            let reserved = RESERVED_CSV_NAMES
+                .iter()
+                .any(|name| name.trim_end_matches(EXTENSION) == key);
+            if !reserved && first_by_stem[&key] == *group {
+                return format!("{}{}", stem, EXTENSION);
+            }
+            let max_len = MAX_ENTRY_NAME_LEN - EXTENSION.len() - HASH_SUFFIX_LEN - 1;
+            let hash = to_hex(&Sha256::digest(group.as_bytes()));
+            format!(
+                "{}_{}{}",
+                sanitize(stem, max_len),
+                &hash[..HASH_SUFFIX_LEN],
+                EXTENSION
+            )
+        })
+        .collect()
+}
+
+/// Make `name` a valid Windows file name of at most `max_len` bytes.
+fn sanitize(name: &str, max_len: usize) -> String {
+    let mut stem: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // `CON.csv`, `con .csv` and even `con.tar.csv` refer to the console
+    let device = stem.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device))
+    {
+        stem.insert(device.len(), '_');
+    }
+
+    if stem.len() > max_len {
+        let mut end = max_len;
+        while !stem.is_char_boundary(end) {
+            end -= 1;
+        }
+        stem.truncate(end);
+    }
+    stem.truncate(stem.trim_end_matches(['.', ' ']).len());
+    if stem.is_empty() {
+        stem.push('_');
+    }
+    stem
+}
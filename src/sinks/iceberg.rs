@@ -0,0 +1,651 @@
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::core::{LoadContext, Processable, Sink};
+use crate::error::{AppError, Result};
+use crate::progress::{Progress, ProgressEvent};
+use crate::sinks::csv_zip::CsvWritable;
+use crate::sinks::file_names::{NameMap, entry_file_names};
+use ahash::AHashMap;
+use async_trait::async_trait;
+use log::{info, warn};
+use parquet::basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::MicroSeconds;
+use parquet::schema::types::Type;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task;
+
+/// Name under which [`IcebergSink`] is registered.
+pub const ICEBERG_FORMAT: &str = "iceberg";
+
+/// Column holding each record's group, which the table is partitioned by.
+pub const GROUP_COLUMN: &str = "group";
+
+/// Columns written as `timestamptz` instead of strings; the first one is
+/// also partitioned by year.
+const TIMESTAMP_COLUMNS: [&str; 3] = ["startDate", "endDate", "creationDate"];
+
+/// Name of the partition field holding the year of `startDate`.
+const YEAR_PARTITION: &str = "startDate_year";
+
+/// Field ids of the two partition fields, numbered from 1000 as the
+/// Iceberg spec suggests.
+const GROUP_PARTITION_ID: i32 = 1000;
+const YEAR_PARTITION_ID: i32 = 1001;
+
+/// Rows per Parquet row group.
+const ROW_GROUP_ROWS: usize = 1 << 20;
+
+/// Writes grouped records as an Apache Iceberg table (format version 2):
+/// Parquet data files partitioned by group and year of `startDate`, plus
+/// the metadata, manifest list and manifest that describe one snapshot.
+///
+/// The output path is the table's directory, which must not hold a table
+/// yet. Its `metadata/v1.metadata.json` can be registered in a catalog as
+/// is; `version-hint.text` also lets file-system catalogs find it. Every
+/// path in the metadata is absolute, under the directory written to or the
+/// [`location`](Self::location) the table will be uploaded to.
+#[derive(Debug, Clone, Default)]
+pub struct IcebergSink {
+    location: Option<String>,
+    names: Arc<NameMap>,
+}
+
+impl IcebergSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record paths under `location`, such as `s3://bucket/health`, instead
+    /// of the local directory, for tables copied there after writing.
+    pub fn location(mut self, location: impl Into<String>) -> Self {
+        self.location = Some(location.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Name the partition directories of the groups in `names` as given
+    /// there, like the CSV entries.
+    pub fn names(mut self, names: Arc<NameMap>) -> Self {
+        self.names = names;
+        self
+    }
+}
+
+#[async_trait]
+impl<T> Sink<T> for IcebergSink
+where
+    T: Processable + CsvWritable + Send + Sync + 'static,
+{
+    async fn load(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        output_path: &Path,
+        context: LoadContext<'_>,
+    ) -> Result<()> {
+        let sink = self.clone();
+        let output_path = output_path.to_path_buf();
+        let progress = context.progress.clone();
+        task::spawn_blocking(move || sink.write_table(grouped_records, &output_path, &progress))
+            .await
+            .map_err(|e| AppError::Unknown(e.to_string()))?
+    }
+}
+
+/// A column of the table schema.
+struct Column {
+    id: i32,
+    name: String,
+    timestamp: bool,
+}
+
+/// A Parquet data file written for one partition.
+struct DataFile {
+    path: String,
+    group: String,
+    year: Option<i32>,
+    records: usize,
+    bytes: u64,
+}
+
+impl IcebergSink {
+    fn write_table<T>(
+        &self,
+        grouped_records: AHashMap<String, Vec<T>>,
+        table_path: &Path,
+        progress: &Progress,
+    ) -> Result<()>
+    where
+        T: Processable + CsvWritable,
+    {
+        let metadata_dir = table_path.join("metadata");
+        if metadata_dir.exists() {
+            return Err(AppError::InvalidConfig(format!(
+                "{} already holds an Iceberg table",
+                table_path.display()
+            )));
+        }
+        fs::create_dir_all(&metadata_dir)?;
+        let location = match &self.location {
+            Some(location) => location.clone(),
+            None => format!("file://{}", fs::canonicalize(table_path)?.display()),
+        };
+
+        let mut groups: Vec<(String, Vec<T>)> = grouped_records
+            .into_iter()
+            .filter(|(_, records)| !records.is_empty())
+            .collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+        let columns = table_columns(groups.iter().flat_map(|(_, records)| records));
+        let dir_names = entry_file_names(groups.iter().map(|(name, _)| name.as_str()), &self.names);
+
+        let mut data_files = Vec::new();
+        for ((group, records), dir_name) in groups.iter().zip(dir_names) {
+            let mut by_year: BTreeMap<Option<i32>, Vec<&T>> = BTreeMap::new();
+            for record in records {
+                by_year.entry(start_year(record)).or_default().push(record);
+            }
+            let dir_name = dir_name.trim_end_matches(".csv");
+            for (year, records) in by_year {
+                let partition = format!(
+                    "data/{}={}/{}={}",
+                    GROUP_COLUMN,
+                    dir_name,
+                    YEAR_PARTITION,
+                    year.map_or("null".to_string(), |year| (1970 + year).to_string())
+                );
+                fs::create_dir_all(table_path.join(&partition))?;
+                let file_name = format!(
+                    "{}/{:05}-{}.parquet",
+                    partition,
+                    data_files.len(),
+                    uuid(&[group.as_bytes(), partition.as_bytes()])
+                );
+                let local_path = table_path.join(&file_name);
+                write_parquet(&local_path, group, &records, &columns)?;
+                data_files.push(DataFile {
+                    path: format!("{}/{}", location, file_name),
+                    group: group.clone(),
+                    year,
+                    records: records.len(),
+                    bytes: fs::metadata(&local_path)?.len(),
+                });
+            }
+            progress.emit(ProgressEvent::GroupWritten {
+                name: group.clone(),
+                records: records.len(),
+            });
+        }
+
+        write_metadata(table_path, &location, &columns, &data_files)?;
+        info!(
+            "Wrote an Iceberg table of {} data files to {}",
+            data_files.len(),
+            table_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// The table's columns: the group, the timestamps and every other
+/// attribute of `records` alphabetically, numbered from 1.
+fn table_columns<'a, T>(records: impl Iterator<Item = &'a T>) -> Vec<Column>
+where
+    T: CsvWritable + 'a,
+{
+    let mut keys: BTreeSet<&str> = BTreeSet::new();
+    for record in records {
+        keys.extend(record.header_keys());
+    }
+    let others = keys
+        .into_iter()
+        .filter(|key| *key != GROUP_COLUMN && !TIMESTAMP_COLUMNS.contains(key));
+    std::iter::once(GROUP_COLUMN)
+        .chain(TIMESTAMP_COLUMNS)
+        .chain(others)
+        .zip(1..)
+        .map(|(name, id)| Column {
+            id,
+            name: name.to_string(),
+            timestamp: TIMESTAMP_COLUMNS.contains(&name),
+        })
+        .collect()
+}
+
+/// Years since 1970 of `record`'s `startDate`, as Iceberg's `year`
+/// transform gives them.
+fn start_year<T: Processable>(record: &T) -> Option<i32> {
+    let secs = parse_timestamp(record.field(TIMESTAMP_COLUMNS[0])?)?;
+    let year: i32 = format_timestamp(secs)[..4].parse().ok()?;
+    Some(year - 1970)
+}
+
+/// Write `records` of `group` to a Parquet file at `path` with those of
+/// `columns` they have, carrying the columns' field ids.
+fn write_parquet<T>(path: &Path, group: &str, records: &[&T], columns: &[Column]) -> Result<()>
+where
+    T: Processable + CsvWritable,
+{
+    let present: BTreeSet<&str> = records.iter().flat_map(|r| r.header_keys()).collect();
+    let columns: Vec<&Column> = columns
+        .iter()
+        .filter(|c| c.name == GROUP_COLUMN || present.contains(c.name.as_str()))
+        .collect();
+    let fields = columns
+        .iter()
+        .map(|column| parquet_field(column).map(Arc::new))
+        .collect::<parquet::errors::Result<Vec<_>>>()
+        .map_err(parquet_error)?;
+    let schema = Type::group_type_builder("table")
+        .with_fields(fields)
+        .build()
+        .map_err(parquet_error)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(Default::default()))
+        .set_created_by(format!("gpt-os version {}", env!("CARGO_PKG_VERSION")))
+        .build();
+    let mut writer =
+        SerializedFileWriter::new(File::create(path)?, Arc::new(schema), Arc::new(properties))
+            .map_err(parquet_error)?;
+
+    let mut unparsed = 0usize;
+    for chunk in records.chunks(ROW_GROUP_ROWS) {
+        let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+        for column in &columns {
+            let mut column_writer = row_group
+                .next_column()
+                .map_err(parquet_error)?
+                .ok_or_else(|| AppError::Unknown("missing Parquet column".to_string()))?;
+            if column.name == GROUP_COLUMN {
+                let values = vec![ByteArray::from(group); chunk.len()];
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)
+                    .map_err(parquet_error)?;
+            } else if column.timestamp {
+                let mut values = Vec::with_capacity(chunk.len());
+                let mut levels = Vec::with_capacity(chunk.len());
+                for record in chunk {
+                    let value = record.field(&column.name).filter(|v| !v.is_empty());
+                    let micros = value.and_then(parse_timestamp).map(|secs| secs * 1_000_000);
+                    unparsed += usize::from(value.is_some() && micros.is_none());
+                    levels.push(i16::from(micros.is_some()));
+                    values.extend(micros);
+                }
+                column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, Some(&levels), None)
+                    .map_err(parquet_error)?;
+            } else {
+                let mut values = Vec::with_capacity(chunk.len());
+                let mut levels = Vec::with_capacity(chunk.len());
+                for record in chunk {
+                    let value = record.field(&column.name).filter(|v| !v.is_empty());
+                    levels.push(i16::from(value.is_some()));
+                    values.extend(value.map(ByteArray::from));
+                }
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, Some(&levels), None)
+                    .map_err(parquet_error)?;
+            }
+            column_writer.close().map_err(parquet_error)?;
+        }
+        row_group.close().map_err(parquet_error)?;
+    }
+    writer.close().map_err(parquet_error)?;
+    if unparsed > 0 {
+        warn!(
+            "Left {} unparseable dates of '{}' out of the Iceberg table",
+            unparsed, group
+        );
+    }
+    Ok(())
+}
+
+fn parquet_field(column: &Column) -> parquet::errors::Result<Type> {
+    let builder = if column.timestamp {
+        Type::primitive_type_builder(&column.name, PhysicalType::INT64).with_logical_type(Some(
+            LogicalType::Timestamp {
+                is_adjusted_to_u_t_c: true,
+                unit: TimeUnit::MICROS(MicroSeconds {}),
+            },
+        ))
+    } else {
+        Type::primitive_type_builder(&column.name, PhysicalType::BYTE_ARRAY)
+            .with_logical_type(Some(LogicalType::String))
+    };
+    let repetition = if column.name == GROUP_COLUMN {
+        Repetition::REQUIRED
+    } else {
+        Repetition::OPTIONAL
+    };
+    builder
+        .with_repetition(repetition)
+        .with_id(Some(column.id))
+        .build()
+}
+
+fn parquet_error(error: parquet::errors::ParquetError) -> AppError {
+    AppError::Unknown(format!("Parquet error: {}", error))
+}
+
+/// Write the manifest, manifest list and table metadata of a snapshot
+/// appending `data_files`.
+fn write_metadata(
+    table_path: &Path,
+    location: &str,
+    columns: &[Column],
+    data_files: &[DataFile],
+) -> Result<()> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as i64);
+    let seed = [location.as_bytes(), &now_ms.to_le_bytes()];
+    let snapshot_id = i64::from_le_bytes(
+        Sha256::digest(seed.concat())[..8]
+            .try_into()
+            .expect("8 bytes"),
+    ) & i64::MAX;
+    let commit = uuid(&seed);
+
+    let schema = json!({
+        "type": "struct",
+        "schema-id": 0,
+        "fields": columns.iter().map(|column| json!({
+            "id": column.id,
+            "name": column.name,
+            "required": column.name == GROUP_COLUMN,
+            "type": if column.timestamp { "timestamptz" } else { "string" },
+        })).collect::<Vec<_>>(),
+    });
+    let partition_fields = json!([
+        {
+            "name": GROUP_COLUMN,
+            "transform": "identity",
+            "source-id": columns[0].id,
+            "field-id": GROUP_PARTITION_ID,
+        },
+        {
+            "name": YEAR_PARTITION,
+            "transform": "year",
+            "source-id": columns[1].id,
+            "field-id": YEAR_PARTITION_ID,
+        },
+    ]);
+
+    // Manifest listing every data file as added
+    let mut entries = Vec::new();
+    for file in data_files {
+        avro::write_int(&mut entries, 1); // status: added
+        avro::write_union(&mut entries, Some(snapshot_id), avro::write_long);
+        // Sequence numbers are inherited from the manifest list
+        avro::write_union(&mut entries, None, avro::write_long);
+        avro::write_union(&mut entries, None, avro::write_long);
+        avro::write_int(&mut entries, 0); // content: data
+        avro::write_string(&mut entries, &file.path);
+        avro::write_string(&mut entries, "PARQUET");
+        avro::write_union(&mut entries, Some(file.group.as_str()), avro::write_string);
+        avro::write_union(&mut entries, file.year, avro::write_int);
+        avro::write_long(&mut entries, file.records as i64);
+        avro::write_long(&mut entries, file.bytes as i64);
+    }
+    let manifest_name = format!("metadata/{}-m0.avro", commit);
+    let manifest = avro::container(
+        &avro::MANIFEST_ENTRY_SCHEMA,
+        &[
+            ("schema", schema.to_string()),
+            ("schema-id", "0".to_string()),
+            ("partition-spec", partition_fields.to_string()),
+            ("partition-spec-id", "0".to_string()),
+            ("format-version", "2".to_string()),
+            ("content", "data".to_string()),
+        ],
+        data_files.len(),
+        &entries,
+        &seed,
+    );
+    fs::write(table_path.join(&manifest_name), &manifest)?;
+
+    // Manifest list holding that one manifest
+    let records: usize = data_files.iter().map(|file| file.records).sum();
+    let mut list = Vec::new();
+    avro::write_string(&mut list, &format!("{}/{}", location, manifest_name));
+    avro::write_long(&mut list, manifest.len() as i64);
+    avro::write_int(&mut list, 0); // partition_spec_id
+    avro::write_int(&mut list, 0); // content: data
+    avro::write_long(&mut list, 1); // sequence_number
+    avro::write_long(&mut list, 1); // min_sequence_number
+    avro::write_long(&mut list, snapshot_id);
+    avro::write_int(&mut list, data_files.len() as i32);
+    avro::write_int(&mut list, 0);
+    avro::write_int(&mut list, 0);
+    avro::write_long(&mut list, records as i64);
+    avro::write_long(&mut list, 0);
+    avro::write_long(&mut list, 0);
+    let list_name = format!("metadata/snap-{}-1-{}.avro", snapshot_id, commit);
+    let manifest_list = avro::container(
+        &avro::MANIFEST_FILE_SCHEMA,
+        &[
+            ("snapshot-id", snapshot_id.to_string()),
+            ("parent-snapshot-id", "null".to_string()),
+            ("sequence-number", "1".to_string()),
+            ("format-version", "2".to_string()),
+        ],
+        1,
+        &list,
+        &seed,
+    );
+    fs::write(table_path.join(&list_name), manifest_list)?;
+
+    let metadata = json!({
+        "format-version": 2,
+        "table-uuid": uuid(&[location.as_bytes()]),
+        "location": location,
+        "last-sequence-number": 1,
+        "last-updated-ms": now_ms,
+        "last-column-id": columns.last().map_or(0, |column| column.id),
+        "current-schema-id": 0,
+        "schemas": [schema],
+        "default-spec-id": 0,
+        "partition-specs": [{ "spec-id": 0, "fields": partition_fields }],
+        "last-partition-id": YEAR_PARTITION_ID,
+        "default-sort-order-id": 0,
+        "sort-orders": [{ "order-id": 0, "fields": [] }],
+        "properties": {
+            "write.format.default": "parquet",
+            "created-by": format!("gpt-os {}", env!("CARGO_PKG_VERSION")),
+        },
+        "current-snapshot-id": snapshot_id,
+        "refs": { "main": { "snapshot-id": snapshot_id, "type": "branch" } },
+        "snapshots": [{
+            "snapshot-id": snapshot_id,
+            "sequence-number": 1,
+            "timestamp-ms": now_ms,
+            "manifest-list": format!("{}/{}", location, list_name),
+            "schema-id": 0,
+            "summary": {
+                "operation": "append",
+                "added-data-files": data_files.len().to_string(),
+                "added-records": records.to_string(),
+                "total-data-files": data_files.len().to_string(),
+                "total-records": records.to_string(),
+            },
+        }],
+        "snapshot-log": [{ "snapshot-id": snapshot_id, "timestamp-ms": now_ms }],
+        "metadata-log": [],
+    });
+    fs::write(
+        table_path.join("metadata/v1.metadata.json"),
+        serde_json::to_vec_pretty(&metadata)?,
+    )?;
+    fs::write(table_path.join("metadata/version-hint.text"), "1")?;
+    Ok(())
+}
+
+/// A version 4 UUID derived from `seed`, so reruns at the same instant
+/// name their files alike.
+fn uuid(seed: &[&[u8]]) -> String {
+    let mut bytes: [u8; 16] = Sha256::digest(seed.concat())[..16]
+        .try_into()
+        .expect("16 bytes");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = crate::manifest::to_hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Just enough of Avro's object container format for Iceberg's manifests.
+mod avro {
+    use serde_json::{Value, json};
+    use sha2::{Digest, Sha256};
+    use std::sync::LazyLock;
+
+    /// `manifest_entry` of the Iceberg v2 spec, with the required fields
+    /// of `data_file` and the table's partition.
+    pub static MANIFEST_ENTRY_SCHEMA: LazyLock<Value> = LazyLock::new(|| {
+        let optional = |name: &str, id: i32, typ: &str| json!({ "name": name, "type": ["null", typ], "default": null, "field-id": id });
+        json!({
+            "type": "record",
+            "name": "manifest_entry",
+            "fields": [
+                { "name": "status", "type": "int", "field-id": 0 },
+                optional("snapshot_id", 1, "long"),
+                optional("sequence_number", 3, "long"),
+                optional("file_sequence_number", 4, "long"),
+                {
+                    "name": "data_file",
+                    "field-id": 2,
+                    "type": {
+                        "type": "record",
+                        "name": "r2",
+                        "fields": [
+                            { "name": "content", "type": "int", "field-id": 134 },
+                            { "name": "file_path", "type": "string", "field-id": 100 },
+                            { "name": "file_format", "type": "string", "field-id": 101 },
+                            {
+                                "name": "partition",
+                                "field-id": 102,
+                                "type": {
+                                    "type": "record",
+                                    "name": "r102",
+                                    "fields": [
+                                        optional(super::GROUP_COLUMN, super::GROUP_PARTITION_ID, "string"),
+                                        optional(super::YEAR_PARTITION, super::YEAR_PARTITION_ID, "int"),
+                                    ],
+                                },
+                            },
+                            { "name": "record_count", "type": "long", "field-id": 103 },
+                            { "name": "file_size_in_bytes", "type": "long", "field-id": 104 },
+                        ],
+                    },
+                },
+            ],
+        })
+    });
+
+    /// `manifest_file` of the Iceberg v2 spec, without its optional fields.
+    pub static MANIFEST_FILE_SCHEMA: LazyLock<Value> = LazyLock::new(|| {
+        let fields = [
+            ("manifest_path", "string", 500),
+            ("manifest_length", "long", 501),
+            ("partition_spec_id", "int", 502),
+            ("content", "int", 517),
+            ("sequence_number", "long", 515),
+            ("min_sequence_number", "long", 516),
+            ("added_snapshot_id", "long", 503),
+            ("added_files_count", "int", 504),
+            ("existing_files_count", "int", 505),
+            ("deleted_files_count", "int", 506),
+            ("added_rows_count", "long", 512),
+            ("existing_rows_count", "long", 513),
+            ("deleted_rows_count", "long", 514),
+        ];
+        json!({
+            "type": "record",
+            "name": "manifest_file",
+            "fields": fields
+                .iter()
+                .map(|(name, typ, id)| json!({ "name": name, "type": typ, "field-id": id }))
+                .collect::<Vec<_>>(),
+        })
+    });
+
+    /// A zigzag-encoded variable-length `long`.
+    pub fn write_long(buf: &mut Vec<u8>, value: i64) {
+        let mut n = ((value << 1) ^ (value >> 63)) as u64;
+        while n >= 0x80 {
+            buf.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        buf.push(n as u8);
+    }
+
+    pub fn write_int(buf: &mut Vec<u8>, value: i32) {
+        write_long(buf, i64::from(value));
+    }
+
+    pub fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+        write_long(buf, bytes.len() as i64);
+        buf.extend_from_slice(bytes);
+    }
+
+    pub fn write_string(buf: &mut Vec<u8>, value: &str) {
+        write_bytes(buf, value.as_bytes());
+    }
+
+    /// A `["null", T]` union holding `value`.
+    pub fn write_union<V>(buf: &mut Vec<u8>, value: Option<V>, write: fn(&mut Vec<u8>, V)) {
+        match value {
+            Some(value) => {
+                write_long(buf, 1);
+                write(buf, value);
+            }
+            None => write_long(buf, 0),
+        }
+    }
+
+    /// An object container file of `count` encoded `records` in one
+    /// uncompressed block, with `metadata` next to the schema.
+    pub fn container(
+        schema: &Value,
+        metadata: &[(&str, String)],
+        count: usize,
+        records: &[u8],
+        seed: &[&[u8]],
+    ) -> Vec<u8> {
+        let sync: [u8; 16] = Sha256::digest([seed.concat(), b"sync".to_vec()].concat())[..16]
+            .try_into()
+            .expect("16 bytes");
+        let mut file = b"Obj\x01".to_vec();
+        let schema = schema.to_string();
+        let entries = [("avro.schema", schema.as_str()), ("avro.codec", "null")]
+            .into_iter()
+            .chain(metadata.iter().map(|(key, value)| (*key, value.as_str())));
+        write_long(&mut file, 2 + metadata.len() as i64);
+        for (key, value) in entries {
+            write_string(&mut file, key);
+            write_string(&mut file, value);
+        }
+        write_long(&mut file, 0);
+        file.extend_from_slice(&sync);
+        write_long(&mut file, count as i64);
+        write_long(&mut file, records.len() as i64);
+        file.extend_from_slice(records);
+        file.extend_from_slice(&sync);
+        file
+    }
+}
@@ -1 +1,9 @@
+pub mod cbor;
 pub mod csv_zip;
+pub mod file_names;
+#[cfg(feature = "native")]
+pub mod iceberg;
+pub mod protobuf;
+#[cfg(feature = "native")]
+pub mod registry;
+pub mod table_schema;
@@ -0,0 +1,170 @@
+use crate::error::{AppError, Result};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, BufReader, Read, Write};
+
+/// Name of the schema entry describing every protobuf entry.
+pub const PROTO_FILE_NAME: &str = "records.proto";
+
+/// Package of the generated messages.
+const PACKAGE: &str = "apple_health";
+
+/// Field numbers protobuf reserves for its own use: 19000 to 19999.
+const FIRST_RESERVED_FIELD: u32 = 19000;
+const RESERVED_FIELDS: u32 = 1000;
+
+/// Messages of the protobuf entries in an archive, one per group, rendered
+/// as a `.proto` file once every group is written.
+#[derive(Debug, Clone, Default)]
+pub struct ProtoSchema {
+    /// Columns of each entry, by entry name.
+    messages: BTreeMap<String, Vec<String>>,
+}
+
+impl ProtoSchema {
+    /// Describe entry `file_name`, whose messages hold the cells of
+    /// `columns` as numbered by [`field_number`].
+    pub fn add(&mut self, file_name: &str, columns: Vec<String>) {
+        self.messages.insert(file_name.to_string(), columns);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The schema as a proto3 file. Every column is a `string` field named
+    /// after it, with a `json_name` giving the column where the column is
+    /// not a valid identifier.
+    pub fn to_proto(&self) -> String {
+        let mut proto = format!(
+            "// Generated by gpt-os {}. Each entry holds messages of the type\n\
+             // noted above it, each preceded by its length as a varint.\n\
+             syntax = \"proto3\";\n\npackage {};\n",
+            env!("CARGO_PKG_VERSION"),
+            PACKAGE
+        );
+        let mut message_names = BTreeSet::new();
+        for (file_name, columns) in &self.messages {
+            let stem = file_name
+                .rsplit_once('.')
+                .map_or(&**file_name, |(stem, _)| stem);
+            let message = unique(identifier(stem), &mut message_names);
+            let _ = write!(proto, "\n// {}\nmessage {} {{\n", file_name, message);
+            let mut field_names = BTreeSet::new();
+            for (index, column) in columns.iter().enumerate() {
+                let name = unique(identifier(column), &mut field_names);
+                let _ = write!(proto, "  string {} = {}", name, field_number(index));
+                if name != *column {
+                    let _ = write!(proto, " [json_name = {:?}]", column);
+                }
+                proto.push_str(";\n");
+            }
+            proto.push_str("}\n");
+        }
+        proto
+    }
+}
+
+/// `name` with every character an identifier cannot hold replaced by `_`,
+/// starting with a letter.
+fn identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !identifier.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        identifier.insert(0, 'f');
+    }
+    identifier
+}
+
+/// `name`, suffixed with a counter if it is taken already ignoring case.
+fn unique(name: String, taken: &mut BTreeSet<String>) -> String {
+    let mut candidate = name.clone();
+    let mut n = 2;
+    while !taken.insert(candidate.to_ascii_lowercase()) {
+        candidate = format!("{}_{}", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+/// Field number of the column at `index`, skipping the reserved numbers.
+pub fn field_number(index: usize) -> u32 {
+    let number = index as u32 + 1;
+    if number >= FIRST_RESERVED_FIELD {
+        number + RESERVED_FIELDS
+    } else {
+        number
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Write the non-empty `cells` as one length-delimited message into
+/// `writer`, reusing `message` as a buffer.
+pub fn write_message<W: Write>(
+    writer: &mut W,
+    cells: &[Cow<'_, str>],
+    message: &mut Vec<u8>,
+) -> io::Result<()> {
+    message.clear();
+    for (index, cell) in cells.iter().enumerate() {
+        if cell.is_empty() {
+            continue;
+        }
+        // Wire type 2: length-delimited
+        write_varint(message, (u64::from(field_number(index)) << 3) | 2);
+        write_varint(message, cell.len() as u64);
+        message.extend_from_slice(cell.as_bytes());
+    }
+    let mut length = Vec::with_capacity(5);
+    write_varint(&mut length, message.len() as u64);
+    writer.write_all(&length)?;
+    writer.write_all(message)
+}
+
+/// Count the length-delimited messages in `reader` without decoding them.
+pub fn count_messages<R: Read>(reader: R) -> Result<usize> {
+    let mut reader = BufReader::new(reader);
+    let mut count = 0;
+    while let Some(length) = read_varint(&mut reader)? {
+        let skipped = io::copy(&mut (&mut reader).take(length), &mut io::sink())?;
+        if skipped < length {
+            return Err(truncated());
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The next varint of `reader`, or `None` at its end.
+fn read_varint<R: Read>(reader: &mut R) -> Result<Option<u64>> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return if shift == 0 {
+                Ok(None)
+            } else {
+                Err(truncated())
+            };
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(truncated())
+}
+
+fn truncated() -> AppError {
+    AppError::Unknown("truncated protobuf message".to_string())
+}
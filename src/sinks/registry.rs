@@ -0,0 +1,116 @@
+use crate::apple_health::types::GenericRecord;
+use crate::backpressure::Backpressure;
+use crate::core::Sink;
+use crate::error::{AppError, Result};
+use crate::report::Reports;
+use crate::retry::RetryPolicy;
+use crate::sinks::csv_zip::{Compression, CsvDialect, CsvZipSink};
+use crate::sinks::file_names::NameMap;
+use crate::sinks::iceberg::{ICEBERG_FORMAT, IcebergSink};
+use crate::zones::HeartRateZones;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A type-erased sink, usable wherever a [`Sink`] is expected.
+pub type DynSink<T> = Box<dyn Sink<T> + Send + Sync>;
+
+/// Builds a sink for the given options.
+pub type SinkFactory = Arc<dyn Fn(&SinkOptions) -> Result<DynSink<GenericRecord>> + Send + Sync>;
+
+/// Name under which [`CsvZipSink`] is registered.
+pub const ZIP_FORMAT: &str = "zip";
+
+/// Settings handed to a [`SinkFactory`]; sinks ignore what does not apply.
+#[derive(Debug, Clone, Default)]
+pub struct SinkOptions {
+    pub compression: Compression,
+    pub dialect: CsvDialect,
+    pub reports: Reports,
+    pub heart_rate_zones: HeartRateZones,
+    /// Entry names of the groups not named after themselves.
+    pub names: Arc<NameMap>,
+    pub backpressure: Backpressure,
+    pub retry: RetryPolicy,
+    /// Where a table sink's files will live, if not where they are written.
+    pub table_location: Option<String>,
+}
+
+/// Output formats available by name, pre-populated with the built-in sinks.
+///
+/// Downstream crates can [`register`](SinkRegistry::register) their own sinks
+/// and select them through [`Format::Named`](crate::Format::Named).
+#[derive(Clone)]
+pub struct SinkRegistry {
+    factories: BTreeMap<String, SinkFactory>,
+}
+
+impl SinkRegistry {
+    /// A registry without any sinks.
+    pub fn empty() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Register `factory` under `name`, replacing any previous registration.
+    pub fn register<F>(&mut self, name: impl Into<String>, factory: F) -> &mut Self
+    where
+        F: Fn(&SinkOptions) -> Result<DynSink<GenericRecord>> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.into(), Arc::new(factory));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SinkFactory> {
+        self.factories.get(name)
+    }
+
+    /// Registered format names, in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Build the sink registered under `name`.
+    pub fn create(&self, name: &str, options: &SinkOptions) -> Result<DynSink<GenericRecord>> {
+        let factory = self.get(name).ok_or_else(|| {
+            AppError::InvalidConfig(format!(
+                "unknown output format '{}' (available: {})",
+                name,
+                self.names().collect::<Vec<_>>().join(", ")
+            ))
+        })?;
+        factory(options)
+    }
+}
+
+impl Default for SinkRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(ZIP_FORMAT, |options| {
+            Ok(Box::new(
+                CsvZipSink::new(options.compression)
+                    .dialect(options.dialect)
+                    .reports(options.reports)
+                    .heart_rate_zones(options.heart_rate_zones)
+                    .names(Arc::clone(&options.names))
+                    .backpressure(options.backpressure)
+                    .retry(options.retry),
+            ))
+        });
+        registry.register(ICEBERG_FORMAT, |options| {
+            let sink = IcebergSink::new().names(Arc::clone(&options.names));
+            Ok(Box::new(match &options.table_location {
+                Some(location) => sink.location(location),
+                None => sink,
+            }))
+        });
+        registry
+    }
+}
+
+impl fmt::Debug for SinkRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
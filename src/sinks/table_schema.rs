@@ -0,0 +1,136 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::error::Result;
+use crate::sinks::csv_zip::{
+    CBOR_EXTENSION, CsvDialect, DecimalSeparator, NDJSON_EXTENSION, PROTOBUF_EXTENSION, is_decimal,
+};
+use serde_json::{Value, json};
+use std::borrow::Cow;
+
+/// Suffix of the schema entry written next to each CSV.
+pub const SCHEMA_SUFFIX: &str = ".schema.json";
+
+/// Name of the schema entry describing CSV entry `file_name`.
+pub fn schema_file_name(file_name: &str) -> String {
+    let stem = file_name
+        .strip_suffix(".csv")
+        .or_else(|| file_name.strip_suffix(NDJSON_EXTENSION))
+        .or_else(|| file_name.strip_suffix(PROTOBUF_EXTENSION))
+        .or_else(|| file_name.strip_suffix(CBOR_EXTENSION))
+        .unwrap_or(file_name);
+    format!("{}{}", stem, SCHEMA_SUFFIX)
+}
+
+/// Type of a column in a Frictionless Table Schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Integer,
+    Number,
+    Date,
+    Datetime,
+    String,
+}
+
+impl FieldType {
+    /// The narrowest type describing `cell` as written in `dialect`, or
+    /// `None` for an empty cell.
+    fn of(cell: &str, dialect: &CsvDialect) -> Option<Self> {
+        if cell.is_empty() {
+            return None;
+        }
+        let digits = cell.strip_prefix('-').unwrap_or(cell);
+        if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Some(FieldType::Integer);
+        }
+        let decimal = match dialect.decimal_separator {
+            DecimalSeparator::Point => is_decimal(cell),
+            DecimalSeparator::Comma => {
+                !cell.contains('.') && is_decimal(&cell.replacen(',', ".", 1))
+            }
+        };
+        if decimal {
+            return Some(FieldType::Number);
+        }
+        Some(match parse_timestamp(cell) {
+            Some(_) if cell.len() == 10 => FieldType::Date,
+            Some(_) => FieldType::Datetime,
+            None => FieldType::String,
+        })
+    }
+
+    /// The narrowest type describing values of both `self` and `other`.
+    fn widen(self, other: FieldType) -> FieldType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (FieldType::Integer, FieldType::Number) | (FieldType::Number, FieldType::Integer) => {
+                FieldType::Number
+            }
+            _ => FieldType::String,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FieldType::Integer => "integer",
+            FieldType::Number => "number",
+            FieldType::Date => "date",
+            FieldType::Datetime => "datetime",
+            FieldType::String => "string",
+        }
+    }
+}
+
+/// Column names and types of one CSV, inferred from the cells written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSchema {
+    fields: Vec<(String, Option<FieldType>)>,
+    dialect: CsvDialect,
+}
+
+impl TableSchema {
+    pub fn new(headers: &[&str], dialect: CsvDialect) -> Self {
+        Self {
+            fields: headers.iter().map(|h| (h.to_string(), None)).collect(),
+            dialect,
+        }
+    }
+
+    /// Widen the column types to fit one row of `cells`, in header order.
+    pub fn add(&mut self, cells: &[Cow<'_, str>]) {
+        for ((_, field), cell) in self.fields.iter_mut().zip(cells) {
+            if let Some(cell) = FieldType::of(cell, &self.dialect) {
+                *field = Some(field.map_or(cell, |field| field.widen(cell)));
+            }
+        }
+    }
+
+    /// The type inferred for each column; columns that were always empty
+    /// are strings.
+    pub fn field_types(&self) -> impl Iterator<Item = (&str, FieldType)> {
+        self.fields
+            .iter()
+            .map(|(name, field)| (name.as_str(), field.unwrap_or(FieldType::String)))
+    }
+
+    /// Serialize as a Frictionless Table Schema.
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        let fields: Vec<Value> = self
+            .field_types()
+            .map(|(name, field)| {
+                let mut value = json!({ "name": name, "type": field.name() });
+                match field {
+                    // Apple Health writes dates as `2023-01-01 08:00:00 -0800`
+                    FieldType::Datetime => value["format"] = json!("any"),
+                    FieldType::Number
+                        if self.dialect.decimal_separator == DecimalSeparator::Comma =>
+                    {
+                        value["decimalChar"] = json!(",")
+                    }
+                    _ => {}
+                }
+                value
+            })
+            .collect();
+        let schema = json!({ "fields": fields, "missingValues": [""] });
+        Ok(serde_json::to_vec_pretty(&schema)?)
+    }
+}
@@ -0,0 +1,190 @@
+//! The CSV export of the Sleep Cycle app, one row per night, read as sleep
+//! analysis records.
+
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::error::Result;
+use crate::intern::intern_key;
+use log::debug;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// HealthKit type of every record, the group Apple's sleep records are in.
+pub const SLEEP_ANALYSIS_TYPE: &str = "HKCategoryTypeIdentifierSleepAnalysis";
+
+/// Value of the record spanning a whole night.
+const IN_BED: &str = "HKCategoryValueSleepAnalysisInBed";
+
+/// Value of the record spanning the time asleep.
+const ASLEEP: &str = "HKCategoryValueSleepAnalysisAsleepUnspecified";
+
+/// Value of `sourceName` for every record.
+const SOURCE_NAME: &str = "Sleep Cycle";
+
+/// Attributes of the columns, by lowercase column name without the unit in
+/// parentheses. The unit goes into an attribute of the same name suffixed
+/// with `Unit`. Older versions of the app name some columns differently.
+const COLUMNS: [(&str, &str); 19] = [
+    ("sleep quality", "sleepQuality"),
+    ("regularity", "regularity"),
+    ("mood", "mood"),
+    ("wake up", "mood"),
+    ("heart rate", "heartRate"),
+    ("steps", "stepCount"),
+    ("activity", "stepCount"),
+    ("alarm mode", "alarmMode"),
+    ("air pressure", "airPressure"),
+    ("city", "city"),
+    ("movements per hour", "movementsPerHour"),
+    ("time in bed", "timeInBed"),
+    ("time asleep", "timeAsleep"),
+    ("time before sleep", "timeBeforeSleep"),
+    ("did snore", "didSnore"),
+    ("snore time", "snoreTime"),
+    ("weather temperature", "weatherTemperature"),
+    ("weather type", "weatherType"),
+    ("notes", "notes"),
+];
+
+/// Whether `path` is a Sleep Cycle export: a `.csv` file whose header
+/// starts with `Start` and has a sleep quality column.
+pub fn is_sleep_cycle_export(path: &Path) -> bool {
+    let is_csv = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let mut header = String::new();
+    is_csv
+        && File::open(path)
+            .and_then(|file| BufReader::new(file).read_line(&mut header))
+            .is_ok()
+        && is_header(&header)
+}
+
+fn is_header(line: &str) -> bool {
+    let line = line.trim_start_matches('\u{feff}').to_ascii_lowercase();
+    line.starts_with("start") && line.contains("sleep quality")
+}
+
+/// Every night of a Sleep Cycle export as a `Record` of
+/// [`SLEEP_ANALYSIS_TYPE`] in bed from the start to the end, with the
+/// other columns (sleep quality, snoring, ...) as attributes, followed by
+/// one asleep when the export gives the time asleep.
+///
+/// The app writes no sleep stages, so the time asleep is taken as one
+/// stretch after the time before sleep. Dates are in local time without an
+/// offset and kept that way.
+pub fn read_export<R: Read>(reader: R) -> Result<Vec<GenericRecord>> {
+    let mut reader = BufReader::new(reader);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    // Semicolons in the current app, commas in older versions
+    let delimiter = if header.contains(';') { b';' } else { b',' };
+    let mut csv = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(header.as_bytes().chain(reader));
+    let headers = csv.headers()?.clone();
+    let mut records = Vec::new();
+    for row in csv.records() {
+        let row = row?;
+        let mut night = record(IN_BED);
+        let mut start = None;
+        let mut end = None;
+        let mut before_sleep = None;
+        let mut asleep = None;
+        for (header, value) in headers.iter().zip(&row) {
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            // `Time in bed (seconds)`
+            let header = header.trim_start_matches('\u{feff}').trim();
+            let (column, unit) = match header.split_once(" (") {
+                Some((column, unit)) => (column, unit.strip_suffix(')')),
+                None => (header, None),
+            };
+            let column = column.to_ascii_lowercase();
+            match column.as_str() {
+                "start" => start = Some(value.to_string()),
+                "end" => end = Some(value.to_string()),
+                _ => {
+                    let Some((_, key)) = COLUMNS.iter().find(|(name, _)| *name == column) else {
+                        debug!("Leaving '{}' out of a Sleep Cycle night", header);
+                        continue;
+                    };
+                    match *key {
+                        "timeBeforeSleep" => before_sleep = seconds(value),
+                        "timeAsleep" => asleep = seconds(value),
+                        _ => {}
+                    }
+                    // `82%`
+                    let (value, unit) = match value.strip_suffix('%') {
+                        Some(value) => (value, Some("%")),
+                        None => (value, unit),
+                    };
+                    insert(&mut night.attributes, key, value);
+                    if let Some(unit) = unit {
+                        insert(&mut night.attributes, &format!("{}Unit", key), unit);
+                    }
+                }
+            }
+        }
+        let Some(start) = start else {
+            continue;
+        };
+        insert(&mut night.attributes, "startDate", &start);
+        if let Some(end) = &end {
+            insert(&mut night.attributes, "endDate", end);
+        }
+        records.push(night);
+
+        if let (Some(seconds), Some(asleep)) = (parse_timestamp(&start), asleep) {
+            let asleep_start = seconds + before_sleep.unwrap_or(0);
+            let mut asleep_end = asleep_start + asleep;
+            if let Some(end) = end.as_deref().and_then(parse_timestamp) {
+                asleep_end = asleep_end.min(end);
+            }
+            let mut record = record(ASLEEP);
+            insert(
+                &mut record.attributes,
+                "startDate",
+                &local_date(asleep_start),
+            );
+            insert(&mut record.attributes, "endDate", &local_date(asleep_end));
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+fn record(value: &str) -> GenericRecord {
+    let mut attributes = Attributes::new();
+    insert(&mut attributes, "type", SLEEP_ANALYSIS_TYPE);
+    insert(&mut attributes, "sourceName", SOURCE_NAME);
+    insert(&mut attributes, "value", value);
+    GenericRecord {
+        element_name: "Record".to_string(),
+        attributes,
+    }
+}
+
+/// Seconds of a duration written as seconds or as `h:mm`.
+fn seconds(duration: &str) -> Option<i64> {
+    match duration.split_once(':') {
+        Some((hours, minutes)) => {
+            Some(hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+        }
+        None => duration.parse::<f64>().ok().map(|s| s.round() as i64),
+    }
+}
+
+/// `seconds` as a date in the local time the export is written in.
+fn local_date(seconds: i64) -> String {
+    format_timestamp(seconds)[..19].replace('T', " ")
+}
+
+fn insert(attributes: &mut Attributes, key: &str, value: &str) {
+    attributes.insert(intern_key(key), attribute_value(key, value));
+}
@@ -0,0 +1,651 @@
+use crate::core::{Processable, RecordCollector};
+use crate::error::{AppError, Result};
+use crate::manifest::{self, Manifest};
+use crate::remote;
+use ahash::AHashMap;
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Read, Write};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Name of the checkpoint written into a checkpoint directory.
+pub const CHECKPOINT_FILE_NAME: &str = "checkpoint.json";
+
+/// Content hashes of the records in the runs of a checkpoint directory,
+/// 16 little-endian bytes each.
+const COLLECTED_FILE_NAME: &str = "collected.bin";
+
+/// Most runs of a group merged, and so open, at once.
+const MAX_MERGE_FAN_IN: usize = 64;
+
+/// Groups records like the in-memory transform, but once their estimated
+/// size exceeds `max_memory` the largest groups are sorted and written to
+/// temporary runs on disk.
+///
+/// Runs are newline-delimited JSON and are merged back in sort order by
+/// [`SpilledGroup::for_each`], so peak memory stays around the budget
+/// regardless of the export size.
+pub struct SpillingGroups<T> {
+    max_memory: usize,
+    used: usize,
+    dir: SpillDir,
+    runs: usize,
+    groups: AHashMap<String, SpillGroup<T>>,
+    export_date: Option<String>,
+    checkpoint: Option<Checkpointing>,
+}
+
+/// Where runs are written: a temporary directory removed with the groups,
+/// or a checkpoint directory kept until [`remove_checkpoint`].
+enum SpillDir {
+    Temp(TempDir),
+    Kept(PathBuf),
+}
+
+impl SpillDir {
+    fn path(&self) -> &Path {
+        match self {
+            SpillDir::Temp(dir) => dir.path(),
+            SpillDir::Kept(dir) => dir,
+        }
+    }
+}
+
+/// State of a run that checkpoints its spills.
+struct Checkpointing {
+    input: InputIdentity,
+    /// Appends the content hash of every spilled record.
+    collected: BufWriter<File>,
+    collected_bytes: u64,
+    /// Records collected before an interruption, by content hash, still to
+    /// be passed over as the input is read again.
+    resumed: AHashMap<u128, u32>,
+}
+
+/// The input a checkpoint was written for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct InputIdentity {
+    path: String,
+    bytes: u64,
+}
+
+/// Contents of [`CHECKPOINT_FILE_NAME`]: every run written before it,
+/// which together hold the records hashed in the first `collected_bytes`
+/// of the collected file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    input: InputIdentity,
+    runs: usize,
+    collected_bytes: u64,
+    export_date: Option<String>,
+    groups: BTreeMap<String, CheckpointGroup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointGroup {
+    count: usize,
+    bytes: usize,
+    runs: Vec<String>,
+}
+
+struct SpillGroup<T> {
+    records: Vec<T>,
+    /// Estimated size of `records`.
+    bytes: usize,
+    /// Estimated size of every record pushed, spilled or not.
+    total_bytes: usize,
+    count: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl<T> Default for SpillGroup<T> {
+    fn default() -> Self {
+        Self {
+            records: Vec::new(),
+            bytes: 0,
+            total_bytes: 0,
+            count: 0,
+            runs: Vec::new(),
+        }
+    }
+}
+
+impl<T> SpillingGroups<T>
+where
+    T: Processable + Serialize + DeserializeOwned,
+{
+    /// Spill to a fresh directory under the system temp dir.
+    pub fn new(max_memory: usize) -> Result<Self> {
+        Ok(Self {
+            max_memory,
+            used: 0,
+            dir: SpillDir::Temp(tempfile::tempdir()?),
+            runs: 0,
+            groups: AHashMap::new(),
+            export_date: None,
+            checkpoint: None,
+        })
+    }
+
+    /// Spill to `dir` and record a checkpoint after every spill, so a run
+    /// over `input` that is interrupted can resume from it. If `dir`
+    /// already holds a checkpoint for `input`, its runs are taken over and
+    /// the records they hold are passed over when they are pushed again.
+    ///
+    /// Records must have a [`Processable::content_hash`].
+    pub fn checkpointed(max_memory: usize, dir: &Path, input: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        // Presigned URLs are signed anew for every run, and downloads have
+        // no size until they start
+        let input = if remote::is_url(input) {
+            InputIdentity {
+                path: remote::redact(input),
+                bytes: 0,
+            }
+        } else {
+            InputIdentity {
+                path: input.display().to_string(),
+                bytes: fs::metadata(input)?.len(),
+            }
+        };
+        let mut groups = Self {
+            max_memory,
+            used: 0,
+            dir: SpillDir::Kept(dir.to_path_buf()),
+            runs: 0,
+            groups: AHashMap::new(),
+            export_date: None,
+            checkpoint: None,
+        };
+        let checkpoint = match File::open(dir.join(CHECKPOINT_FILE_NAME)) {
+            Ok(file) => Some(serde_json::from_reader::<_, Checkpoint>(BufReader::new(
+                file,
+            ))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let collected_bytes = checkpoint.as_ref().map_or(0, |c| c.collected_bytes);
+        let mut resumed = AHashMap::new();
+        if let Some(checkpoint) = checkpoint {
+            if checkpoint.input != input {
+                return Err(AppError::InvalidConfig(format!(
+                    "the checkpoint in {} was written for {} ({} bytes)",
+                    dir.display(),
+                    checkpoint.input.path,
+                    checkpoint.input.bytes
+                )));
+            }
+            let mut hashes = File::open(dir.join(COLLECTED_FILE_NAME))?.take(collected_bytes);
+            let mut hash = [0; 16];
+            for _ in 0..collected_bytes / 16 {
+                hashes.read_exact(&mut hash)?;
+                *resumed.entry(u128::from_le_bytes(hash)).or_insert(0) += 1;
+            }
+            for (name, group) in checkpoint.groups {
+                groups.groups.insert(
+                    name,
+                    SpillGroup {
+                        total_bytes: group.bytes,
+                        count: group.count,
+                        runs: group.runs.iter().map(|run| dir.join(run)).collect(),
+                        ..SpillGroup::default()
+                    },
+                );
+            }
+            groups.runs = checkpoint.runs;
+            groups.export_date = checkpoint.export_date;
+            info!(
+                records = collected_bytes / 16,
+                runs = groups.runs;
+                "Resuming from the checkpoint in {}: {} records in {} runs",
+                dir.display(),
+                collected_bytes / 16,
+                groups.runs
+            );
+        }
+        // Hashes past the checkpoint belong to runs it does not list
+        let collected = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(COLLECTED_FILE_NAME))?;
+        collected.set_len(collected_bytes)?;
+        groups.checkpoint = Some(Checkpointing {
+            input,
+            collected: BufWriter::new(collected),
+            collected_bytes,
+            resumed,
+        });
+        Ok(groups)
+    }
+
+    /// Number of runs written to disk so far.
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+
+    /// Sort what is left in memory and hand the groups over for loading,
+    /// first merging the runs of groups with more than can be open at once.
+    pub fn finish(mut self) -> Result<SpilledGroups<T>> {
+        self.compact(merge_fan_in())?;
+        let mut groups: Vec<SpilledGroup<T>> = self
+            .groups
+            .into_iter()
+            .map(|(name, mut group)| {
+                group.records.sort_by_cached_key(Processable::sort_key);
+                SpilledGroup {
+                    name,
+                    records: group.records,
+                    bytes: group.total_bytes,
+                    count: group.count,
+                    runs: group.runs,
+                }
+            })
+            .collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(SpilledGroups {
+            groups,
+            _dir: self.dir,
+        })
+    }
+
+    /// Merge the runs of every group with more than `fan_in` of them in
+    /// passes over at most `fan_in` consecutive runs at a time, until
+    /// [`SpilledGroup::for_each`] has no more than `fan_in` to open.
+    ///
+    /// Consecutive runs hold consecutive arrivals, so merging them keeps
+    /// the order of equal keys. The runs merged are only removed once a
+    /// checkpoint lists the merged ones instead.
+    fn compact(&mut self, fan_in: usize) -> Result<()> {
+        let sync = self.checkpoint.is_some();
+        let mut names: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.runs.len() > fan_in)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        for name in names {
+            loop {
+                let runs = self.groups[&name].runs.clone();
+                if runs.len() <= fan_in {
+                    break;
+                }
+                let mut merged = Vec::with_capacity(runs.len().div_ceil(fan_in));
+                for chunk in runs.chunks(fan_in) {
+                    if let [run] = chunk {
+                        merged.push(run.clone());
+                        continue;
+                    }
+                    let path = self.dir.path().join(format!("run-{}.jsonl", self.runs));
+                    let mut out = BufWriter::new(File::create(&path)?);
+                    merge(chunk, &[] as &[T], |record| {
+                        serde_json::to_writer(&mut out, record)?;
+                        out.write_all(b"\n")?;
+                        Ok(())
+                    })?;
+                    out.flush()?;
+                    if sync {
+                        out.get_ref().sync_data()?;
+                    }
+                    merged.push(path);
+                    self.runs += 1;
+                }
+                debug!(
+                    "Merged {} runs of '{}' into {}",
+                    runs.len(),
+                    name,
+                    merged.len()
+                );
+                let group = self.groups.get_mut(&name).expect("group exists");
+                group.runs = merged;
+                self.write_checkpoint()?;
+                let kept = &self.groups[&name].runs;
+                for run in runs.iter().filter(|run| !kept.contains(run)) {
+                    fs::remove_file(run)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Spill the largest in-memory groups until usage is back under half
+    /// the budget, leaving room before the next spill.
+    fn spill(&mut self) -> Result<()> {
+        let mut by_size: Vec<(usize, String)> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| !group.records.is_empty())
+            .map(|(name, group)| (group.bytes, name.clone()))
+            .collect();
+        by_size.sort_unstable_by(|a, b| b.cmp(a));
+
+        for (bytes, name) in by_size {
+            if self.used <= self.max_memory / 2 {
+                break;
+            }
+            let path = self.dir.path().join(format!("run-{}.jsonl", self.runs));
+            let group = self.groups.get_mut(&name).expect("group exists");
+            let mut records = std::mem::take(&mut group.records);
+            records.sort_by_cached_key(Processable::sort_key);
+            write_run(&path, &records, self.checkpoint.is_some())?;
+            if let Some(checkpoint) = &mut self.checkpoint {
+                for record in &records {
+                    checkpoint
+                        .collected
+                        .write_all(&content_hash(record)?.to_le_bytes())?;
+                }
+                checkpoint.collected_bytes += 16 * records.len() as u64;
+            }
+            debug!(
+                "Spilled {} records ({} bytes) of '{}' to {}",
+                records.len(),
+                bytes,
+                name,
+                path.display()
+            );
+            group.runs.push(path);
+            group.bytes = 0;
+            self.used -= bytes;
+            self.runs += 1;
+        }
+        self.write_checkpoint()
+    }
+
+    /// Record the runs written so far, once they and their hashes are on
+    /// disk, replacing the previous checkpoint in one rename.
+    fn write_checkpoint(&mut self) -> Result<()> {
+        let Some(checkpointing) = &mut self.checkpoint else {
+            return Ok(());
+        };
+        checkpointing.collected.flush()?;
+        checkpointing.collected.get_ref().sync_data()?;
+        let checkpoint = Checkpoint {
+            input: checkpointing.input.clone(),
+            runs: self.runs,
+            collected_bytes: checkpointing.collected_bytes,
+            export_date: self.export_date.clone(),
+            groups: self
+                .groups
+                .iter()
+                .filter(|(_, group)| !group.runs.is_empty())
+                .map(|(name, group)| {
+                    let runs = group
+                        .runs
+                        .iter()
+                        .filter_map(|run| run.file_name())
+                        .map(|run| run.to_string_lossy().into_owned())
+                        .collect();
+                    let spilled = CheckpointGroup {
+                        count: group.count - group.records.len(),
+                        bytes: group.total_bytes - group.bytes,
+                        runs,
+                    };
+                    (name.clone(), spilled)
+                })
+                .collect(),
+        };
+        let path = self.dir.path().join(CHECKPOINT_FILE_NAME);
+        let partial = path.with_extension("json.partial");
+        let mut file = File::create(&partial)?;
+        serde_json::to_writer(&mut file, &checkpoint)?;
+        file.sync_data()?;
+        fs::rename(&partial, &path)?;
+        debug!(
+            "Checkpointed {} records in {} runs",
+            checkpointing.collected_bytes / 16,
+            self.runs
+        );
+        Ok(())
+    }
+}
+
+/// Most runs to open at once: a quarter of the soft limit on open files,
+/// leaving the rest to the input, the archive and the other threads, and at
+/// most [`MAX_MERGE_FAN_IN`].
+fn merge_fan_in() -> usize {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes the limit into the struct it is given
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+            let quarter = usize::try_from(limit.rlim_cur / 4).unwrap_or(usize::MAX);
+            return quarter.clamp(2, MAX_MERGE_FAN_IN);
+        }
+    }
+    MAX_MERGE_FAN_IN
+}
+
+/// Remove the checkpoint, runs and hashes left in `dir` by
+/// [`SpillingGroups::checkpointed`], once the run they belong to is done.
+pub fn remove_checkpoint(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().map(|name| name.to_string_lossy());
+        let ours = name.is_some_and(|name| {
+            name == CHECKPOINT_FILE_NAME
+                || name == COLLECTED_FILE_NAME
+                || (name.starts_with("run-") && name.ends_with(".jsonl"))
+        });
+        if ours {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn content_hash<T: Processable>(record: &T) -> Result<u128> {
+    record
+        .content_hash()
+        .ok_or_else(|| AppError::InvalidConfig("these records cannot be checkpointed".to_string()))
+}
+
+impl<T> RecordCollector<T> for SpillingGroups<T>
+where
+    T: Processable + Serialize + DeserializeOwned,
+{
+    fn push(&mut self, key: String, record: T) -> Result<()> {
+        if let Some(checkpoint) = &mut self.checkpoint
+            && !checkpoint.resumed.is_empty()
+        {
+            let hash = content_hash(&record)?;
+            if let Some(remaining) = checkpoint.resumed.get_mut(&hash) {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    checkpoint.resumed.remove(&hash);
+                }
+                return Ok(());
+            }
+        }
+        if let Some(date) = manifest::export_date(&key, &record)
+            && self.export_date.as_deref() < Some(date)
+        {
+            self.export_date = Some(date.to_string());
+        }
+
+        let size = record.estimated_size();
+        let group = self.groups.entry(key).or_default();
+        group.records.push(record);
+        group.bytes += size;
+        group.total_bytes += size;
+        group.count += 1;
+        self.used += size;
+        if self.used > self.max_memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self, manifest: &mut Manifest) {
+        manifest.record_counts = self
+            .groups
+            .iter()
+            .map(|(name, group)| (name.clone(), group.count))
+            .collect::<BTreeMap<_, _>>();
+        manifest.export_date = self.export_date.clone();
+    }
+}
+
+/// Write `records` to a run at `path`, making sure they reach the disk
+/// with `sync` before a checkpoint can list the run.
+fn write_run<T: Serialize>(path: &Path, records: &[T], sync: bool) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for record in records {
+        serde_json::to_writer(&mut out, record)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    if sync {
+        out.get_ref().sync_data()?;
+    }
+    Ok(())
+}
+
+/// The groups collected by [`SpillingGroups`], sorted by name. Temporary
+/// runs are removed when this is dropped.
+pub struct SpilledGroups<T> {
+    groups: Vec<SpilledGroup<T>>,
+    _dir: SpillDir,
+}
+
+impl<T: DeserializeOwned> SpilledGroups<T> {
+    pub fn groups(&self) -> &[SpilledGroup<T>] {
+        &self.groups
+    }
+
+    /// Read every group fully back into memory, in no particular order.
+    pub fn into_map(self) -> Result<AHashMap<String, Vec<T>>> {
+        let mut grouped = AHashMap::with_capacity(self.groups.len());
+        for group in self.groups {
+            let mut records = group.records;
+            records.reserve(group.count - records.len());
+            for run in &group.runs {
+                let mut reader = RunReader::<T>::open(run)?;
+                while let Some(record) = reader.next()? {
+                    records.push(record);
+                }
+            }
+            grouped.insert(group.name, records);
+        }
+        Ok(grouped)
+    }
+}
+
+/// One group, partly in memory and partly in sorted runs on disk.
+pub struct SpilledGroup<T> {
+    pub name: String,
+    records: Vec<T>,
+    bytes: usize,
+    count: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl<T> SpilledGroup<T>
+where
+    T: Processable + DeserializeOwned,
+{
+    /// Number of records in the group.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Number of sorted runs of the group on disk.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Estimated in-memory size of all records in the group.
+    pub fn estimated_size(&self) -> usize {
+        self.bytes
+    }
+
+    /// Visit every record of the group in sort-key order, merging the runs
+    /// on disk with the records still in memory. May be called repeatedly.
+    pub fn for_each<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(&T) -> Result<()>,
+    {
+        merge(&self.runs, &self.records, f)
+    }
+}
+
+/// Visit the records of the sorted `runs` and the sorted `memory` in
+/// sort-key order. Earlier runs win ties and the records in memory, which
+/// arrived last, lose them, so equal keys keep their arrival order.
+fn merge<T, F>(runs: &[PathBuf], memory: &[T], mut f: F) -> Result<()>
+where
+    T: Processable + DeserializeOwned,
+    F: FnMut(&T) -> Result<()>,
+{
+    let mut runs = runs
+        .iter()
+        .map(|run| RunReader::<T>::open(run))
+        .collect::<Result<Vec<_>>>()?;
+    // Keys are computed once per record as it becomes a head
+    let keyed = |record: T| (record.sort_key(), record);
+    let mut heads = runs
+        .iter_mut()
+        .map(|run| Ok(run.next()?.map(keyed)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut memory = memory.iter().map(|record| (record.sort_key(), record));
+    let mut memory_head = memory.next();
+
+    loop {
+        // Earlier runs win ties so equal keys keep their arrival order
+        let mut smallest: Option<(usize, Option<i64>)> = None;
+        for (i, head) in heads.iter().enumerate() {
+            let Some((key, _)) = head else { continue };
+            if smallest.is_none_or(|(_, smallest_key)| *key < smallest_key) {
+                smallest = Some((i, *key));
+            }
+        }
+        let from_memory = match (&memory_head, smallest) {
+            (Some((key, _)), Some((_, smallest_key))) => *key < smallest_key,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return Ok(()),
+        };
+
+        if from_memory {
+            let (_, record) = memory_head.take().expect("memory head present");
+            f(record)?;
+            memory_head = memory.next();
+        } else {
+            let (s, _) = smallest.expect("a run has records");
+            let (_, record) = heads[s].take().expect("head present");
+            f(&record)?;
+            heads[s] = runs[s].next()?.map(keyed);
+        }
+    }
+}
+
+struct RunReader<T> {
+    lines: Lines<BufReader<File>>,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> RunReader<T> {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            lines: BufReader::new(File::open(path)?).lines(),
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<T>> {
+        match self.lines.next() {
+            Some(line) => Ok(Some(serde_json::from_str(&line?)?)),
+            None => Ok(None),
+        }
+    }
+}
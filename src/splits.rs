@@ -0,0 +1,199 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use crate::zones::{WORKOUT_GROUP, Workout};
+use std::collections::BTreeMap;
+
+/// Types whose samples record the distance covered during a workout.
+const DISTANCE_GROUPS: [&str; 5] = [
+    "HKQuantityTypeIdentifierDistanceWalkingRunning",
+    "HKQuantityTypeIdentifierDistanceCycling",
+    "HKQuantityTypeIdentifierDistanceSwimming",
+    "HKQuantityTypeIdentifierDistanceWheelchair",
+    "HKQuantityTypeIdentifierDistanceDownhillSnowSports",
+];
+
+/// Units the splits are measured in, with their length in meters.
+const SPLIT_UNITS: [(&str, f64); 2] = [("km", 1000.0), ("mi", 1609.344)];
+
+/// Smallest share of a unit a workout must cover past its last full split
+/// for that remainder to get a row of its own.
+const MIN_PARTIAL_SPLIT: f64 = 0.01;
+
+/// Length in meters of one `unit` of a distance sample.
+fn meters(unit: &str) -> Option<f64> {
+    match unit {
+        "m" => Some(1.0),
+        "cm" => Some(0.01),
+        "km" => Some(1000.0),
+        "ft" => Some(0.3048),
+        "yd" => Some(0.9144),
+        "mi" => Some(1609.344),
+        _ => None,
+    }
+}
+
+/// One distance sample, spread evenly over its dates.
+#[derive(Debug, Clone, PartialEq)]
+struct Distance {
+    start: i64,
+    end: i64,
+    meters: f64,
+    source: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Workouts(Vec<Workout>),
+    Distances(Vec<Distance>),
+}
+
+/// What a group contributes to the splits of the workouts, collected for
+/// [`workout_splits_csv`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitSource(Source);
+
+impl SplitSource {
+    /// Collector for `group`, or `None` if it takes no part in the join.
+    pub fn new(group: &str) -> Option<Self> {
+        if group == WORKOUT_GROUP {
+            Some(Self(Source::Workouts(Vec::new())))
+        } else if DISTANCE_GROUPS.contains(&group) {
+            Some(Self(Source::Distances(Vec::new())))
+        } else {
+            None
+        }
+    }
+
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        match &mut self.0 {
+            Source::Workouts(workouts) => workouts.extend(Workout::of(record)),
+            Source::Distances(distances) => {
+                let date = |field: &str| record.field(field).and_then(parse_timestamp);
+                let value = record.field("value").and_then(|v| v.parse::<f64>().ok());
+                let unit = record.field("unit").and_then(meters);
+                let (Some(start), Some(end), Some(value), Some(unit)) =
+                    (date("startDate"), date("endDate"), value, unit)
+                else {
+                    return;
+                };
+                if value.is_finite() && value > 0.0 {
+                    distances.push(Distance {
+                        start,
+                        end: end.max(start),
+                        meters: value * unit,
+                        source: record.field("sourceName").unwrap_or_default().to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// The distance samples recorded during `workout` by a single source: the
+/// workout's own if it recorded any, otherwise the one that covered the
+/// most, so a phone and a watch counting the same steps are not added up.
+fn workout_distances<'a>(workout: &Workout, distances: &[&'a Distance]) -> Vec<&'a Distance> {
+    let first = distances.partition_point(|d| d.start < workout.start);
+    let last = distances.partition_point(|d| d.start < workout.end);
+    let during = &distances[first..last];
+    let mut totals: BTreeMap<&str, f64> = BTreeMap::new();
+    for distance in during {
+        *totals.entry(&distance.source).or_default() += distance.meters;
+    }
+    let source = if totals.contains_key(workout.source.as_str()) {
+        workout.source.as_str()
+    } else {
+        match totals.iter().max_by(|a, b| a.1.total_cmp(b.1)) {
+            Some((source, _)) => source,
+            None => return Vec::new(),
+        }
+    };
+    during
+        .iter()
+        .filter(|d| d.source == source)
+        .copied()
+        .collect()
+}
+
+/// `5:07` for 307 seconds.
+fn pace(seconds: f64) -> String {
+    let seconds = seconds.round() as i64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Kilometer and mile splits of every workout with distance samples, in
+/// order of their start: for each split, the distance it covers in its
+/// unit (less than 1 for the last, partial one), the seconds it took, the
+/// seconds since the workout started and the pace per unit. Each distance
+/// sample is spread evenly over its dates, and the workout is keyed by its
+/// type and start date as written in `Workout.csv`.
+pub fn workout_splits_csv<'a>(
+    sources: impl IntoIterator<Item = &'a SplitSource>,
+) -> Result<Vec<u8>> {
+    let (mut workouts, mut distances) = (Vec::new(), Vec::new());
+    for source in sources {
+        match &source.0 {
+            Source::Workouts(w) => workouts.extend(w),
+            Source::Distances(d) => distances.extend(d),
+        }
+    }
+    workouts.sort_by_key(|w| (w.start, w.end));
+    distances.sort_by_key(|d| (d.start, d.end));
+
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record([
+        "workoutActivityType",
+        "startDate",
+        "unit",
+        "split",
+        "distance",
+        "split_seconds",
+        "elapsed_seconds",
+        "pace",
+    ])?;
+    for workout in workouts {
+        let during = workout_distances(workout, &distances);
+        for (unit, length) in SPLIT_UNITS {
+            // Seconds since the workout started at which each full split
+            // was completed
+            let mut marks: Vec<f64> = Vec::new();
+            let mut covered = 0.0;
+            let mut finished = workout.start;
+            for distance in &during {
+                let from = covered;
+                covered += distance.meters;
+                let end = distance.end.min(workout.end).max(distance.start);
+                finished = finished.max(end);
+                while (marks.len() + 1) as f64 * length <= covered {
+                    let share = ((marks.len() + 1) as f64 * length - from) / distance.meters;
+                    let at = distance.start as f64 + share * (end - distance.start) as f64;
+                    marks.push(at - workout.start as f64);
+                }
+            }
+            let remainder = covered / length - marks.len() as f64;
+            let mut splits: Vec<(f64, f64)> = marks.iter().map(|&at| (1.0, at)).collect();
+            if remainder >= MIN_PARTIAL_SPLIT {
+                splits.push((remainder, (finished - workout.start) as f64));
+            }
+
+            let mut previous = 0.0;
+            for (i, (distance, elapsed)) in splits.into_iter().enumerate() {
+                let elapsed = elapsed.round();
+                let seconds = elapsed - previous;
+                previous = elapsed;
+                w.write_record([
+                    workout.activity.as_str(),
+                    &workout.start_date,
+                    unit,
+                    &(i + 1).to_string(),
+                    &((distance * 100.0).round() / 100.0).to_string(),
+                    &seconds.to_string(),
+                    &elapsed.to_string(),
+                    &pace(seconds / distance),
+                ])?;
+            }
+        }
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
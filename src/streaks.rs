@@ -0,0 +1,149 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use std::collections::BTreeMap;
+
+/// Group holding the daily Apple Watch activity rings.
+pub const ACTIVITY_GROUP: &str = "ActivitySummary";
+
+/// An activity ring, closed on days its value reaches its goal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ring {
+    Move,
+    Exercise,
+    Stand,
+}
+
+impl Ring {
+    const ALL: [Ring; 3] = [Ring::Move, Ring::Exercise, Ring::Stand];
+
+    fn name(self) -> &'static str {
+        match self {
+            Ring::Move => "move",
+            Ring::Exercise => "exercise",
+            Ring::Stand => "stand",
+        }
+    }
+
+    /// Whether the ring was closed on the day of `record`, or `None` if it
+    /// had no goal. The move ring counts minutes instead of energy when the
+    /// energy goal is zero, as for users in move-time mode.
+    fn closed<T: Processable>(self, record: &T) -> Option<bool> {
+        let number = |field: &str| {
+            record
+                .field(field)
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|v| v.is_finite())
+                .unwrap_or_default()
+        };
+        let (value, goal) = match self {
+            Ring::Move if number("activeEnergyBurnedGoal") > 0.0 => {
+                ("activeEnergyBurned", "activeEnergyBurnedGoal")
+            }
+            Ring::Move => ("appleMoveTime", "appleMoveTimeGoal"),
+            Ring::Exercise => ("appleExerciseTime", "appleExerciseTimeGoal"),
+            Ring::Stand => ("appleStandHours", "appleStandHoursGoal"),
+        };
+        let goal = number(goal);
+        (goal > 0.0).then(|| number(value) >= goal)
+    }
+}
+
+/// Which rings were closed on each day of an activity summary group.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RingDays {
+    /// Days since the Unix epoch, to the date and each ring's state.
+    days: BTreeMap<i64, (String, [Option<bool>; 3])>,
+}
+
+impl RingDays {
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        let Some(date) = record.field("dateComponents") else {
+            return;
+        };
+        let Some(timestamp) = parse_timestamp(date) else {
+            return;
+        };
+        let rings = Ring::ALL.map(|ring| ring.closed(record));
+        self.days
+            .insert(timestamp.div_euclid(86_400), (date.to_string(), rings));
+    }
+}
+
+/// Days, closed days and longest run of consecutive closed days of one ring
+/// (or of all three) over a period.
+#[derive(Debug, Clone, Copy, Default)]
+struct Attainment {
+    days: usize,
+    closed: usize,
+    longest_streak: usize,
+    streak: usize,
+    last_closed: Option<i64>,
+}
+
+impl Attainment {
+    fn add(&mut self, day: i64, closed: Option<bool>) {
+        let Some(closed) = closed else {
+            return;
+        };
+        self.days += 1;
+        if !closed {
+            return;
+        }
+        self.closed += 1;
+        self.streak = match self.last_closed {
+            Some(last) if last == day - 1 => self.streak + 1,
+            _ => 1,
+        };
+        self.last_closed = Some(day);
+        self.longest_streak = self.longest_streak.max(self.streak);
+    }
+
+    fn percent(&self) -> String {
+        if self.days == 0 {
+            return String::new();
+        }
+        let percent = self.closed as f64 * 100.0 / self.days as f64;
+        ((percent * 10.0).round() / 10.0).to_string()
+    }
+}
+
+/// Goal attainment over the whole export (period `all`) and per month: the
+/// days with a summary, then for each ring and for all three closed
+/// together the days closed, their share of the days with a goal and the
+/// longest streak of consecutive closed days.
+pub fn streaks_csv(rings: Option<&RingDays>) -> Result<Vec<u8>> {
+    let mut periods: BTreeMap<&str, (usize, [Attainment; 4])> = BTreeMap::new();
+    for (&day, (date, closed)) in rings.map(|r| &r.days).into_iter().flatten() {
+        let all = closed.iter().all(|ring| *ring == Some(true));
+        for period in ["all", &date[..7]] {
+            let (days, attainment) = periods.entry(period).or_default();
+            *days += 1;
+            for (attainment, closed) in attainment.iter_mut().zip(closed) {
+                attainment.add(day, *closed);
+            }
+            attainment[3].add(day, Some(all));
+        }
+    }
+
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut header = vec!["period".to_string(), "days".to_string()];
+    for ring in Ring::ALL.map(Ring::name).into_iter().chain(["all_rings"]) {
+        header.push(format!("{}_closed", ring));
+        header.push(format!("{}_percent", ring));
+        header.push(format!("{}_longest_streak", ring));
+    }
+    w.write_record(&header)?;
+    // List `all` first, although the months sort before it
+    let (all, months): (Vec<_>, Vec<_>) = periods.into_iter().partition(|(p, _)| *p == "all");
+    for (period, (days, attainment)) in all.into_iter().chain(months) {
+        let mut row = vec![period.to_string(), days.to_string()];
+        for attainment in attainment {
+            row.push(attainment.closed.to_string());
+            row.push(attainment.percent());
+            row.push(attainment.longest_streak.to_string());
+        }
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
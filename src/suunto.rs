@@ -0,0 +1,268 @@
+//! Workouts exported one per JSON file from the Suunto app, whose
+//! `DeviceLog` holds a summary and the samples recorded.
+
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::error::Result;
+use crate::intern::intern_key;
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Group of the samples recorded during each workout.
+pub const SAMPLE_ELEMENT: &str = "SuuntoSample";
+
+/// Attribute naming the JSON file a record was read from, which ties the
+/// samples to their workout.
+pub const SUUNTO_FILE_ATTRIBUTE: &str = "suuntoFile";
+
+/// Value of `sourceName` for every workout.
+const SOURCE_NAME: &str = "Suunto";
+
+/// Bytes at the start of a file searched for the `DeviceLog` key.
+const SNIFF_BYTES: u64 = 64 * 1024;
+
+/// Suunto stores heart rates and cadences in Hz, energy in joules,
+/// temperatures in kelvin and positions in radians.
+#[derive(Clone, Copy)]
+enum Unit {
+    Plain,
+    PerMinute,
+    Kilocalories,
+    Celsius,
+    Degrees,
+}
+
+use Unit::*;
+
+/// Attributes and units of the summary fields of a workout.
+const HEADER_FIELDS: [(&str, &str, Unit, &str); 6] = [
+    ("Distance", "totalDistance", Plain, "m"),
+    ("Energy", "totalEnergyBurned", Kilocalories, "kcal"),
+    ("Ascent", "elevationAscended", Plain, "m"),
+    ("Descent", "elevationDescended", Plain, "m"),
+    ("StepCount", "stepCount", Plain, "count"),
+    ("PeakTrainingEffect", "peakTrainingEffect", Plain, ""),
+];
+
+/// Summary fields holding the average and maximum of a quantity, either
+/// as an object or as an array with one object.
+const HEADER_STATISTICS: [(&str, &str, Unit, &str); 3] = [
+    ("HR", "HeartRate", PerMinute, "count/min"),
+    ("Cadence", "Cadence", PerMinute, "count/min"),
+    ("Speed", "Speed", Plain, "m/s"),
+];
+
+/// Attributes of the sample fields.
+const SAMPLE_FIELDS: [(&str, &str, Unit); 10] = [
+    ("HR", "heartRate", PerMinute),
+    ("Speed", "speed", Plain),
+    ("Distance", "distance", Plain),
+    ("Altitude", "altitude", Plain),
+    ("Cadence", "cadence", PerMinute),
+    ("Power", "power", Plain),
+    ("Temperature", "temperature", Celsius),
+    ("VerticalSpeed", "verticalSpeed", Plain),
+    ("Latitude", "latitude", Degrees),
+    ("Longitude", "longitude", Degrees),
+];
+
+/// Activities whose HealthKit type is not the activity without spaces.
+const ACTIVITY_TYPES: [(&str, &str); 7] = [
+    ("Trail running", "Running"),
+    ("Treadmill", "Running"),
+    ("Mountain biking", "Cycling"),
+    ("Indoor cycling", "Cycling"),
+    ("Pool swimming", "Swimming"),
+    ("Openwater swimming", "Swimming"),
+    ("Gym", "TraditionalStrengthTraining"),
+];
+
+/// Whether `path` is a workout exported by the Suunto app: a `.json` file
+/// with a `DeviceLog`.
+pub fn is_suunto_workout(path: &Path) -> bool {
+    let is_json = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let mut start = Vec::new();
+    is_json
+        && File::open(path)
+            .and_then(|file| file.take(SNIFF_BYTES).read_to_end(&mut start))
+            .is_ok()
+        && memchr::memmem::find(&start, b"\"DeviceLog\"").is_some()
+}
+
+/// The workout of the Suunto JSON file at `path` as a `Workout` with
+/// attributes named as in Apple's export, and its samples as
+/// [`SAMPLE_ELEMENT`]s with heart rates and cadences per minute,
+/// temperatures in °C and positions in degrees.
+///
+/// Dates with an offset are converted to UTC; those without are local
+/// times and kept that way.
+pub fn read_workout<R: Read>(reader: R, path: &Path) -> Result<Vec<GenericRecord>> {
+    let json: Value = serde_json::from_reader(BufReader::new(reader))?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let file_name = file_name.as_ref();
+    let log = &json["DeviceLog"];
+    let mut records = Vec::new();
+    if let Some(header) = log["Header"].as_object() {
+        let device = log["Device"]["Name"].as_str();
+        records.push(workout_record(header, device, file_name));
+    }
+    for sample in log["Samples"].as_array().into_iter().flatten() {
+        if let Some(record) = sample.as_object().and_then(|s| sample_record(s, file_name)) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+fn workout_record(
+    header: &Map<String, Value>,
+    device: Option<&str>,
+    file_name: &str,
+) -> GenericRecord {
+    let mut attributes = Attributes::new();
+    insert(&mut attributes, "sourceName", SOURCE_NAME);
+    insert(&mut attributes, SUUNTO_FILE_ATTRIBUTE, file_name);
+    if let Some(device) = device {
+        insert(&mut attributes, "device", device);
+    }
+    if let Some(activity) = header.get("Activity").and_then(Value::as_str) {
+        insert(
+            &mut attributes,
+            "workoutActivityType",
+            &activity_type(activity),
+        );
+    }
+    let seconds = header.get("Duration").and_then(Value::as_f64);
+    if let Some(seconds) = seconds {
+        insert(&mut attributes, "duration", &format_number(seconds / 60.0));
+        insert(&mut attributes, "durationUnit", "min");
+    }
+    if let Some(start) = header.get("DateTime").and_then(Value::as_str) {
+        insert(&mut attributes, "startDate", &date(start, 0.0));
+        if let Some(seconds) = seconds {
+            insert(&mut attributes, "endDate", &date(start, seconds));
+        }
+    }
+    for (field, key, unit, unit_name) in HEADER_FIELDS {
+        if let Some(value) = header.get(field).and_then(Value::as_f64) {
+            insert(&mut attributes, key, &convert(value, unit));
+            if !unit_name.is_empty() {
+                insert(&mut attributes, &format!("{}Unit", key), unit_name);
+            }
+        }
+    }
+    for (field, quantity, unit, unit_name) in HEADER_STATISTICS {
+        let statistics = match header.get(field) {
+            Some(Value::Array(values)) => values.first().and_then(Value::as_object),
+            Some(Value::Object(statistics)) => Some(statistics),
+            _ => None,
+        };
+        for (statistic, prefix) in [("Avg", "average"), ("Max", "max")] {
+            if let Some(value) = statistics.and_then(|s| s.get(statistic)?.as_f64()) {
+                let key = format!("{}{}", prefix, quantity);
+                insert(&mut attributes, &key, &convert(value, unit));
+                insert(&mut attributes, &format!("{}Unit", key), unit_name);
+            }
+        }
+    }
+    GenericRecord {
+        element_name: "Workout".to_string(),
+        attributes,
+    }
+}
+
+/// A sample with its time and the fields in [`SAMPLE_FIELDS`], or `None`
+/// for samples holding none of them, such as events.
+fn sample_record(sample: &Map<String, Value>, file_name: &str) -> Option<GenericRecord> {
+    let time = sample.get("TimeISO8601")?.as_str()?;
+    let mut attributes = Attributes::new();
+    for (field, key, unit) in SAMPLE_FIELDS {
+        if let Some(value) = sample.get(field).and_then(Value::as_f64) {
+            insert(&mut attributes, key, &convert(value, unit));
+        }
+    }
+    if attributes.is_empty() {
+        return None;
+    }
+    let time = date(time, 0.0);
+    insert(&mut attributes, "startDate", &time);
+    insert(&mut attributes, "endDate", &time);
+    insert(&mut attributes, SUUNTO_FILE_ATTRIBUTE, file_name);
+    Some(GenericRecord {
+        element_name: SAMPLE_ELEMENT.to_string(),
+        attributes,
+    })
+}
+
+/// `date` moved on by `seconds`: in UTC if it has an offset, otherwise in
+/// the same local time. Dates that cannot be parsed are kept as they are.
+fn date(date: &str, seconds: f64) -> String {
+    let Some(start) = parse_timestamp(date) else {
+        return date.to_string();
+    };
+    let moved = format_timestamp(start + seconds.round() as i64);
+    // `2021-03-14T09:30:00` and `2021-03-14T09:30:00.000` have no offset
+    let local = date.get(19..).is_some_and(|rest| {
+        rest.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit())
+            .is_empty()
+    });
+    if local {
+        moved[..19].replace('T', " ")
+    } else {
+        moved
+    }
+}
+
+fn activity_type(activity: &str) -> String {
+    let name = ACTIVITY_TYPES
+        .iter()
+        .find(|(from, _)| from.eq_ignore_ascii_case(activity))
+        .map_or_else(
+            || {
+                // `Cross-country skiing` as `CrossCountrySkiing`
+                activity
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .map(|word| {
+                        let mut chars = word.chars();
+                        chars.next().map_or_else(String::new, |first| {
+                            first.to_ascii_uppercase().to_string() + chars.as_str()
+                        })
+                    })
+                    .collect()
+            },
+            |(_, to)| to.to_string(),
+        );
+    format!("HKWorkoutActivityType{}", name)
+}
+
+fn convert(value: f64, unit: Unit) -> String {
+    format_number(match unit {
+        Plain => value,
+        PerMinute => value * 60.0,
+        Kilocalories => value / 4184.0,
+        Celsius => value - 273.15,
+        Degrees => value.to_degrees(),
+    })
+}
+
+/// `value` without trailing zeros, rounded to about a centimeter of
+/// latitude.
+fn format_number(value: f64) -> String {
+    let formatted = format!("{:.7}", value);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+fn insert(attributes: &mut Attributes, key: &str, value: &str) {
+    attributes.insert(intern_key(key), attribute_value(key, value));
+}
@@ -0,0 +1,176 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::apple_health::types::GenericRecord;
+use crate::core::Processable;
+use crate::error::Result;
+use crate::report::GroupSummary;
+use crate::xml_utils;
+use std::io::Write;
+use std::path::Path;
+
+/// Prefix of the quantity types a bare metric name such as `BodyMass`
+/// stands for.
+const QUANTITY_PREFIX: &str = "HKQuantityTypeIdentifier";
+
+/// Welch's t statistic two windows' means must exceed to mark a change.
+const CHANGE_THRESHOLD: f64 = 3.0;
+
+/// Fewest days on each side of a day for it to be tested as a change.
+const MIN_CHANGE_DAYS: usize = 3;
+
+/// The metric's value on one day, with the trend up to that day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrendPoint {
+    pub date: String,
+    pub value: f64,
+    /// Mean of the daily values in the window ending on this day.
+    pub rolling_mean: f64,
+    /// Least-squares slope of the same values, in units per day, when the
+    /// window holds at least two days.
+    pub slope: Option<f64>,
+    /// Whether the level shifts here: the means of the windows before and
+    /// after the day differ markedly, and more than around any nearby day.
+    pub change_point: bool,
+}
+
+/// Record type analysed for `metric`, which may omit the
+/// `HKQuantityTypeIdentifier` prefix.
+pub fn metric_type(metric: &str) -> String {
+    if metric.contains("TypeIdentifier") {
+        metric.to_string()
+    } else {
+        format!("{}{}", QUANTITY_PREFIX, metric)
+    }
+}
+
+/// Daily values of `metric` in the export at `input_path` and their rolling
+/// mean, slope and change points over windows of `window_days` days. Days
+/// are combined as in `daily_summary.csv`; other types are averaged.
+pub fn trends(input_path: &Path, metric: &str, window_days: u32) -> Result<Vec<TrendPoint>> {
+    let mut summary = GroupSummary::daily(metric_type(metric));
+    xml_utils::with_export_reader(input_path, |reader| {
+        xml_utils::for_each_element(reader, |element| {
+            if let Ok(record) = GenericRecord::from_xml(element)
+                && record.grouping_key() == summary.name
+            {
+                summary.add(&record);
+            }
+        })
+    })?;
+    let days: Vec<(&str, i64, f64)> = summary
+        .daily_values()
+        .into_iter()
+        .filter_map(|(date, value)| Some((date, parse_timestamp(date)? / 86_400, value)))
+        .collect();
+    Ok(analyse(&days, i64::from(window_days.max(1))))
+}
+
+fn analyse(days: &[(&str, i64, f64)], window: i64) -> Vec<TrendPoint> {
+    let scores: Vec<Option<f64>> = (0..days.len())
+        .map(|i| change_score(days, i, window))
+        .collect();
+    days.iter()
+        .enumerate()
+        .map(|(i, &(date, day, value))| {
+            let points: Vec<(i64, f64)> = days
+                .iter()
+                .filter(|(_, d, _)| (day - window + 1..=day).contains(d))
+                .map(|&(_, d, v)| (d, v))
+                .collect();
+            let values: Vec<f64> = points.iter().map(|p| p.1).collect();
+            // A change is only reported at the strongest day around it
+            let change_point = scores[i].is_some_and(|score| {
+                days.iter().zip(&scores).all(|((_, d, _), other)| {
+                    (d - day).abs() > window || other.is_none_or(|other| other <= score)
+                })
+            });
+            TrendPoint {
+                date: date.to_string(),
+                value,
+                rolling_mean: mean(&values),
+                slope: slope(&points),
+                change_point,
+            }
+        })
+        .collect()
+}
+
+/// Welch's t statistic between the windows ending on and starting after
+/// day `i`, if it passes [`CHANGE_THRESHOLD`].
+fn change_score(days: &[(&str, i64, f64)], i: usize, window: i64) -> Option<f64> {
+    let day = days[i].1;
+    let side = |range: std::ops::RangeInclusive<i64>| -> Vec<f64> {
+        days.iter()
+            .filter(|(_, d, _)| range.contains(d))
+            .map(|p| p.2)
+            .collect()
+    };
+    let before = side(day - window + 1..=day);
+    let after = side(day + 1..=day + window);
+    if before.len() < MIN_CHANGE_DAYS || after.len() < MIN_CHANGE_DAYS {
+        return None;
+    }
+    let error =
+        (variance(&before) / before.len() as f64 + variance(&after) / after.len() as f64).sqrt();
+    let difference = (mean(&after) - mean(&before)).abs();
+    let score = if error > 0.0 {
+        difference / error
+    } else if difference > 0.0 {
+        f64::INFINITY
+    } else {
+        0.0
+    };
+    (score > CHANGE_THRESHOLD).then_some(score)
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Sample variance.
+fn variance(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64
+}
+
+fn slope(points: &[(i64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0 as f64).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (covariance, spread) = points.iter().fold((0.0, 0.0), |(c, s), &(x, y)| {
+        let dx = x as f64 - mean_x;
+        (c + dx * (y - mean_y), s + dx * dx)
+    });
+    (spread > 0.0).then(|| covariance / spread)
+}
+
+/// Write `points` as CSV with the columns `date`, `value`, `rolling_mean`,
+/// `slope_per_day` and `change_point`.
+pub fn write_csv<W: Write>(points: &[TrendPoint], writer: W) -> Result<()> {
+    let round = |value: f64, digits: i32| {
+        let scale = 10f64.powi(digits);
+        // Adding zero turns a rounded `-0` into `0`
+        ((value * scale).round() / scale + 0.0).to_string()
+    };
+    let mut w = csv::Writer::from_writer(writer);
+    w.write_record([
+        "date",
+        "value",
+        "rolling_mean",
+        "slope_per_day",
+        "change_point",
+    ])?;
+    for point in points {
+        w.write_record([
+            point.date.clone(),
+            round(point.value, 2),
+            round(point.rolling_mean, 2),
+            point.slope.map(|s| round(s, 4)).unwrap_or_default(),
+            point.change_point.to_string(),
+        ])?;
+    }
+    w.flush()?;
+    Ok(())
+}
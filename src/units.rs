@@ -0,0 +1,212 @@
+use crate::core::Processable;
+use crate::sinks::csv_zip::{CsvDialect, round_decimal};
+use ahash::AHashMap;
+use std::borrow::Cow;
+
+/// Columns keeping the exported value and unit of the records whose value
+/// was converted with [`UnitMode::Converted`].
+pub const ORIGINAL_COLUMNS: [&str; 2] = ["originalValue", "originalUnit"];
+
+/// Decimal places kept of a converted value.
+const CONVERTED_PLACES: usize = 6;
+
+/// Spellings of a unit found in exports, with the one HealthKit writes.
+const ALIASES: [(&str, &str); 12] = [
+    ("Cal", "kcal"),
+    ("kCal", "kcal"),
+    ("count/minute", "count/min"),
+    ("bpm", "count/min"),
+    ("lbs", "lb"),
+    ("sec", "s"),
+    ("mins", "min"),
+    ("minute", "min"),
+    ("hour", "hr"),
+    ("h", "hr"),
+    ("°C", "degC"),
+    ("°F", "degF"),
+];
+
+/// Units measuring the same quantity as `(unit, scale, offset)`, where
+/// `value * scale + offset` is the value in the first unit listed.
+const DIMENSIONS: [&[(&str, f64, f64)]; 7] = [
+    &[
+        ("g", 1.0, 0.0),
+        ("mg", 0.001, 0.0),
+        ("mcg", 1e-6, 0.0),
+        ("kg", 1000.0, 0.0),
+        ("oz", 28.349523125, 0.0),
+        ("lb", 453.59237, 0.0),
+        ("st", 6350.29318, 0.0),
+    ],
+    &[
+        ("m", 1.0, 0.0),
+        ("mm", 0.001, 0.0),
+        ("cm", 0.01, 0.0),
+        ("km", 1000.0, 0.0),
+        ("in", 0.0254, 0.0),
+        ("ft", 0.3048, 0.0),
+        ("yd", 0.9144, 0.0),
+        ("mi", 1609.344, 0.0),
+    ],
+    &[("kcal", 1.0, 0.0), ("kJ", 1.0 / 4.184, 0.0)],
+    &[
+        ("mL", 1.0, 0.0),
+        ("L", 1000.0, 0.0),
+        ("fl_oz_us", 29.5735295625, 0.0),
+        ("cup_us", 236.5882365, 0.0),
+    ],
+    &[
+        ("s", 1.0, 0.0),
+        ("ms", 0.001, 0.0),
+        ("min", 60.0, 0.0),
+        ("hr", 3600.0, 0.0),
+        ("d", 86_400.0, 0.0),
+    ],
+    &[
+        ("degC", 1.0, 0.0),
+        ("degF", 5.0 / 9.0, -160.0 / 9.0),
+        ("K", 1.0, -273.15),
+    ],
+    &[
+        ("m/s", 1.0, 0.0),
+        ("km/hr", 1.0 / 3.6, 0.0),
+        ("mi/hr", 0.44704, 0.0),
+    ],
+];
+
+/// What happens to the units of the records on output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum UnitMode {
+    /// Units are written as exported.
+    #[default]
+    AsExported,
+    /// Spellings of the same unit, such as `Cal` and `kcal`, are written as
+    /// the one HealthKit uses.
+    Normalized,
+    /// Spellings are normalized and the values of each type converted to
+    /// its most common unit where they measure the same quantity, keeping
+    /// the exported value and unit in the [`ORIGINAL_COLUMNS`].
+    Converted,
+}
+
+/// The spelling HealthKit uses for `unit`, such as `kcal` for `Cal`.
+pub fn normalize_unit(unit: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == unit)
+        .map_or(unit, |(_, unit)| unit)
+}
+
+/// `value` in unit `from` converted to unit `to`, if both measure the same
+/// quantity.
+pub fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+    let dimension = DIMENSIONS
+        .iter()
+        .find(|units| units.iter().any(|(unit, ..)| *unit == from))?;
+    let find = |name: &str| dimension.iter().find(|(unit, ..)| *unit == name);
+    let ((_, scale, offset), (_, to_scale, to_offset)) = (find(from)?, find(to)?);
+    Some((value * scale + offset - to_offset) / to_scale)
+}
+
+/// The units of one group, counted before it is written to find the unit
+/// its values are converted to.
+#[derive(Debug, Clone, Default)]
+pub struct UnitPlan {
+    mode: UnitMode,
+    counts: AHashMap<String, usize>,
+    /// Unit values are converted to, when the group has values in others.
+    target: Option<String>,
+    value_at: Option<usize>,
+    unit_at: Option<usize>,
+}
+
+impl UnitPlan {
+    pub fn new(mode: UnitMode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        if self.mode != UnitMode::Converted {
+            return;
+        }
+        if let Some(unit) = record.field("unit").map(normalize_unit) {
+            match self.counts.get_mut(unit) {
+                Some(count) => *count += 1,
+                None => {
+                    self.counts.insert(unit.to_string(), 1);
+                }
+            }
+        }
+    }
+
+    /// Settle on the target unit once every record was added, and find the
+    /// value and unit among the group's `headers`.
+    pub fn finish(&mut self, headers: &[&str]) {
+        let position = |column: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(column));
+        self.value_at = position("value");
+        self.unit_at = position("unit");
+        // The most common unit, and the first alphabetically among those
+        let target = self
+            .counts
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(unit, _)| unit);
+        self.target = target
+            .filter(|target| {
+                self.value_at.is_some()
+                    && self
+                        .counts
+                        .keys()
+                        .any(|unit| unit != *target && convert(1.0, unit, target).is_some())
+            })
+            .cloned();
+    }
+
+    /// Columns written after the group's headers.
+    pub fn extra_columns(&self) -> &'static [&'static str] {
+        if self.target.is_some() {
+            &ORIGINAL_COLUMNS
+        } else {
+            &[]
+        }
+    }
+
+    /// Normalize or convert the unit and value among the `cells` of
+    /// `record`, and append the [`extra_columns`](Self::extra_columns).
+    pub fn apply<'a, T: Processable>(
+        &self,
+        record: &'a T,
+        cells: &mut Vec<Cow<'a, str>>,
+        dialect: &CsvDialect,
+    ) {
+        if self.mode == UnitMode::AsExported {
+            return;
+        }
+        let exported = record.field("unit");
+        let unit = exported.map(normalize_unit);
+        if let (Some(at), Some(unit)) = (self.unit_at, unit) {
+            cells[at] = Cow::Borrowed(unit);
+        }
+        let Some(target) = &self.target else {
+            return;
+        };
+        let converted = unit.filter(|unit| unit != target).and_then(|unit| {
+            let value = record.field("value")?.parse::<f64>().ok()?;
+            convert(value, unit, target)
+        });
+        match (converted, self.value_at, self.unit_at) {
+            (Some(value), Some(value_at), Some(unit_at)) => {
+                let value = round_decimal(&value.to_string(), CONVERTED_PLACES);
+                let value = Cow::Owned(dialect.round(&value).into_owned());
+                let original = std::mem::replace(&mut cells[value_at], value);
+                cells[unit_at] = Cow::Owned(target.clone());
+                cells.extend([original, Cow::Borrowed(exported.unwrap_or_default())]);
+            }
+            _ => cells.extend([Cow::Borrowed(""), Cow::Borrowed("")]),
+        }
+    }
+}
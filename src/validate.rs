@@ -0,0 +1,207 @@
+use crate::apple_health::auto_export::{self, is_auto_export};
+use crate::apple_health::export_info::{EXPORT_INFO_GROUP, ExportInfo};
+use crate::apple_health::types::GenericRecord;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use crate::fit;
+use crate::runkeeper;
+use crate::sinks::cbor::MapReader;
+use crate::sinks::csv_zip::{
+    CBOR_EXTENSION, NDJSON_EXTENSION, PROTOBUF_EXTENSION, csv_reader, group_name,
+};
+use crate::sinks::file_names::{NameMap, entry_file_names};
+use crate::sinks::protobuf::count_messages;
+use crate::sleep_cycle;
+use crate::suunto;
+use crate::wahoo;
+use crate::xml_utils::{self, Nesting};
+use ahash::AHashSet;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Record counts for a single type in the source export and the output archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeCount {
+    pub name: String,
+    pub source: usize,
+    pub output: usize,
+}
+
+impl TypeCount {
+    pub fn matches(&self) -> bool {
+        self.source == self.output
+    }
+}
+
+/// Per-type reconciliation of a source export against a produced archive.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub types: Vec<TypeCount>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.types.iter().all(TypeCount::matches)
+    }
+
+    pub fn mismatches(&self) -> impl Iterator<Item = &TypeCount> {
+        self.types.iter().filter(|t| !t.matches())
+    }
+}
+
+/// Re-count the records per type in `input_path` and in the CSVs of
+/// `output_path`, reporting every type whose counts differ. `names` are the
+/// entry names the archive was written with, and `dedup_exact` whether
+/// exact duplicates were dropped from it.
+///
+/// The source is parsed sequentially, independent of the parallel extraction
+/// pipeline, so the check does not share its failure modes.
+pub fn validate(
+    input_path: &Path,
+    output_path: &Path,
+    names: &NameMap,
+    dedup_exact: bool,
+) -> Result<ValidationReport> {
+    let source = count_source(input_path, names, dedup_exact)?;
+    let output = count_output(output_path)?;
+    Ok(compare(&source, &output))
+}
+
+/// Check that every CSV in `output_path` holds as many rows as its group
+/// in `record_counts` has records, guarding against entries cut short while
+/// the archive was written.
+pub fn reconcile(
+    output_path: &Path,
+    record_counts: BTreeMap<String, usize>,
+    names: &NameMap,
+) -> Result<()> {
+    let expected = by_entry_name(record_counts, names);
+    let report = compare(&expected, &count_output(output_path)?);
+    if report.is_ok() {
+        return Ok(());
+    }
+    let mismatches: Vec<String> = report
+        .mismatches()
+        .map(|t| format!("{} has {} rows for {} records", t.name, t.output, t.source))
+        .collect();
+    Err(AppError::OutputMismatch(mismatches.join(", ")))
+}
+
+fn compare(source: &BTreeMap<String, usize>, output: &BTreeMap<String, usize>) -> ValidationReport {
+    let mut names: Vec<&String> = source.keys().chain(output.keys()).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let types = names
+        .into_iter()
+        .map(|name| TypeCount {
+            name: name.clone(),
+            source: source.get(name).copied().unwrap_or(0),
+            output: output.get(name).copied().unwrap_or(0),
+        })
+        .collect();
+    ValidationReport { types }
+}
+
+fn count_source(
+    input_path: &Path,
+    names: &NameMap,
+    dedup_exact: bool,
+) -> Result<BTreeMap<String, usize>> {
+    let mut counts = BTreeMap::new();
+    let mut seen = AHashSet::new();
+    let mut count = |record: GenericRecord| {
+        if dedup_exact && record.content_hash().is_some_and(|hash| !seen.insert(hash)) {
+            return;
+        }
+        *counts.entry(record.grouping_key()).or_insert(0) += 1;
+    };
+    let records = if input_path.is_dir() {
+        Some(fit::read_folder(input_path)?)
+    } else if suunto::is_suunto_workout(input_path) {
+        Some(suunto::read_workout(File::open(input_path)?, input_path)?)
+    } else if is_auto_export(input_path) {
+        Some(auto_export::read_records(File::open(input_path)?)?)
+    } else if runkeeper::is_runkeeper_export(input_path) {
+        Some(runkeeper::read_export(File::open(input_path)?)?)
+    } else if sleep_cycle::is_sleep_cycle_export(input_path) {
+        Some(sleep_cycle::read_export(File::open(input_path)?)?)
+    } else if wahoo::is_wahoo_export(input_path) {
+        Some(wahoo::read_export(File::open(input_path)?, input_path)?)
+    } else {
+        None
+    };
+    if let Some(records) = records {
+        records.into_iter().for_each(count);
+        return Ok(by_entry_name(counts, names));
+    }
+    let mut export_info = ExportInfo::default();
+    let mut nesting = Nesting::default();
+    xml_utils::with_export_reader(input_path, |reader| {
+        xml_utils::for_each_element(reader, |element| {
+            let Ok(record) = GenericRecord::from_xml(element) else {
+                return;
+            };
+            if let Some(record) = nesting.push(record)
+                && let Some(record) = export_info.absorb(record)
+            {
+                count(record);
+            }
+        })
+    })?;
+    if let Some(record) = nesting
+        .finish()
+        .and_then(|record| export_info.absorb(record))
+    {
+        count(record);
+    }
+    if export_info.into_record().is_some() {
+        counts.insert(EXPORT_INFO_GROUP.to_string(), 1);
+    }
+    Ok(by_entry_name(counts, names))
+}
+
+/// Key per-group counts by the names the sink gives the groups' entries.
+fn by_entry_name(counts: BTreeMap<String, usize>, names: &NameMap) -> BTreeMap<String, usize> {
+    let file_names = entry_file_names(counts.keys().map(String::as_str), names);
+    file_names
+        .iter()
+        .zip(counts.into_values())
+        .filter_map(|(file_name, count)| Some((group_name(file_name)?.to_string(), count)))
+        .collect()
+}
+
+fn count_output(output_path: &Path) -> Result<BTreeMap<String, usize>> {
+    let mut archive = ZipArchive::new(File::open(output_path)?)?;
+    let mut counts = BTreeMap::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        let Some(name) = group_name(entry.name()).map(str::to_string) else {
+            continue;
+        };
+        let rows = if entry.name().ends_with(NDJSON_EXTENSION) {
+            let mut rows = 0usize;
+            for line in BufReader::new(entry).split(b'\n') {
+                rows += usize::from(!line?.is_empty());
+            }
+            rows
+        } else if entry.name().ends_with(PROTOBUF_EXTENSION) {
+            count_messages(entry)?
+        } else if entry.name().ends_with(CBOR_EXTENSION) {
+            MapReader::new(entry).count()?
+        } else {
+            let mut reader = csv_reader(entry)?;
+            let mut rows = 0usize;
+            let mut record = csv::ByteRecord::new();
+            while reader.read_byte_record(&mut record)? {
+                rows += 1;
+            }
+            rows
+        };
+        counts.insert(name, rows);
+    }
+    Ok(counts)
+}
@@ -0,0 +1,224 @@
+//! Workouts recorded by Wahoo ELEMNT units, exported as FIT files, as the
+//! CSV files of the Wahoo app, or as a ZIP of either.
+
+use crate::apple_health::dates::{format_timestamp, parse_timestamp};
+use crate::apple_health::types::{Attributes, GenericRecord, attribute_value};
+use crate::error::Result;
+use crate::fit;
+use crate::intern::intern_key;
+use log::{debug, warn};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+/// Group of the samples of the CSV files.
+pub const SAMPLE_ELEMENT: &str = "WahooSample";
+
+/// Attribute naming the CSV file a sample was read from.
+pub const CSV_FILE_ATTRIBUTE: &str = "csvFile";
+
+/// Value of `sourceName` for every record.
+const SOURCE_NAME: &str = "Wahoo";
+
+/// Bytes at the start of a CSV file searched for its table of samples.
+const SNIFF_BYTES: u64 = 64 * 1024;
+
+/// Columns holding the time of each sample, in seconds since the Unix
+/// epoch or as a date.
+const TIME_COLUMNS: [&str; 2] = ["timestamp", "time"];
+
+/// Attributes of the sample columns, named as for FIT records, by column
+/// name in the Wahoo app's exports and in the simpler spelling some tools
+/// write.
+const COLUMNS: [(&str, &str); 17] = [
+    ("hr_heartrate", "heartRate"),
+    ("heartrate", "heartRate"),
+    ("bike_cad_cadence", "cadence"),
+    ("cadence", "cadence"),
+    ("bike_spd_speed", "speed"),
+    ("gps_speed", "speed"),
+    ("speed", "speed"),
+    ("bike_pwr_power", "power"),
+    ("power", "power"),
+    ("gps_distance", "distance"),
+    ("distance", "distance"),
+    ("gps_lat", "latitude"),
+    ("latitude", "latitude"),
+    ("gps_lon", "longitude"),
+    ("longitude", "longitude"),
+    ("gps_altitude", "altitude"),
+    ("altitude", "altitude"),
+];
+
+/// Whether `path` is a Wahoo export: a `.fit` file, a `.csv` file with a
+/// table of samples, or a ZIP of such files without an `export.xml`.
+pub fn is_wahoo_export(path: &Path) -> bool {
+    match extension(path).as_deref() {
+        Some("fit") => path.is_file(),
+        Some("csv") => {
+            let mut start = Vec::new();
+            File::open(path)
+                .and_then(|file| file.take(SNIFF_BYTES).read_to_end(&mut start))
+                .is_ok()
+                && has_sample_table(&start)
+        }
+        Some("zip") => File::open(path)
+            .ok()
+            .and_then(|file| zip::ZipArchive::new(file).ok())
+            .is_some_and(|archive| {
+                let names: Vec<&str> = archive.file_names().collect();
+                names.iter().any(|name| is_workout_file(name))
+                    && !names
+                        .iter()
+                        .any(|name| name.ends_with("export.xml") || name.ends_with(".zip"))
+            }),
+        _ => false,
+    }
+}
+
+/// Every record of the Wahoo export at `path`, read from `reader`: the
+/// sessions, laps and samples of its FIT files as decoded by
+/// [`fit::decode`], and the samples of its CSV files as
+/// [`SAMPLE_ELEMENT`]s, each with `sourceName` `Wahoo`.
+///
+/// The CSV values are kept as written. Files in a ZIP that cannot be read
+/// are skipped with a warning.
+pub fn read_export<R: Read + Seek>(mut reader: R, path: &Path) -> Result<Vec<GenericRecord>> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut records = if extension(path).as_deref() == Some("zip") {
+        let mut archive = zip::ZipArchive::new(reader)?;
+        let mut records = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            if !entry.is_file() || !is_workout_file(entry.name()) {
+                continue;
+            }
+            let name = entry
+                .name()
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            match read_file(entry, &name) {
+                Ok(read) => {
+                    debug!("Read {} records of {}", read.len(), name);
+                    records.extend(read);
+                }
+                Err(e) => warn!("Skipping {}: {}", name, e),
+            }
+        }
+        records
+    } else {
+        read_file(&mut reader, &file_name)?
+    };
+    for record in &mut records {
+        insert(&mut record.attributes, "sourceName", SOURCE_NAME);
+    }
+    Ok(records)
+}
+
+fn read_file<R: Read>(mut reader: R, file_name: &str) -> Result<Vec<GenericRecord>> {
+    if extension(Path::new(file_name)).as_deref() == Some("fit") {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        return fit::decode(&bytes, file_name);
+    }
+    let mut csv = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let mut rows = csv.records();
+    // The Wahoo app writes a summary above the table of samples
+    let mut header = None;
+    for row in rows.by_ref() {
+        let row = row?;
+        if let Some(columns) = sample_columns(&row) {
+            header = Some(columns);
+            break;
+        }
+    }
+    let Some((time, columns)) = header else {
+        debug!("No table of samples in {}", file_name);
+        return Ok(Vec::new());
+    };
+
+    let mut samples = Vec::new();
+    for row in rows {
+        let row = row?;
+        let Some(date) = row.get(time).and_then(date) else {
+            continue;
+        };
+        let mut attributes = Attributes::new();
+        for &(index, key) in &columns {
+            if let Some(value) = row.get(index).map(str::trim).filter(|v| !v.is_empty()) {
+                insert(&mut attributes, key, value);
+            }
+        }
+        if attributes.is_empty() {
+            continue;
+        }
+        insert(&mut attributes, "startDate", &date);
+        insert(&mut attributes, "endDate", &date);
+        insert(&mut attributes, CSV_FILE_ATTRIBUTE, file_name);
+        samples.push(GenericRecord {
+            element_name: SAMPLE_ELEMENT.to_string(),
+            attributes,
+        });
+    }
+    Ok(samples)
+}
+
+/// Index of the time column and the index and attribute of each sample
+/// column if `row` is the header of a table of samples.
+fn sample_columns(row: &csv::StringRecord) -> Option<(usize, Vec<(usize, &'static str)>)> {
+    let name = |index: usize| row[index].trim().to_ascii_lowercase();
+    let time = (0..row.len()).find(|&i| TIME_COLUMNS.contains(&name(i).as_str()))?;
+    let columns: Vec<_> = (0..row.len())
+        .filter_map(|i| {
+            let name = name(i);
+            COLUMNS
+                .iter()
+                .find(|(column, _)| *column == name)
+                .map(|&(_, key)| (i, key))
+        })
+        .collect();
+    (!columns.is_empty()).then_some((time, columns))
+}
+
+fn has_sample_table(start: &[u8]) -> bool {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(start)
+        .records()
+        .map_while(|row| row.ok())
+        .any(|row| sample_columns(&row).is_some())
+}
+
+/// UTC date of a time in seconds since the Unix epoch or written as a
+/// date.
+fn date(time: &str) -> Option<String> {
+    let time = time.trim();
+    match time.parse::<f64>() {
+        Ok(seconds) => Some(format_timestamp(seconds.floor() as i64)),
+        Err(_) => parse_timestamp(time).map(format_timestamp),
+    }
+}
+
+fn is_workout_file(name: &str) -> bool {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    !name.starts_with('.') && matches!(extension(Path::new(name)).as_deref(), Some("fit" | "csv"))
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+}
+
+fn insert(attributes: &mut Attributes, key: &str, value: &str) {
+    attributes.insert(intern_key(key), attribute_value(key, value));
+}
@@ -0,0 +1,9 @@
+use crate::in_memory::convert_bytes;
+use crate::sinks::csv_zip::Compression;
+use wasm_bindgen::prelude::*;
+
+/// Convert the bytes of an `export.zip` or `export.xml` into a CSV archive.
+#[wasm_bindgen]
+pub fn convert(export: &[u8]) -> Result<Vec<u8>, JsError> {
+    convert_bytes(export, Compression::default()).map_err(|e| JsError::new(&e.to_string()))
+}
@@ -1,142 +1,779 @@
-use crossbeam_channel as channel;
+use log::{debug, warn};
+use memchr::memmem;
 use quick_xml::events::{BytesStart, Event};
-use rayon::ThreadPool;
 use std::{
-    path::PathBuf,
-    sync::{Arc, OnceLock},
+    fs::File,
+    io::{Chain, Cursor, Read, Seek},
+    ops::Range,
+    path::Path,
+};
+#[cfg(feature = "native")]
+use {
+    crate::backpressure::ByteBudget,
+    crate::progress::{ByteProgress, Progress, ProgressReader},
+    crossbeam_channel as channel,
+    rayon::Scope,
+    std::sync::Mutex,
+    tokio::task,
 };
-use tokio::task;
 
 use crate::error::{AppError, Result};
 
 pub const BUFFER_SIZE: usize = 1024 * 128; // 128 KB for L2 cache optimization
+#[cfg(feature = "native")]
 const BATCH_SIZE: usize = 500; // Number of records to batch for parallel processing
+#[cfg(feature = "native")]
+const CHUNK_SIZE: usize = 16 * 1024 * 1024; // Target size of independently parsed chunks
+
+/// Parses one element starting at the given byte offset of the document;
+/// errors are forwarded to the receiver alongside records.
+pub type ParseFn<T> = fn(&BytesStart, u64) -> Result<T>;
+
+/// Element that only occurs inside another one; batches are never split
+/// right before it, so it is parsed after its parent.
+#[cfg(feature = "native")]
+const NESTED_ELEMENT: &[u8] = b"MetadataEntry";
 
-pub type ParseFn<T> = fn(&BytesStart) -> Option<T>;
+/// Parsed elements that can take in the elements nested inside them.
+pub trait Nest: Sized {
+    /// Fold `child`, the element parsed right after `self`, into `self`, or
+    /// hand it back when it stands on its own.
+    fn nest(&mut self, child: Self) -> Option<Self>;
+}
 
-static THREAD_POOL: OnceLock<ThreadPool> = OnceLock::new();
+/// Holds each parsed element back until the next one shows whether it
+/// nests inside it.
+#[derive(Debug)]
+pub struct Nesting<T> {
+    pending: Option<T>,
+}
 
-pub fn get_thread_pool() -> Result<&'static ThreadPool> {
-    if let Some(pool) = THREAD_POOL.get() {
-        return Ok(pool);
+impl<T: Nest> Default for Nesting<T> {
+    fn default() -> Self {
+        Self { pending: None }
     }
+}
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .build()
-        .map_err(AppError::ThreadPoolError)?;
+impl<T: Nest> Nesting<T> {
+    /// Take in the next element, returning the previous one once it is
+    /// complete.
+    pub fn push(&mut self, element: T) -> Option<T> {
+        match &mut self.pending {
+            Some(parent) => {
+                let element = parent.nest(element)?;
+                self.pending.replace(element)
+            }
+            None => {
+                self.pending = Some(element);
+                None
+            }
+        }
+    }
 
-    let _ = THREAD_POOL.set(pool);
-    Ok(THREAD_POOL.get().expect("thread pool set"))
+    /// The last element, complete at the end of the input.
+    pub fn finish(self) -> Option<T> {
+        self.pending
+    }
 }
 
+#[cfg(feature = "native")]
+/// Limit the global rayon pool, which parses XML and serializes CSVs, to
+/// `threads` workers. Must be called before any processing starts.
+pub fn configure_threads(threads: usize) -> Result<()> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(AppError::ThreadPoolError)
+}
+
+#[cfg(feature = "native")]
 /// Parallel XML processing logic using a batched streaming approach.
+///
+/// Batches are parsed on the global rayon pool inside a scope, so every
+/// record has been sent before this returns.
 fn process_xml_reader_parallel<T, R>(
     reader: R,
-    sender: channel::Sender<T>,
+    sender: channel::Sender<Result<T>>,
     parse_fn: ParseFn<T>,
-    pool: &ThreadPool,
+    budget: ByteBudget,
 ) -> Result<()>
 where
-    T: Send + 'static,
+    T: Nest + Send + 'static,
     R: std::io::Read,
 {
+    let (skipped, reader) = skip_doctype(reader)?;
     let buf_reader = std::io::BufReader::with_capacity(BUFFER_SIZE, reader);
     let mut xml_reader = quick_xml::reader::Reader::from_reader(buf_reader);
     xml_reader.config_mut().trim_text(true);
     let mut buf = Vec::with_capacity(BUFFER_SIZE);
-    let mut batch = Vec::with_capacity(BATCH_SIZE);
 
-    loop {
-        match xml_reader.read_event_into(&mut buf) {
-            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
-                // Skip the root element to avoid processing it
-                if e.name().as_ref() == b"HealthData" {
-                    continue;
+    rayon::in_place_scope(|scope| {
+        let mut batcher = Batcher::new(sender, parse_fn, budget, scope);
+        loop {
+            let offset = skipped + xml_reader.buffer_position();
+            match xml_reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => batcher.push(offset, e),
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    // Hand over what was read before the error
+                    batcher.flush();
+                    return Err(xml_error(&xml_reader, skipped, e));
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+        batcher.flush();
+        Ok(())
+    })
+}
+
+#[cfg(feature = "native")]
+/// Like [`process_xml_reader_parallel`] over an in-memory document, reading
+/// events straight from `data` without an intermediate buffer.
+fn process_xml_slice_parallel<T>(
+    data: &[u8],
+    sender: channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+    progress: Progress,
+) -> Result<()>
+where
+    T: Nest + Send + 'static,
+{
+    let skipped = doctype_end(data).unwrap_or(0);
+    let mut xml_reader = quick_xml::reader::Reader::from_reader(&data[skipped..]);
+    xml_reader.config_mut().trim_text(true);
+    let skipped = skipped as u64;
+    let mut bytes = ByteProgress::new(progress, Some(data.len() as u64));
+
+    rayon::in_place_scope(|scope| {
+        let mut batcher = Batcher::new(sender, parse_fn, budget, scope);
+        loop {
+            let offset = skipped + xml_reader.buffer_position();
+            match xml_reader.read_event() {
+                Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => batcher.push(offset, e),
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    batcher.flush();
+                    return Err(xml_error(&xml_reader, skipped, e));
                 }
+                _ => {}
+            }
+            bytes.update(skipped + xml_reader.buffer_position(), false);
+        }
+        bytes.update(data.len() as u64, true);
+        batcher.flush();
+        Ok(())
+    })
+}
 
-                batch.push(e.to_owned());
-                if batch.len() < BATCH_SIZE {
-                    continue;
+#[cfg(feature = "native")]
+/// Split an in-memory document into chunks on `<Record` boundaries and parse
+/// them concurrently, each chunk tokenized and parsed by its own task.
+fn process_xml_chunks_parallel<T>(
+    data: &[u8],
+    sender: channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+    progress: Progress,
+) -> Result<()>
+where
+    T: Nest + Send + 'static,
+{
+    let parsed = Mutex::new((ByteProgress::new(progress, Some(data.len() as u64)), 0));
+    let first_error = Mutex::new(None);
+
+    let skipped = doctype_end(data).unwrap_or(0);
+    rayon::in_place_scope(|scope| {
+        for range in record_chunks(&data[skipped..], CHUNK_SIZE) {
+            let range = skipped + range.start..skipped + range.end;
+            let len = range.len();
+            budget.acquire(len);
+            let sender = sender.clone();
+            let budget = budget.clone();
+            let (parsed, first_error) = (&parsed, &first_error);
+            scope.spawn(move |_| {
+                let result =
+                    parse_chunk(&data[range.clone()], range.start as u64, &sender, parse_fn);
+                budget.release(len);
+                if let Err(e) = result {
+                    first_error
+                        .lock()
+                        .expect("error lock poisoned")
+                        .get_or_insert(e);
                 }
+                let (bytes, done) = &mut *parsed.lock().expect("progress lock poisoned");
+                *done += len as u64;
+                bytes.update(*done, false);
+            });
+        }
+    });
+
+    let (mut bytes, _) = parsed.into_inner().expect("progress lock poisoned");
+    bytes.update(data.len() as u64, true);
+    match first_error.into_inner().expect("error lock poisoned") {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Parse every element of one chunk produced by [`record_chunks`]; `base` is
+/// the chunk's offset in the whole document.
+#[cfg(feature = "native")]
+fn parse_chunk<T: Nest>(
+    chunk: &[u8],
+    base: u64,
+    sender: &channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+) -> Result<()> {
+    let mut xml_reader = quick_xml::reader::Reader::from_reader(chunk);
+    let config = xml_reader.config_mut();
+    config.trim_text(true);
+    // A chunk may close elements opened in an earlier one, such as the
+    // `Correlation` around a `Record` or the `HealthData` root
+    config.check_end_names = false;
+    config.allow_unmatched_ends = true;
 
-                let current_batch = std::mem::take(&mut batch);
-                let sender_clone = sender.clone();
-                pool.spawn(move || {
-                    for event in &current_batch {
-                        if let Some(record) = parse_fn(event) {
-                            if sender_clone.send(record).is_err() {
-                                break;
-                            }
-                        }
-                    }
-                });
+    let mut nesting = Nesting::default();
+    let outcome = loop {
+        let offset = base + xml_reader.buffer_position();
+        match xml_reader.read_event() {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.name().as_ref() != b"HealthData" =>
+            {
+                let result = parse_fn(e, offset).map_err(|e| e.at_offset(offset));
+                if !send_nested(sender, &mut nesting, result) {
+                    return Ok(());
+                }
             }
-            Ok(Event::Eof) => break,
-            Err(e) => return Err(AppError::ParseError(e.to_string())),
+            Ok(Event::Eof) => break Ok(()),
+            Err(e) => break Err(xml_error(&xml_reader, base, e)),
             _ => {}
         }
-        buf.clear();
+    };
+    if let Some(record) = nesting.finish() {
+        let _ = sender.send(Ok(record));
+    }
+    outcome
+}
+
+/// Split `data` into ranges of roughly `chunk_size` bytes that each end
+/// right before a `<Record` tag, so no element straddles two ranges.
+///
+/// The ranges cover `data` in order. Exports are a flat list of elements
+/// whose attribute values cannot contain `<`, which makes every `<Record`
+/// a safe place to cut.
+pub fn record_chunks(data: &[u8], chunk_size: usize) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = next_record_tag(data, start.saturating_add(chunk_size.max(1)));
+        chunks.push(start..end);
+        start = end;
     }
+    chunks
+}
+
+/// Position of the first `<Record` tag at or after `from`, or the end of
+/// `data` when there is none.
+fn next_record_tag(data: &[u8], from: usize) -> usize {
+    const TAG: &[u8] = b"<Record";
+    let Some(rest) = data.get(from..) else {
+        return data.len();
+    };
+    memmem::find_iter(rest, TAG)
+        .find(|&i| {
+            matches!(
+                rest.get(i + TAG.len()),
+                Some(b' ' | b'\t' | b'\r' | b'\n' | b'/' | b'>')
+            )
+        })
+        .map_or(data.len(), |i| from + i)
+}
+
+/// Collects elements into batches and parses each full batch on the scope's
+/// pool, waiting while the raw bytes of unparsed batches exceed the budget.
+#[cfg(feature = "native")]
+struct Batcher<'a, 's, T> {
+    batch: Vec<(u64, BytesStart<'static>)>,
+    batch_bytes: usize,
+    sender: channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+    scope: &'a Scope<'s>,
+}
 
-    // Process the final partial batch
-    if !batch.is_empty() {
-        let sender_clone = sender.clone();
-        pool.spawn(move || {
-            for event in &batch {
-                if let Some(record) = parse_fn(event) {
-                    if sender_clone.send(record).is_err() {
-                        break;
-                    }
+#[cfg(feature = "native")]
+impl<'a, 's, T: Nest + Send + 'static> Batcher<'a, 's, T> {
+    fn new(
+        sender: channel::Sender<Result<T>>,
+        parse_fn: ParseFn<T>,
+        budget: ByteBudget,
+        scope: &'a Scope<'s>,
+    ) -> Self {
+        Self {
+            batch: Vec::with_capacity(BATCH_SIZE),
+            batch_bytes: 0,
+            sender,
+            parse_fn,
+            budget,
+            scope,
+        }
+    }
+
+    fn push(&mut self, offset: u64, element: &BytesStart) {
+        // Skip the root element to avoid processing it
+        if element.name().as_ref() == b"HealthData" {
+            return;
+        }
+        if self.batch.len() >= BATCH_SIZE && element.name().as_ref() != NESTED_ELEMENT {
+            self.flush();
+        }
+        self.batch_bytes += element.len();
+        self.batch.push((offset, element.to_owned()));
+    }
+
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        let bytes = std::mem::take(&mut self.batch_bytes);
+        self.budget.acquire(bytes);
+        let sender = self.sender.clone();
+        let parse_fn = self.parse_fn;
+        let budget = self.budget.clone();
+        self.scope.spawn(move |_| {
+            parse_batch(&batch, &sender, parse_fn);
+            budget.release(bytes);
+        });
+    }
+}
+
+/// Parse one batch of `(offset, element)` pairs, tagging errors with the
+/// element's byte offset.
+#[cfg(feature = "native")]
+fn parse_batch<T: Nest>(
+    batch: &[(u64, BytesStart<'static>)],
+    sender: &channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+) {
+    let mut nesting = Nesting::default();
+    for (offset, event) in batch {
+        let result = parse_fn(event, *offset).map_err(|e| e.at_offset(*offset));
+        if !send_nested(sender, &mut nesting, result) {
+            return;
+        }
+    }
+    if let Some(record) = nesting.finish() {
+        let _ = sender.send(Ok(record));
+    }
+}
+
+/// Send `result` once it can no longer take in nested elements, returning
+/// whether the receiver is still listening.
+#[cfg(feature = "native")]
+fn send_nested<T: Nest>(
+    sender: &channel::Sender<Result<T>>,
+    nesting: &mut Nesting<T>,
+    result: Result<T>,
+) -> bool {
+    let ready = match result {
+        Ok(record) => nesting.push(record).map(Ok),
+        Err(e) => Some(Err(e)),
+    };
+    ready.is_none_or(|ready| sender.send(ready).is_ok())
+}
+
+/// Convert a parse error of `reader`, which started reading `base` bytes into
+/// the document.
+fn xml_error<R>(reader: &quick_xml::reader::Reader<R>, base: u64, e: quick_xml::Error) -> AppError {
+    AppError::Xml {
+        offset: base + reader.error_position(),
+        message: e.to_string(),
+    }
+}
+
+/// Length of the prologue of `data` up to the end of its `<!DOCTYPE ...>`
+/// declaration: `Some(0)` when the document has no DOCTYPE, and `None` when
+/// `data` ends before the declaration does.
+///
+/// quick-xml finds the end of a DOCTYPE by balancing `<` and `>`, which
+/// breaks on the unpaired brackets that comments and quoted strings in the
+/// internal subset may contain. This scanner honours both, so the parsers
+/// can start right after the DTD instead.
+pub fn doctype_end(data: &[u8]) -> Option<usize> {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    const DOCTYPE: &[u8] = b"<!DOCTYPE";
+    let mut i = if data.starts_with(BOM) { BOM.len() } else { 0 };
+    loop {
+        while data.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        let rest = &data[i..];
+        if rest.starts_with(b"<?") {
+            i += 2 + memmem::find(&rest[2..], b"?>")? + 2;
+        } else if rest.starts_with(b"<!--") {
+            i += 4 + memmem::find(&rest[4..], b"-->")? + 3;
+        } else if rest.starts_with(DOCTYPE) {
+            return declaration_end(data, i + DOCTYPE.len());
+        } else if DOCTYPE.starts_with(rest) || b"<!--".starts_with(rest) || BOM.starts_with(data) {
+            // Too little data to tell what comes next
+            return None;
+        } else {
+            return Some(0);
+        }
+    }
+}
+
+/// End of the DOCTYPE declaration whose body starts at `i`.
+fn declaration_end(data: &[u8], mut i: usize) -> Option<usize> {
+    // Depth of `[...]` internal subsets
+    let mut depth = 0usize;
+    while let Some(&byte) = data.get(i) {
+        let rest = &data[i..];
+        if rest.starts_with(b"<!--") {
+            i += 4 + memmem::find(&rest[4..], b"-->")? + 3;
+        } else if rest.starts_with(b"<?") {
+            i += 2 + memmem::find(&rest[2..], b"?>")? + 2;
+        } else {
+            match byte {
+                b'"' | b'\'' => i += 1 + memchr::memchr(byte, &rest[1..])? + 1,
+                b'[' => {
+                    depth += 1;
+                    i += 1;
+                }
+                b']' => {
+                    depth = depth.saturating_sub(1);
+                    i += 1;
                 }
+                b'>' if depth == 0 => return Some(i + 1),
+                _ => i += 1,
             }
-        });
+        }
     }
+    None
+}
 
+/// The rest of a document after [`skip_doctype`]: the buffered bytes past
+/// the DOCTYPE followed by the remainder of the original reader.
+pub type AfterDoctype<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Read past the prologue of a document up to the end of its DOCTYPE (see
+/// [`doctype_end`]), returning how many bytes were skipped and a reader over
+/// the rest. Documents without a DOCTYPE are left untouched.
+pub fn skip_doctype<R: Read>(mut reader: R) -> std::io::Result<(u64, AfterDoctype<R>)> {
+    let mut prologue = Vec::new();
+    let skipped = loop {
+        if let Some(end) = doctype_end(&prologue) {
+            break end;
+        }
+        let len = prologue.len();
+        prologue.resize(len + BUFFER_SIZE, 0);
+        let read = reader.read(&mut prologue[len..])?;
+        prologue.truncate(len + read);
+        if read == 0 {
+            // Let the parser report whatever is wrong with the prologue
+            break 0;
+        }
+    };
+    let mut rest = Cursor::new(prologue);
+    rest.set_position(skipped as u64);
+    Ok((skipped as u64, rest.chain(reader)))
+}
+
+/// Sequentially visit every element of an export (except the `HealthData`
+/// root) on the current thread.
+pub fn for_each_element<R, F>(reader: R, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&BytesStart),
+{
+    try_for_each_element(reader, |element| {
+        f(element);
+        Ok(())
+    })
+}
+
+/// Like [`for_each_element`], but stops at the first error `f` returns.
+pub fn try_for_each_element<R, F>(reader: R, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&BytesStart) -> Result<()>,
+{
+    let (skipped, reader) = skip_doctype(reader)?;
+    let buf_reader = std::io::BufReader::with_capacity(BUFFER_SIZE, reader);
+    let mut xml_reader = quick_xml::reader::Reader::from_reader(buf_reader);
+    xml_reader.config_mut().trim_text(true);
+    let mut buf = Vec::with_capacity(BUFFER_SIZE);
+
+    loop {
+        match xml_reader.read_event_into(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.name().as_ref() != b"HealthData" =>
+            {
+                f(e)?;
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_error(&xml_reader, skipped, e)),
+            _ => {}
+        }
+        buf.clear();
+    }
     Ok(())
 }
 
+/// Open the export at `input_path` (plain `export.xml` or a ZIP containing it,
+/// or a URL to download either from) and pass a reader over the XML to `f`.
+///
+/// ZIP archives nested in the archive, as left by re-sharing an export or
+/// zipping several together, are searched too, and `f` is called once for
+/// every `export.xml` found, in archive order.
+pub fn with_export_reader<F>(input_path: &Path, mut f: F) -> Result<()>
+where
+    F: FnMut(&mut dyn Read) -> Result<()>,
+{
+    #[cfg(feature = "native")]
+    if crate::remote::is_url(input_path) {
+        let mut download = crate::remote::Download::open(
+            &input_path.to_string_lossy(),
+            crate::retry::RetryPolicy::default(),
+        )?;
+        let name = crate::remote::file_name(input_path);
+        if name.extension().and_then(|s| s.to_str()) != Some("zip") {
+            return f(&mut download);
+        }
+        return with_streamed_export_reader(download, f);
+    }
+    let mut file = File::open(input_path)?;
+    if input_path.extension().and_then(|s| s.to_str()) != Some("zip") {
+        return f(&mut file);
+    }
+    with_zipped_export_reader(file, f)
+}
+
+/// Like [`with_export_reader`] for a ZIP archive that is already open.
+///
+/// Zip64 archives are supported. When the central directory cannot be read,
+/// for instance because the archive is truncated, `export.xml` is looked up
+/// with [`with_streamed_export_reader`] instead.
+pub fn with_zipped_export_reader<R, F>(zip: R, mut f: F) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&mut dyn Read) -> Result<()>,
+{
+    with_sized_export_entries(zip, &mut |export_file, _| f(export_file))
+}
+
+/// Find `export.xml` by walking the local file headers from the start of a
+/// ZIP stream, without reading the central directory at its end.
+///
+/// Only the entry being read is held in memory and the input need not be
+/// seekable or complete, but entry sizes must be recorded in the local
+/// headers (or their Zip64 extra fields), as they are in Apple's exports.
+pub fn with_streamed_export_reader<R, F>(zip: R, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&mut dyn Read) -> Result<()>,
+{
+    match streamed_export_entries(zip, &mut |export_file, _| f(export_file))? {
+        0 => Err(AppError::MissingEntry("export.xml".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Callback receiving each `export.xml` of an archive and its uncompressed
+/// size.
+type ExportEntryFn<'a> = dyn FnMut(&mut dyn Read, u64) -> Result<()> + 'a;
+
+/// Pass every `export.xml` of the archive and of the archives nested in it
+/// to `f`, failing when there is none.
+fn with_sized_export_entries<R: Read + Seek>(zip: R, f: &mut ExportEntryFn) -> Result<()> {
+    match sized_export_entries(zip, f)? {
+        0 => Err(AppError::MissingEntry("export.xml".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Pass every `export.xml` of the archive and of the archives nested in it
+/// to `f`, falling back to the local file headers when the central
+/// directory is unreadable. Returns the number of exports found.
+fn sized_export_entries<R: Read + Seek>(mut zip: R, f: &mut ExportEntryFn) -> Result<usize> {
+    let mut archive = match zip::ZipArchive::new(&mut zip) {
+        Ok(archive) => archive,
+        Err(e) => {
+            warn!(
+                "Cannot read the ZIP central directory ({}), scanning local headers instead",
+                e
+            );
+            zip.rewind()?;
+            return streamed_export_entries(zip, f);
+        }
+    };
+    let mut found = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        found += export_entry(&mut entry, f)?;
+    }
+    Ok(found)
+}
+
+fn streamed_export_entries<R: Read>(mut zip: R, f: &mut ExportEntryFn) -> Result<usize> {
+    let mut found = 0;
+    loop {
+        // Dropping an entry skips to the next local header
+        let mut entry = match zip::read::read_zipfile_from_stream(&mut zip) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            // A truncated archive ends after its last complete entry
+            Err(e) if found > 0 => {
+                warn!("Stopped scanning the ZIP stream after its exports ({})", e);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        found += export_entry(&mut entry, f)?;
+    }
+    Ok(found)
+}
+
+/// Pass `entry` to `f` if it is an `export.xml`, or the exports it holds
+/// if it is itself an archive. Returns the number of exports found.
+fn export_entry<R: Read>(
+    entry: &mut zip::read::ZipFile<'_, R>,
+    f: &mut ExportEntryFn,
+) -> Result<usize> {
+    let name = entry.name().to_string();
+    if name.ends_with("export.xml") {
+        let size = entry.size();
+        f(entry, size)?;
+        return Ok(1);
+    }
+    if !name.to_ascii_lowercase().ends_with(".zip") {
+        return Ok(0);
+    }
+    debug!("Looking for exports in nested archive '{}'", name);
+    // Entries cannot seek, so the nested archive is copied out to read its
+    // central directory
+    #[cfg(feature = "native")]
+    let mut nested = tempfile::tempfile()?;
+    #[cfg(not(feature = "native"))]
+    let mut nested = std::io::Cursor::new(Vec::new());
+    std::io::copy(entry, &mut nested)?;
+    nested.rewind()?;
+    sized_export_entries(nested, f)
+}
+
+#[cfg(feature = "native")]
 pub async fn process_stream_parallel<T, R>(
     reader: R,
-    sender: channel::Sender<T>,
+    sender: channel::Sender<Result<T>>,
     parse_fn: ParseFn<T>,
+    budget: ByteBudget,
 ) -> Result<()>
 where
-    T: Send + 'static,
+    T: Nest + Send + 'static,
     R: std::io::Read + Send + 'static,
 {
-    let pool = get_thread_pool()?;
-    task::spawn_blocking(move || process_xml_reader_parallel(reader, sender, parse_fn, pool))
+    task::spawn_blocking(move || process_xml_reader_parallel(reader, sender, parse_fn, budget))
         .await
         .map_err(|e| AppError::Unknown(e.to_string()))?
 }
 
-/// Stream and process `export.xml` directly from a ZIP file in parallel
-pub async fn process_zip_stream_parallel<T>(
-    input_path: Arc<PathBuf>,
-    sender: channel::Sender<T>,
+/// Process a plain `export.xml` in parallel through a read-only memory map
+/// of `file`, avoiding the copies made by buffered reads.
+///
+/// The file must not be modified while it is being processed.
+#[cfg(feature = "native")]
+pub async fn process_mmap_parallel<T>(
+    file: File,
+    sender: channel::Sender<Result<T>>,
     parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+    progress: Progress,
 ) -> Result<()>
 where
-    T: Send + 'static,
+    T: Nest + Send + 'static,
 {
-    let pool = get_thread_pool()?;
-    let file = std::fs::File::open(input_path.as_ref())?;
-    let mut archive = zip::ZipArchive::new(file)?;
-    let export_file_name = archive
-        .file_names()
-        .find(|name| name.ends_with("export.xml"))
-        .map(|s| s.to_string());
-
-    if let Some(name) = export_file_name {
-        task::spawn_blocking(move || -> Result<()> {
-            let export_file = archive.by_name(&name)?;
-            process_xml_reader_parallel(export_file, sender, parse_fn, pool)
+    // SAFETY: the mapping is only read, and callers opt in knowing the input
+    // must stay unchanged for the duration of the run.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    task::spawn_blocking(move || {
+        process_xml_slice_parallel(&map, sender, parse_fn, budget, progress)
+    })
+    .await
+    .map_err(|e| AppError::Unknown(e.to_string()))?
+}
+
+/// Like [`process_mmap_parallel`], but splits the mapping into chunks on
+/// `<Record` boundaries that are tokenized and parsed on all threads at
+/// once instead of read by a single thread.
+///
+/// The file must not be modified while it is being processed.
+#[cfg(feature = "native")]
+pub async fn process_mmap_chunked<T>(
+    file: File,
+    sender: channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+    progress: Progress,
+) -> Result<()>
+where
+    T: Nest + Send + 'static,
+{
+    // SAFETY: as in `process_mmap_parallel`, the mapping is only read.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    task::spawn_blocking(move || {
+        process_xml_chunks_parallel(&map, sender, parse_fn, budget, progress)
+    })
+    .await
+    .map_err(|e| AppError::Unknown(e.to_string()))?
+}
+
+/// Process every `export.xml` of a ZIP stream that cannot seek, such as a
+/// download, found through its local file headers as by
+/// [`with_streamed_export_reader`].
+#[cfg(feature = "native")]
+pub async fn process_streamed_zip_parallel<T, R>(
+    reader: R,
+    sender: channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+) -> Result<()>
+where
+    T: Nest + Send + 'static,
+    R: Read + Send + 'static,
+{
+    task::spawn_blocking(move || {
+        with_streamed_export_reader(reader, |export_file| {
+            process_xml_reader_parallel(export_file, sender.clone(), parse_fn, budget.clone())
         })
-        .await
-        .map_err(|e| AppError::Unknown(e.to_string()))?
-    } else {
-        Err(AppError::ParseError(
-            "Could not find export.xml in the zip archive".to_string(),
-        ))
-    }
+    })
+    .await
+    .map_err(|e| AppError::Unknown(e.to_string()))?
+}
+
+#[cfg(feature = "native")]
+/// Stream and process every `export.xml` directly from a ZIP file in
+/// parallel, one after another
+pub async fn process_zip_stream_parallel<T, R>(
+    file: R,
+    sender: channel::Sender<Result<T>>,
+    parse_fn: ParseFn<T>,
+    budget: ByteBudget,
+    progress: Progress,
+) -> Result<()>
+where
+    T: Nest + Send + 'static,
+    R: Read + Seek + Send + 'static,
+{
+    task::spawn_blocking(move || {
+        with_sized_export_entries(file, &mut |export_file, size| {
+            let reader = ProgressReader::new(export_file, progress.clone(), Some(size));
+            process_xml_reader_parallel(reader, sender.clone(), parse_fn, budget.clone())
+        })
+    })
+    .await
+    .map_err(|e| AppError::Unknown(e.to_string()))?
 }
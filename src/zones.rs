@@ -0,0 +1,236 @@
+use crate::apple_health::dates::parse_timestamp;
+use crate::apple_health::export_info::EXPORT_INFO_GROUP;
+use crate::core::Processable;
+use crate::error::{AppError, Result};
+use std::str::FromStr;
+
+/// Group of the heart rate samples joined with the workouts.
+pub const HEART_RATE_GROUP: &str = "HKQuantityTypeIdentifierHeartRate";
+
+/// Group of the workouts.
+pub const WORKOUT_GROUP: &str = "Workout";
+
+/// Longest time, in seconds, one sample is taken to last when the next one
+/// is further away, so a watch taken off mid-workout does not fill a zone.
+const MAX_SAMPLE_SECONDS: i64 = 60;
+
+/// Lower bounds of zones 1 to 5 as shares of the maximum heart rate.
+const ZONE_SHARES: [f64; 5] = [0.5, 0.6, 0.7, 0.8, 0.9];
+
+const SECONDS_PER_YEAR: f64 = 365.2425 * 86_400.0;
+
+/// How the heart rate zones of `WorkoutHeartRateZones.csv` are bounded.
+///
+/// Parses from `age`, a maximum heart rate such as `190`, or the lower
+/// bounds of zones 1 to 5 such as `95,114,133,152,171`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HeartRateZones {
+    /// 50, 60, 70, 80 and 90% of a maximum heart rate of 220 minus the age
+    /// at the workout, from the date of birth in the export.
+    #[default]
+    AgeBased,
+    /// The same shares of the given maximum heart rate.
+    MaxHeartRate(f64),
+    /// Lower bounds in bpm of zones 1 to 5.
+    Bounds([f64; 5]),
+}
+
+impl HeartRateZones {
+    /// The maximum heart rate the zones derive from, if they do.
+    fn max_heart_rate(self, start: i64, birth: Option<i64>) -> Option<f64> {
+        match self {
+            HeartRateZones::AgeBased => {
+                let age = ((start - birth?) as f64 / SECONDS_PER_YEAR).floor();
+                Some(220.0 - age)
+            }
+            HeartRateZones::MaxHeartRate(max) => Some(max),
+            HeartRateZones::Bounds(_) => None,
+        }
+    }
+
+    fn bounds(self, max_heart_rate: Option<f64>) -> Option<[f64; 5]> {
+        match self {
+            HeartRateZones::Bounds(bounds) => Some(bounds),
+            _ => max_heart_rate.map(|max| ZONE_SHARES.map(|share| share * max)),
+        }
+    }
+}
+
+impl FromStr for HeartRateZones {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "age" {
+            return Ok(HeartRateZones::AgeBased);
+        }
+        let bpm = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().ok().filter(|v| *v > 0.0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("'{}' is not a heart rate in bpm", s))?;
+        match bpm[..] {
+            [max] => Ok(HeartRateZones::MaxHeartRate(max)),
+            [z1, z2, z3, z4, z5] if z1 < z2 && z2 < z3 && z3 < z4 && z4 < z5 => {
+                Ok(HeartRateZones::Bounds([z1, z2, z3, z4, z5]))
+            }
+            _ => Err(format!(
+                "'{}' is neither a maximum heart rate nor five rising zone bounds",
+                s
+            )),
+        }
+    }
+}
+
+/// One workout, with its dates as written in the export.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Workout {
+    pub(crate) activity: String,
+    pub(crate) start_date: String,
+    pub(crate) end_date: String,
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+    pub(crate) source: String,
+}
+
+impl Workout {
+    /// The workout `record` describes, or `None` without valid dates.
+    pub(crate) fn of<T: Processable>(record: &T) -> Option<Self> {
+        let date = |field: &str| {
+            let date = record.field(field)?;
+            Some((parse_timestamp(date)?, date.to_string()))
+        };
+        let ((start, start_date), (end, end_date)) = (date("startDate")?, date("endDate")?);
+        let field = |name: &str| record.field(name).unwrap_or_default().to_string();
+        Some(Self {
+            activity: field("workoutActivityType"),
+            start_date,
+            end_date,
+            start,
+            end: end.max(start),
+            source: field("sourceName"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Workouts(Vec<Workout>),
+    /// Start and bpm of every heart rate sample.
+    HeartRate(Vec<(i64, f64)>),
+    DateOfBirth(Option<i64>),
+}
+
+/// What a group contributes to the heart rate zones of the workouts,
+/// collected for [`heart_rate_zones_csv`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneSource(Source);
+
+impl ZoneSource {
+    /// Collector for `group`, or `None` if it takes no part in the join.
+    pub fn new(group: &str) -> Option<Self> {
+        let source = match group {
+            WORKOUT_GROUP => Source::Workouts(Vec::new()),
+            HEART_RATE_GROUP => Source::HeartRate(Vec::new()),
+            EXPORT_INFO_GROUP => Source::DateOfBirth(None),
+            _ => return None,
+        };
+        Some(Self(source))
+    }
+
+    pub fn add<T: Processable>(&mut self, record: &T) {
+        match &mut self.0 {
+            Source::Workouts(workouts) => workouts.extend(Workout::of(record)),
+            Source::HeartRate(samples) => {
+                let start = record.field("startDate").and_then(parse_timestamp);
+                let bpm = record.field("value").and_then(|v| v.parse::<f64>().ok());
+                if let (Some(start), Some(bpm)) = (start, bpm)
+                    && bpm.is_finite()
+                {
+                    samples.push((start, bpm));
+                }
+            }
+            Source::DateOfBirth(birth) => {
+                *birth = record.field("dateOfBirth").and_then(parse_timestamp);
+            }
+        }
+    }
+}
+
+/// One row per workout, in order of their start: the workout's type and
+/// dates, the heart rate samples taken during it, their mean and peak, the
+/// maximum heart rate the zones derive from and the minutes spent below
+/// zone 1 and in each of zones 1 to 5. A sample lasts until the next one,
+/// the end of the workout or a minute, whichever comes first. The zone
+/// columns are empty when `zones` is age-based and the export has no date
+/// of birth.
+pub fn heart_rate_zones_csv<'a>(
+    sources: impl IntoIterator<Item = &'a ZoneSource>,
+    zones: HeartRateZones,
+) -> Result<Vec<u8>> {
+    let (mut workouts, mut samples, mut birth) = (Vec::new(), Vec::new(), None);
+    for source in sources {
+        match &source.0 {
+            Source::Workouts(w) => workouts.extend(w),
+            Source::HeartRate(s) => samples.extend_from_slice(s),
+            Source::DateOfBirth(b) => birth = birth.or(*b),
+        }
+    }
+    workouts.sort_by_key(|w| (w.start, w.end));
+    samples.sort_by_key(|(start, _)| *start);
+
+    let minutes = |seconds: i64| ((seconds as f64 / 60.0 * 100.0).round() / 100.0).to_string();
+    let round = |bpm: f64| ((bpm * 10.0).round() / 10.0).to_string();
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record([
+        "workoutActivityType",
+        "startDate",
+        "endDate",
+        "samples",
+        "mean_bpm",
+        "peak_bpm",
+        "max_heart_rate",
+        "below_z1_minutes",
+        "z1_minutes",
+        "z2_minutes",
+        "z3_minutes",
+        "z4_minutes",
+        "z5_minutes",
+    ])?;
+    for workout in workouts {
+        let first = samples.partition_point(|(t, _)| *t < workout.start);
+        let last = samples.partition_point(|(t, _)| *t <= workout.end);
+        let during = &samples[first..last];
+        let max_heart_rate = zones.max_heart_rate(workout.start, birth);
+        let bounds = zones.bounds(max_heart_rate);
+
+        // Seconds below zone 1, then in each zone
+        let mut seconds = [0i64; 6];
+        for (i, (start, bpm)) in during.iter().enumerate() {
+            let next = samples.get(first + i + 1).map_or(workout.end, |(t, _)| *t);
+            let end = next.min(workout.end).min(start + MAX_SAMPLE_SECONDS);
+            let zone = bounds.map_or(0, |bounds| bounds.iter().filter(|b| bpm >= b).count());
+            seconds[zone] += end - start;
+        }
+
+        let mut row = vec![
+            workout.activity.clone(),
+            workout.start_date.clone(),
+            workout.end_date.clone(),
+            during.len().to_string(),
+        ];
+        if during.is_empty() {
+            row.extend([String::new(), String::new()]);
+        } else {
+            let mean = during.iter().map(|(_, bpm)| bpm).sum::<f64>() / during.len() as f64;
+            let peak = during.iter().map(|(_, bpm)| *bpm).fold(f64::MIN, f64::max);
+            row.extend([round(mean), round(peak)]);
+        }
+        row.push(max_heart_rate.map(round).unwrap_or_default());
+        match bounds {
+            Some(_) => row.extend(seconds.map(minutes)),
+            None => row.extend(std::iter::repeat_n(String::new(), seconds.len())),
+        }
+        w.write_record(&row)?;
+    }
+    w.into_inner().map_err(|e| AppError::Unknown(e.to_string()))
+}
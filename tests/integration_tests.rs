@@ -10,6 +10,13 @@ use tempfile::NamedTempFile;
 use zip::{ZipArchive, ZipWriter, write::FileOptions};
 
 const SAMPLE_EXPORT: &str = "tests/fixtures/sample_export.xml";
+const ENTITIES_EXPORT: &str = "tests/fixtures/entities_export.xml";
+const DOCTYPE_EXPORT: &str = "tests/fixtures/doctype_export.xml";
+const CLINICAL_EXPORT: &str = "tests/fixtures/clinical_export.xml";
+const AUTO_EXPORT: &str = "tests/fixtures/auto_export.json";
+const FIT_FOLDER: &str = "tests/fixtures/healthfit";
+const SUUNTO_WORKOUT: &str = "tests/fixtures/suunto_workout.json";
+const DUPLICATE_DATES_EXPORT: &str = "tests/fixtures/duplicate_dates_export.xml";
 
 #[test]
 fn test_integration() {
@@ -25,45 +32,1894 @@ fn test_integration() {
     assert!(output_zip.path().exists());
 }
 
+#[test]
+fn test_manifest_is_embedded() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&entries["manifest.json"]).expect("manifest json");
+    assert_eq!(manifest["tool_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(manifest["export_date"], "2023-01-01T00:00:00Z");
+    assert_eq!(manifest["inputs"][0]["path"], SAMPLE_EXPORT);
+    assert_eq!(
+        manifest["inputs"][0]["sha256"].as_str().map(str::len),
+        Some(64)
+    );
+    assert_eq!(
+        manifest["record_counts"]["HKQuantityTypeIdentifierStepCount"],
+        1
+    );
+}
+
+#[test]
+fn test_checksums_match_entries() {
+    use sha2::{Digest, Sha256};
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let sums = String::from_utf8(entries["SHA256SUMS"].clone()).expect("utf8");
+    let mut listed = 0;
+    for line in sums.lines() {
+        let (digest, name) = line.split_once("  ").expect("sha256sum line");
+        let actual: String = Sha256::digest(&entries[name])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(digest, actual, "digest mismatch for {}", name);
+        listed += 1;
+    }
+    // Every entry except the checksum file itself is listed.
+    assert_eq!(listed, entries.len() - 1);
+}
+
+#[test]
+fn test_export_info_is_consolidated() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    assert!(!entries.contains_key("ExportDate.csv"));
+    assert!(!entries.contains_key("Me.csv"));
+    let csv = String::from_utf8(entries["export_info.csv"].clone()).expect("utf8");
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "biologicalSex,bloodType,dateOfBirth,exportDate,skinType",
+            "Female,ONegative,1990-05-04,2023-01-01T00:00:00Z,III",
+        ]
+    );
+}
+
+#[test]
+fn test_column_order() {
+    for (args, header) in [
+        (
+            &[][..],
+            "startDate,endDate,device,duration,sourceName,sourceVersion,totalEnergyBurned,\
+             workoutActivityType",
+        ),
+        (
+            &["--alphabetical-columns"][..],
+            "device,duration,endDate,sourceName,sourceVersion,startDate,totalEnergyBurned,\
+             workoutActivityType",
+        ),
+    ] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(args)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        let entries = read_zip(output_zip.path());
+        let csv = String::from_utf8(entries["Workout.csv"].clone()).expect("utf8");
+        assert_eq!(csv.lines().next(), Some(header));
+    }
+}
+
+#[test]
+fn test_record_ids() {
+    let mut csvs = Vec::new();
+    for extra in [&[][..], &["--max-memory", "1K"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("--record-ids")
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        let entries = read_zip(output_zip.path());
+        let csv = String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone())
+            .expect("utf8");
+        csvs.push(csv);
+
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+    }
+
+    let lines: Vec<&str> = csvs[0].lines().collect();
+    assert!(lines[0].starts_with("id,startDate,"), "{}", lines[0]);
+    let (id, _) = lines[1].split_once(',').expect("cells");
+    assert_eq!(id.len(), 32);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    // The same records get the same ids whichever way they were written
+    assert_eq!(csvs[0], csvs[1]);
+}
+
+#[test]
+fn test_provenance_columns() {
+    let xml = fs::read(SAMPLE_EXPORT).expect("sample export");
+    for extra in [&[][..], &["--chunked"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("--provenance")
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+
+        let entries = read_zip(output_zip.path());
+        let csv = String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone())
+            .expect("utf8");
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let headers = reader.headers().expect("headers").clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).expect(name);
+        let (source_file, xml_offset) = (column("source_file"), column("xml_offset"));
+        for row in reader.records() {
+            let row = row.expect("row");
+            assert_eq!(&row[source_file], SAMPLE_EXPORT);
+            // Offsets are taken before the whitespace preceding the tag
+            let offset: usize = row[xml_offset].parse().expect("offset");
+            let tag = xml[offset..].trim_ascii_start();
+            assert!(tag.starts_with(b"<Record"), "{}", offset);
+        }
+    }
+}
+
+#[test]
+fn test_name_map() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    let mut name_map = NamedTempFile::new().expect("temp file");
+    writeln!(name_map, "HKQuantityTypeIdentifierBodyMass = \"weight\"").expect("write");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--name-map")
+        .arg(name_map.path())
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    assert!(entries.contains_key("weight.csv"));
+    assert!(!entries.contains_key("HKQuantityTypeIdentifierBodyMass.csv"));
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg("--name-map")
+        .arg(name_map.path())
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    // A map that is not TOML is rejected before converting
+    writeln!(name_map, "not toml").expect("write");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--name-map")
+        .arg(name_map.path())
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_epoch_columns() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--epoch-columns")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv =
+        String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone()).expect("utf8");
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines[0],
+        "startDate,startDate_ms,endDate,endDate_ms,creationDate,type,value,unit"
+    );
+    assert!(
+        lines[1]
+            .starts_with("2023-01-01T08:00:00Z,1672560000000,2023-01-01T08:00:00Z,1672560000000,"),
+        "{}",
+        lines[1]
+    );
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_drop_empty_columns() {
+    let xml = r#"<HealthData locale="en_US">
+ <Record type="HKQuantityTypeIdentifierStepCount" unit="count" value="10" device="" sourceVersion="" startDate="2023-01-01T08:00:00Z" endDate="2023-01-01T08:10:00Z"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" unit="count" value="20" device="" sourceVersion="17.1" startDate="2023-01-01T09:00:00Z" endDate="2023-01-01T09:10:00Z"/>
+</HealthData>"#;
+    let mut export = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("temp file");
+    export.write_all(xml.as_bytes()).expect("write");
+
+    for (args, header) in [
+        (
+            &[][..],
+            "startDate,endDate,type,value,unit,device,sourceVersion",
+        ),
+        (
+            &["--drop-empty-columns"][..],
+            "startDate,endDate,type,value,unit,sourceVersion",
+        ),
+    ] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(args)
+            .arg(export.path())
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        let entries = read_zip(output_zip.path());
+        let csv = String::from_utf8(entries["HKQuantityTypeIdentifierStepCount.csv"].clone())
+            .expect("utf8");
+        assert_eq!(csv.lines().next(), Some(header));
+    }
+}
+
+#[test]
+fn test_dedup_exact() {
+    // The second record repeats the first with its attributes reordered,
+    // the third differs in its value only
+    let xml = r#"<HealthData locale="en_US">
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="70.5" startDate="2023-01-01T08:00:00Z" endDate="2023-01-01T08:00:00Z"/>
+ <Record value="70.5" unit="kg" type="HKQuantityTypeIdentifierBodyMass" startDate="2023-01-01T08:00:00Z" endDate="2023-01-01T08:00:00Z"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="70.6" startDate="2023-01-01T08:00:00Z" endDate="2023-01-01T08:00:00Z"/>
+</HealthData>"#;
+    let mut export = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("temp file");
+    export.write_all(xml.as_bytes()).expect("write");
+
+    for (args, rows) in [(&[][..], 3), (&["--dedup-exact"][..], 2)] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(args)
+            .arg(export.path())
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        let entries = read_zip(output_zip.path());
+        let csv = String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone())
+            .expect("utf8");
+        assert_eq!(csv.lines().count(), 1 + rows, "{}", csv);
+
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .args(args)
+            .arg(export.path())
+            .arg(output_zip.path())
+            .assert()
+            .success();
+    }
+}
+
+#[test]
+fn test_unit_modes() {
+    let xml = r#"<HealthData locale="en_US">
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="70.5" startDate="2023-01-01T08:00:00Z" endDate="2023-01-01T08:00:00Z"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="70.1" startDate="2023-01-02T08:00:00Z" endDate="2023-01-02T08:00:00Z"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="lbs" value="155" startDate="2023-01-03T08:00:00Z" endDate="2023-01-03T08:00:00Z"/>
+ <Record type="HKQuantityTypeIdentifierActiveEnergyBurned" unit="Cal" value="12" startDate="2023-01-01T08:00:00Z" endDate="2023-01-01T08:10:00Z"/>
+ <Record type="HKQuantityTypeIdentifierActiveEnergyBurned" unit="kcal" value="9" startDate="2023-01-01T09:00:00Z" endDate="2023-01-01T09:10:00Z"/>
+</HealthData>"#;
+    let mut export = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("temp file");
+    export.write_all(xml.as_bytes()).expect("write");
+
+    let convert = |mode: &str| {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(["--units", mode])
+            .arg(export.path())
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(export.path())
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        read_zip(output_zip.path())
+    };
+    let csv = |entries: &HashMap<String, Vec<u8>>, name: &str| {
+        String::from_utf8(entries[name].clone()).expect("utf8")
+    };
+
+    let normalized = convert("normalized");
+    let energy = csv(
+        &normalized,
+        "HKQuantityTypeIdentifierActiveEnergyBurned.csv",
+    );
+    assert!(
+        energy.lines().skip(1).all(|row| row.ends_with(",kcal")),
+        "{}",
+        energy
+    );
+    let mass = csv(&normalized, "HKQuantityTypeIdentifierBodyMass.csv");
+    assert!(
+        mass.lines().last().unwrap().ends_with(",155,lb"),
+        "{}",
+        mass
+    );
+
+    let converted = convert("converted");
+    let mass = csv(&converted, "HKQuantityTypeIdentifierBodyMass.csv");
+    let lines: Vec<&str> = mass.lines().collect();
+    assert!(
+        lines[0].ends_with(",value,unit,originalValue,originalUnit"),
+        "{}",
+        lines[0]
+    );
+    assert!(lines[1].ends_with(",70.5,kg,,"), "{}", lines[1]);
+    assert!(lines[3].ends_with(",70.306817,kg,155,lbs"), "{}", lines[3]);
+    // Spellings of one unit need no conversion, nor the extra columns
+    let energy = csv(&converted, "HKQuantityTypeIdentifierActiveEnergyBurned.csv");
+    assert!(!energy.contains("originalUnit"), "{}", energy);
+}
+
+#[test]
+fn test_decimal_comma_with_excel_hint() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--decimal-separator", "comma", "--excel-hint"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv =
+        String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone()).expect("utf8");
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "sep=;");
+    assert_eq!(lines[1], "startDate;endDate;creationDate;type;value;unit");
+    assert!(lines[2].ends_with(";70,5;kg"), "{}", lines[2]);
+    // Only measurements get the comma, not version numbers
+    let workouts = String::from_utf8(entries["Workout.csv"].clone()).expect("utf8");
+    assert!(workouts.contains(";8.0;"), "{}", workouts);
+
+    // Archives in any dialect read back with the right row counts
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_decimal_comma_leaves_ndjson_and_cbor_alone() {
+    for layout in ["--ndjson", "--cbor"] {
+        let mut archives = Vec::new();
+        for extra in [&[][..], &["--decimal-separator", "comma"][..]] {
+            let output_zip = NamedTempFile::new().expect("temp file");
+            Command::cargo_bin("gpt-os")
+                .expect("binary")
+                .arg(layout)
+                .args(extra)
+                .arg(SAMPLE_EXPORT)
+                .arg(output_zip.path())
+                .assert()
+                .success();
+            let mut entries = read_zip(output_zip.path());
+            for entry in ["manifest.json", "SHA256SUMS"] {
+                entries.remove(entry);
+            }
+            archives.push(entries);
+        }
+        assert_eq!(archives[0], archives[1], "{}", layout);
+    }
+}
+
+#[test]
+fn test_round_only_changes_measurements() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--ndjson", "--round", "0"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    let entries = read_zip(output_zip.path());
+    let mass = String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.ndjson"].clone())
+        .expect("utf8");
+    assert!(mass.contains(r#""value":"71""#), "{}", mass);
+    let workouts = String::from_utf8(entries["Workout.ndjson"].clone()).expect("utf8");
+    assert!(
+        workouts.contains(r#""sourceVersion":"8.0""#),
+        "{}",
+        workouts
+    );
+}
+
+#[test]
+fn test_html_report() {
+    let mut reports = Vec::new();
+    for extra in [&[][..], &["--max-memory", "1K"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(["--report", "html"])
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        let entries = read_zip(output_zip.path());
+        reports.push(String::from_utf8(entries["report.html"].clone()).expect("utf8"));
+    }
+
+    let html = &reports[0];
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<tr><th>Date coverage</th><td>2023-01-01 to 2023-01-01</td></tr>"));
+    assert!(html.contains(
+        "<tr><td>Workout</td><td class=\"n\">1</td><td>2023-01-01</td><td>2023-01-01</td><td>Apple Watch (1)</td></tr>"
+    ));
+    assert!(html.contains("HKQuantityTypeIdentifierBodyMass"));
+    // Spilled groups are summarized the same way
+    assert_eq!(reports[0], reports[1]);
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    assert!(!read_zip(output_zip.path()).contains_key("report.html"));
+}
+
+#[test]
+fn test_statistics_report() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--report", "html,statistics,sources", "--report", "daily"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    let entries = read_zip(output_zip.path());
+    assert!(entries.contains_key("report.html"));
+    let statistics = String::from_utf8(entries["statistics.csv"].clone()).expect("utf8");
+    let mut lines = statistics.lines();
+    assert_eq!(
+        lines.next(),
+        Some("type,records,first,last,sources,min,mean,max")
+    );
+    assert!(lines.any(|line| line
+        == "HKQuantityTypeIdentifierBodyMass,1,2023-01-01T08:00:00Z,2023-01-01T08:00:00Z,0,70.5,70.5,70.5"));
+    assert!(statistics.contains("\nWorkout,1,2023-01-01T09:00:00Z,2023-01-01T09:00:00Z,1,,,\n"));
+    let sources = String::from_utf8(entries["sources.csv"].clone()).expect("utf8");
+    assert!(sources.starts_with("type,source,records,first,last\n"));
+    assert!(
+        sources.contains("\nWorkout,Apple Watch,1,2023-01-01T09:00:00Z,2023-01-01T09:00:00Z\n")
+    );
+    assert_eq!(
+        entries["daily_summary.csv"],
+        b"date,steps,active_energy_kcal,exercise_minutes,resting_heart_rate,sleep_minutes\n\
+          2023-01-01,10000,,,,\n"
+    );
+}
+
+#[test]
+fn test_charts() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--charts", "--report", "html"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    let entries = read_zip(output_zip.path());
+    let steps = String::from_utf8(entries["charts/steps.svg"].clone()).expect("utf8");
+    assert!(steps.starts_with("<svg"));
+    assert!(steps.contains("2023-01-01"));
+    assert!(entries.contains_key("charts/weight.svg"));
+    // The sample has no resting heart rate to draw
+    assert!(!entries.contains_key("charts/resting_heart_rate.svg"));
+
+    let html = String::from_utf8(entries["report.html"].clone()).expect("utf8");
+    assert!(html.contains(&format!("<figure>{}</figure>", steps)));
+}
+
+#[test]
+fn test_correlations_report() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--report", "correlations,html"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    let entries = read_zip(output_zip.path());
+    // One day of steps and weight is too little to correlate
+    assert_eq!(
+        entries["correlations.csv"],
+        b"metric,steps,body_mass\nsteps,,\nbody_mass,,\n"
+    );
+    let heatmap = String::from_utf8(entries["charts/correlations.svg"].clone()).expect("utf8");
+    assert!(heatmap.starts_with("<svg"));
+    let html = String::from_utf8(entries["report.html"].clone()).expect("utf8");
+    assert!(html.contains(&format!("<figure>{}</figure>", heatmap)));
+}
+
+#[test]
+fn test_rollup_archives() {
+    let xml = r#"<HealthData>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="1000" startDate="2023-01-01 08:00:00 -0500" endDate="2023-01-01 08:10:00 -0500"/>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="2000" startDate="2023-01-02 08:00:00 -0500" endDate="2023-01-02 08:10:00 -0500"/>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="500" startDate="2023-01-02 20:00:00 -0500" endDate="2023-01-02 20:10:00 -0500"/>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="4000" startDate="2023-02-03 08:00:00 -0500" endDate="2023-02-03 08:10:00 -0500"/>
+<Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="30" startDate="2023-01-03 07:00:00 -0500" endDate="2023-01-03 07:30:00 -0500"/>
+</HealthData>
+"#;
+    let dir = tempfile::tempdir().expect("temp dir");
+    let input = dir.path().join("export.xml");
+    fs::write(&input, xml).expect("write input");
+    let output = dir.path().join("health.zip");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--rollup", "weekly,monthly"])
+        .arg(&input)
+        .arg(&output)
+        .assert()
+        .success();
+    assert!(output.exists());
+    let weekly = read_zip(&dir.path().join("health_weekly.zip"));
+    assert_eq!(
+        weekly["HKQuantityTypeIdentifierStepCount.csv"],
+        b"week,records,days,total,mean,min,max\n\
+          2022-12-26,1,1,1000,1000,1000,1000\n\
+          2023-01-02,2,1,2500,2500,2500,2500\n\
+          2023-01-30,1,1,4000,4000,4000,4000\n"
+    );
+    assert_eq!(
+        weekly["Workout.csv"],
+        b"week,records,days,total,mean,min,max\n2023-01-02,1,0,,,,\n"
+    );
+    let monthly = read_zip(&dir.path().join("health_monthly.zip"));
+    assert_eq!(
+        monthly["HKQuantityTypeIdentifierStepCount.csv"],
+        b"month,records,days,total,mean,min,max\n\
+          2023-01,3,2,3500,1750,1000,2500\n\
+          2023-02,1,1,4000,4000,4000,4000\n"
+    );
+
+    let output = dir.path().join("only.zip");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--rollup", "monthly", "--rollup-only"])
+        .arg(&input)
+        .arg(&output)
+        .assert()
+        .success();
+    assert!(!output.exists());
+    assert_eq!(read_zip(&dir.path().join("only_monthly.zip")), monthly);
+}
+
+#[test]
+fn test_rollup_reads_decimal_commas() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let output = dir.path().join("health.zip");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--decimal-separator", "comma", "--rollup", "monthly"])
+        .arg(SAMPLE_EXPORT)
+        .arg(&output)
+        .assert()
+        .success();
+    let monthly = read_zip(&dir.path().join("health_monthly.zip"));
+    assert_eq!(
+        monthly["HKQuantityTypeIdentifierBodyMass.csv"],
+        b"month,records,days,total,mean,min,max\n2023-01,1,1,70.5,70.5,70.5,70.5\n"
+    );
+}
+
+#[test]
+fn test_rollup_needs_zip_format() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let output = dir.path().join("health");
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--format", "iceberg", "--rollup", "weekly", "--rollup-only"])
+        .arg(SAMPLE_EXPORT)
+        .arg(&output)
+        .assert()
+        .code(2);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).into_owned();
+    assert!(stderr.contains("--rollup needs --format zip"), "{}", stderr);
+    assert!(!output.exists());
+}
+
+#[test]
+fn test_workout_calendar() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--report", "calendar"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    let entries = read_zip(output_zip.path());
+    let calendar = String::from_utf8(entries["workouts.ics"].clone()).expect("utf8");
+    assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+    assert!(calendar.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+    // The sample's duration has no unit, so it is taken from the dates
+    assert!(calendar.contains(
+        "DTSTART:20230101T090000Z\r\n\
+         DTEND:20230101T093000Z\r\n\
+         SUMMARY:Walking\r\n\
+         DESCRIPTION:Duration: 30 min\\nEnergy: 150 kcal\\nSource: Apple Watch\r\n"
+    ));
+    assert_eq!(calendar.matches("BEGIN:VEVENT").count(), 1);
+}
+
+#[test]
+fn test_ecg_recordings_are_indexed() {
+    let ecg = "Name,Jane Appleseed\n\
+               Date of Birth,\"Jan 1, 1990\"\n\
+               Recorded Date,2023-01-01 10:00:00 -0800\n\
+               Classification,Sinus Rhythm\n\
+               Symptoms,\n\
+               Software Version,2\n\
+               Device,\"Watch6,1\"\n\
+               Sample Rate,512 hertz\n\
+               Lead,Lead I\n\
+               Unit,\u{b5}V\n\
+               \n\
+               -12.5\n\
+               3.25\n\
+               40\n";
+    let mut zip_input = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    {
+        let mut writer = ZipWriter::new(zip_input.as_file_mut());
+        for (name, data) in [
+            (
+                "apple_health_export/export.xml",
+                fs::read(SAMPLE_EXPORT).expect("read xml"),
+            ),
+            (
+                "apple_health_export/electrocardiograms/ecg_2023-01-01.csv",
+                ecg.as_bytes().to_vec(),
+            ),
+        ] {
+            writer
+                .start_file(name, FileOptions::<()>::default())
+                .expect("start file");
+            writer.write_all(&data).expect("write");
+        }
+        writer.finish().expect("finish");
+    }
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--report", "ecg"])
+        .arg(zip_input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    let entries = read_zip(output_zip.path());
+    assert_eq!(
+        entries["electrocardiograms/ecg_2023-01-01.csv"],
+        ecg.as_bytes()
+    );
+    let index = String::from_utf8(entries["ECGRecordings.csv"].clone()).expect("utf8");
+    assert_eq!(
+        index.lines().collect::<Vec<_>>(),
+        [
+            "date,classification,symptoms,average_heart_rate,software_version,device,\
+             sample_rate_hz,samples,file",
+            "2023-01-01 10:00:00 -0800,Sinus Rhythm,,,2,\"Watch6,1\",512,3,\
+             electrocardiograms/ecg_2023-01-01.csv",
+        ]
+    );
+
+    // The recordings are not mistaken for groups when validating
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(zip_input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_extract_assets_subcommand() {
+    let route = "<?xml version=\"1.0\"?><gpx version=\"1.1\"><trk><trkseg/></trk></gpx>";
+    let ecg = "Recorded Date,2023-01-01 10:00:00 -0800\n\n-12.5\n";
+    let export = zip_bytes(&[
+        (
+            "apple_health_export/export.xml",
+            &fs::read(SAMPLE_EXPORT).expect("read xml"),
+        ),
+        (
+            "apple_health_export/workout-routes/route_2023-01-01_8.30am.gpx",
+            route.as_bytes(),
+        ),
+        (
+            "apple_health_export/electrocardiograms/ecg_2023-01-01.csv",
+            ecg.as_bytes(),
+        ),
+    ]);
+    let input = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    fs::write(input.path(), export).expect("write zip");
+
+    let routes_only = tempfile::tempdir().expect("temp dir");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["extract-assets", "--only", "routes", "-o"])
+        .arg(routes_only.path())
+        .arg(input.path())
+        .assert()
+        .success();
+    let route_file = routes_only
+        .path()
+        .join("workout-routes/route_2023-01-01_8.30am.gpx");
+    assert_eq!(fs::read_to_string(route_file).expect("route"), route);
+    assert!(!routes_only.path().join("electrocardiograms").exists());
+
+    let all = tempfile::tempdir().expect("temp dir");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["extract-assets", "-o"])
+        .arg(all.path())
+        .arg(input.path())
+        .assert()
+        .success();
+    let ecg_file = all.path().join("electrocardiograms/ecg_2023-01-01.csv");
+    assert_eq!(fs::read_to_string(ecg_file).expect("ecg"), ecg);
+    assert!(all.path().join("workout-routes").is_dir());
+    // Nothing but the assets is written
+    assert_eq!(fs::read_dir(all.path()).expect("read dir").count(), 2);
+}
+
+#[test]
+fn test_table_schemas() {
+    let mut archives = Vec::new();
+    for extra in [&[][..], &["--max-memory", "1K"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(["--report", "schema"])
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        archives.push(read_zip(output_zip.path()));
+    }
+
+    let schema: serde_json::Value =
+        serde_json::from_slice(&archives[0]["HKQuantityTypeIdentifierBodyMass.schema.json"])
+            .expect("json");
+    let fields = schema["fields"].as_array().expect("fields");
+    assert_eq!(fields.len(), 6);
+    assert!(fields.contains(&serde_json::json!({ "name": "value", "type": "number" })));
+    assert!(fields.contains(
+        &serde_json::json!({ "name": "startDate", "type": "datetime", "format": "any" })
+    ));
+    let csvs = archives[0]
+        .keys()
+        .filter(|name| name.ends_with(".csv"))
+        .count();
+    let schemas = archives[0]
+        .keys()
+        .filter(|name| name.ends_with(".schema.json"))
+        .count();
+    assert_eq!(csvs, schemas);
+    // Spilled groups get the same schemas
+    for (name, schema) in &archives[0] {
+        if name.ends_with(".schema.json") {
+            assert_eq!(&archives[1][name], schema, "{}", name);
+        }
+    }
+}
+
+#[test]
+fn test_validate_subcommand() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    // An archive missing rows must be reported as a mismatch
+    let truncated = NamedTempFile::new().expect("temp file");
+    {
+        let mut writer = ZipWriter::new(truncated.reopen().expect("reopen"));
+        for (name, data) in read_zip(output_zip.path()) {
+            writer
+                .start_file(name.as_str(), FileOptions::<()>::default())
+                .expect("start file");
+            if name != "Workout.csv" {
+                writer.write_all(&data).expect("write");
+            }
+        }
+        writer.finish().expect("finish");
+    }
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(SAMPLE_EXPORT)
+        .arg(truncated.path())
+        .assert()
+        .code(6);
+}
+
+#[test]
+fn test_generate_subcommand_output_converts_and_validates() {
+    let export = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["generate", "--records", "2000", "--types", "8", "-o"])
+        .arg(export.path())
+        .assert()
+        .success();
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(export.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(export.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_merge_subcommand_dedupes_rows() {
+    let first = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(first.path())
+        .assert()
+        .success();
+
+    // A second export sharing most rows plus one extra body-mass sample
+    let xml = fs::read_to_string(SAMPLE_EXPORT).expect("read xml");
+    let extra = r#"<Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="69.9" creationDate="2022-12-31T08:00:00Z" startDate="2022-12-31T08:00:00Z" endDate="2022-12-31T08:00:00Z" />"#;
+    let mut second_xml = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("xml input");
+    second_xml
+        .write_all(
+            xml.replace("</HealthData>", &format!("{}\n</HealthData>", extra))
+                .as_bytes(),
+        )
+        .expect("write");
+    let second = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(second_xml.path())
+        .arg(second.path())
+        .assert()
+        .success();
+
+    let merged = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("merge")
+        .arg(first.path())
+        .arg(second.path())
+        .arg("-o")
+        .arg(merged.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(merged.path());
+    let body_mass =
+        String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone()).expect("utf8");
+    let lines: Vec<&str> = body_mass.lines().collect();
+    assert_eq!(lines.len(), 3, "header plus two distinct samples");
+    assert!(lines[1].contains("69.9"), "rows stay sorted by date");
+    assert!(lines[2].contains("70.5"));
+
+    let workouts = String::from_utf8(entries["Workout.csv"].clone()).expect("utf8");
+    assert_eq!(workouts.lines().count(), 2, "duplicate workout dropped");
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&entries["manifest.json"]).expect("manifest json");
+    assert_eq!(manifest["inputs"].as_array().map(Vec::len), Some(2));
+}
+
+#[test]
+fn test_threads_flag_limits_parallelism() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--threads", "2"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    assert!(!read_zip(output_zip.path()).is_empty());
+}
+
+#[test]
+fn test_quiet_json_logging() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+
+    let output = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--quiet", "--log-format", "json"])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .output()
+        .expect("run");
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).expect("utf8");
+    assert!(!stderr.is_empty());
+    for line in stderr.lines() {
+        let value: serde_json::Value = serde_json::from_str(line).expect("json log line");
+        assert!(value.get("msg").is_some());
+    }
+}
+
+#[test]
+fn test_max_memory_spills_with_same_output() {
+    // Records sharing a start date keep their order either way
+    for input in [SAMPLE_EXPORT, DUPLICATE_DATES_EXPORT] {
+        let default_output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg(input)
+            .arg(default_output.path())
+            .assert()
+            .success();
+
+        let bounded_output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(["--max-memory", "1K"])
+            .arg(input)
+            .arg(bounded_output.path())
+            .assert()
+            .success();
+
+        let mut default_map = read_zip(default_output.path());
+        let mut bounded_map = read_zip(bounded_output.path());
+        for entry in ["manifest.json", "SHA256SUMS"] {
+            default_map.remove(entry);
+            bounded_map.remove(entry);
+        }
+        assert_eq!(default_map, bounded_map);
+    }
+
+    // Every step count has a date, and those sharing one keep input order
+    let output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--max-memory", "1K"])
+        .arg(DUPLICATE_DATES_EXPORT)
+        .arg(output.path())
+        .assert()
+        .success();
+    let csv = read_zip(output.path())
+        .remove("HKQuantityTypeIdentifierStepCount.csv")
+        .expect("step counts");
+    let rows: Vec<(i64, u32)> = String::from_utf8(csv)
+        .expect("utf8")
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let cells: Vec<&str> = line.split(',').collect();
+            let date = gpt_os::apple_health::dates::parse_timestamp(cells[0]).expect("date");
+            (date, cells[3].parse().expect("value"))
+        })
+        .collect();
+    assert_eq!(rows.len(), 48);
+    let mut sorted = rows.clone();
+    sorted.sort();
+    assert_eq!(rows, sorted);
+
+    let bounded_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--max-memory", "12X"])
+        .arg(SAMPLE_EXPORT)
+        .arg(bounded_output.path())
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_tight_backpressure_produces_same_output() {
+    let default_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(default_output.path())
+        .assert()
+        .success();
+
+    let tight_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--in-flight-records", "1", "--in-flight-bytes", "1"])
+        .arg(SAMPLE_EXPORT)
+        .arg(tight_output.path())
+        .assert()
+        .success();
+
+    let mut default_map = read_zip(default_output.path());
+    let mut tight_map = read_zip(tight_output.path());
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        default_map.remove(entry);
+        tight_map.remove(entry);
+    }
+    assert_eq!(default_map, tight_map);
+}
+
+#[test]
+fn test_mmap_produces_same_output() {
+    let default_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(default_output.path())
+        .assert()
+        .success();
+
+    let mmap_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--mmap")
+        .arg(SAMPLE_EXPORT)
+        .arg(mmap_output.path())
+        .assert()
+        .success();
+
+    let mut default_map = read_zip(default_output.path());
+    let mut mmap_map = read_zip(mmap_output.path());
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        default_map.remove(entry);
+        mmap_map.remove(entry);
+    }
+    assert_eq!(default_map, mmap_map);
+}
+
+#[test]
+fn test_chunked_produces_same_output() {
+    let default_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(default_output.path())
+        .assert()
+        .success();
+
+    let chunked_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--chunked")
+        .arg(SAMPLE_EXPORT)
+        .arg(chunked_output.path())
+        .assert()
+        .success();
+
+    let mut default_map = read_zip(default_output.path());
+    let mut chunked_map = read_zip(chunked_output.path());
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        default_map.remove(entry);
+        chunked_map.remove(entry);
+    }
+    assert_eq!(default_map, chunked_map);
+}
+
+#[test]
+fn test_prescan_produces_same_output() {
+    let default_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(default_output.path())
+        .assert()
+        .success();
+
+    let prescan_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--prescan")
+        .arg(SAMPLE_EXPORT)
+        .arg(prescan_output.path())
+        .assert()
+        .success();
+
+    let mut default_map = read_zip(default_output.path());
+    let mut prescan_map = read_zip(prescan_output.path());
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        default_map.remove(entry);
+        prescan_map.remove(entry);
+    }
+    assert_eq!(default_map, prescan_map);
+}
+
 #[test]
 fn test_zipped_input_produces_same_output() {
     let xml_output = NamedTempFile::new().expect("temp file");
     Command::cargo_bin("gpt-os")
         .expect("binary")
         .arg(SAMPLE_EXPORT)
-        .arg(xml_output.path())
+        .arg(xml_output.path())
+        .assert()
+        .success();
+
+    let xml_data = fs::read(SAMPLE_EXPORT).expect("read xml");
+    let mut zip_input = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    {
+        let mut writer = ZipWriter::new(&mut zip_input);
+        writer
+            .start_file("export.xml", FileOptions::<()>::default())
+            .expect("start file");
+        writer.write_all(&xml_data).expect("write");
+        writer.finish().expect("finish");
+        zip_input
+            .as_file_mut()
+            .seek(std::io::SeekFrom::Start(0))
+            .unwrap();
+    }
+
+    let zip_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(zip_input.path())
+        .arg(zip_output.path())
+        .assert()
+        .success();
+
+    // The manifest records the input path and hash, which legitimately differ,
+    // and so does its checksum line.
+    let mut xml_map = read_zip(xml_output.path());
+    let mut zip_map = read_zip(zip_output.path());
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        xml_map.remove(entry);
+        zip_map.remove(entry);
+    }
+    assert_eq!(xml_map, zip_map);
+}
+
+#[test]
+fn test_zip64_and_truncated_inputs_produce_same_output() {
+    let xml_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SAMPLE_EXPORT)
+        .arg(xml_output.path())
+        .assert()
+        .success();
+    let mut expected = read_zip(xml_output.path());
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        expected.remove(entry);
+    }
+
+    // Zip64 size fields on a small entry exercise the same reader path as a
+    // >4 GB export
+    let mut zip64 = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    zip64
+        .start_file(
+            "apple_health_export/export.xml",
+            FileOptions::<()>::default().large_file(true),
+        )
+        .expect("start file");
+    zip64
+        .write_all(&fs::read(SAMPLE_EXPORT).expect("read xml"))
+        .expect("write");
+    let zip64 = zip64.finish().expect("finish").into_inner();
+    // Without its central directory the export is found from the local headers
+    let central_directory = zip64
+        .windows(4)
+        .position(|w| w == b"PK\x01\x02")
+        .expect("central directory");
+    let truncated = zip64[..central_directory].to_vec();
+
+    for input_bytes in [zip64, truncated] {
+        let input = tempfile::Builder::new()
+            .suffix(".zip")
+            .tempfile()
+            .expect("zip input");
+        fs::write(input.path(), input_bytes).expect("write zip");
+        let output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg(input.path())
+            .arg(output.path())
+            .assert()
+            .success();
+        let mut actual = read_zip(output.path());
+        for entry in ["manifest.json", "SHA256SUMS"] {
+            actual.remove(entry);
+        }
+        assert_eq!(actual, expected);
+    }
+}
+
+/// A ZIP archive holding `entries`, each a name and its bytes.
+fn zip_bytes(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, bytes) in entries {
+        writer
+            .start_file(*name, FileOptions::<()>::default())
+            .expect("start file");
+        writer.write_all(bytes).expect("write");
+    }
+    writer.finish().expect("finish").into_inner()
+}
+
+#[test]
+fn test_nested_zip_inputs() {
+    let xml_data = fs::read(SAMPLE_EXPORT).expect("read xml");
+    let export_zip = zip_bytes(&[("apple_health_export/export.xml", &xml_data)]);
+    // An export re-wrapped when shared, and two exports zipped together
+    let rewrapped = zip_bytes(&[("export.zip", &export_zip)]);
+    let combined = zip_bytes(&[
+        ("phone/export.zip", &export_zip),
+        ("watch/apple_health_export/export.xml", &xml_data),
+    ]);
+
+    let convert = |input_bytes: Vec<u8>| {
+        let input = tempfile::Builder::new()
+            .suffix(".zip")
+            .tempfile()
+            .expect("zip input");
+        fs::write(input.path(), input_bytes).expect("write zip");
+        let output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg(input.path())
+            .arg(output.path())
+            .assert()
+            .success();
+        read_zip(output.path())
+    };
+    let rows = |entries: &HashMap<String, Vec<u8>>, name: &str| {
+        String::from_utf8_lossy(&entries[name]).lines().count() - 1
+    };
+
+    let single = convert(export_zip.clone());
+    let mut nested = convert(rewrapped);
+    let mut expected = single.clone();
+    for entry in ["manifest.json", "SHA256SUMS"] {
+        expected.remove(entry);
+        nested.remove(entry);
+    }
+    assert_eq!(nested, expected);
+
+    // Records of both exports are kept
+    let both = convert(combined);
+    for name in ["HKQuantityTypeIdentifierStepCount.csv", "Workout.csv"] {
+        assert_eq!(rows(&both, name), 2 * rows(&single, name), "{}", name);
+    }
+
+    // Nested archives without an export still leave the entry missing
+    let empty = zip_bytes(&[("export.zip", &zip_bytes(&[("notes.txt", b"none")]))]);
+    let input = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    fs::write(input.path(), empty).expect("write zip");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(input.path())
+        .arg(NamedTempFile::new().expect("temp file").path())
+        .assert()
+        .code(3);
+}
+
+/// Serve `body` over HTTP at the returned base URL. Every response to a
+/// request for the whole file breaks off halfway, so the file can only be
+/// read to the end by resuming; the counters track requests for the whole
+/// file and resumed requests.
+fn flaky_server(
+    body: Vec<u8>,
+) -> (
+    String,
+    std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    std::sync::Arc<std::sync::atomic::AtomicUsize>,
+) {
+    use std::io::{BufRead, BufReader};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("http://{}", listener.local_addr().expect("addr"));
+    let started = std::sync::Arc::new(AtomicUsize::new(0));
+    let resumed = std::sync::Arc::new(AtomicUsize::new(0));
+    let (started_counter, counter) = (started.clone(), resumed.clone());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.expect("accept");
+            let mut reader = BufReader::new(stream.try_clone().expect("clone"));
+            let mut start = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).expect("header");
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':')
+                    && name.eq_ignore_ascii_case("range")
+                {
+                    let offset = value.trim().trim_start_matches("bytes=");
+                    start = Some(
+                        offset
+                            .trim_end_matches('-')
+                            .parse::<usize>()
+                            .expect("range"),
+                    );
+                }
+            }
+            let response = match start {
+                Some(start) => {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut response = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                         Content-Range: bytes {}-{}/{}\r\nETag: \"v1\"\r\n\
+                         Connection: close\r\n\r\n",
+                        body.len() - start,
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(&body[start..]);
+                    response
+                }
+                None => {
+                    started_counter.fetch_add(1, Ordering::SeqCst);
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\
+                         Connection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(&body[..body.len() / 2]);
+                    response
+                }
+            };
+            let _ = stream.write_all(&response);
+        }
+    });
+    (url, started, resumed)
+}
+
+#[test]
+fn test_url_input_is_downloaded_and_resumed() {
+    let xml_data = fs::read(SAMPLE_EXPORT).expect("read xml");
+    let export_zip = zip_bytes(&[("apple_health_export/export.xml", &xml_data)]);
+    let local = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    fs::write(local.path(), &export_zip).expect("write zip");
+    let local_output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(local.path())
+        .arg(local_output.path())
+        .assert()
+        .success();
+    let mut expected = read_zip(local_output.path());
+    for (name, body) in [("export.zip", export_zip), ("export.xml", xml_data)] {
+        let sha256 = gpt_os::manifest::sha256_reader(&body[..]).expect("hash");
+        let (base, started, resumed) = flaky_server(body);
+        let url = format!("{}/exports/{}?X-Amz-Signature=secret", base, name);
+        let output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg(&url)
+            .arg(output.path())
+            .assert()
+            .success();
+        assert!(resumed.load(std::sync::atomic::Ordering::SeqCst) > 0);
+        // The download is hashed as it is read rather than fetched again
+        assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let mut actual = read_zip(output.path());
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&actual["manifest.json"]).expect("manifest");
+        // The signature of a presigned URL is not written to the archive
+        assert_eq!(
+            manifest["inputs"][0]["path"],
+            format!("{}/exports/{}", base, name)
+        );
+        assert_eq!(manifest["inputs"][0]["sha256"], sha256);
+        for entry in ["manifest.json", "SHA256SUMS"] {
+            expected.remove(entry);
+            actual.remove(entry);
+        }
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn test_entities_are_unescaped_in_csv() {
+    let output = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(ENTITIES_EXPORT)
+        .arg(output.path())
+        .assert()
+        .success();
+
+    let files = read_zip(output.path());
+    let steps = &files["HKQuantityTypeIdentifierStepCount.csv"];
+    let mut reader = csv::Reader::from_reader(steps.as_slice());
+    let headers = reader.headers().expect("headers").clone();
+    let column = |name: &str| headers.iter().position(|h| h == name).expect(name);
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.expect("row")).collect();
+    assert_eq!(&rows[0][column("sourceName")], "Jane's iPhone");
+    assert!(rows[0][column("device")].starts_with("<<HKDevice: 0x283f5c0f0>, name:iPhone"));
+    assert_eq!(&rows[1][column("sourceName")], "Fitness & Health");
+
+    let mindful = &files["HKCategoryTypeIdentifierMindfulSession.csv"];
+    let mut reader = csv::Reader::from_reader(mindful.as_slice());
+    let headers = reader.headers().expect("headers").clone();
+    let source = headers
+        .iter()
+        .position(|h| h == "sourceName")
+        .expect("sourceName");
+    let row = reader.records().next().expect("row").expect("row");
+    assert_eq!(&row[source], "Notes\n\"Calm\"");
+}
+
+#[test]
+fn test_medication_and_clinical_records_get_own_files() {
+    for mode in [None, Some("--chunked")] {
+        let output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(mode)
+            .arg(CLINICAL_EXPORT)
+            .arg(output.path())
+            .assert()
+            .success();
+
+        let files = read_zip(output.path());
+        let medications = String::from_utf8(files["Medications.csv"].clone()).expect("utf8");
+        let lines: Vec<&str> = medications.lines().collect();
+        assert_eq!(
+            lines[0],
+            "startDate,endDate,creationDate,type,value,dose,frequency,sourceName,wasUserEntered"
+        );
+        assert!(lines[1].contains(",500 mg,") && lines[1].contains(",twice daily,"));
+        assert!(lines[2].contains(",250 mg,") && lines[2].ends_with(",1"));
+        assert!(files.contains_key("ClinicalMedications.csv"));
+        assert!(files.contains_key("ClinicalLabResults.csv"));
+        assert!(!files.contains_key("ClinicalRecord.csv"));
+        // Metadata of other records is left as it was
+        assert!(files.contains_key("MetadataEntry.csv"));
+
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(CLINICAL_EXPORT)
+            .arg(output.path())
+            .assert()
+            .success();
+    }
+}
+
+#[test]
+fn test_doctype_with_unpaired_brackets_is_skipped() {
+    let mut outputs = Vec::new();
+    for flags in [&[][..], &["--mmap"], &["--chunked"]] {
+        let output = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .args(flags)
+            .arg(DOCTYPE_EXPORT)
+            .arg(output.path())
+            .assert()
+            .success();
+        let mut files = read_zip(output.path());
+        for entry in ["manifest.json", "SHA256SUMS"] {
+            files.remove(entry);
+        }
+        outputs.push(files);
+    }
+
+    let steps = String::from_utf8(outputs[0]["HKQuantityTypeIdentifierStepCount.csv"].clone())
+        .expect("utf8");
+    assert_eq!(steps.lines().count(), 3);
+    assert!(outputs[0].contains_key("Workout.csv"));
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(outputs[0], outputs[2]);
+}
+
+#[test]
+fn test_trends_subcommand() {
+    // Ten days around 80 kg, then ten around 75 kg
+    let mut xml = String::from("<HealthData>\n");
+    for day in 1..=20 {
+        let kg = if day <= 10 { 80 } else { 75 };
+        xml.push_str(&format!(
+            "<Record type=\"HKQuantityTypeIdentifierBodyMass\" value=\"{}\" startDate=\"2023-01-{:02} 08:00:00 -0500\"/>\n",
+            kg, day
+        ));
+    }
+    xml.push_str("</HealthData>\n");
+    let input = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("input");
+    fs::write(input.path(), xml).expect("write input");
+
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["trends", "--metric", "BodyMass", "--window", "5d"])
+        .arg(input.path())
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines[0],
+        "date,value,rolling_mean,slope_per_day,change_point"
+    );
+    assert_eq!(lines[1], "2023-01-01,80,80,,false");
+    assert_eq!(lines[10], "2023-01-10,80,80,0,true");
+    assert_eq!(lines[12], "2023-01-12,75,78,-1.5,false");
+    let changes: Vec<&str> = lines
+        .iter()
+        .filter(|l| l.ends_with(",true"))
+        .copied()
+        .collect();
+    assert_eq!(changes, ["2023-01-10,80,80,0,true"]);
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["trends", "--metric", "RestingHeartRate"])
+        .arg(input.path())
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn test_pivot_subcommand() {
+    let xml = r#"<HealthData>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="1200" startDate="2023-01-01 08:10:00 -0500" endDate="2023-01-01 08:20:00 -0500"/>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="800" startDate="2023-01-01 17:00:00 -0500" endDate="2023-01-01 17:15:00 -0500"/>
+<Record type="HKQuantityTypeIdentifierStepCount" sourceName="Watch" value="500" startDate="2023-01-02 08:30:00 -0500" endDate="2023-01-02 08:40:00 -0500"/>
+<Record type="HKQuantityTypeIdentifierBodyMass" sourceName="Scale" value="80.25" startDate="2023-01-02 07:00:00 -0500" endDate="2023-01-02 07:00:00 -0500"/>
+<Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Watch" value="HKCategoryValueSleepAnalysisAsleepCore" startDate="2023-01-01 23:30:00 -0500" endDate="2023-01-02 01:00:00 -0500"/>
+</HealthData>
+"#;
+    let input = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("input");
+    fs::write(input.path(), xml).expect("write input");
+
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["pivot", "--metrics", "steps,weight,sleep_minutes"])
+        .arg(input.path())
+        .assert()
+        .success();
+    assert_eq!(
+        String::from_utf8_lossy(&assert.get_output().stdout),
+        "date,steps,weight,sleep_minutes\n2023-01-01,2000,,\n2023-01-02,500,80.25,90\n"
+    );
+
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args([
+            "pivot",
+            "--metrics",
+            "StepCount,sleep",
+            "--interval",
+            "hour",
+        ])
+        .arg(input.path())
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "hour,StepCount,sleep",
+            "2023-01-01 08:00,1200,",
+            "2023-01-01 17:00,800,",
+            "2023-01-01 23:00,,30",
+            "2023-01-02 00:00,,60",
+            "2023-01-02 08:00,500,",
+        ]
+    );
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["pivot", "--metrics", "resting_hr"])
+        .arg(input.path())
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn test_select_subcommand() {
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("select")
+        .arg(SAMPLE_EXPORT)
+        .arg(".type, .startDate, .value")
+        .args(["--where", r#".type=="HKQuantityTypeIdentifierBodyMass""#])
+        .assert()
+        .success();
+    assert_eq!(
+        String::from_utf8_lossy(&assert.get_output().stdout),
+        "[\"HKQuantityTypeIdentifierBodyMass\",\"2023-01-01T08:00:00Z\",\"70.5\"]\n"
+    );
+
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["select", "--raw-output"])
+        .arg(SAMPLE_EXPORT)
+        .arg(".type")
+        .args(["--where", ".value > 1000"])
+        .assert()
+        .success();
+    assert_eq!(
+        String::from_utf8_lossy(&assert.get_output().stdout),
+        "HKQuantityTypeIdentifierStepCount\n"
+    );
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("select")
+        .arg(SAMPLE_EXPORT)
+        .arg(".type")
+        .args(["--where", ".value >"])
+        .assert()
+        .code(2);
+}
+
+/// Accept one HTTP request on a local port, answer it with `204` and hand
+/// back its request line and body.
+fn webhook() -> (String, std::thread::JoinHandle<(String, String)>) {
+    use std::io::{BufRead, BufReader};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("http://{}/hook", listener.local_addr().expect("addr"));
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("accept");
+        let mut reader = BufReader::new(stream.try_clone().expect("clone"));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).expect("request line");
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).expect("header");
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse().expect("length");
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).expect("body");
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+            .expect("respond");
+        (
+            request_line.trim().to_string(),
+            String::from_utf8(body).expect("utf8"),
+        )
+    });
+    (url, handle)
+}
+
+#[test]
+fn test_notify_url() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    let (url, server) = webhook();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--notify-url", &url])
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
         .assert()
         .success();
+    let (request_line, body) = server.join().expect("server");
+    assert_eq!(request_line, "POST /hook HTTP/1.1");
+    let notification: serde_json::Value = serde_json::from_str(&body).expect("json");
+    assert_eq!(notification["status"], "succeeded");
+    assert_eq!(notification["records"], 6);
+    assert_eq!(notification["record_counts"]["Workout"], 1);
+    assert_eq!(
+        notification["output"],
+        output_zip.path().display().to_string()
+    );
+    let archive = fs::read(output_zip.path()).expect("read output");
+    assert_eq!(notification["output_bytes"], archive.len());
+    assert_eq!(
+        notification["output_sha256"].as_str().expect("hash").len(),
+        64
+    );
+    assert!(notification["timings"]["total"].is_number());
+    assert!(notification["error"].is_null());
 
-    let xml_data = fs::read(SAMPLE_EXPORT).expect("read xml");
-    let mut zip_input = tempfile::Builder::new()
-        .suffix(".zip")
+    let (url, server) = webhook();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--notify-url", &url])
+        .arg("tests/fixtures/missing_export.xml")
+        .arg(output_zip.path())
+        .assert()
+        .code(3);
+    let (_, body) = server.join().expect("server");
+    let notification: serde_json::Value = serde_json::from_str(&body).expect("json");
+    assert_eq!(notification["status"], "failed");
+    assert!(notification["error"].is_string());
+    assert!(notification["output_sha256"].is_null());
+}
+
+#[test]
+fn test_recover_keeps_records_before_truncation() {
+    let xml = fs::read_to_string(SAMPLE_EXPORT).expect("read xml");
+    let cut = xml.find("<Workout").expect("workout") + 20;
+    let input = tempfile::Builder::new()
+        .suffix(".xml")
         .tempfile()
-        .expect("zip input");
-    {
-        let mut writer = ZipWriter::new(&mut zip_input);
-        writer
-            .start_file("export.xml", FileOptions::<()>::default())
-            .expect("start file");
-        writer.write_all(&xml_data).expect("write");
-        writer.finish().expect("finish");
-        zip_input
-            .as_file_mut()
-            .seek(std::io::SeekFrom::Start(0))
-            .unwrap();
-    }
+        .expect("truncated input");
+    fs::write(input.path(), &xml[..cut]).expect("write truncated input");
 
-    let zip_output = NamedTempFile::new().expect("temp file");
+    let output = NamedTempFile::new().expect("temp file");
     Command::cargo_bin("gpt-os")
         .expect("binary")
-        .arg(zip_input.path())
-        .arg(zip_output.path())
+        .arg(input.path())
+        .arg(output.path())
+        .assert()
+        .code(4);
+
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--recover")
+        .arg(input.path())
+        .arg(output.path())
         .assert()
         .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    assert!(
+        stdout.contains("data ends around 2023-01-01T08:00:00Z"),
+        "{stdout}"
+    );
 
-    let xml_map = read_zip(xml_output.path());
-    let zip_map = read_zip(zip_output.path());
-    assert_eq!(xml_map, zip_map);
+    let files = read_zip(output.path());
+    assert!(files.contains_key("HKQuantityTypeIdentifierBodyMass.csv"));
+    assert!(files.contains_key("HKQuantityTypeIdentifierStepCount.csv"));
+    assert!(!files.contains_key("Workout.csv"));
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&files["manifest.json"]).expect("manifest json");
+    assert!(manifest["truncated"]["offset"].as_u64().expect("offset") >= cut as u64 - 20);
 }
 
 fn read_zip(path: &Path) -> HashMap<String, Vec<u8>> {
@@ -79,3 +1935,707 @@ fn read_zip(path: &Path) -> HashMap<String, Vec<u8>> {
     map
 }
 // Additional tests can be added here to cover more scenarios
+
+#[test]
+fn test_last_period() {
+    // Only the record dated after the window's start is converted
+    let xml = r#"<HealthData locale="en_US">
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="70.5" startDate="2001-01-01T08:00:00Z" endDate="2001-01-01T08:00:00Z"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" value="71.5" startDate="2999-01-01T08:00:00Z" endDate="2999-01-01T08:00:00Z"/>
+</HealthData>"#;
+    let mut export = tempfile::Builder::new()
+        .suffix(".xml")
+        .tempfile()
+        .expect("temp file");
+    export.write_all(xml.as_bytes()).expect("write");
+    let dir = tempfile::tempdir().expect("temp dir");
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--last", "4w"])
+        .arg(export.path())
+        .arg(dir.path().join("health.zip"))
+        .assert()
+        .success();
+
+    // The archive is named health_<since>_<today>.zip
+    let names: Vec<String> = fs::read_dir(dir.path())
+        .expect("read dir")
+        .map(|entry| {
+            entry
+                .expect("entry")
+                .file_name()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    assert_eq!(names.len(), 1, "{:?}", names);
+    let name = &names[0];
+    assert!(
+        name.starts_with("health_") && name.ends_with(".zip"),
+        "{}",
+        name
+    );
+    assert_eq!(
+        name.len(),
+        "health_2024-01-01_2024-01-29.zip".len(),
+        "{}",
+        name
+    );
+
+    let entries = read_zip(&dir.path().join(name));
+    let csv =
+        String::from_utf8(entries["HKQuantityTypeIdentifierBodyMass.csv"].clone()).expect("utf8");
+    assert_eq!(csv.lines().count(), 2, "{}", csv);
+    assert!(csv.contains("71.5") && !csv.contains("70.5"), "{}", csv);
+    let manifest = String::from_utf8(entries["manifest.json"].clone()).expect("utf8");
+    assert!(manifest.contains("since="), "{}", manifest);
+}
+
+#[test]
+fn test_trace_file() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    let trace = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--trace-file")
+        .arg(trace.path())
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let trace: serde_json::Value =
+        serde_json::from_slice(&fs::read(trace.path()).expect("read")).expect("json");
+    let spans: Vec<&str> = trace
+        .as_array()
+        .expect("events")
+        .iter()
+        .filter_map(|event| event["name"].as_str())
+        .collect();
+    for span in [
+        "extract",
+        "parse",
+        "transform",
+        "load",
+        "serialize",
+        "write_archive",
+    ] {
+        assert!(spans.contains(&span), "{} missing from {:?}", span, spans);
+    }
+}
+
+#[test]
+fn test_profile() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    let profile = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--profile")
+        .arg(profile.path())
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let profile: serde_json::Value =
+        serde_json::from_slice(&fs::read(profile.path()).expect("read")).expect("json");
+    for phase in ["extract", "transform", "load", "total"] {
+        assert!(profile["phases"][phase].is_f64(), "{} missing", phase);
+    }
+    assert!(profile["spans"]["parse"]["count"].as_u64() >= Some(1));
+    let workouts = &profile["groups"]["Workout"];
+    assert!(workouts["records"].as_u64() > Some(0));
+    assert!(workouts["serialize_seconds"].is_f64());
+    assert!(profile["channel_waits"].is_object());
+    if cfg!(target_os = "linux") {
+        assert!(profile["peak_memory_bytes"].as_u64() > Some(0));
+    }
+}
+
+#[test]
+fn test_exit_codes() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("tests/fixtures/missing_export.xml")
+        .arg(output_zip.path())
+        .assert()
+        .code(3);
+
+    let mut not_xml = NamedTempFile::new().expect("temp file");
+    write!(not_xml, "<HealthData><Record type=").expect("write");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(not_xml.path())
+        .arg(output_zip.path())
+        .assert()
+        .code(4);
+
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--help")
+        .assert()
+        .success();
+    let help = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    assert!(help.contains("Exit codes:"), "{}", help);
+    assert!(help.contains("130  Cancelled"), "{}", help);
+}
+
+#[test]
+fn test_log_file() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    let log_file = NamedTempFile::new().expect("temp file");
+    fs::write(log_file.path(), "earlier run\n").expect("write");
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("--log-file")
+        .arg(log_file.path())
+        .arg(SAMPLE_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    // The file keeps debug lines the console leaves out, after earlier runs
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).into_owned();
+    let log = fs::read_to_string(log_file.path()).expect("log");
+    assert!(log.starts_with("earlier run\n"));
+    assert!(log.contains("Starting Apple Health Transformer"), "{}", log);
+    assert!(log.contains(" DEBUG "), "{}", log);
+    assert!(!stderr.contains(" DEBUG "), "{}", stderr);
+}
+
+#[test]
+fn test_bench() {
+    let assert = Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["bench", "--runs", "2", SAMPLE_EXPORT])
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    let phases: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    assert_eq!(
+        phases,
+        ["phase", "extract", "transform", "full"],
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_health_auto_export_input() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(AUTO_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(AUTO_EXPORT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv = |name: &str| String::from_utf8(entries[name].clone()).expect("utf8");
+    let steps = csv("HKQuantityTypeIdentifierStepCount.csv");
+    assert_eq!(steps.lines().count(), 3);
+    assert!(steps.starts_with("startDate,endDate,type,value,unit,sourceName"));
+    assert!(steps.contains(
+        "2024-01-15 00:00:00 -0800,2024-01-15 00:00:00 -0800,\
+         HKQuantityTypeIdentifierStepCount,8421,count,iPhone"
+    ));
+    let heart_rate = csv("HKQuantityTypeIdentifierHeartRate.csv");
+    assert!(heart_rate.contains(",71.4,count/min,"));
+    let sleep = csv("HKCategoryTypeIdentifierSleepAnalysis.csv");
+    assert!(sleep.contains("2024-01-14 23:10:00 -0800,2024-01-15 06:40:00 -0800"));
+
+    let workouts = csv("Workout.csv");
+    let mut rows = workouts.lines();
+    let headers: Vec<&str> = rows.next().unwrap().split(',').collect();
+    let row: Vec<&str> = rows.next().unwrap().split(',').collect();
+    let cell = |name: &str| row[headers.iter().position(|h| *h == name).expect(name)];
+    assert_eq!(cell("workoutActivityType"), "Outdoor Run");
+    assert_eq!(cell("duration"), "30");
+    assert_eq!(cell("durationUnit"), "min");
+    assert_eq!(cell("distance"), "5.2");
+    assert_eq!(cell("distanceUnit"), "km");
+    assert!(!headers.contains(&"id"));
+    assert!(!headers.contains(&"heartRateData"));
+}
+
+#[test]
+fn test_fit_folder_input() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(FIT_FOLDER)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(FIT_FOLDER)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv = |name: &str| String::from_utf8(entries[name].clone()).expect("utf8");
+    let samples = csv("FitRecord.csv");
+    assert_eq!(
+        samples.lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,distance,fitFile,heartRate,latitude,longitude",
+            "2024-01-15T15:00:00Z,2024-01-15T15:00:00Z,0,2024-01-15-070000-Running.fit,128,47.6062,-122.3321",
+            // An invalid heart rate is left empty
+            "2024-01-15T15:01:00Z,2024-01-15T15:01:00Z,250.5,2024-01-15-070000-Running.fit,,47.607,-122.333",
+            // A compressed timestamp header counts from the last timestamp
+            "2024-01-15T15:01:05Z,2024-01-15T15:01:05Z,310,2024-01-15-070000-Running.fit,141,,",
+        ]
+    );
+    assert!(csv("FitLap.csv").contains(
+        "2024-01-15T15:00:00Z,2024-01-15T15:30:00Z,135,2024-01-15-070000-Running.fit,running,5200,1800"
+    ));
+    let sessions = csv("FitSession.csv");
+    assert!(sessions.starts_with("startDate,endDate,avgHeartRate,fitFile,numLaps,sport"));
+    assert_eq!(sessions.lines().count(), 2);
+}
+
+#[test]
+fn test_runkeeper_export_input() {
+    let activities = "Activity Id,Date,Type,Route Name,Distance (km),Duration,Average Pace,\
+                      Average Speed (km/h),Calories Burned,Climb (m),Average Heart Rate (bpm),\
+                      Friend's Tagged,Notes,GPX File\n\
+                      a1b2,2016-05-04 07:12:34,Running,,5.02,28:30,5:41,10.57,402,35,151,,\
+                      Easy run,2016-05-04-071234.gpx\n\
+                      c3d4,2016-06-11 18:00:00,Cross-Country Skiing,,12.5,1:05:00,5:12,11.54,\
+                      780,120,,,,\n";
+    let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="Runkeeper" xmlns="http://www.topografix.com/GPX/1/1">
+<trk><name>Running 5/4/16 7:12 am</name><trkseg>
+<trkpt lat="47.606200000" lon="-122.332100000"><ele>56.0</ele><time>2016-05-04T14:12:34Z</time></trkpt>
+<trkpt lat="47.607000000" lon="-122.333000000"><ele>57.5</ele><time>2016-05-04T14:12:40Z</time></trkpt>
+</trkseg></trk></gpx>"#;
+    let export = zip_bytes(&[
+        ("cardioActivities.csv", activities.as_bytes()),
+        ("2016-05-04-071234.gpx", gpx.as_bytes()),
+    ]);
+    let input = tempfile::Builder::new()
+        .prefix("runkeeper-data-export")
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    fs::write(input.path(), export).expect("write zip");
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv = |name: &str| String::from_utf8(entries[name].clone()).expect("utf8");
+    let workouts = csv("Workout.csv");
+    let mut lines = workouts.lines();
+    assert_eq!(
+        lines.next(),
+        Some(
+            "startDate,endDate,activityId,averageHeartRate,averageHeartRateUnit,averagePace,\
+             averageSpeed,averageSpeedUnit,duration,durationUnit,elevationAscended,\
+             elevationAscendedUnit,gpxFile,notes,sourceName,totalDistance,totalDistanceUnit,\
+             totalEnergyBurned,workoutActivityType"
+        )
+    );
+    assert_eq!(
+        lines.next(),
+        Some(
+            "2016-05-04 07:12:34,2016-05-04 07:41:04,a1b2,151,bpm,5:41,10.57,km/h,28.5,min,35,m,\
+             2016-05-04-071234.gpx,Easy run,RunKeeper,5.02,km,402,HKWorkoutActivityTypeRunning"
+        )
+    );
+    assert!(
+        lines
+            .next()
+            .expect("second workout")
+            .ends_with("HKWorkoutActivityTypeCrossCountrySkiing")
+    );
+    assert_eq!(
+        csv("RunKeeperRoutePoint.csv").lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,elevation,gpxFile,latitude,longitude",
+            "2016-05-04T14:12:34Z,2016-05-04T14:12:34Z,56.0,2016-05-04-071234.gpx,47.606200000,-122.332100000",
+            "2016-05-04T14:12:40Z,2016-05-04T14:12:40Z,57.5,2016-05-04-071234.gpx,47.607000000,-122.333000000",
+        ]
+    );
+}
+
+#[test]
+fn test_suunto_workout_input() {
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(SUUNTO_WORKOUT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(SUUNTO_WORKOUT)
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv = |name: &str| String::from_utf8(entries[name].clone()).expect("utf8");
+    assert_eq!(
+        csv("Workout.csv").lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,averageHeartRate,averageHeartRateUnit,device,duration,durationUnit,\
+             elevationAscended,elevationAscendedUnit,elevationDescended,elevationDescendedUnit,\
+             maxHeartRate,maxHeartRateUnit,sourceName,stepCount,stepCountUnit,suuntoFile,\
+             totalDistance,totalDistanceUnit,totalEnergyBurned,totalEnergyBurnedUnit,\
+             workoutActivityType",
+            "2021-03-14T08:30:00Z,2021-03-14T09:00:01Z,150,count/min,Suunto 9 Baro,30.0083333,min,\
+             35,m,33,m,177,count/min,Suunto,5234,count,suunto_workout.json,5012,m,400.0956023,\
+             kcal,HKWorkoutActivityTypeRunning",
+        ]
+    );
+    // The lap event holds no sample fields and is left out
+    assert_eq!(
+        csv("SuuntoSample.csv").lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,altitude,distance,heartRate,latitude,longitude,speed,suuntoFile,\
+             temperature",
+            "2021-03-14T08:30:01Z,2021-03-14T08:30:01Z,120.5,2.8,123,,,2.8,suunto_workout.json,15",
+            "2021-03-14T08:30:02Z,2021-03-14T08:30:02Z,,,,47.6013336,7.494288,,suunto_workout.json,",
+        ]
+    );
+}
+
+#[test]
+fn test_wahoo_export_input() {
+    let fit = fs::read(format!("{}/2024-01-15-070000-Running.fit", FIT_FOLDER)).expect("fit");
+    // The Wahoo app writes a summary above the samples
+    let csv = "Workout Name,Morning Ride\n\
+               Device,ELEMNT BOLT\n\
+               \n\
+               timestamp,hr_heartrate,bike_cad_cadence,bike_spd_speed,bike_pwr_power,gps_lat,gps_lon\n\
+               1705330800,131,88,8.1,210,47.6062,-122.3321\n\
+               1705330801,,89,8.2,215,,\n\
+               1705330802,,,,,,\n";
+    let export = zip_bytes(&[
+        ("2024-01-15-070000-ELEMNT BOLT-0.fit", &fit),
+        ("2024-01-15-070000-ELEMNT BOLT-0.csv", csv.as_bytes()),
+    ]);
+    let input = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile()
+        .expect("zip input");
+    fs::write(input.path(), export).expect("write zip");
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let csv = |name: &str| String::from_utf8(entries[name].clone()).expect("utf8");
+    assert_eq!(
+        csv("WahooSample.csv").lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,cadence,csvFile,heartRate,latitude,longitude,power,sourceName,speed",
+            "2024-01-15T15:00:00Z,2024-01-15T15:00:00Z,88,2024-01-15-070000-ELEMNT BOLT-0.csv,131,\
+             47.6062,-122.3321,210,Wahoo,8.1",
+            "2024-01-15T15:00:01Z,2024-01-15T15:00:01Z,89,2024-01-15-070000-ELEMNT BOLT-0.csv,,,,\
+             215,Wahoo,8.2",
+        ]
+    );
+    // The FIT file is decoded as from a HealthFit folder
+    let sessions = csv("FitSession.csv");
+    assert!(
+        sessions.starts_with("startDate,endDate,avgHeartRate,fitFile,numLaps,sourceName,sport")
+    );
+    assert!(sessions.contains("ELEMNT BOLT-0.fit,1,Wahoo,running"));
+    assert_eq!(csv("FitRecord.csv").lines().count(), 4);
+}
+
+#[test]
+fn test_sleep_cycle_export_input() {
+    let export = "Start;End;Sleep Quality;Heart rate (bpm);Time in bed (seconds);\
+                  Time asleep (seconds);Time before sleep (seconds);Did snore;Snore time;Notes\n\
+                  2019-01-01 23:30:12;2019-01-02 07:05:44;82%;58;27332.0;24120.0;900.0;true;312;\
+                  Coffee\n\
+                  2019-01-02 23:58:00;2019-01-03 06:30:10;64%;;23530.0;;;false;0;\n";
+    let input = tempfile::Builder::new()
+        .suffix(".csv")
+        .tempfile()
+        .expect("csv input");
+    fs::write(input.path(), export).expect("write csv");
+
+    let output_zip = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg(input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("validate")
+        .arg(input.path())
+        .arg(output_zip.path())
+        .assert()
+        .success();
+
+    let entries = read_zip(output_zip.path());
+    let sleep = String::from_utf8(entries["HKCategoryTypeIdentifierSleepAnalysis.csv"].clone())
+        .expect("utf8");
+    assert_eq!(
+        sleep.lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,type,value,didSnore,heartRate,heartRateUnit,notes,sleepQuality,\
+             sleepQualityUnit,snoreTime,sourceName,timeAsleep,timeAsleepUnit,timeBeforeSleep,\
+             timeBeforeSleepUnit,timeInBed,timeInBedUnit",
+            "2019-01-01 23:30:12,2019-01-02 07:05:44,HKCategoryTypeIdentifierSleepAnalysis,\
+             HKCategoryValueSleepAnalysisInBed,true,58,bpm,Coffee,82,%,312,Sleep Cycle,24120.0,\
+             seconds,900.0,seconds,27332.0,seconds",
+            // The time asleep as one stretch after the time before sleep
+            "2019-01-01 23:45:12,2019-01-02 06:27:12,HKCategoryTypeIdentifierSleepAnalysis,\
+             HKCategoryValueSleepAnalysisAsleepUnspecified,,,,,,,,Sleep Cycle,,,,,,",
+            "2019-01-02 23:58:00,2019-01-03 06:30:10,HKCategoryTypeIdentifierSleepAnalysis,\
+             HKCategoryValueSleepAnalysisInBed,false,,,,64,%,0,Sleep Cycle,,,,,23530.0,seconds",
+        ]
+    );
+}
+
+#[test]
+fn test_ndjson_entries() {
+    let mut archives = Vec::new();
+    for extra in [&[][..], &["--max-memory", "1K"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("--ndjson")
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        archives.push(output_zip);
+    }
+
+    let entries = read_zip(archives[0].path());
+    assert!(!entries.contains_key("Workout.csv"));
+    let workouts = String::from_utf8(entries["Workout.ndjson"].clone()).expect("utf8");
+    let lines: Vec<serde_json::Value> = workouts
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("json line"))
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(
+        lines[0]["workoutActivityType"],
+        "HKWorkoutTypeIdentifierWalking"
+    );
+    assert_eq!(lines[0]["duration"], "1800");
+    // Spilled groups are written the same way
+    assert_eq!(
+        entries["Workout.ndjson"],
+        read_zip(archives[1].path())["Workout.ndjson"]
+    );
+
+    let merged = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("merge")
+        .arg(archives[0].path())
+        .arg(archives[1].path())
+        .arg("-o")
+        .arg(merged.path())
+        .assert()
+        .success();
+    let csv = String::from_utf8(read_zip(merged.path())["Workout.csv"].clone()).expect("utf8");
+    assert_eq!(csv.lines().count(), 2);
+}
+
+#[test]
+fn test_cbor_entries() {
+    let mut archives = Vec::new();
+    for extra in [&[][..], &["--max-memory", "1K"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("--cbor")
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        archives.push(output_zip);
+    }
+
+    let entries = read_zip(archives[0].path());
+    assert!(!entries.contains_key("Workout.csv"));
+    let workouts = &entries["Workout.cbor"];
+    // A single map (major type 5) of text strings (major type 3)
+    assert_eq!(workouts[0] >> 5, 5);
+    let activity = b"HKWorkoutTypeIdentifierWalking";
+    let mut item = vec![0x78, activity.len() as u8];
+    item.extend_from_slice(activity);
+    assert!(workouts.windows(item.len()).any(|w| w == item));
+    // Spilled groups are written the same way
+    assert_eq!(
+        entries["Workout.cbor"],
+        read_zip(archives[1].path())["Workout.cbor"]
+    );
+
+    let merged = NamedTempFile::new().expect("temp file");
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .arg("merge")
+        .arg(archives[0].path())
+        .arg(archives[1].path())
+        .arg("-o")
+        .arg(merged.path())
+        .assert()
+        .success();
+    let csv = String::from_utf8(read_zip(merged.path())["Workout.csv"].clone()).expect("utf8");
+    assert_eq!(csv.lines().count(), 2);
+    assert!(csv.contains("HKWorkoutTypeIdentifierWalking"));
+    assert!(csv.contains("1800"));
+}
+
+#[test]
+fn test_protobuf_entries() {
+    let mut archives = Vec::new();
+    for extra in [&[][..], &["--max-memory", "1K"][..]] {
+        let output_zip = NamedTempFile::new().expect("temp file");
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("--protobuf")
+            .args(extra)
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        Command::cargo_bin("gpt-os")
+            .expect("binary")
+            .arg("validate")
+            .arg(SAMPLE_EXPORT)
+            .arg(output_zip.path())
+            .assert()
+            .success();
+        archives.push(read_zip(output_zip.path()));
+    }
+
+    let entries = &archives[0];
+    assert!(!entries.contains_key("Workout.csv"));
+    let proto = String::from_utf8(entries["records.proto"].clone()).expect("utf8");
+    assert!(proto.contains("syntax = \"proto3\";"));
+    let message = proto
+        .split("message Workout {")
+        .nth(1)
+        .and_then(|rest| rest.split('}').next())
+        .expect("Workout message");
+    let number = |field: &str| -> u64 {
+        let line = message
+            .lines()
+            .find(|line| {
+                line.trim_start()
+                    .starts_with(&format!("string {} =", field))
+            })
+            .expect("field");
+        line.split(['=', ';'])
+            .nth(1)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    };
+
+    // One length-delimited message of string fields
+    let data = &entries["Workout.pb"];
+    let varint = |pos: &mut usize| {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = data[*pos];
+            *pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    };
+    let mut pos = 0;
+    let length = varint(&mut pos) as usize;
+    assert_eq!(pos + length, data.len());
+    let mut fields = std::collections::BTreeMap::new();
+    while pos < data.len() {
+        let tag = varint(&mut pos);
+        assert_eq!(tag & 7, 2);
+        let len = varint(&mut pos) as usize;
+        fields.insert(
+            tag >> 3,
+            String::from_utf8(data[pos..pos + len].to_vec()).unwrap(),
+        );
+        pos += len;
+    }
+    assert_eq!(fields[&number("duration")], "1800");
+    assert_eq!(
+        fields[&number("workoutActivityType")],
+        "HKWorkoutTypeIdentifierWalking"
+    );
+    // Spilled groups are written the same way
+    assert_eq!(entries["Workout.pb"], archives[1]["Workout.pb"]);
+    assert_eq!(entries["records.proto"], archives[1]["records.proto"]);
+
+    Command::cargo_bin("gpt-os")
+        .expect("binary")
+        .args(["--protobuf", "--ndjson", SAMPLE_EXPORT])
+        .assert()
+        .code(2);
+}
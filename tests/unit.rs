@@ -1,15 +1,33 @@
 use ahash::AHashMap;
+use gpt_os::apple_health::extractor::AppleHealthExtractor;
 use gpt_os::apple_health::types::GenericRecord;
-use gpt_os::core::{Processable, Sink};
-use gpt_os::sinks::csv_zip::CsvZipSink;
+use gpt_os::core::{Engine, Extractor, LoadContext, Processable, Sink, SinkTo};
+use gpt_os::error::SkipSummary;
+use gpt_os::manifest::Manifest;
+use gpt_os::progress::Progress;
+use gpt_os::sinks::csv_zip::{Compression, CompressionMethod, CsvZipSink};
+use gpt_os::{ConversionBuilder, Format, ProgressEvent, SinkRegistry};
 use quick_xml::Reader;
 use quick_xml::events::Event;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Cursor, Read};
+use std::sync::Arc;
 use tempfile::NamedTempFile;
+use tokio_stream::StreamExt;
 use tokio_test::block_on;
 use zip::ZipArchive;
 
+fn load_context() -> LoadContext<'static> {
+    static MANIFEST: std::sync::OnceLock<Manifest> = std::sync::OnceLock::new();
+    static PROGRESS: std::sync::OnceLock<Progress> = std::sync::OnceLock::new();
+    static SKIPPED: std::sync::OnceLock<SkipSummary> = std::sync::OnceLock::new();
+    LoadContext {
+        manifest: MANIFEST.get_or_init(Manifest::default),
+        progress: PROGRESS.get_or_init(Progress::default),
+        skipped: SKIPPED.get_or_init(SkipSummary::default),
+    }
+}
+
 #[test]
 fn record_from_xml_optional_fields() {
     let xml = r#"<Record type="Heart" value="60" creationDate="2020" startDate="2020" endDate="2020" sourceName="watch"/>"#;
@@ -20,8 +38,8 @@ fn record_from_xml_optional_fields() {
         Event::Empty(e) => {
             let rec = GenericRecord::from_xml(&e).unwrap();
             assert_eq!(rec.element_name, "Record");
-            assert_eq!(rec.attributes.get("type").unwrap(), "Heart");
-            assert_eq!(rec.attributes.get("value").unwrap(), "60");
+            assert_eq!(rec.field("type"), Some("Heart"));
+            assert_eq!(rec.field("value"), Some("60"));
             assert_eq!(rec.attributes.get("unit"), None);
             assert_eq!(rec.attributes.get("sourceVersion"), None);
             assert_eq!(rec.attributes.get("device"), None);
@@ -40,13 +58,10 @@ fn workout_from_xml_numeric_fields() {
         Event::Empty(e) => {
             let workout = GenericRecord::from_xml(&e).unwrap();
             assert_eq!(workout.element_name, "Workout");
-            assert_eq!(
-                workout.attributes.get("workoutActivityType").unwrap(),
-                "Run"
-            );
-            assert_eq!(workout.attributes.get("duration").unwrap(), "42.5");
-            assert_eq!(workout.attributes.get("totalDistance").unwrap(), "5.2");
-            assert_eq!(workout.attributes.get("totalEnergyBurned").unwrap(), "300");
+            assert_eq!(workout.field("workoutActivityType"), Some("Run"));
+            assert_eq!(workout.field("duration"), Some("42.5"));
+            assert_eq!(workout.field("totalDistance"), Some("5.2"));
+            assert_eq!(workout.field("totalEnergyBurned"), Some("300"));
             assert_eq!(workout.attributes.get("device"), None);
         }
         _ => panic!("Expected empty Workout event"),
@@ -63,17 +78,11 @@ fn activity_summary_from_xml_numeric_fields() {
         Event::Empty(e) => {
             let summary = GenericRecord::from_xml(&e).unwrap();
             assert_eq!(summary.element_name, "ActivitySummary");
-            assert_eq!(
-                summary.attributes.get("dateComponents").unwrap(),
-                "2023-01-01"
-            );
-            assert_eq!(summary.attributes.get("activeEnergyBurned").unwrap(), "300");
-            assert_eq!(
-                summary.attributes.get("activeEnergyBurnedGoal").unwrap(),
-                "500"
-            );
-            assert_eq!(summary.attributes.get("appleExerciseTime").unwrap(), "30");
-            assert_eq!(summary.attributes.get("appleStandHours").unwrap(), "12");
+            assert_eq!(summary.field("dateComponents"), Some("2023-01-01"));
+            assert_eq!(summary.field("activeEnergyBurned"), Some("300"));
+            assert_eq!(summary.field("activeEnergyBurnedGoal"), Some("500"));
+            assert_eq!(summary.field("appleExerciseTime"), Some("30"));
+            assert_eq!(summary.field("appleStandHours"), Some("12"));
         }
         _ => panic!("Expected empty ActivitySummary event"),
     }
@@ -89,13 +98,100 @@ fn generic_record_from_xml() {
         Event::Empty(e) => {
             let g = GenericRecord::from_xml(&e).unwrap();
             assert_eq!(g.element_name, "Correlation");
-            assert_eq!(g.attributes.get("type").unwrap(), "Blood");
-            assert_eq!(g.attributes.get("startDate").unwrap(), "2020");
+            assert_eq!(g.field("type"), Some("Blood"));
+            assert_eq!(g.field("startDate"), Some("2020"));
         }
         _ => panic!("Expected empty event"),
     }
 }
 
+#[test]
+fn generic_record_unescapes_attribute_values() {
+    let xml = r#"<Record type="Steps" sourceName="Jane&apos;s &amp; Co" device="&lt;&lt;HKDevice: 0x1&gt;, name:iPhone&gt;" note="a&#xA;b&#x22;"/>"#;
+    let mut reader = Reader::from_str(xml);
+    match reader.read_event().unwrap() {
+        Event::Empty(e) => {
+            let rec = GenericRecord::from_xml(&e).unwrap();
+            assert_eq!(rec.field("sourceName"), Some("Jane's & Co"));
+            assert_eq!(rec.field("device"), Some("<<HKDevice: 0x1>, name:iPhone>"));
+            assert_eq!(rec.field("note"), Some("a\nb\""));
+        }
+        _ => panic!("Expected empty Record event"),
+    }
+
+    let mut reader = Reader::from_str(r#"<Record type="Steps" sourceName="&bogus;"/>"#);
+    match reader.read_event().unwrap() {
+        Event::Empty(e) => assert!(matches!(
+            GenericRecord::from_xml(&e),
+            Err(gpt_os::error::AppError::InvalidElement(_))
+        )),
+        _ => panic!("Expected empty Record event"),
+    }
+}
+
+#[test]
+fn generic_record_serializes_as_flat_map() {
+    let mut attributes = AHashMap::new();
+    attributes.insert("type".into(), "HKQuantityTypeIdentifierBodyMass".into());
+    attributes.insert("value".into(), "72".into());
+    let record = GenericRecord {
+        element_name: "Record".to_string(),
+        attributes,
+    };
+
+    let json = serde_json::to_value(&record).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "element": "Record",
+            "type": "HKQuantityTypeIdentifierBodyMass",
+            "value": "72"
+        })
+    );
+    let back: GenericRecord = serde_json::from_value(json).unwrap();
+    assert_eq!(back, record);
+}
+
+#[test]
+fn generic_record_attribute_keys_are_interned() {
+    let parse = |xml: &str| {
+        let mut reader = Reader::from_str(xml);
+        match reader.read_event().unwrap() {
+            Event::Empty(e) => GenericRecord::from_xml(&e).unwrap(),
+            other => panic!("unexpected event {:?}", other),
+        }
+    };
+    let first = parse(r#"<Record type="Steps" value="1"/>"#);
+    let second = parse(r#"<Record type="Steps" value="1"/>"#);
+    let key = |record: &GenericRecord| {
+        record
+            .attributes
+            .keys()
+            .find(|k| &***k == "value")
+            .cloned()
+            .unwrap()
+    };
+    assert!(Arc::ptr_eq(&key(&first), &key(&second)));
+
+    // Only repetitive attributes share their values
+    assert!(Arc::ptr_eq(
+        &first.attributes["type"],
+        &second.attributes["type"]
+    ));
+    assert!(!Arc::ptr_eq(
+        &first.attributes["value"],
+        &second.attributes["value"]
+    ));
+
+    let json = serde_json::to_string(&first).unwrap();
+    let back: GenericRecord = serde_json::from_str(&json).unwrap();
+    assert!(Arc::ptr_eq(&key(&first), &key(&back)));
+    assert!(Arc::ptr_eq(
+        &first.attributes["type"],
+        &back.attributes["type"]
+    ));
+}
+
 #[test]
 fn generic_record_grouping_key_for_record() {
     let xml = r#"<Record type="HKQuantityTypeIdentifierBodyMass" value="70" startDate="2020" endDate="2020" creationDate="2020" sourceName="watch"/>"#;
@@ -131,14 +227,14 @@ fn csv_sink_sorts_records_by_date() {
     let r1 = parse(xml1);
     let r2 = parse(xml2);
 
-    assert_eq!(r1.sort_key(), Some("2023-01-02T00:00:00Z"));
-    assert_eq!(r2.sort_key(), Some("2023-01-01T00:00:00Z"));
+    assert_eq!(r1.sort_key(), Some(1_672_617_600));
+    assert_eq!(r2.sort_key(), Some(1_672_531_200));
 
     let mut map: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
     map.entry("Steps".to_string()).or_default().extend([r1, r2]);
 
     let tmp = NamedTempFile::new().unwrap();
-    block_on(CsvZipSink.load(map, tmp.path())).unwrap();
+    block_on(CsvZipSink::default().load(map, tmp.path(), load_context())).unwrap();
 
     let file = File::open(tmp.path()).unwrap();
     let mut archive = ZipArchive::new(file).unwrap();
@@ -149,3 +245,2028 @@ fn csv_sink_sorts_records_by_date() {
     assert!(lines[1].contains("2023-01-01T00:00:00Z"));
     assert!(lines[2].contains("2023-01-02T00:00:00Z"));
 }
+
+#[test]
+fn dates_parse_to_offset_aware_timestamps() {
+    use gpt_os::apple_health::dates::parse_timestamp;
+
+    let utc = Some(1_672_588_800);
+    assert_eq!(parse_timestamp("2023-01-01T16:00:00Z"), utc);
+    assert_eq!(parse_timestamp("2023-01-01 08:00:00 -0800"), utc);
+    assert_eq!(parse_timestamp("2023-01-01T17:00:00+01:00"), utc);
+    assert_eq!(parse_timestamp("2023-01-01T16:00:00.250Z"), utc);
+    assert_eq!(parse_timestamp("2023-01-01"), Some(1_672_531_200));
+    assert_eq!(parse_timestamp("1969-12-31 23:59:59 +0000"), Some(-1));
+    assert_eq!(parse_timestamp("yesterday"), None);
+    assert_eq!(parse_timestamp("2023-13-01"), None);
+
+    // Across the end of daylight saving time the local strings sort the
+    // other way round from the instants they name
+    let parse = |xml: &str| {
+        let mut reader = Reader::from_str(xml);
+        match reader.read_event().unwrap() {
+            Event::Empty(e) => GenericRecord::from_xml(&e).unwrap(),
+            other => panic!("unexpected event {:?}", other),
+        }
+    };
+    let later = parse(r#"<Record type="Steps" startDate="2023-11-05 01:10:00 -0800"/>"#);
+    let earlier = parse(r#"<Record type="Steps" startDate="2023-11-05 01:30:00 -0700"/>"#);
+    assert!(earlier.sort_key() < later.sort_key());
+}
+
+#[test]
+fn compression_rejects_invalid_levels() {
+    assert!(Compression::new(CompressionMethod::Stored, Some(1)).is_err());
+    assert!(Compression::new(CompressionMethod::Deflate, Some(10)).is_err());
+    assert!(Compression::new(CompressionMethod::Zstd, Some(22)).is_ok());
+    assert!(Compression::new(CompressionMethod::Bzip2, None).is_ok());
+}
+
+#[test]
+fn csv_sink_uses_configured_compression_method() {
+    let mut map: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
+    let records = map.entry("Steps".to_string()).or_default();
+    for i in 0..1000 {
+        let mut attributes = AHashMap::new();
+        attributes.insert(
+            "startDate".into(),
+            format!("2023-01-01T00:00:{:02}Z", i % 60).into(),
+        );
+        attributes.insert("value".into(), i.to_string().into());
+        records.push(GenericRecord {
+            element_name: "Record".to_string(),
+            attributes,
+        });
+    }
+
+    let tmp = NamedTempFile::new().unwrap();
+    let compression = Compression::new(CompressionMethod::Zstd, Some(19)).unwrap();
+    block_on(CsvZipSink::new(compression).load(map, tmp.path(), load_context())).unwrap();
+
+    let file = File::open(tmp.path()).unwrap();
+    let mut archive = ZipArchive::new(file).unwrap();
+    let f = archive.by_index(0).unwrap();
+    assert_eq!(f.compression(), zip::CompressionMethod::Zstd);
+}
+
+#[test]
+fn csv_sink_writes_to_in_memory_and_stream_targets() {
+    let steps = || {
+        let mut map: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
+        let records = map.entry("Steps".to_string()).or_default();
+        for i in 0..1000 {
+            let mut attributes = AHashMap::new();
+            attributes.insert("value".into(), i.to_string().into());
+            records.push(GenericRecord {
+                element_name: "Record".to_string(),
+                attributes,
+            });
+        }
+        map
+    };
+    let sink = CsvZipSink::default();
+
+    let cursor = block_on(sink.load_to(steps(), Cursor::new(Vec::new()), load_context())).unwrap();
+    let streamed = block_on(sink.load_to_stream(steps(), Vec::new(), load_context())).unwrap();
+
+    for bytes in [cursor.into_inner(), streamed] {
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut csv_data = String::new();
+        archive
+            .by_name("Steps.csv")
+            .unwrap()
+            .read_to_string(&mut csv_data)
+            .unwrap();
+        assert_eq!(csv_data.lines().count(), 1001);
+        assert!(archive.by_name("SHA256SUMS").is_ok());
+    }
+}
+
+#[test]
+fn csv_sink_parallel_compression_matches_sequential_writer() {
+    let groups = || {
+        let mut map: AHashMap<String, Vec<GenericRecord>> = AHashMap::new();
+        for (group, count) in [("Steps", 3000), ("HeartRate", 2000), ("Tiny", 1)] {
+            let records = map.entry(group.to_string()).or_default();
+            for i in 0..count {
+                let mut attributes = AHashMap::new();
+                attributes.insert("value".into(), (i * 7 % 101).to_string().into());
+                records.push(GenericRecord {
+                    element_name: "Record".to_string(),
+                    attributes,
+                });
+            }
+        }
+        map
+    };
+    let sink = CsvZipSink::default();
+    let parallel = block_on(sink.load_to(groups(), Cursor::new(Vec::new()), load_context()))
+        .unwrap()
+        .into_inner();
+    let sequential = sink
+        .write_sequential(groups(), Cursor::new(Vec::new()), load_context())
+        .unwrap()
+        .into_inner();
+
+    let mut parallel = ZipArchive::new(Cursor::new(parallel)).unwrap();
+    let mut sequential = ZipArchive::new(Cursor::new(sequential)).unwrap();
+    assert_eq!(parallel.len(), sequential.len());
+    for i in 0..sequential.len() {
+        let mut expected = sequential.by_index(i).unwrap();
+        let mut actual = parallel.by_name(expected.name()).unwrap();
+        assert_eq!(actual.compression(), expected.compression());
+        let (mut a, mut e) = (Vec::new(), Vec::new());
+        actual.read_to_end(&mut a).unwrap();
+        expected.read_to_end(&mut e).unwrap();
+        assert_eq!(a, e);
+    }
+}
+
+#[test]
+fn conversion_builder_filters_types() {
+    let tmp = NamedTempFile::new().unwrap();
+    block_on(
+        ConversionBuilder::new("tests/fixtures/sample_export.xml")
+            .output(tmp.path())
+            .format(Format::Zip)
+            .types(["Workout", "HKQuantityTypeIdentifierBodyMass"])
+            .run(),
+    )
+    .unwrap();
+
+    let file = File::open(tmp.path()).unwrap();
+    let archive = ZipArchive::new(file).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        [
+            "HKQuantityTypeIdentifierBodyMass.csv",
+            "SHA256SUMS",
+            "Workout.csv",
+            "manifest.json"
+        ]
+    );
+}
+
+#[test]
+fn conversion_reports_progress() {
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    let tmp = NamedTempFile::new().unwrap();
+    block_on(
+        ConversionBuilder::new("tests/fixtures/sample_export.xml")
+            .output(tmp.path())
+            .progress(move |event| sink.lock().unwrap().push(event.clone()))
+            .run(),
+    )
+    .unwrap();
+
+    let events = events.lock().unwrap();
+    let size = std::fs::metadata("tests/fixtures/sample_export.xml")
+        .unwrap()
+        .len();
+    assert!(events.contains(&ProgressEvent::BytesRead {
+        bytes: size,
+        total: Some(size)
+    }));
+    assert!(events.contains(&ProgressEvent::RecordsParsed { records: 6 }));
+    assert!(events.contains(&ProgressEvent::GroupWritten {
+        name: "Workout".to_string(),
+        records: 1
+    }));
+}
+
+#[test]
+fn metrics_are_served_for_prometheus() {
+    use gpt_os::metrics::Metrics;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    let metrics = Arc::new(Metrics::default());
+    let observer = Arc::clone(&metrics);
+    let tmp = NamedTempFile::new().unwrap();
+    let report = block_on(
+        ConversionBuilder::new("tests/fixtures/sample_export.xml")
+            .output(tmp.path())
+            .progress(move |event| observer.observe(event))
+            .run(),
+    )
+    .unwrap();
+    metrics.finish(&report);
+
+    let addr = Arc::clone(&metrics)
+        .serve("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let get = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // A client that connects and sends nothing holds up no one else
+    let _idle = std::net::TcpStream::connect(addr).unwrap();
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    assert_eq!(body, metrics.render());
+    for line in [
+        "# TYPE gpt_os_records_parsed_total counter",
+        "gpt_os_records_parsed_total 6",
+        &format!("gpt_os_groups_written_total {}", report.record_counts.len()),
+        &format!("gpt_os_records_written_total {}", report.total_records()),
+        "gpt_os_errors_total 0",
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "{} missing from {}",
+            line,
+            body
+        );
+    }
+    assert!(body.contains("gpt_os_phase_duration_seconds{phase=\"load\"} "));
+    assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn chained_extractor_interleaves_every_source() {
+    use gpt_os::core::ChainedExtractor;
+    use tokio::sync::mpsc;
+
+    // Stands in for another data source of the export
+    struct Clinical(usize);
+
+    #[async_trait::async_trait]
+    impl Extractor<GenericRecord> for Clinical {
+        async fn extract(
+            &self,
+            _input_path: &std::path::Path,
+            _progress: &Progress,
+        ) -> gpt_os::Result<mpsc::Receiver<gpt_os::Result<GenericRecord>>> {
+            let (tx, rx) = mpsc::channel(4);
+            let records = self.0;
+            tokio::spawn(async move {
+                for i in 0..records {
+                    let mut attributes = AHashMap::new();
+                    attributes.insert("type".into(), "ClinicalRecord".into());
+                    attributes.insert("value".into(), i.to_string().into());
+                    let record = GenericRecord {
+                        element_name: "ClinicalRecord".to_string(),
+                        attributes,
+                    };
+                    let _ = tx.send(Ok(record)).await;
+                }
+            });
+            Ok(rx)
+        }
+    }
+
+    let extractor = ChainedExtractor::new()
+        .with(AppleHealthExtractor::new())
+        .with(Clinical(10));
+    let engine = Engine::new(extractor, CsvZipSink::default());
+    let tmp = NamedTempFile::new().unwrap();
+    let report = block_on(engine.run(
+        std::path::Path::new("tests/fixtures/sample_export.xml"),
+        tmp.path(),
+    ))
+    .unwrap();
+
+    assert_eq!(report.record_counts["ClinicalRecord"], 10);
+    assert_eq!(report.record_counts["Workout"], 1);
+    assert_eq!(report.total_records(), 16);
+}
+
+#[test]
+fn conversion_returns_run_report() {
+    let tmp = NamedTempFile::new().unwrap();
+    let report = block_on(
+        ConversionBuilder::new("tests/fixtures/sample_export.xml")
+            .output(tmp.path())
+            .run(),
+    )
+    .unwrap();
+
+    assert_eq!(report.total_records(), 6);
+    assert_eq!(report.record_counts["Workout"], 1);
+    assert_eq!(report.skipped_records(), 0);
+    assert_eq!(
+        report.output_bytes,
+        std::fs::metadata(tmp.path()).unwrap().len()
+    );
+    assert!(report.total_duration >= report.load_duration);
+}
+
+#[test]
+fn extractor_exposes_a_record_stream() {
+    let workouts: Vec<GenericRecord> = block_on(async {
+        AppleHealthExtractor::new()
+            .extract_stream(
+                "tests/fixtures/sample_export.xml".as_ref(),
+                &Progress::default(),
+            )
+            .await
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|record| record.grouping_key() == "Workout")
+            .collect()
+            .await
+    });
+
+    assert_eq!(workouts.len(), 1);
+    assert_eq!(workouts[0].element_name, "Workout");
+}
+
+#[test]
+fn conversion_uses_sinks_registered_by_name() {
+    struct CountSink;
+
+    #[async_trait::async_trait]
+    impl Sink<GenericRecord> for CountSink {
+        async fn load(
+            &self,
+            grouped_records: AHashMap<String, Vec<GenericRecord>>,
+            output_path: &std::path::Path,
+            _context: LoadContext<'_>,
+        ) -> gpt_os::Result<()> {
+            let total: usize = grouped_records.values().map(Vec::len).sum();
+            std::fs::write(output_path, total.to_string())?;
+            Ok(())
+        }
+    }
+
+    let mut registry = SinkRegistry::default();
+    registry.register("count", |_| Ok(Box::new(CountSink)));
+    assert_eq!(
+        registry.names().collect::<Vec<_>>(),
+        ["count", "iceberg", "zip"]
+    );
+
+    let tmp = NamedTempFile::new().unwrap();
+    let conversion = ConversionBuilder::new("tests/fixtures/sample_export.xml")
+        .output(tmp.path())
+        .registry(registry);
+    block_on(conversion.clone().format(Format::from("count")).run()).unwrap();
+    assert_eq!(std::fs::read_to_string(tmp.path()).unwrap(), "6");
+
+    let err = block_on(conversion.format(Format::from("parquet")).run()).unwrap_err();
+    assert!(err.to_string().contains("unknown output format 'parquet'"));
+}
+
+#[test]
+fn iceberg_table_references_its_parquet_files() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let dir = tempfile::tempdir().unwrap();
+    let table = dir.path().join("health");
+    let conversion = ConversionBuilder::new("tests/fixtures/sample_export.xml")
+        .output(&table)
+        .format(Format::from("iceberg"));
+    block_on(conversion.clone().run()).unwrap();
+
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(table.join("metadata/v1.metadata.json")).unwrap())
+            .unwrap();
+    assert_eq!(metadata["format-version"], 2);
+    let location = metadata["location"].as_str().unwrap();
+    let local = |uri: &str| table.join(uri.strip_prefix(location).unwrap().trim_start_matches('/'));
+    let manifest_list = metadata["snapshots"][0]["manifest-list"].as_str().unwrap();
+    assert!(
+        std::fs::read(local(manifest_list))
+            .unwrap()
+            .starts_with(b"Obj\x01")
+    );
+    let summary = &metadata["snapshots"][0]["summary"];
+    assert_eq!(summary["total-records"], "6");
+
+    let mut rows = 0;
+    for entry in walk(&table.join("data")) {
+        let file = SerializedFileReader::new(File::open(&entry).unwrap()).unwrap();
+        rows += file.metadata().file_metadata().num_rows();
+        let manifest = std::fs::read_dir(table.join("metadata"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.to_string_lossy().ends_with("-m0.avro"))
+            .unwrap();
+        let name = entry.file_name().unwrap().to_string_lossy().into_owned();
+        let manifest = std::fs::read(manifest).unwrap();
+        assert!(manifest.windows(name.len()).any(|w| w == name.as_bytes()));
+    }
+    assert_eq!(rows, 6);
+    assert!(
+        table
+            .join("data/group=Workout/startDate_year=2023")
+            .is_dir()
+    );
+
+    let err = block_on(conversion.run()).unwrap_err();
+    assert!(err.to_string().contains("already holds an Iceberg table"));
+}
+
+fn walk(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(walk(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+#[test]
+fn in_memory_conversion_writes_archive() {
+    let export = std::fs::read("tests/fixtures/sample_export.xml").unwrap();
+    let bytes = gpt_os::in_memory::convert_bytes(&export, Compression::default()).unwrap();
+
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort_unstable();
+    assert!(names.contains(&"Workout.csv"));
+    assert!(names.contains(&"export_info.csv"));
+    assert!(names.contains(&"manifest.json"));
+    assert!(names.contains(&"SHA256SUMS"));
+
+    let mut manifest = String::new();
+    archive
+        .by_name("manifest.json")
+        .unwrap()
+        .read_to_string(&mut manifest)
+        .unwrap();
+    assert!(manifest.contains("<memory>"));
+}
+
+#[test]
+fn skipped_records_are_reported_with_their_location() {
+    let mut xml = b"<HealthData><Record type=\"Steps\" value=\"1\"/>".to_vec();
+    let bad_offset = xml.len() as u64;
+    xml.extend_from_slice(b"<Record type=\"Steps\" value=\"\xff\"/></HealthData>");
+    let mut input = tempfile::Builder::new().suffix(".xml").tempfile().unwrap();
+    std::io::Write::write_all(&mut input, &xml).unwrap();
+
+    let tmp = NamedTempFile::new().unwrap();
+    let report = block_on(
+        ConversionBuilder::new(input.path())
+            .output(tmp.path())
+            .run(),
+    )
+    .unwrap();
+
+    assert_eq!(report.skipped_records(), 1);
+    let (category, skipped) = report.skipped.categories().next().unwrap();
+    assert_eq!(category, "Record/@value");
+    assert_eq!(skipped.first.offset, Some(bad_offset));
+    assert!(skipped.first.to_string().starts_with(&format!(
+        "<Record> attribute 'value' at byte {}: ",
+        bad_offset
+    )));
+
+    let mut archive = ZipArchive::new(File::open(tmp.path()).unwrap()).unwrap();
+    let mut errors = String::new();
+    archive
+        .by_name("errors.csv")
+        .unwrap()
+        .read_to_string(&mut errors)
+        .unwrap();
+    let mut lines = errors.lines();
+    assert_eq!(
+        lines.next(),
+        Some("element,attribute,offset,reason,snippet")
+    );
+    let row = lines.next().unwrap();
+    assert!(
+        row.starts_with(&format!("Record,value,{},", bad_offset)),
+        "{row}"
+    );
+    assert!(
+        row.ends_with(",\"<Record type=\"\"Steps\"\" value=\"\"\u{fffd}\"\"\""),
+        "{row}"
+    );
+    assert_eq!(lines.next(), None);
+
+    let validation =
+        gpt_os::validate::validate(input.path(), tmp.path(), &Default::default(), false).unwrap();
+    assert!(validation.types.iter().all(|t| t.name != "errors"));
+}
+
+#[test]
+fn bounded_run_spills_and_matches_in_memory_output() {
+    let mut xml = String::from("<HealthData>\n");
+    for i in 0..2000 {
+        // Interleave two types and emit dates out of order
+        let second = (i * 7919) % 2000;
+        let kind = if i % 2 == 0 { "Steps" } else { "Heart" };
+        xml.push_str(&format!(
+            "<Record type=\"{}\" startDate=\"2023-01-01T00:{:02}:{:02}Z\" value=\"{}\"/>\n",
+            kind,
+            second / 60,
+            second % 60,
+            i
+        ));
+    }
+    xml.push_str("</HealthData>\n");
+    let mut input = tempfile::Builder::new().suffix(".xml").tempfile().unwrap();
+    std::io::Write::write_all(&mut input, xml.as_bytes()).unwrap();
+
+    let engine = Engine::new(AppleHealthExtractor::new(), CsvZipSink::default());
+    let in_memory = NamedTempFile::new().unwrap();
+    let bounded = NamedTempFile::new().unwrap();
+    block_on(engine.run(input.path(), in_memory.path())).unwrap();
+    let report = block_on(engine.run_bounded(input.path(), bounded.path(), 16 * 1024)).unwrap();
+    assert_eq!(report.record_counts["Steps"], 1000);
+
+    let read = |path: &std::path::Path, name: &str| {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut data = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        data
+    };
+    for name in ["Steps.csv", "Heart.csv"] {
+        assert_eq!(read(in_memory.path(), name), read(bounded.path(), name));
+    }
+    let csv_sums = |path| {
+        read(path, "SHA256SUMS")
+            .lines()
+            .filter(|line| line.ends_with(".csv"))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(csv_sums(in_memory.path()), csv_sums(bounded.path()));
+}
+
+#[test]
+fn spilled_runs_are_merged_in_passes() {
+    use gpt_os::core::RecordCollector;
+    use gpt_os::spill::SpillingGroups;
+
+    // Each record spills on its own, so the group ends up with 3000 runs
+    // of records sharing one of ten start times
+    let mut groups = SpillingGroups::new(1).unwrap();
+    for i in 0..3000 {
+        let mut attributes = AHashMap::new();
+        attributes.insert(
+            "startDate".into(),
+            format!("2023-01-01T00:00:{:02}Z", (i * 7) % 10).into(),
+        );
+        attributes.insert("value".into(), i.to_string().into());
+        let record = GenericRecord {
+            element_name: "Record".to_string(),
+            attributes,
+        };
+        groups.push("Steps".to_string(), record).unwrap();
+    }
+    assert!(groups.runs() >= 3000);
+
+    let spilled = groups.finish().unwrap();
+    let group = &spilled.groups()[0];
+    assert!(group.runs() <= 64);
+    assert_eq!(group.len(), 3000);
+
+    // Records come out by start time, and in arrival order within one
+    let mut seen = Vec::new();
+    group
+        .for_each(|record| {
+            let second = record.field("startDate").unwrap()[17..19].to_string();
+            let value: usize = record.field("value").unwrap().parse().unwrap();
+            seen.push((second, value));
+            Ok(())
+        })
+        .unwrap();
+    let mut expected = seen.clone();
+    expected.sort();
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn checkpointed_spills_resume_after_an_interruption() {
+    use gpt_os::core::RecordCollector;
+    use gpt_os::spill::{CHECKPOINT_FILE_NAME, SpillingGroups, remove_checkpoint};
+
+    let input = NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut input.as_file(), b"<HealthData/>").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    // Every tenth record repeats the one before it
+    let records: Vec<(String, GenericRecord)> = (0..1000)
+        .map(|i| {
+            let value = if i % 10 == 9 { i - 1 } else { i };
+            let kind = if i % 3 == 0 { "Steps" } else { "Heart" };
+            let mut attributes = AHashMap::new();
+            attributes.insert("type".into(), kind.into());
+            attributes.insert("value".into(), value.to_string().into());
+            let record = GenericRecord {
+                element_name: "Record".to_string(),
+                attributes,
+            };
+            (kind.to_string(), record)
+        })
+        .collect();
+
+    // The first run stops part-way, after spilling some records
+    let mut groups = SpillingGroups::checkpointed(8 * 1024, dir.path(), input.path()).unwrap();
+    for (key, record) in records.iter().take(600).cloned() {
+        groups.push(key, record).unwrap();
+    }
+    assert!(groups.runs() > 0);
+    drop(groups);
+    assert!(dir.path().join(CHECKPOINT_FILE_NAME).exists());
+
+    // Reading the whole input again collects every record once
+    let mut groups = SpillingGroups::checkpointed(8 * 1024, dir.path(), input.path()).unwrap();
+    for (key, record) in records.iter().cloned() {
+        groups.push(key, record).unwrap();
+    }
+    let grouped = groups.finish().unwrap().into_map().unwrap();
+    for kind in ["Steps", "Heart"] {
+        let values = |records: &mut dyn Iterator<Item = &GenericRecord>| {
+            let mut values: Vec<String> = records
+                .map(|r| r.field("value").unwrap().to_string())
+                .collect();
+            values.sort();
+            values
+        };
+        let expected = values(&mut records.iter().filter(|(k, _)| k == kind).map(|(_, r)| r));
+        assert_eq!(values(&mut grouped[kind].iter()), expected);
+    }
+
+    remove_checkpoint(dir.path()).unwrap();
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    // A checkpoint for another input is refused
+    let other = NamedTempFile::new().unwrap();
+    let mut groups = SpillingGroups::checkpointed(1024, dir.path(), input.path()).unwrap();
+    for (key, record) in records.iter().take(200).cloned() {
+        groups.push(key, record).unwrap();
+    }
+    drop(groups);
+    assert!(SpillingGroups::<GenericRecord>::checkpointed(1024, dir.path(), other.path()).is_err());
+}
+
+#[test]
+fn spilled_groups_are_streamed_with_zip64() {
+    use gpt_os::core::RecordCollector;
+    use gpt_os::spill::SpillingGroups;
+
+    // A small group still gets Zip64 sizes, as its CSV size is not known
+    // before it is written
+    let mut groups = SpillingGroups::new(1).unwrap();
+    for i in 0..10 {
+        let mut attributes = AHashMap::new();
+        attributes.insert(
+            "startDate".into(),
+            format!("2023-01-01T00:00:{:02}Z", i).into(),
+        );
+        attributes.insert("value".into(), i.to_string().into());
+        let record = GenericRecord {
+            element_name: "Small".to_string(),
+            attributes,
+        };
+        groups.push("Small".to_string(), record).unwrap();
+    }
+
+    let output = NamedTempFile::new().unwrap();
+    let sink = CsvZipSink::new(Compression::default());
+    block_on(sink.load_spilled(groups.finish().unwrap(), output.path(), load_context())).unwrap();
+
+    let bytes = std::fs::read(output.path()).unwrap();
+    let mut archive = ZipArchive::new(Cursor::new(&bytes)).unwrap();
+    let start = archive.by_name("Small.csv").unwrap().header_start() as usize;
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+    // Version needed to extract: 4.5 for Zip64
+    assert_eq!(u16_at(start + 4), 45);
+    let (name_len, extra_len) = (u16_at(start + 26), u16_at(start + 28));
+    let mut extra = &bytes[start + 30 + name_len..start + 30 + name_len + extra_len];
+    let mut ids = Vec::new();
+    while extra.len() >= 4 {
+        ids.push(u16::from_le_bytes([extra[0], extra[1]]));
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        extra = &extra[4 + len..];
+    }
+    assert!(ids.contains(&0x0001), "{:?}", ids);
+}
+
+#[test]
+fn generated_export_is_reproducible_and_parses() {
+    use gpt_os::generate::{GenerateOptions, generate};
+    use gpt_os::in_memory::convert_bytes;
+
+    let options = GenerateOptions {
+        records: 5000,
+        types: 12,
+        seed: 7,
+    };
+    let mut first = Vec::new();
+    generate(&mut first, &options).unwrap();
+    let mut second = Vec::new();
+    generate(&mut second, &options).unwrap();
+    assert_eq!(first, second);
+
+    let archive = convert_bytes(&first, Compression::default()).unwrap();
+    let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+    let mut manifest = String::new();
+    archive
+        .by_name("manifest.json")
+        .unwrap()
+        .read_to_string(&mut manifest)
+        .unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    let counts = manifest["record_counts"].as_object().unwrap();
+    let records: u64 = counts
+        .iter()
+        .filter(|(name, _)| name.as_str() != "export_info")
+        .map(|(_, count)| count.as_u64().unwrap())
+        .sum();
+    assert_eq!(records, 5000);
+    let record_types = counts
+        .keys()
+        .filter(|name| name.starts_with("HKQuantityTypeIdentifier"))
+        .count();
+    assert_eq!(record_types, 12);
+    assert!(counts.contains_key("Workout"));
+    assert!(counts.contains_key("ActivitySummary"));
+
+    let zero_types = GenerateOptions {
+        types: 0,
+        ..options
+    };
+    assert!(generate(&mut Vec::new(), &zero_types).is_err());
+}
+
+#[test]
+fn byte_budget_blocks_producers_until_released() {
+    use gpt_os::backpressure::ByteBudget;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let budget = ByteBudget::new(100);
+    // An oversized item still passes when nothing else is queued
+    budget.acquire(150);
+    assert_eq!(budget.used(), 150);
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let producer = {
+        let budget = budget.clone();
+        std::thread::spawn(move || {
+            budget.acquire(10);
+            done_tx.send(()).unwrap();
+        })
+    };
+    assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    budget.release(150);
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    producer.join().unwrap();
+    assert_eq!(budget.used(), 10);
+
+    // Closing lets every producer through
+    budget.acquire(90);
+    budget.close();
+    budget.acquire(1000);
+}
+
+#[test]
+fn parse_size_accepts_binary_suffixes() {
+    use gpt_os::config::parse_size;
+
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("64k"), Ok(64 * 1024));
+    assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+    assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+    assert!(parse_size("12X").is_err());
+    assert!(parse_size("M").is_err());
+}
+
+#[test]
+fn parse_window_accepts_days_and_weeks() {
+    use gpt_os::config::parse_window;
+
+    assert_eq!(parse_window("90d"), Ok(90));
+    assert_eq!(parse_window("12W"), Ok(84));
+    assert_eq!(parse_window("30"), Ok(30));
+    assert!(parse_window("0d").is_err());
+    assert!(parse_window("3m").is_err());
+}
+
+#[test]
+fn parallel_parse_sends_every_record_before_returning() {
+    use gpt_os::backpressure::ByteBudget;
+    use gpt_os::xml_utils::process_stream_parallel;
+
+    let records = 2_345;
+    let mut xml = String::from("<HealthData>");
+    for i in 0..records {
+        xml.push_str(&format!(
+            r#"<Record type="Steps" value="{i}" startDate="2023-01-01 00:00:00 +0000"/>"#
+        ));
+    }
+    xml.push_str("</HealthData>");
+
+    // Unbounded so nothing is drained until the parse has returned
+    let (tx, rx) = crossbeam_channel::unbounded();
+    block_on(process_stream_parallel(
+        Cursor::new(xml.into_bytes()),
+        tx,
+        |e, _| GenericRecord::from_xml(e),
+        ByteBudget::new(4096),
+    ))
+    .unwrap();
+
+    let parsed: Vec<_> = rx.try_iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(parsed.len(), records);
+}
+
+#[test]
+fn parallel_parse_nests_medication_metadata() {
+    use gpt_os::backpressure::ByteBudget;
+    use gpt_os::xml_utils::process_stream_parallel;
+
+    // Enough elements to span several batches, whose boundaries must not
+    // separate a record from its metadata
+    let doses = 700;
+    let mut xml = String::from("<HealthData>");
+    for i in 0..doses {
+        xml.push_str(&format!(
+            r#"<Record type="HKMedicationDoseEvent" value="1" startDate="2023-01-01 08:00:00 +0000">
+<MetadataEntry key="dose" value="{i} mg"/>
+<MetadataEntry key="HKMetadataKeyWasUserEntered" value="1"/>
+</Record>"#
+        ));
+    }
+    xml.push_str(
+        r#"<Record type="HKQuantityTypeIdentifierStepCount" value="10" startDate="2023-01-01 08:00:00 +0000">
+<MetadataEntry key="HKMetadataKeyWasUserEntered" value="1"/>
+</Record>
+<Record type="HKCategoryTypeIdentifierMedicationReminder" value="1" startDate="2023-01-01 08:00:00 +0000">
+<MetadataEntry key="HKMetadataKeyWasUserEntered" value="1"/>
+</Record>
+<ClinicalRecord type="HKClinicalTypeIdentifierLabResultRecord" identifier="a" receivedDate="2023-01-02 08:00:00 +0000"/>
+</HealthData>"#,
+    );
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+    block_on(process_stream_parallel(
+        Cursor::new(xml.into_bytes()),
+        tx,
+        |e, _| GenericRecord::from_xml(e),
+        ByteBudget::new(4096),
+    ))
+    .unwrap();
+
+    let parsed: Vec<GenericRecord> = rx.try_iter().collect::<Result<_, _>>().unwrap();
+    let mut groups: AHashMap<String, Vec<&GenericRecord>> = AHashMap::new();
+    for record in &parsed {
+        groups
+            .entry(record.grouping_key())
+            .or_default()
+            .push(record);
+    }
+    let mut names: Vec<&str> = groups.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        [
+            "ClinicalLabResults",
+            "HKCategoryTypeIdentifierMedicationReminder",
+            "HKQuantityTypeIdentifierStepCount",
+            "Medications",
+            "MetadataEntry"
+        ]
+    );
+    let medications = &groups["Medications"];
+    assert_eq!(medications.len(), doses);
+    let mut seen: Vec<&str> = medications
+        .iter()
+        .map(|record| {
+            assert_eq!(record.field("wasUserEntered"), Some("1"));
+            record.field("dose").unwrap()
+        })
+        .collect();
+    seen.sort_unstable();
+    seen.dedup();
+    assert_eq!(seen.len(), doses);
+    // Metadata of other records keeps its own group, even of types merely
+    // mentioning medication
+    assert_eq!(groups["MetadataEntry"].len(), 2);
+}
+
+#[test]
+fn record_chunks_split_between_elements() {
+    use gpt_os::generate::{GenerateOptions, generate};
+    use gpt_os::xml_utils::{for_each_element, record_chunks};
+
+    let options = GenerateOptions {
+        records: 3000,
+        types: 8,
+        seed: 3,
+    };
+    let mut data = Vec::new();
+    generate(&mut data, &options).unwrap();
+
+    let chunks = record_chunks(&data, 4096);
+    assert!(chunks.len() > 10);
+    assert_eq!(chunks.first().unwrap().start, 0);
+    assert_eq!(chunks.last().unwrap().end, data.len());
+    for pair in chunks.windows(2) {
+        assert_eq!(pair[0].end, pair[1].start);
+        assert!(data[pair[1].clone()].starts_with(b"<Record "));
+    }
+
+    let mut expected = 0;
+    for_each_element(data.as_slice(), |_| expected += 1).unwrap();
+    let mut elements = 0;
+    for range in chunks {
+        let mut reader = Reader::from_reader(&data[range]);
+        reader.config_mut().check_end_names = false;
+        reader.config_mut().allow_unmatched_ends = true;
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) | Event::Empty(e) if e.name().as_ref() != b"HealthData" => {
+                    elements += 1
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(elements, expected);
+}
+
+#[test]
+fn prescan_counts_records_across_read_boundaries() {
+    use gpt_os::apple_health::prescan::count_record_types;
+    use gpt_os::generate::{GenerateOptions, generate};
+    use gpt_os::xml_utils::for_each_element;
+
+    /// Hands out a few bytes per read so matches straddle reads.
+    struct Trickle<'a>(&'a [u8]);
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(7);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    let options = GenerateOptions {
+        records: 2000,
+        types: 6,
+        seed: 11,
+    };
+    let mut data = Vec::new();
+    generate(&mut data, &options).unwrap();
+
+    let mut expected: AHashMap<String, usize> = AHashMap::new();
+    for_each_element(data.as_slice(), |e| {
+        if e.name().as_ref() == b"Record" {
+            let typ = e.try_get_attribute("type").unwrap().unwrap();
+            *expected
+                .entry(String::from_utf8(typ.value.into_owned()).unwrap())
+                .or_default() += 1;
+        }
+    })
+    .unwrap();
+
+    assert_eq!(count_record_types(data.as_slice()).unwrap(), expected);
+    assert_eq!(count_record_types(Trickle(&data)).unwrap(), expected);
+}
+
+#[test]
+fn doctype_end_honours_comments_and_quotes() {
+    use gpt_os::xml_utils::doctype_end;
+
+    let dtd = "<?xml version=\"1.0\"?>\n<!DOCTYPE HealthData [\n<!-- Record -> MetadataEntry -->\n<!ATTLIST Record device CDATA \"<<HKDevice>>\" note CDATA '>'>\n]>";
+    let doc = format!("{dtd}\n<HealthData/>");
+    assert_eq!(doctype_end(doc.as_bytes()), Some(dtd.len()));
+    assert_eq!(doctype_end(b"<HealthData locale=\"en_US\"/>"), Some(0));
+    assert_eq!(
+        doctype_end(b"<?xml version=\"1.0\"?>\n<HealthData/>"),
+        Some(0)
+    );
+    // Cut off inside the prologue
+    assert_eq!(doctype_end(&doc.as_bytes()[..dtd.len() - 1]), None);
+    assert_eq!(doctype_end(b"<?xml version=\"1.0\"?>\n<!DOC"), None);
+    assert_eq!(doctype_end(b" \n"), None);
+}
+
+#[test]
+fn xml_error_offsets_account_for_skipped_doctype() {
+    use gpt_os::error::AppError;
+    use gpt_os::xml_utils::for_each_element;
+
+    let body = "<HealthData><Record type=\"a\"/><!bogus></HealthData>";
+    let dtd = "<!DOCTYPE HealthData [<!-- a -> b --><!ELEMENT HealthData ANY>]>\n";
+    let offset = |doc: &str| match for_each_element(doc.as_bytes(), |_| {}) {
+        Err(AppError::Xml { offset, .. }) => offset,
+        other => panic!("expected an XML error, got {:?}", other),
+    };
+    assert_eq!(
+        offset(&format!("{dtd}{body}")),
+        offset(body) + dtd.len() as u64
+    );
+}
+
+#[test]
+fn timestamps_format_back_to_dates() {
+    use gpt_os::apple_health::dates::{format_timestamp, parse_timestamp};
+
+    for date in [
+        "1970-01-01T00:00:00Z",
+        "1969-12-31T23:59:59Z",
+        "2000-02-29T12:34:56Z",
+        "2023-03-12T10:00:00Z",
+        "2100-12-31T23:59:59Z",
+    ] {
+        assert_eq!(format_timestamp(parse_timestamp(date).unwrap()), date);
+    }
+    assert_eq!(
+        format_timestamp(parse_timestamp("2023-01-01 00:00:00 -0800").unwrap()),
+        "2023-01-01T08:00:00Z"
+    );
+}
+
+const HK_TYPE_IDENTIFIERS: [&str; 12] = [
+    "HKQuantityTypeIdentifierStepCount",
+    "HKQuantityTypeIdentifierHeartRate",
+    "HKQuantityTypeIdentifierBodyMass",
+    "HKQuantityTypeIdentifierActiveEnergyBurned",
+    "HKQuantityTypeIdentifierDistanceWalkingRunning",
+    "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+    "HKCategoryTypeIdentifierSleepAnalysis",
+    "HKCategoryTypeIdentifierAppleStandHour",
+    "HKCategoryTypeIdentifierMindfulSession",
+    "HKDataTypeSleepDurationGoal",
+    "Workout",
+    "ActivitySummary",
+];
+
+/// Whether `name` extracts as-is on Windows.
+fn is_windows_safe(name: &str) -> bool {
+    use gpt_os::sinks::file_names::MAX_ENTRY_NAME_LEN;
+    const RESERVED: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+    let Some(stem) = name.strip_suffix(".csv") else {
+        return false;
+    };
+    let device = stem.split('.').next().unwrap().trim_end();
+    let reserved = RESERVED.iter().any(|r| r.eq_ignore_ascii_case(device))
+        || device.get(..3).is_some_and(|prefix| {
+            ["COM", "LPT"]
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(prefix))
+                && matches!(device.as_bytes()[3..], [b'1'..=b'9'])
+        });
+    name.len() <= MAX_ENTRY_NAME_LEN
+        && !stem.is_empty()
+        && !stem.ends_with(['.', ' '])
+        && !reserved
+        && !name
+            .chars()
+            .any(|c| c.is_control() || "<>:\"/\\|?*".contains(c))
+}
+
+#[test]
+fn entry_file_names_keep_hk_type_identifiers() {
+    use gpt_os::sinks::file_names::{NameMap, entry_file_names};
+
+    let none = NameMap::new();
+    let names = entry_file_names(HK_TYPE_IDENTIFIERS, &none);
+    for (group, name) in HK_TYPE_IDENTIFIERS.iter().zip(&names) {
+        assert_eq!(name, &format!("{group}.csv"));
+    }
+    assert_eq!(
+        entry_file_names(
+            ["CON", "aux.tar", "Steps.", "a/b", "errors", "Statistics"],
+            &none
+        ),
+        [
+            "CON_.csv",
+            "aux_.tar.csv",
+            "Steps.csv",
+            "a_b.csv",
+            "errors_be4bd567.csv",
+            "Statistics_a656107e.csv"
+        ]
+    );
+
+    let names = NameMap::from([
+        (
+            "HKQuantityTypeIdentifierStepCount".to_string(),
+            "steps".to_string(),
+        ),
+        (
+            "HKQuantityTypeIdentifierBodyMass".to_string(),
+            "weight/kg".to_string(),
+        ),
+    ]);
+    assert_eq!(
+        entry_file_names(
+            [
+                "HKQuantityTypeIdentifierStepCount",
+                "HKQuantityTypeIdentifierBodyMass",
+                "HKQuantityTypeIdentifierHeartRate"
+            ],
+            &names
+        ),
+        [
+            "steps.csv",
+            "weight_kg.csv",
+            "HKQuantityTypeIdentifierHeartRate.csv"
+        ]
+    );
+}
+
+mod entry_file_name_properties {
+    use super::{HK_TYPE_IDENTIFIERS, is_windows_safe};
+    use gpt_os::sinks::file_names::{NameMap, entry_file_names};
+    use proptest::prelude::*;
+
+    /// Real identifiers, variants of them that sanitize alike, and noise.
+    fn group_name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(&HK_TYPE_IDENTIFIERS[..]).prop_map(str::to_string),
+            (
+                prop::sample::select(&HK_TYPE_IDENTIFIERS[..]),
+                "[:/. ]{0,3}",
+                any::<bool>()
+            )
+                .prop_map(|(id, suffix, upper)| {
+                    let id = if upper {
+                        id.to_uppercase()
+                    } else {
+                        id.to_string()
+                    };
+                    id + &suffix
+                }),
+            "(?i)(con|prn|aux|nul|com[1-9]|lpt[1-9])[. ]{0,2}[a-z]{0,3}",
+            ".{0,200}",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn names_are_windows_safe_and_unique(
+            groups in prop::collection::btree_set(group_name(), 1..12)
+        ) {
+            let names = entry_file_names(groups.iter().map(String::as_str), &NameMap::new());
+            prop_assert_eq!(names.len(), groups.len());
+            for name in &names {
+                prop_assert!(is_windows_safe(name), "{:?}", name);
+            }
+            let mut lowercase: Vec<String> = names.iter().map(|n| n.to_lowercase()).collect();
+            lowercase.sort_unstable();
+            lowercase.dedup();
+            prop_assert_eq!(lowercase.len(), names.len());
+        }
+
+        #[test]
+        fn names_are_stable_when_read_back(
+            groups in prop::collection::btree_set(group_name(), 1..12)
+        ) {
+            let names = entry_file_names(groups.iter().map(String::as_str), &NameMap::new());
+            let stems: Vec<&str> = names.iter().map(|n| n.strip_suffix(".csv").unwrap()).collect();
+            prop_assert_eq!(entry_file_names(stems, &NameMap::new()), names);
+        }
+    }
+}
+
+#[test]
+fn decimal_comma_only_changes_decimal_numbers() {
+    use gpt_os::sinks::csv_zip::{CsvDialect, DecimalSeparator};
+
+    let comma = CsvDialect {
+        decimal_separator: DecimalSeparator::Comma,
+        ..CsvDialect::default()
+    };
+    for (value, expected) in [
+        ("70.5", "70,5"),
+        ("-0.25", "-0,25"),
+        ("1.5e3", "1,5e3"),
+        ("72", "72"),
+        ("17.0.1", "17.0.1"),
+        (".5", ".5"),
+        ("2023-01-01 08:00:00 -0800", "2023-01-01 08:00:00 -0800"),
+        (
+            "HKCategoryValueSleepAnalysisAsleep",
+            "HKCategoryValueSleepAnalysisAsleep",
+        ),
+    ] {
+        assert_eq!(comma.number(value), expected);
+        assert_eq!(CsvDialect::default().number(value), value);
+    }
+}
+
+#[test]
+fn rounding_only_changes_decimal_numbers() {
+    use gpt_os::sinks::csv_zip::{CsvDialect, DecimalSeparator};
+
+    let two_places = CsvDialect {
+        decimal_places: Some(2),
+        ..CsvDialect::default()
+    };
+    for (value, expected) in [
+        ("74.83462809917356", "74.83"),
+        ("70.5", "70.5"),
+        ("70.001", "70"),
+        ("-0.001", "0"),
+        ("1.23456e2", "123.46"),
+        ("72", "72"),
+        ("17.0.1", "17.0.1"),
+        ("2023-01-01 08:00:00 -0800", "2023-01-01 08:00:00 -0800"),
+    ] {
+        assert_eq!(two_places.number(value), expected);
+    }
+
+    // Halves are rounded away from zero
+    for (places, value, expected) in [
+        (0, "70.5", "71"),
+        (0, "-2.5", "-3"),
+        (0, "0.5", "1"),
+        (2, "0.125", "0.13"),
+    ] {
+        let dialect = CsvDialect {
+            decimal_places: Some(places),
+            ..CsvDialect::default()
+        };
+        assert_eq!(dialect.round(value), expected);
+    }
+
+    let comma = CsvDialect {
+        decimal_separator: DecimalSeparator::Comma,
+        ..two_places
+    };
+    assert_eq!(comma.number("74.83462809917356"), "74,83");
+}
+
+#[test]
+fn case_variant_attributes_share_a_column() {
+    let xml = br#"<HealthData>
+<Record type="Steps" value="1" Unit="count" startDate="2023-01-01T00:00:00Z"/>
+<Record type="Steps" value="2" unit="count" startDate="2023-01-02T00:00:00Z"/>
+</HealthData>"#;
+    let bytes = gpt_os::in_memory::convert_bytes(xml, Compression::default()).unwrap();
+
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+    let mut csv = String::new();
+    archive
+        .by_name("Steps.csv")
+        .unwrap()
+        .read_to_string(&mut csv)
+        .unwrap();
+    assert_eq!(
+        csv.lines().collect::<Vec<_>>(),
+        [
+            "startDate,type,value,Unit",
+            "2023-01-01T00:00:00Z,Steps,1,count",
+            "2023-01-02T00:00:00Z,Steps,2,count",
+        ]
+    );
+}
+
+#[test]
+fn reconcile_reports_rows_missing_from_the_archive() {
+    use gpt_os::AppError;
+    use gpt_os::validate::reconcile;
+
+    let tmp = NamedTempFile::new().unwrap();
+    let report = block_on(
+        ConversionBuilder::new("tests/fixtures/sample_export.xml")
+            .output(tmp.path())
+            .run(),
+    )
+    .unwrap();
+    reconcile(
+        tmp.path(),
+        report.record_counts.clone(),
+        &Default::default(),
+    )
+    .unwrap();
+
+    let mut counts = report.record_counts;
+    *counts.get_mut("Workout").unwrap() += 1;
+    match reconcile(tmp.path(), counts, &Default::default()) {
+        Err(AppError::OutputMismatch(message)) => {
+            assert_eq!(message, "Workout has 1 rows for 2 records");
+        }
+        other => panic!("expected a mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn extractor_reports_parse_failures_after_every_record() {
+    let xml = std::fs::read_to_string("tests/fixtures/sample_export.xml").unwrap();
+    let cut = xml.find("<Workout").unwrap() + 20;
+    let mut input = tempfile::Builder::new().suffix(".xml").tempfile().unwrap();
+    std::io::Write::write_all(&mut input, &xml.as_bytes()[..cut]).unwrap();
+
+    for extractor in [
+        AppleHealthExtractor::new(),
+        AppleHealthExtractor::new().mmap(true),
+        AppleHealthExtractor::new().chunked(true),
+    ] {
+        let results: Vec<_> = block_on(async {
+            extractor
+                .extract_stream(input.path(), &Progress::default())
+                .await
+                .unwrap()
+                .collect()
+                .await
+        });
+
+        let (last, records) = results.split_last().unwrap();
+        assert!(
+            matches!(last, Err(gpt_os::AppError::Xml { .. })),
+            "{:?}",
+            last
+        );
+        assert!(records.iter().all(Result::is_ok));
+        assert_eq!(records.len(), 3);
+    }
+}
+
+#[test]
+fn sources_report_lists_each_source_per_type() {
+    use gpt_os::report::{GroupSummary, sources_csv};
+
+    let mut steps = GroupSummary::new("HKQuantityTypeIdentifierStepCount");
+    for (source, start) in [
+        ("Watch", "2023-01-02 09:00:00 -0500"),
+        ("iPhone", "2023-01-01 08:00:00 -0500"),
+        ("Watch", "2023-01-05 09:00:00 -0500"),
+        ("", "2023-01-03 09:00:00 -0500"),
+    ] {
+        let mut attributes = vec![(Arc::from("startDate"), Arc::from(start))];
+        if !source.is_empty() {
+            attributes.push((Arc::from("sourceName"), Arc::from(source)));
+        }
+        steps.add(&GenericRecord {
+            element_name: "Record".to_string(),
+            attributes: attributes.into_iter().collect(),
+        });
+    }
+
+    let csv = sources_csv(&[steps]).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "type,source,records,first,last\n\
+         HKQuantityTypeIdentifierStepCount,Watch,2,2023-01-02T14:00:00Z,2023-01-05T14:00:00Z\n\
+         HKQuantityTypeIdentifierStepCount,iPhone,1,2023-01-01T13:00:00Z,2023-01-01T13:00:00Z\n"
+    );
+}
+
+#[test]
+fn daily_summary_rolls_up_each_day() {
+    use gpt_os::report::{GroupSummary, daily_summary_csv, home_assistant_csv};
+
+    let record = |fields: &[(&str, &str)]| GenericRecord {
+        element_name: "Record".to_string(),
+        attributes: fields
+            .iter()
+            .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+            .collect(),
+    };
+    let mut steps = GroupSummary::new("HKQuantityTypeIdentifierStepCount");
+    for (source, start, value) in [
+        ("iPhone", "2023-01-01 08:00:00 -0500", "4000"),
+        ("iPhone", "2023-01-01 20:00:00 -0500", "2000"),
+        ("Watch", "2023-01-01 08:00:00 -0500", "5000"),
+        ("Watch", "2023-01-02 09:00:00 -0500", "300"),
+    ] {
+        steps.add(&record(&[
+            ("sourceName", source),
+            ("startDate", start),
+            ("value", value),
+        ]));
+    }
+    let mut heart = GroupSummary::new("HKQuantityTypeIdentifierRestingHeartRate");
+    for value in ["60", "63"] {
+        heart.add(&record(&[
+            ("startDate", "2023-01-02 07:00:00 -0500"),
+            ("value", value),
+        ]));
+    }
+    let mut sleep = GroupSummary::new("HKCategoryTypeIdentifierSleepAnalysis");
+    for (value, start, end) in [
+        (
+            "HKCategoryValueSleepAnalysisInBed",
+            "2023-01-01 22:00:00 -0500",
+            "2023-01-02 07:00:00 -0500",
+        ),
+        (
+            "HKCategoryValueSleepAnalysisAsleepCore",
+            "2023-01-01 23:00:00 -0500",
+            "2023-01-02 03:00:00 -0500",
+        ),
+        (
+            "HKCategoryValueSleepAnalysisAsleepREM",
+            "2023-01-02 03:00:00 -0500",
+            "2023-01-02 04:30:00 -0500",
+        ),
+    ] {
+        sleep.add(&record(&[
+            ("value", value),
+            ("startDate", start),
+            ("endDate", end),
+        ]));
+    }
+    let mut other = GroupSummary::new("HKQuantityTypeIdentifierBodyMass");
+    other.add(&record(&[
+        ("startDate", "2023-01-03 07:00:00 -0500"),
+        ("value", "70"),
+    ]));
+
+    let groups = [steps, heart, sleep, other];
+    let csv = daily_summary_csv(&groups).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "date,steps,active_energy_kcal,exercise_minutes,resting_heart_rate,sleep_minutes\n\
+         2023-01-01,6000,,,,\n\
+         2023-01-02,300,,,61.5,330\n"
+    );
+    let csv = home_assistant_csv(&groups).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "statistic_id,unit,start,min,max,mean\n\
+         apple_health:steps,steps,01.01.2023 00:00,6000,6000,6000\n\
+         apple_health:steps,steps,02.01.2023 00:00,300,300,300\n\
+         apple_health:resting_heart_rate,bpm,02.01.2023 00:00,61.5,61.5,61.5\n\
+         apple_health:sleep_minutes,min,02.01.2023 00:00,330,330,330\n"
+    );
+}
+
+#[test]
+fn correlations_pair_daily_metrics() {
+    use gpt_os::charts::correlation_heatmap;
+    use gpt_os::correlations::{CorrelationMatrix, correlations_csv};
+    use gpt_os::report::GroupSummary;
+
+    let record = |start: String, value: &str| GenericRecord {
+        element_name: "Record".to_string(),
+        attributes: [("startDate", start.as_str()), ("value", value)]
+            .iter()
+            .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+            .collect(),
+    };
+    let mut steps = GroupSummary::new("HKQuantityTypeIdentifierStepCount");
+    let mut heart = GroupSummary::new("HKQuantityTypeIdentifierRestingHeartRate");
+    let mut weight = GroupSummary::new("HKQuantityTypeIdentifierBodyMass");
+    for (day, count, bpm) in [(1, "1000", "60"), (2, "3000", "57"), (3, "2000", "59")] {
+        let start = format!("2023-01-0{} 08:00:00 -0500", day);
+        steps.add(&record(start.clone(), count));
+        heart.add(&record(start, bpm));
+    }
+    // Too few days to correlate
+    for day in [1, 2] {
+        weight.add(&record(format!("2023-01-0{} 07:00:00 -0500", day), "70"));
+    }
+
+    let matrix = CorrelationMatrix::new(&[steps, heart, weight]);
+    assert_eq!(matrix.metrics, ["steps", "resting_heart_rate", "body_mass"]);
+    assert_eq!(
+        String::from_utf8(correlations_csv(&matrix).unwrap()).unwrap(),
+        "metric,steps,resting_heart_rate,body_mass\n\
+         steps,1,-0.982,\n\
+         resting_heart_rate,-0.982,1,\n\
+         body_mass,,,\n"
+    );
+    let heatmap = correlation_heatmap(&matrix).unwrap().unwrap();
+    assert_eq!(heatmap.file_name, "charts/correlations.svg");
+    assert!(heatmap.svg.contains("resting_heart_rate"));
+    assert!(heatmap.svg.contains("-0.98"));
+
+    let single = CorrelationMatrix::new(&[GroupSummary::new("HKQuantityTypeIdentifierStepCount")]);
+    assert!(single.metrics.is_empty());
+    assert_eq!(correlation_heatmap(&single).unwrap(), None);
+}
+
+#[test]
+fn table_schema_widens_column_types() {
+    use gpt_os::sinks::csv_zip::{CsvDialect, DecimalSeparator};
+    use gpt_os::sinks::table_schema::{FieldType, TableSchema};
+    use std::borrow::Cow;
+
+    let rows = |schema: &mut TableSchema, rows: &[[&'static str; 4]]| {
+        for row in rows {
+            schema.add(&row.map(Cow::Borrowed));
+        }
+    };
+    let headers = ["count", "value", "date", "note"];
+    let mut schema = TableSchema::new(&headers, CsvDialect::default());
+    rows(
+        &mut schema,
+        &[
+            ["1", "2", "2023-01-01", ""],
+            ["-3", "2.5", "2023-01-01 08:00:00 -0500", ""],
+        ],
+    );
+    assert_eq!(
+        schema.field_types().collect::<Vec<_>>(),
+        [
+            ("count", FieldType::Integer),
+            ("value", FieldType::Number),
+            ("date", FieldType::String),
+            ("note", FieldType::String),
+        ]
+    );
+
+    let comma = CsvDialect {
+        decimal_separator: DecimalSeparator::Comma,
+        ..CsvDialect::default()
+    };
+    let mut schema = TableSchema::new(&headers, comma);
+    rows(
+        &mut schema,
+        &[
+            ["1", "2,5", "2023-01-01", "8.0"],
+            ["", "", "2023-01-02", ""],
+        ],
+    );
+    assert_eq!(
+        schema.field_types().map(|(_, t)| t).collect::<Vec<_>>(),
+        [
+            FieldType::Integer,
+            FieldType::Number,
+            FieldType::Date,
+            FieldType::String
+        ]
+    );
+    let json: serde_json::Value = serde_json::from_slice(&schema.to_json().unwrap()).unwrap();
+    assert_eq!(
+        json["fields"][1],
+        serde_json::json!({ "name": "value", "type": "number", "decimalChar": "," })
+    );
+    assert_eq!(json["missingValues"], serde_json::json!([""]));
+}
+
+#[test]
+fn streaks_count_closed_rings_per_month() {
+    use gpt_os::report::GroupSummary;
+    use gpt_os::streaks::streaks_csv;
+
+    let mut summary = GroupSummary::new("ActivitySummary");
+    for (date, energy, exercise, stand) in [
+        ("2023-01-30", "600", "40", "12"),
+        ("2023-01-31", "550", "10", "12"),
+        ("2023-02-01", "520", "35", "13"),
+        ("2023-02-02", "100", "30", "12"),
+        // No summary on the 3rd breaks the stand streak
+        ("2023-02-04", "500", "30", "12"),
+    ] {
+        let attributes = [
+            ("dateComponents", date),
+            ("activeEnergyBurned", energy),
+            ("activeEnergyBurnedGoal", "500"),
+            ("appleExerciseTime", exercise),
+            ("appleExerciseTimeGoal", "30"),
+            ("appleStandHours", stand),
+            ("appleStandHoursGoal", "12"),
+        ];
+        summary.add(&GenericRecord {
+            element_name: "ActivitySummary".to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+                .collect(),
+        });
+    }
+
+    let csv = String::from_utf8(streaks_csv(summary.rings()).unwrap()).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "period,days,move_closed,move_percent,move_longest_streak,\
+             exercise_closed,exercise_percent,exercise_longest_streak,\
+             stand_closed,stand_percent,stand_longest_streak,\
+             all_rings_closed,all_rings_percent,all_rings_longest_streak",
+            "all,5,4,80,3,4,80,2,5,100,4,3,60,1",
+            "2023-01,2,2,100,2,1,50,1,2,100,2,1,50,1",
+            "2023-02,3,2,66.7,1,3,100,2,3,100,2,2,66.7,1",
+        ]
+    );
+    assert_eq!(
+        String::from_utf8(streaks_csv(None).unwrap())
+            .unwrap()
+            .lines()
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn cycle_tracking_combines_types_per_day() {
+    use gpt_os::cycle::cycle_tracking_csv;
+    use gpt_os::report::GroupSummary;
+
+    let mut groups: Vec<GroupSummary> = Vec::new();
+    for (group, start, value) in [
+        (
+            "HKCategoryTypeIdentifierMenstrualFlow",
+            "2023-03-01 08:00:00 +0100",
+            "HKCategoryValueMenstrualFlowMedium",
+        ),
+        (
+            "HKCategoryTypeIdentifierMenstrualFlow",
+            "2023-03-02 08:00:00 +0100",
+            "HKCategoryValueMenstrualFlowLight",
+        ),
+        (
+            "HKCategoryTypeIdentifierAbdominalCramps",
+            "2023-03-01 09:00:00 +0100",
+            "HKCategoryValueSeverityModerate",
+        ),
+        (
+            "HKCategoryTypeIdentifierHeadache",
+            "2023-03-01 20:00:00 +0100",
+            "HKCategoryValueSeverityUnspecified",
+        ),
+        (
+            "HKCategoryTypeIdentifierHeadache",
+            "2023-03-02 20:00:00 +0100",
+            "HKCategoryValueSeverityNotPresent",
+        ),
+        (
+            "HKCategoryTypeIdentifierOvulationTestResult",
+            "2023-03-14 07:00:00 +0100",
+            "HKCategoryValueOvulationTestResultLuteinizingHormoneSurge",
+        ),
+        (
+            "HKCategoryTypeIdentifierSexualActivity",
+            "2023-03-14 22:00:00 +0100",
+            "HKCategoryValueNotApplicable",
+        ),
+        // Not a cycle-tracking type
+        (
+            "HKQuantityTypeIdentifierStepCount",
+            "2023-03-05 22:00:00 +0100",
+            "100",
+        ),
+    ] {
+        let index = match groups.iter().position(|g| g.name == group) {
+            Some(index) => index,
+            None => {
+                groups.push(GroupSummary::new(group));
+                groups.len() - 1
+            }
+        };
+        let attributes = [("startDate", start), ("value", value)];
+        groups[index].add(&GenericRecord {
+            element_name: "Record".to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+                .collect(),
+        });
+    }
+
+    let csv = cycle_tracking_csv(groups.iter().filter_map(GroupSummary::cycle_days)).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap().lines().collect::<Vec<_>>(),
+        [
+            "date,menstrual_flow,intermenstrual_bleeding,ovulation_test,cervical_mucus,\
+             basal_body_temperature,sexual_activity,pregnancy_test,progesterone_test,symptoms",
+            "2023-03-01,Medium,,,,,,,,AbdominalCramps (Moderate); Headache",
+            "2023-03-02,Light,,,,,,,,",
+            "2023-03-14,,,LuteinizingHormoneSurge,,,true,,,",
+        ]
+    );
+}
+
+#[test]
+fn audio_exposure_averages_sound_energy_per_day() {
+    use gpt_os::audio::audio_exposure_csv;
+    use gpt_os::report::GroupSummary;
+
+    let record = |start: &str, end: &str, value: &str| {
+        let attributes = [("startDate", start), ("endDate", end), ("value", value)];
+        GenericRecord {
+            element_name: "Record".to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+                .collect(),
+        }
+    };
+    let mut environment = GroupSummary::new("HKQuantityTypeIdentifierEnvironmentalAudioExposure");
+    // An hour at 90 dB and an hour at 70 dB average to 87 dB, not 80
+    environment.add(&record(
+        "2023-01-01 10:00:00 +0000",
+        "2023-01-01 11:00:00 +0000",
+        "90",
+    ));
+    environment.add(&record(
+        "2023-01-01 11:00:00 +0000",
+        "2023-01-01 12:00:00 +0000",
+        "70",
+    ));
+    let mut headphones = GroupSummary::new("HKQuantityTypeIdentifierHeadphoneAudioExposure");
+    headphones.add(&record(
+        "2023-01-02 08:00:00 +0000",
+        "2023-01-02 08:30:00 +0000",
+        "65",
+    ));
+
+    let groups = [environment, headphones];
+    let csv = audio_exposure_csv(groups.iter().filter_map(GroupSummary::audio_days)).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap().lines().collect::<Vec<_>>(),
+        [
+            "date,environmental_leq_db,environmental_max_db,environmental_minutes,\
+             environmental_over_threshold,headphone_leq_db,headphone_max_db,headphone_minutes,\
+             headphone_over_threshold",
+            "2023-01-01,87,90,120,true,,,,",
+            "2023-01-02,,,,,65,65,30,false",
+        ]
+    );
+}
+
+#[test]
+fn workouts_join_heart_rate_into_zones() {
+    use gpt_os::report::{GroupSummary, ReportFormat, Reports};
+    use gpt_os::zones::{HeartRateZones, heart_rate_zones_csv};
+
+    let record = |element: &str, attributes: &[(&str, &str)]| GenericRecord {
+        element_name: element.to_string(),
+        attributes: attributes
+            .iter()
+            .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+            .collect(),
+    };
+    let reports = Reports::default().with(ReportFormat::Zones);
+    let mut workouts = GroupSummary::for_reports("Workout", reports);
+    workouts.add(&record(
+        "Workout",
+        &[
+            ("workoutActivityType", "HKWorkoutActivityTypeRunning"),
+            ("startDate", "2023-01-01 10:00:00 +0000"),
+            ("endDate", "2023-01-01 10:05:00 +0000"),
+        ],
+    ));
+    let mut heart_rate = GroupSummary::for_reports("HKQuantityTypeIdentifierHeartRate", reports);
+    for (time, bpm) in [
+        ("09:59:00", "80"),
+        ("10:00:00", "100"),
+        ("10:00:30", "130"),
+        // Counts for a minute, not until the next sample
+        ("10:01:00", "150"),
+        ("10:04:00", "185"),
+        ("10:06:00", "90"),
+    ] {
+        heart_rate.add(&record(
+            "Record",
+            &[
+                ("startDate", &format!("2023-01-01 {} +0000", time)),
+                ("value", bpm),
+            ],
+        ));
+    }
+    let mut export_info = GroupSummary::for_reports("export_info", reports);
+    export_info.add(&record("ExportInfo", &[("dateOfBirth", "1983-06-15")]));
+    // Summaries for other reports leave the samples out
+    assert!(
+        GroupSummary::new("HKQuantityTypeIdentifierHeartRate")
+            .zone_source()
+            .is_none()
+    );
+
+    let groups = [export_info, heart_rate, workouts];
+    let sources = || groups.iter().filter_map(GroupSummary::zone_source);
+    // 39 years old at the workout: zones start at 90.5, 108.6, 126.7, 144.8
+    // and 162.9 bpm
+    let csv = heart_rate_zones_csv(sources(), HeartRateZones::default()).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap().lines().collect::<Vec<_>>(),
+        [
+            "workoutActivityType,startDate,endDate,samples,mean_bpm,peak_bpm,max_heart_rate,\
+             below_z1_minutes,z1_minutes,z2_minutes,z3_minutes,z4_minutes,z5_minutes",
+            "HKWorkoutActivityTypeRunning,2023-01-01 10:00:00 +0000,2023-01-01 10:05:00 +0000,\
+             4,141.3,185,181,0,0.5,0,0.5,1,1",
+        ]
+    );
+
+    let bounds = "100,120,140,160,180".parse::<HeartRateZones>().unwrap();
+    let csv = heart_rate_zones_csv(sources(), bounds).unwrap();
+    assert!(
+        String::from_utf8(csv)
+            .unwrap()
+            .ends_with(",4,141.3,185,,0,0.5,0.5,1,0,1\n")
+    );
+    assert!("180,160".parse::<HeartRateZones>().is_err());
+}
+
+#[test]
+fn workout_splits_interpolate_distance_samples() {
+    use gpt_os::report::{GroupSummary, ReportFormat, Reports};
+    use gpt_os::splits::workout_splits_csv;
+
+    let record = |element: &str, attributes: &[(&str, &str)]| GenericRecord {
+        element_name: element.to_string(),
+        attributes: attributes
+            .iter()
+            .map(|(k, v)| (Arc::from(*k), Arc::from(*v)))
+            .collect(),
+    };
+    let reports = Reports::default().with(ReportFormat::Splits);
+    let mut workouts = GroupSummary::for_reports("Workout", reports);
+    workouts.add(&record(
+        "Workout",
+        &[
+            ("workoutActivityType", "HKWorkoutActivityTypeRunning"),
+            ("sourceName", "Watch"),
+            ("startDate", "2023-01-01 10:00:00 +0000"),
+            ("endDate", "2023-01-01 10:20:00 +0000"),
+        ],
+    ));
+    let mut distance =
+        GroupSummary::for_reports("HKQuantityTypeIdentifierDistanceWalkingRunning", reports);
+    for (source, start, end, value, unit) in [
+        ("Watch", "10:00:00", "10:05:00", "800", "m"),
+        ("Watch", "10:05:00", "10:10:00", "0.8", "km"),
+        ("Watch", "10:10:00", "10:12:00", "400", "m"),
+        // The phone counted the same run
+        ("Phone", "10:00:00", "10:10:00", "1.5", "km"),
+    ] {
+        distance.add(&record(
+            "Record",
+            &[
+                ("sourceName", source),
+                ("startDate", &format!("2023-01-01 {} +0000", start)),
+                ("endDate", &format!("2023-01-01 {} +0000", end)),
+                ("value", value),
+                ("unit", unit),
+            ],
+        ));
+    }
+
+    let groups = [distance, workouts];
+    let csv = workout_splits_csv(groups.iter().filter_map(GroupSummary::split_source)).unwrap();
+    let start = "HKWorkoutActivityTypeRunning,2023-01-01 10:00:00 +0000";
+    assert_eq!(
+        String::from_utf8(csv).unwrap().lines().collect::<Vec<_>>(),
+        [
+            "workoutActivityType,startDate,unit,split,distance,split_seconds,elapsed_seconds,pace"
+                .to_string(),
+            format!("{},km,1,1,375,375,6:15", start),
+            format!("{},km,2,1,345,720,5:45", start),
+            format!("{},mi,1,1,603,603,10:03", start),
+            format!("{},mi,2,0.24,117,720,8:02", start),
+        ]
+    );
+}
+
+#[test]
+fn mindful_samples_merge_into_sessions() {
+    use gpt_os::mindfulness::mindful_sessions_csv;
+    use gpt_os::report::GroupSummary;
+
+    let mut summary = GroupSummary::new("HKCategoryTypeIdentifierMindfulSession");
+    for (source, start, end) in [
+        ("Calm", "10:30:00", "10:35:00"),
+        ("Calm", "10:00:00", "10:05:00"),
+        // Within a minute of the previous sample, and one inside it
+        ("Calm", "10:05:30", "10:10:00"),
+        ("Calm", "10:08:00", "10:09:00"),
+        ("Breathe", "10:02:00", "10:07:00"),
+    ] {
+        let attributes = [
+            ("sourceName", source.to_string()),
+            ("startDate", format!("2023-01-01 {} -0500", start)),
+            ("endDate", format!("2023-01-01 {} -0500", end)),
+        ];
+        summary.add(&GenericRecord {
+            element_name: "Record".to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (Arc::from(*k), Arc::from(v.as_str())))
+                .collect(),
+        });
+    }
+
+    let csv = String::from_utf8(mindful_sessions_csv(summary.mindful_samples()).unwrap()).unwrap();
+    assert_eq!(
+        csv.lines().collect::<Vec<_>>(),
+        [
+            "startDate,endDate,duration_minutes,sourceName,samples",
+            "2023-01-01 10:00:00 -0500,2023-01-01 10:10:00 -0500,9.5,Calm,3",
+            "2023-01-01 10:02:00 -0500,2023-01-01 10:07:00 -0500,5,Breathe,1",
+            "2023-01-01 10:30:00 -0500,2023-01-01 10:35:00 -0500,5,Calm,1",
+        ]
+    );
+}
+
+#[test]
+fn retrying_reader_recovers_from_transient_errors() {
+    use gpt_os::retry::{RetryPolicy, Retrying};
+    use std::io::{Error, ErrorKind};
+    use std::time::Duration;
+
+    // Fails with a timeout the first `failures` times it is read
+    struct Flaky {
+        failures: u32,
+        data: Cursor<Vec<u8>>,
+    }
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Error::new(ErrorKind::TimedOut, "mount went away"));
+            }
+            self.data.read(buf)
+        }
+    }
+    let flaky = |failures| Flaky {
+        failures,
+        data: Cursor::new(b"<HealthData/>".to_vec()),
+    };
+    let policy = RetryPolicy {
+        retries: 2,
+        backoff: Duration::from_millis(1),
+    };
+
+    let mut read = String::new();
+    Retrying::new(flaky(2), policy)
+        .read_to_string(&mut read)
+        .unwrap();
+    assert_eq!(read, "<HealthData/>");
+
+    let error = Retrying::new(flaky(3), policy)
+        .read_to_string(&mut String::new())
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+    // Errors that will not go away are not retried
+    let mut attempts = 0;
+    let result: std::io::Result<()> = policy.run("Reading", || {
+        attempts += 1;
+        Err(Error::from(ErrorKind::NotFound))
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+}
+
+#[test]
+fn selection_projects_records_matching_a_condition() {
+    use gpt_os::select::Selection;
+
+    let record = |typ: &str, value: &str| {
+        let mut attributes = AHashMap::new();
+        attributes.insert("type".into(), typ.into());
+        attributes.insert("value".into(), value.into());
+        GenericRecord {
+            element_name: "Record".to_string(),
+            attributes,
+        }
+    };
+    let light = record("HKQuantityTypeIdentifierBodyMass", "9.5");
+    let heavy = record("HKQuantityTypeIdentifierBodyMass", "80.2");
+    let steps = record("HKQuantityTypeIdentifierStepCount", "1200");
+
+    let selection = Selection::parse(
+        ".type, .value, .unit",
+        Some(r#".type == "HKQuantityTypeIdentifierBodyMass" and (.value > 10 or .value == "9.5")"#),
+    )
+    .unwrap();
+    // Numbers compare as numbers, so 9.5 < 10 < 80.2
+    assert!(selection.matches(&light) && selection.matches(&heavy));
+    assert!(!selection.matches(&steps));
+    assert_eq!(
+        selection.project(&heavy, false),
+        r#"["HKQuantityTypeIdentifierBodyMass","80.2",null]"#
+    );
+    assert_eq!(
+        selection.project(&heavy, true),
+        "HKQuantityTypeIdentifierBodyMass\t80.2\t"
+    );
+
+    let selection = Selection::parse(".value", Some(".unit == null")).unwrap();
+    assert!(selection.matches(&steps));
+    assert_eq!(selection.project(&steps, false), r#""1200""#);
+    let whole = Selection::parse(".", None).unwrap();
+    assert_eq!(
+        whole.project(&steps, false),
+        r#"{"type":"HKQuantityTypeIdentifierStepCount","value":"1200"}"#
+    );
+
+    for (projection, condition) in [
+        ("type", None),
+        (".type .value", None),
+        (".type", Some(".value >")),
+        (".type", Some("(.value > 1")),
+        (".type", Some(".value ~ 1")),
+    ] {
+        assert!(
+            Selection::parse(projection, condition).is_err(),
+            "{} --where {:?}",
+            projection,
+            condition
+        );
+    }
+}